@@ -0,0 +1,641 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// The age v1 file format, see https://age-encryption.org/v1
+//
+// A random 16 byte file key is wrapped once per recipient in a text header (X25519 or scrypt stanzas),
+// the header is authenticated with an HMAC keyed from the file key.
+// The payload is encrypted with the STREAM construction: ChaCha20Poly1305 over 64 KiB chunks,
+// the nonce is an 11 byte counter and a flag for the last chunk.
+// AgeEncryptor and AgeDecryptor work incrementally, encrypt and decrypt on whole buffers.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use bech32::{FromBase32, ToBase32, Variant};
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, KeyInit};
+use hmac::{Hmac, Mac};
+use rand_chacha::rand_core::{CryptoRng, RngCore};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::error::{Error, Result};
+
+const VERSION_LINE: &str = "age-encryption.org/v1";
+const X25519_LABEL: &[u8] = b"age-encryption.org/v1/X25519";
+const SCRYPT_LABEL: &[u8] = b"age-encryption.org/v1/scrypt";
+const RECIPIENT_HRP: &str = "age";
+const IDENTITY_HRP: &str = "age-secret-key-";
+const ARMOR_BEGIN: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+const ARMOR_END: &str = "-----END AGE ENCRYPTED FILE-----";
+const LINE_LEN: usize = 64;
+
+const FILE_KEY_LEN: usize = 16;
+const NONCE_LEN: usize = 16;
+const CHUNK_LEN: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+// Headers are read into memory, this bounds how much
+const MAX_HEADER_LEN: usize = 1024 * 1024;
+
+/// Work factor (log2 N) of new scrypt stanzas, the same as the age tool
+pub const DEFAULT_SCRYPT_WORK_FACTOR: u8 = 18;
+/// Highest work factor accepted when decrypting, each step doubles time and memory
+pub const DEFAULT_MAX_SCRYPT_WORK_FACTOR: u8 = 22;
+
+/// Someone a file is encrypted to
+#[derive(Clone)]
+pub enum AgeRecipient {
+    /// An `age1...` recipient, see parse_recipient
+    X25519(PublicKey),
+    /// A passphrase, it cannot be combined with other recipients
+    Scrypt {
+        passphrase: Vec<u8>,
+        work_factor: u8,
+    },
+}
+
+/// Something that can decrypt a file
+#[derive(Clone)]
+pub enum AgeIdentity {
+    /// An `AGE-SECRET-KEY-1...` identity, see parse_identity
+    X25519(StaticSecret),
+    Scrypt {
+        passphrase: Vec<u8>,
+        max_work_factor: u8,
+    },
+}
+
+/// Encode an X25519 public key as an `age1...` recipient
+pub fn encode_recipient(public_key: &PublicKey) -> Result<String> {
+    bech32::encode(
+        RECIPIENT_HRP,
+        public_key.as_bytes().to_base32(),
+        Variant::Bech32,
+    )
+    .map_err(|_| Error::Internal("Could not encode key"))
+}
+
+pub fn parse_recipient(recipient: &str) -> Result<PublicKey> {
+    Ok(PublicKey::from(parse_bech32(recipient, RECIPIENT_HRP)?))
+}
+
+/// Encode an X25519 secret key as an `AGE-SECRET-KEY-1...` identity
+pub fn encode_identity(secret: &StaticSecret) -> Result<String> {
+    let identity = bech32::encode(IDENTITY_HRP, secret.to_bytes().to_base32(), Variant::Bech32)
+        .map_err(|_| Error::Internal("Could not encode key"))?;
+    Ok(identity.to_uppercase())
+}
+
+/// Parse an identity, lines starting with # and empty lines of an identity file are skipped
+pub fn parse_identity(identity: &str) -> Result<StaticSecret> {
+    let identity = match identity
+        .lines()
+        .map(|v| v.trim())
+        .find(|v| !v.is_empty() && !v.starts_with('#'))
+    {
+        None => return Err(Error::NotFound("No identity found")),
+        Some(v) => v,
+    };
+    Ok(StaticSecret::from(parse_bech32(identity, IDENTITY_HRP)?))
+}
+
+fn parse_bech32(data: &str, expected_hrp: &str) -> Result<[u8; 32]> {
+    let (hrp, data, variant) = bech32::decode(data).map_err(Error::from)?;
+    if hrp != expected_hrp || variant != Variant::Bech32 {
+        return Err(Error::Unsupported("Invalid age key type"));
+    }
+    let key = Vec::<u8>::from_base32(&data).map_err(Error::from)?;
+    key.as_slice()
+        .try_into()
+        .map_err(|_| Error::Malformed("Invalid age key length"))
+}
+
+fn hkdf_sha256(ikm: &[u8], salt: &[u8], info: &[u8]) -> Result<[u8; 32]> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(salt).map_err(Error::from)?;
+    mac.update(ikm);
+    let prk = mac.finalize().into_bytes();
+    // One block is enough for 32 bytes
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&prk).map_err(Error::from)?;
+    mac.update(info);
+    mac.update(&[1]);
+    let mut output = [0u8; 32];
+    output.copy_from_slice(&mac.finalize().into_bytes());
+    Ok(output)
+}
+
+// Stanza bodies are wrapped with a fixed zero nonce, every wrapping key is used once
+fn wrap_file_key(key: &[u8; 32], file_key: &[u8; FILE_KEY_LEN]) -> Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(key));
+    let mut buffer = file_key.to_vec();
+    cipher
+        .encrypt_in_place(GenericArray::from_slice(&[0u8; 12]), &[], &mut buffer)
+        .map_err(|_| Error::Internal("Encryption failed"))?;
+    Ok(buffer)
+}
+
+fn unwrap_file_key(key: &[u8; 32], body: &[u8]) -> Option<[u8; FILE_KEY_LEN]> {
+    let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(key));
+    let mut buffer = body.to_vec();
+    cipher
+        .decrypt_in_place(GenericArray::from_slice(&[0u8; 12]), &[], &mut buffer)
+        .ok()?;
+    buffer.as_slice().try_into().ok()
+}
+
+fn scrypt_key(passphrase: &[u8], salt: &[u8], work_factor: u8) -> Result<[u8; 32]> {
+    let params = scrypt::Params::new(work_factor, 8, 1, 32).map_err(Error::from)?;
+    let mut full_salt = SCRYPT_LABEL.to_vec();
+    full_salt.extend_from_slice(salt);
+    let mut key = [0u8; 32];
+    scrypt::scrypt(passphrase, &full_salt, &params, &mut key).map_err(Error::from)?;
+    Ok(key)
+}
+
+struct Stanza {
+    tag: String,
+    args: Vec<String>,
+    body: Vec<u8>,
+}
+
+impl Stanza {
+    fn wrap<R: CryptoRng + RngCore>(
+        csprng: &mut R,
+        recipient: &AgeRecipient,
+        file_key: &[u8; FILE_KEY_LEN],
+    ) -> Result<Self> {
+        match recipient {
+            AgeRecipient::X25519(public_key) => {
+                let ephemeral = StaticSecret::random_from_rng(&mut *csprng);
+                let share = PublicKey::from(&ephemeral);
+                let shared_secret = ephemeral.diffie_hellman(public_key);
+                if !shared_secret.was_contributory() {
+                    return Err(Error::Malformed("Non-contributory Diffie-Hellman result"));
+                }
+                let mut salt = share.as_bytes().to_vec();
+                salt.extend_from_slice(public_key.as_bytes());
+                let key = hkdf_sha256(shared_secret.as_bytes(), &salt, X25519_LABEL)?;
+
+                Ok(Self {
+                    tag: "X25519".to_string(),
+                    args: alloc::vec![STANDARD_NO_PAD.encode(share.as_bytes())],
+                    body: wrap_file_key(&key, file_key)?,
+                })
+            }
+            AgeRecipient::Scrypt {
+                passphrase,
+                work_factor,
+            } => {
+                let mut salt = [0u8; 16];
+                csprng.fill_bytes(&mut salt);
+                let key = scrypt_key(passphrase, &salt, *work_factor)?;
+
+                Ok(Self {
+                    tag: "scrypt".to_string(),
+                    args: alloc::vec![STANDARD_NO_PAD.encode(salt), work_factor.to_string()],
+                    body: wrap_file_key(&key, file_key)?,
+                })
+            }
+        }
+    }
+
+    // Err for malformed stanzas of a known type, None if the identity does not match
+    fn unwrap(&self, identity: &AgeIdentity) -> Result<Option<[u8; FILE_KEY_LEN]>> {
+        match (self.tag.as_str(), identity) {
+            ("X25519", AgeIdentity::X25519(secret)) => {
+                if self.args.len() != 1 || self.body.len() != FILE_KEY_LEN + TAG_LEN {
+                    return Err(Error::Malformed("Malformed X25519 stanza"));
+                }
+                let share: [u8; 32] = decode_base64(&self.args[0])?
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| Error::Malformed("Malformed X25519 stanza"))?;
+                let shared_secret = secret.diffie_hellman(&PublicKey::from(share));
+                if !shared_secret.was_contributory() {
+                    return Err(Error::Malformed("Non-contributory Diffie-Hellman result"));
+                }
+                let mut salt = share.to_vec();
+                salt.extend_from_slice(PublicKey::from(secret).as_bytes());
+                let key = hkdf_sha256(shared_secret.as_bytes(), &salt, X25519_LABEL)?;
+                Ok(unwrap_file_key(&key, &self.body))
+            }
+            (
+                "scrypt",
+                AgeIdentity::Scrypt {
+                    passphrase,
+                    max_work_factor,
+                },
+            ) => {
+                if self.args.len() != 2 || self.body.len() != FILE_KEY_LEN + TAG_LEN {
+                    return Err(Error::Malformed("Malformed scrypt stanza"));
+                }
+                let salt = decode_base64(&self.args[0])?;
+                let work_factor = &self.args[1];
+                if salt.len() != 16 || work_factor.starts_with('0') {
+                    return Err(Error::Malformed("Malformed scrypt stanza"));
+                }
+                let work_factor: u8 = work_factor.parse().map_err(Error::from)?;
+                if work_factor > *max_work_factor {
+                    return Err(Error::TooLarge("scrypt work factor is too high"));
+                }
+                let key = scrypt_key(passphrase, &salt, work_factor)?;
+                Ok(unwrap_file_key(&key, &self.body))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn encode(&self, output: &mut String) {
+        output.push_str("-> ");
+        output.push_str(&self.tag);
+        for arg in &self.args {
+            output.push(' ');
+            output.push_str(arg);
+        }
+        output.push('\n');
+
+        // Every line is full except the last, which may be empty
+        let body = STANDARD_NO_PAD.encode(&self.body);
+        let mut rest = body.as_str();
+        loop {
+            let (line, next) = rest.split_at(rest.len().min(LINE_LEN));
+            output.push_str(line);
+            output.push('\n');
+            if line.len() < LINE_LEN {
+                break;
+            }
+            rest = next;
+        }
+    }
+}
+
+fn decode_base64(data: &str) -> Result<Vec<u8>> {
+    // Canonical unpadded base64 only
+    STANDARD_NO_PAD.decode(data).map_err(Error::from)
+}
+
+fn header_mac(file_key: &[u8; FILE_KEY_LEN], header: &[u8]) -> Result<Hmac<Sha256>> {
+    let key = hkdf_sha256(file_key, &[], b"header")?;
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key).map_err(Error::from)?;
+    mac.update(header);
+    Ok(mac)
+}
+
+struct Header {
+    stanzas: Vec<Stanza>,
+    mac: Vec<u8>,
+    // Length of the authenticated part, up to and including "---"
+    mac_input_len: usize,
+}
+
+// Parse the header from the start of `data`. Ok(None) if more data is needed,
+// otherwise the header and the length of the header including the final newline.
+fn parse_header(data: &[u8]) -> Result<Option<(Header, usize)>> {
+    let mut position = 0;
+    let next_line = |position: &mut usize| -> Result<Option<(usize, &str)>> {
+        let end = match data[*position..].iter().position(|v| *v == b'\n') {
+            None => return Ok(None),
+            Some(v) => *position + v,
+        };
+        let start = *position;
+        let line = core::str::from_utf8(&data[start..end]).map_err(Error::from)?;
+        *position = end + 1;
+        Ok(Some((start, line)))
+    };
+    let incomplete = |data: &[u8]| match data.len() > MAX_HEADER_LEN {
+        true => Err(Error::TooLarge("age header is too long")),
+        false => Ok(None),
+    };
+
+    match next_line(&mut position)? {
+        None => return incomplete(data),
+        Some((_, line)) if line == VERSION_LINE => (),
+        Some(_) => return Err(Error::Unsupported("Not an age v1 file")),
+    }
+
+    let mut stanzas = Vec::new();
+    loop {
+        let (start, line) = match next_line(&mut position)? {
+            None => return incomplete(data),
+            Some(v) => v,
+        };
+        if let Some(mac) = line.strip_prefix("--- ") {
+            let mac = decode_base64(mac)?;
+            if mac.len() != 32 {
+                return Err(Error::Malformed("Malformed age header MAC"));
+            }
+            let header = Header {
+                stanzas,
+                mac,
+                mac_input_len: start + 3,
+            };
+            return Ok(Some((header, position)));
+        }
+        let line = match line.strip_prefix("-> ") {
+            None => return Err(Error::Malformed("Malformed age header")),
+            Some(v) => v,
+        };
+        let mut args: Vec<String> = line.split(' ').map(|v| v.to_string()).collect();
+        if args
+            .iter()
+            .any(|v| v.is_empty() || !v.bytes().all(|c| (0x21..=0x7e).contains(&c)))
+        {
+            return Err(Error::Malformed("Malformed age stanza"));
+        }
+        let tag = args.remove(0);
+
+        let mut body = String::new();
+        loop {
+            let line = match next_line(&mut position)? {
+                None => return incomplete(data),
+                Some((_, v)) => v,
+            };
+            if line.len() > LINE_LEN {
+                return Err(Error::Malformed("Malformed age stanza"));
+            }
+            body.push_str(line);
+            if line.len() < LINE_LEN {
+                break;
+            }
+        }
+        stanzas.push(Stanza {
+            tag,
+            args,
+            body: decode_base64(&body)?,
+        });
+    }
+}
+
+fn chunk_nonce(counter: u64, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[3..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+fn payload_cipher(file_key: &[u8; FILE_KEY_LEN], nonce: &[u8]) -> Result<ChaCha20Poly1305> {
+    let key = hkdf_sha256(file_key, nonce, b"payload")?;
+    Ok(ChaCha20Poly1305::new(GenericArray::from_slice(&key)))
+}
+
+/// Incremental encryption, the output of new, update and finish together form the file
+pub struct AgeEncryptor {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    buffer: Vec<u8>,
+}
+
+impl AgeEncryptor {
+    /// Returns the encryptor and the header with the payload nonce
+    pub fn new<R: CryptoRng + RngCore>(
+        csprng: &mut R,
+        recipients: &[AgeRecipient],
+    ) -> Result<(Self, Vec<u8>)> {
+        if recipients.is_empty() {
+            return Err(Error::InvalidArgument("No recipients"));
+        }
+        if recipients.len() > 1
+            && recipients
+                .iter()
+                .any(|v| matches!(v, AgeRecipient::Scrypt { .. }))
+        {
+            return Err(Error::InvalidArgument(
+                "A passphrase cannot be combined with other recipients",
+            ));
+        }
+
+        let mut file_key = [0u8; FILE_KEY_LEN];
+        csprng.fill_bytes(&mut file_key);
+
+        let mut header = VERSION_LINE.to_string();
+        header.push('\n');
+        for recipient in recipients {
+            Stanza::wrap(csprng, recipient, &file_key)?.encode(&mut header);
+        }
+        header.push_str("---");
+        let mac = header_mac(&file_key, header.as_bytes())?
+            .finalize()
+            .into_bytes();
+        header.push(' ');
+        header.push_str(&STANDARD_NO_PAD.encode(mac));
+        header.push('\n');
+
+        let mut nonce = [0u8; NONCE_LEN];
+        csprng.fill_bytes(&mut nonce);
+        let mut output = header.into_bytes();
+        output.extend_from_slice(&nonce);
+
+        Ok((
+            Self {
+                cipher: payload_cipher(&file_key, &nonce)?,
+                counter: 0,
+                buffer: Vec::new(),
+            },
+            output,
+        ))
+    }
+
+    fn seal_chunk(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>> {
+        let nonce = chunk_nonce(self.counter, last);
+        self.counter = match self.counter.checked_add(1) {
+            None => return Err(Error::TooLarge("age payload is too long")),
+            Some(v) => v,
+        };
+        let mut buffer = chunk.to_vec();
+        self.cipher
+            .encrypt_in_place(GenericArray::from_slice(&nonce), &[], &mut buffer)
+            .map_err(|_| Error::Internal("Encryption failed"))?;
+        Ok(buffer)
+    }
+
+    /// Encrypt what fills complete chunks, the rest is kept for the next call
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.buffer.extend_from_slice(data);
+        let mut output = Vec::new();
+        // A full chunk can still be the last one, so keep it until more data arrives
+        while self.buffer.len() > CHUNK_LEN {
+            let chunk: Vec<u8> = self.buffer.drain(..CHUNK_LEN).collect();
+            output.extend_from_slice(&self.seal_chunk(&chunk, false)?);
+        }
+        Ok(output)
+    }
+
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        let chunk = core::mem::take(&mut self.buffer);
+        self.seal_chunk(&chunk, true)
+    }
+}
+
+/// Incremental decryption, returns plaintext once it is authenticated
+pub struct AgeDecryptor {
+    identities: Vec<AgeIdentity>,
+    cipher: Option<ChaCha20Poly1305>,
+    counter: u64,
+    buffer: Vec<u8>,
+}
+
+impl AgeDecryptor {
+    pub fn new(identities: Vec<AgeIdentity>) -> Self {
+        Self {
+            identities,
+            cipher: None,
+            counter: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    // Parse the header once it is complete and set up the payload cipher
+    fn read_header(&mut self) -> Result<()> {
+        let (header, len) = match parse_header(&self.buffer)? {
+            None => return Ok(()),
+            Some(v) => v,
+        };
+        if self.buffer.len() < len + NONCE_LEN {
+            return Ok(());
+        }
+
+        if header.stanzas.iter().any(|v| v.tag == "scrypt") && header.stanzas.len() != 1 {
+            return Err(Error::Malformed("scrypt stanza must be the only stanza"));
+        }
+        let mut file_key = None;
+        'stanzas: for stanza in &header.stanzas {
+            for identity in &self.identities {
+                if let Some(v) = stanza.unwrap(identity)? {
+                    file_key = Some(v);
+                    break 'stanzas;
+                }
+            }
+        }
+        let file_key = match file_key {
+            None => return Err(Error::NotFound("No identity matches the age file")),
+            Some(v) => v,
+        };
+        header_mac(&file_key, &self.buffer[..header.mac_input_len])?
+            .verify_slice(&header.mac)
+            .map_err(|_| Error::Authentication("age header MAC mismatch"))?;
+
+        self.cipher = Some(payload_cipher(
+            &file_key,
+            &self.buffer[len..len + NONCE_LEN],
+        )?);
+        self.buffer.drain(..len + NONCE_LEN);
+        Ok(())
+    }
+
+    fn open_chunk(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>> {
+        let cipher = match &self.cipher {
+            None => return Err(Error::Malformed("age header is incomplete")),
+            Some(v) => v,
+        };
+        let nonce = chunk_nonce(self.counter, last);
+        let mut buffer = chunk.to_vec();
+        cipher
+            .decrypt_in_place(GenericArray::from_slice(&nonce), &[], &mut buffer)
+            .map_err(Error::from)?;
+        self.counter = match self.counter.checked_add(1) {
+            None => return Err(Error::TooLarge("age payload is too long")),
+            Some(v) => v,
+        };
+        Ok(buffer)
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.buffer.extend_from_slice(data);
+        if self.cipher.is_none() {
+            self.read_header()?;
+        }
+        let mut output = Vec::new();
+        if self.cipher.is_none() {
+            return Ok(output);
+        }
+        while self.buffer.len() > CHUNK_LEN + TAG_LEN {
+            let chunk: Vec<u8> = self.buffer.drain(..CHUNK_LEN + TAG_LEN).collect();
+            output.extend_from_slice(&self.open_chunk(&chunk, false)?);
+        }
+        Ok(output)
+    }
+
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        if self.cipher.is_none() {
+            self.read_header()?;
+        }
+        let chunk = core::mem::take(&mut self.buffer);
+        // Only an empty file has an empty last chunk
+        if chunk.len() == TAG_LEN && self.counter > 0 {
+            return Err(Error::Malformed("Empty last chunk in age payload"));
+        }
+        self.open_chunk(&chunk, true)
+    }
+}
+
+/// Encrypt a whole file, optionally ASCII armored
+pub fn encrypt<R: CryptoRng + RngCore>(
+    csprng: &mut R,
+    recipients: &[AgeRecipient],
+    data: &[u8],
+    armored: bool,
+) -> Result<Vec<u8>> {
+    let (mut encryptor, mut output) = AgeEncryptor::new(csprng, recipients)?;
+    output.extend_from_slice(&encryptor.update(data)?);
+    output.extend_from_slice(&encryptor.finish()?);
+    match armored {
+        true => Ok(armor(&output).into_bytes()),
+        false => Ok(output),
+    }
+}
+
+/// Decrypt a whole file, armored or binary
+pub fn decrypt(identities: Vec<AgeIdentity>, data: &[u8]) -> Result<Vec<u8>> {
+    let trimmed = data.trim_ascii_start();
+    let binary = match trimmed.starts_with(ARMOR_BEGIN.as_bytes()) {
+        true => dearmor(core::str::from_utf8(trimmed).map_err(Error::from)?)?,
+        false => data.to_vec(),
+    };
+    let mut decryptor = AgeDecryptor::new(identities);
+    let mut output = decryptor.update(&binary)?;
+    output.extend_from_slice(&decryptor.finish()?);
+    Ok(output)
+}
+
+/// PEM style ASCII armor of an age file
+pub fn armor(data: &[u8]) -> String {
+    let encoded = STANDARD.encode(data);
+    let mut output = ARMOR_BEGIN.to_string();
+    output.push('\n');
+    for line in encoded.as_bytes().chunks(LINE_LEN) {
+        // Base64 is ASCII
+        output.push_str(&String::from_utf8_lossy(line));
+        output.push('\n');
+    }
+    output.push_str(ARMOR_END);
+    output.push('\n');
+    output
+}
+
+pub fn dearmor(data: &str) -> Result<Vec<u8>> {
+    let mut lines = data.trim().lines().map(|v| v.trim_end_matches('\r'));
+    if lines.next() != Some(ARMOR_BEGIN) {
+        return Err(Error::Malformed("Missing armor begin line"));
+    }
+    let lines: Vec<&str> = lines.collect();
+    let (end, body) = match lines.split_last() {
+        None => return Err(Error::Malformed("Missing armor end line")),
+        Some(v) => v,
+    };
+    if *end != ARMOR_END {
+        return Err(Error::Malformed("Missing armor end line"));
+    }
+    if let Some((last, full)) = body.split_last() {
+        if last.len() > LINE_LEN || full.iter().any(|v| v.len() != LINE_LEN) {
+            return Err(Error::Malformed("Invalid armor line length"));
+        }
+    }
+    STANDARD.decode(body.concat()).map_err(Error::from)
+}
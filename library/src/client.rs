@@ -19,24 +19,111 @@
 // - ed25519 for signing
 // - chacha20-poly1305 for encryption
 
+// With a pre-shared key (PSK) both sides sign HMAC(psk, ephemeral public key) along with the key,
+// a peer with a different PSK fails the signature check. The PSK is hashed into the session key,
+// so the session stays secret as long as either the PSK or the ephemeral keys are.
+
+// In the hybrid mode the initiator also sends an ML-KEM-768 encapsulation key,
+// the responder answers with a ciphertext and both shared secrets are hashed into the session key
+
 use alloc::string::String;
 use alloc::string::ToString;
 use alloc::vec::Vec;
-use anyhow::Error;
 use argon2::Argon2;
-use chacha20poly1305::aead::generic_array::GenericArray;
-use chacha20poly1305::{AeadCore, AeadInPlace, KeyInit, XChaCha20Poly1305};
 use ed25519_dalek::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey};
 use ed25519_dalek::SecretKey;
 use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hmac::{Hmac, Mac};
 use lz4_flex::compress_prepend_size;
 use rand_chacha::rand_core::{RngCore, SeedableRng};
 use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
 use x25519_dalek::{PublicKey, StaticSecret};
 
+use crate::age;
+use crate::compression;
+pub use crate::compression::Compression;
+use crate::envelope::{self, Envelope, Stanza};
+pub use crate::envelope::{EnvelopeRecipient, OpenedEnvelope};
+pub use crate::error::Error;
+use crate::error::Result;
+use crate::group::{Group, SenderKeyMessage};
+pub use crate::group::{GroupId, SenderKeyDistribution};
+use crate::hpke;
+use crate::identity;
+pub use crate::identity::Certificate;
+pub use crate::limits::Limits;
+pub use crate::message::MessageOptions;
+pub use crate::message::ReceivedMessage;
+use crate::message::{self, MessageHeader};
+use crate::minisign;
+use crate::mlkem;
+use crate::noise;
+use crate::padding;
+pub use crate::padding::Padding;
+pub use crate::sealed_sender::SealedSenderMessage;
+use crate::sealed_sender::{self, SealedContent, SenderCertificate};
+use crate::session::{direction_key, Session};
+pub use crate::session::{Delivery, RekeyPolicy, SessionId};
+use crate::signing;
+pub use crate::signing::{SignStream, SignatureInfo, VerifyStream};
+use crate::stream;
+pub use crate::stream::{StreamDecryptor, StreamEncryptor};
+use crate::suite;
+pub use crate::suite::CipherSuite;
+
+/// Key exchange mode, chosen by the initiator and signed in the kex packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum KexMode {
+    /// X25519 only, the shared key is the Diffie-Hellman output
+    X25519,
+    /// X25519 combined with ML-KEM-768
+    HybridMlKem768,
+}
+
+impl KexMode {
+    pub(crate) fn id(&self) -> u8 {
+        match self {
+            KexMode::X25519 => 0,
+            KexMode::HybridMlKem768 => 1,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Result<Self> {
+        match id {
+            0 => Ok(KexMode::X25519),
+            1 => Ok(KexMode::HybridMlKem768),
+            _ => Err(Error::Unsupported("Unknown key exchange mode")),
+        }
+    }
+}
+
+pub(crate) struct PendingKex {
+    pub(crate) ephemeral_key: StaticSecret,
+    pub(crate) mode: KexMode,
+    pub(crate) kem_key: Option<mlkem::DecapsulationKey>,
+    pub(crate) psk_id: Option<String>,
+}
+
+// A key exchange finished on our side, before its session is stored
+pub(crate) struct KexOutcome {
+    pub(crate) mode: KexMode,
+    // Certified identity of the peer, the only sender accepted in the session
+    pub(crate) peer_key: VerifyingKey,
+    pub(crate) shared_key: [u8; 32],
+    pub(crate) suite: CipherSuite,
+}
+
+// Oldest sessions are dropped once a peer has more than this
+const MAX_SESSIONS_PER_PEER: usize = 16;
+// Starts password protected exports that record their cipher suite
+const PASSWORD_MAGIC: [u8; 4] = *b"CCPW";
+
 struct CAData {
     secret_key: Option<SecretKey>,
     verifying_key: VerifyingKey,
+    // Derived from the CA key, None for instances exported before it existed
+    minisign_key: Option<VerifyingKey>,
 }
 
 pub struct Client {
@@ -44,11 +131,22 @@ pub struct Client {
     signing_key_signature: ed25519_dalek::Signature,
     ca_data: CAData,
     // Save the ep keys while the kex is ongoing
-    // Key is the UUID or E-Mail of the recipient
-    kex_map: hashbrown::HashMap<String, StaticSecret>,
-
-    // Shared key map
-    shared_keys: hashbrown::HashMap<String, [u8; 32]>,
+    // Key is the UUID or E-Mail of the recipient, several exchanges with one recipient may be pending
+    kex_map: hashbrown::HashMap<String, Vec<PendingKex>>,
+    // Pre-shared keys by identifier, provisioned out of band and never exported
+    psks: hashbrown::HashMap<String, [u8; 32]>,
+
+    // Shared key map, newest session last
+    shared_keys: hashbrown::HashMap<String, Vec<Session>>,
+    groups: hashbrown::HashMap<GroupId, Group>,
+    rekey_policy: RekeyPolicy,
+    limits: Limits,
+    // Suites we accept, most preferred first
+    cipher_suites: Vec<CipherSuite>,
+    // Unix time in seconds as last told by set_time, needed for time based rekeying and expiry
+    now: Option<u64>,
+    // Time-to-live of outgoing messages unless MessageOptions sets one
+    message_ttl: Option<u64>,
     csprng: rand_chacha::ChaChaRng,
 }
 
@@ -66,31 +164,39 @@ impl Client {
             ca_data: CAData {
                 secret_key: Some(*ca_signing_key.as_bytes()),
                 verifying_key: ca_signing_key.verifying_key(),
+                minisign_key: Some(minisign::derive_signing_key(&ca_signing_key).verifying_key()),
             },
             kex_map: hashbrown::HashMap::new(),
+            psks: hashbrown::HashMap::new(),
             shared_keys: hashbrown::HashMap::new(),
+            groups: hashbrown::HashMap::new(),
+            rekey_policy: RekeyPolicy::default(),
+            limits: Limits::default(),
+            cipher_suites: suite::ALL_SUITES.to_vec(),
+            now: None,
+            message_ttl: None,
             csprng,
         }
     }
 
-    pub fn export_user(&mut self, password: &[u8]) -> anyhow::Result<Vec<u8>> {
+    pub fn export_user(&mut self, password: &[u8]) -> Result<Vec<u8>> {
         let signing_key = self
             .signing_key
             .to_pkcs8_der()
-            .map_err(Error::msg)?
+            .map_err(|_| Error::Internal("Could not encode key"))?
             .as_bytes()
             .to_vec();
         // User is expected to have full CA data
         let ca_private_key = match self.ca_data.secret_key {
             None => {
-                return Err(Error::msg("CA data is missing"));
+                return Err(Error::NoCa("CA data is missing"));
             }
             Some(v) => v,
         };
         let ca_sig_key = SigningKey::from_bytes(&ca_private_key);
         let ca_key = ca_sig_key
             .to_pkcs8_der()
-            .map_err(Error::msg)?
+            .map_err(|_| Error::Internal("Could not encode key"))?
             .as_bytes()
             .to_vec();
 
@@ -99,69 +205,26 @@ impl Client {
             ca_key,
         };
 
-        let mut serialized = bson::to_vec(&user).map_err(Error::msg)?;
-
-        // Encrypt the user data with a password
-        // Hash the password to expand it to a 32 byte key
-        let mut salt = [0u8; 16];
-        self.csprng.fill_bytes(&mut salt);
-
-        let mut output_key_material = [0u8; 32];
-        let argon2 = Argon2::default();
-        argon2
-            .hash_password_into(password, &salt, &mut output_key_material)
-            .map_err(Error::msg)?;
-
-        // Encrypt the user data with the derived key using chacha20-poly
-        let key = GenericArray::from_slice(&output_key_material);
-        let cipher = XChaCha20Poly1305::new(key);
-        let nonce = XChaCha20Poly1305::generate_nonce(&mut self.csprng);
-
-        // aead_data is salt + nonce
-        let mut aead_data = salt.to_vec();
-        aead_data.extend_from_slice(&nonce);
-
-        cipher
-            .encrypt_in_place(&nonce, &aead_data, &mut serialized)
-            .map_err(Error::msg)?;
+        let serialized = bson::to_vec(&user).map_err(Error::from)?;
 
-        aead_data.extend_from_slice(&serialized);
-
-        Ok(aead_data)
+        let suite = self.preferred_suite();
+        seal_with_password(&mut self.csprng, suite, password, serialized)
     }
 
-    pub fn import_user(password: &[u8], data: &[u8]) -> anyhow::Result<Self> {
-        // Retrieve salt & nonce from the data
-        // The first 16 bytes are the salt
-        // The next 24 bytes are the nonce
-        let salt = &data[0..16];
-        let nonce = &data[16..40];
-
-        let mut serialized = data[40..].to_vec();
-
-        // Hash the password to expand it to a 32 byte key
-        let mut output_key_material = [0u8; 32];
-        let argon2 = Argon2::default();
-        argon2
-            .hash_password_into(password, salt, &mut output_key_material)
-            .map_err(Error::msg)?;
-
-        // Decrypt the user data with the derived key using chacha20-poly
-        let key = GenericArray::from_slice(&output_key_material);
-        let cipher = XChaCha20Poly1305::new(key);
-        let nonce = GenericArray::from_slice(nonce);
-        let associated_data = &data[0..40];
+    pub fn import_user(password: &[u8], data: &[u8]) -> Result<Self> {
+        Self::import_user_with_limits(password, data, Limits::default())
+    }
 
-        cipher
-            .decrypt_in_place(nonce, associated_data, &mut serialized)
-            .map_err(Error::msg)?;
+    /// Like import_user, the client keeps `limits`, see set_limits
+    pub fn import_user_with_limits(password: &[u8], data: &[u8], limits: Limits) -> Result<Self> {
+        let serialized = open_with_password(password, data)?;
 
-        let user: UserForExport = bson::from_slice(&serialized).map_err(Error::msg)?;
+        let user: UserForExport = limits.parse_bson(&serialized)?;
 
         // Import the user data
-        let signing_key = SigningKey::from_pkcs8_der(&user.signing_key).map_err(Error::msg)?;
+        let signing_key = SigningKey::from_pkcs8_der(&user.signing_key).map_err(Error::from)?;
 
-        let ca_signing_key = SigningKey::from_pkcs8_der(&user.ca_key).map_err(Error::msg)?;
+        let ca_signing_key = SigningKey::from_pkcs8_der(&user.ca_key).map_err(Error::from)?;
 
         let sig = ca_signing_key.sign(signing_key.verifying_key().as_bytes());
 
@@ -171,29 +234,214 @@ impl Client {
             ca_data: CAData {
                 secret_key: Some(ca_signing_key.to_bytes()),
                 verifying_key: ca_signing_key.verifying_key(),
+                minisign_key: Some(minisign::derive_signing_key(&ca_signing_key).verifying_key()),
             },
             kex_map: hashbrown::HashMap::new(),
+            psks: hashbrown::HashMap::new(),
             shared_keys: hashbrown::HashMap::new(),
+            groups: hashbrown::HashMap::new(),
+            rekey_policy: RekeyPolicy::default(),
+            limits,
+            cipher_suites: suite::ALL_SUITES.to_vec(),
+            now: None,
+            message_ttl: None,
             csprng: rand_chacha::ChaChaRng::from_entropy(),
         })
     }
 
-    pub fn generate_instance(&mut self) -> anyhow::Result<Vec<u8>> {
+    /// Export every established session and pending key exchange, protected like export_user.
+    /// The export can only be imported into the same identity.
+    pub fn export_sessions(&mut self, password: &[u8]) -> Result<Vec<u8>> {
+        let mut peers: Vec<String> = self
+            .shared_keys
+            .keys()
+            .chain(self.kex_map.keys())
+            .cloned()
+            .collect();
+        peers.sort();
+        peers.dedup();
+        self.export_peer_sessions(&peers, password)
+    }
+
+    /// Export the established sessions and pending key exchanges with a single peer
+    pub fn export_session(&mut self, peer: &str, password: &[u8]) -> Result<Vec<u8>> {
+        if !self.shared_keys.contains_key(peer) && !self.kex_map.contains_key(peer) {
+            return Err(Error::NoSession("No session found"));
+        }
+        self.export_peer_sessions(&[peer.to_string()], password)
+    }
+
+    fn export_peer_sessions(&mut self, peers: &[String], password: &[u8]) -> Result<Vec<u8>> {
+        let mut sessions = SessionsForExport {
+            owner: self.owner().to_vec(),
+            shared_keys: Vec::new(),
+            pending: Vec::new(),
+        };
+
+        for peer in peers {
+            for session in self.shared_keys.get(peer).into_iter().flatten() {
+                sessions.shared_keys.push(SharedKeyForExport {
+                    peer: peer.clone(),
+                    key: session.key.to_vec(),
+                    id: Some(session.id.to_vec()),
+                    epoch: session.epoch,
+                    previous_keys: session
+                        .previous_keys
+                        .iter()
+                        .map(|(epoch, key)| EpochKeyForExport {
+                            epoch: *epoch,
+                            key: key.to_vec(),
+                        })
+                        .collect(),
+                    messages: session.messages,
+                    bytes: session.bytes,
+                    epoch_started_at: session.epoch_started_at,
+                    send_seq: session.send_seq,
+                    recv_highest: session.recv_highest,
+                    recv_window: session.recv_window,
+                    strict_order: session.strict_order,
+                    compression: Some(session.compression.id()),
+                    padding: session.padding,
+                    suite: Some(session.suite.id()),
+                    peer_key_id: session.peer_key_id.map(|v| v.to_vec()),
+                });
+            }
+            for pending in self.kex_map.get(peer).into_iter().flatten() {
+                sessions.pending.push(PendingKexForExport {
+                    peer: peer.clone(),
+                    ephemeral_key: pending.ephemeral_key.to_bytes().to_vec(),
+                    mode: pending.mode.id(),
+                    kem_key: pending.kem_key.as_ref().map(|dk| dk.as_bytes().to_vec()),
+                    psk_id: pending.psk_id.clone(),
+                });
+            }
+        }
+
+        let serialized = bson::to_vec(&sessions).map_err(Error::from)?;
+
+        let suite = self.preferred_suite();
+        seal_with_password(&mut self.csprng, suite, password, serialized)
+    }
+
+    /// Import sessions from export_sessions or export_session.
+    /// They are added to the sessions that already exist.
+    pub fn import_sessions(&mut self, password: &[u8], data: &[u8]) -> Result<()> {
+        let serialized = open_with_password(password, data)?;
+        let sessions: SessionsForExport = self.limits.parse_bson(&serialized)?;
+
+        if sessions.owner != self.owner() {
+            return Err(Error::InvalidArgument(
+                "Sessions belong to a different identity",
+            ));
+        }
+
+        // Parse everything first, so a malformed export does not leave a partial import
+        let mut shared_keys = Vec::new();
+        for v in sessions.shared_keys {
+            let key: [u8; 32] = v
+                .key
+                .as_slice()
+                .try_into()
+                .map_err(|_| Error::Malformed("Invalid shared key length"))?;
+            let mut session = Session::new(key);
+            // Exports without an id predate rekeying, the key is still the initial one
+            if let Some(id) = v.id {
+                session.id = id
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| Error::Malformed("Invalid session id length"))?;
+            }
+            session.epoch = v.epoch;
+            for previous in v.previous_keys {
+                let key: [u8; 32] = previous
+                    .key
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| Error::Malformed("Invalid shared key length"))?;
+                session.previous_keys.push((previous.epoch, key));
+            }
+            session.messages = v.messages;
+            session.bytes = v.bytes;
+            session.epoch_started_at = v.epoch_started_at;
+            session.send_seq = v.send_seq;
+            session.recv_highest = v.recv_highest;
+            session.recv_window = v.recv_window;
+            session.strict_order = v.strict_order;
+            session.compression = match v.compression {
+                None => Compression::default(),
+                Some(id) => Compression::from_id(id)?,
+            };
+            session.padding = v.padding;
+            session.suite = match v.suite {
+                None => CipherSuite::XChaCha20Poly1305,
+                Some(id) => CipherSuite::from_id(id)?,
+            };
+            session.peer_key_id = match v.peer_key_id {
+                None => None,
+                Some(id) => Some(
+                    id.as_slice()
+                        .try_into()
+                        .map_err(|_| Error::Malformed("Invalid key id length"))?,
+                ),
+            };
+            shared_keys.push((v.peer, session));
+        }
+
+        let mut pending = Vec::new();
+        for v in sessions.pending {
+            let ephemeral_key: [u8; 32] = v
+                .ephemeral_key
+                .as_slice()
+                .try_into()
+                .map_err(|_| Error::Malformed("Invalid ephemeral key length"))?;
+            let kem_key = match v.kem_key {
+                None => None,
+                Some(dk) => Some(mlkem::DecapsulationKey::from_bytes(&dk)?),
+            };
+            pending.push((
+                v.peer,
+                PendingKex {
+                    ephemeral_key: StaticSecret::from(ephemeral_key),
+                    mode: KexMode::from_id(v.mode)?,
+                    kem_key,
+                    psk_id: v.psk_id,
+                },
+            ));
+        }
+
+        for (peer, session) in shared_keys {
+            self.add_session(&peer, session);
+        }
+        for (peer, v) in pending {
+            let public_key = PublicKey::from(&v.ephemeral_key);
+            let entries = self.kex_map.entry(peer).or_default();
+            entries.retain(|p| PublicKey::from(&p.ephemeral_key) != public_key);
+            entries.push(v);
+        }
+
+        Ok(())
+    }
+
+    pub fn generate_instance(&mut self) -> Result<Vec<u8>> {
         // An instance is a Client without the CA private key
         let signing_key = SigningKey::generate(&mut self.csprng);
 
-        let sig = match self.ca_data.secret_key {
+        let ca_key = match self.ca_data.secret_key {
             None => {
-                return Err(Error::msg("CA data is missing"));
+                return Err(Error::NoCa("CA data is missing"));
             }
             Some(v) => SigningKey::from_bytes(&v),
-        }
-        .sign(signing_key.verifying_key().as_bytes());
+        };
+        let sig = ca_key.sign(signing_key.verifying_key().as_bytes());
+
+        // Instances cannot derive the minisign key, the CA certifies it for them
+        let minisign_key = minisign::derive_signing_key(&ca_key).verifying_key();
+        let minisign_key_sig = ca_key.sign(&minisign::certified_bytes(&minisign_key));
 
         let v = InstanceForExport {
             signing_key: signing_key
                 .to_pkcs8_der()
-                .map_err(Error::msg)?
+                .map_err(|_| Error::Internal("Could not encode key"))?
                 .as_bytes()
                 .to_vec(),
             sig: sig.to_bytes().to_vec(),
@@ -201,12 +449,14 @@ impl Client {
                 .ca_data
                 .verifying_key
                 .to_public_key_der()
-                .map_err(Error::msg)?
+                .map_err(|_| Error::Internal("Could not encode key"))?
                 .as_bytes()
                 .to_vec(),
+            minisign_key: Some(minisign_key.to_bytes().to_vec()),
+            minisign_key_sig: Some(minisign_key_sig.to_bytes().to_vec()),
         };
 
-        let serialized = bson::to_vec(&v).map_err(Error::msg)?;
+        let serialized = bson::to_vec(&v).map_err(Error::from)?;
 
         // Compress the instance data
         let compressed = compress_prepend_size(&serialized);
@@ -214,16 +464,39 @@ impl Client {
         Ok(compressed)
     }
 
-    pub fn import_instance(data: &[u8]) -> anyhow::Result<Self> {
+    pub fn import_instance(data: &[u8]) -> Result<Self> {
+        Self::import_instance_with_limits(data, Limits::default())
+    }
+
+    /// Like import_instance, the client keeps `limits`, see set_limits
+    pub fn import_instance_with_limits(data: &[u8], limits: Limits) -> Result<Self> {
         // Decompress the instance data
-        let uncompressed = lz4_flex::decompress_size_prepended(data).map_err(Error::msg)?;
+        let uncompressed = compression::decompress(
+            Compression::Lz4,
+            data,
+            limits.max_decompressed_size.min(limits.max_document_size),
+        )?;
 
-        let v: InstanceForExport = bson::from_slice(&uncompressed).map_err(Error::msg)?;
+        let v: InstanceForExport = limits.parse_bson(&uncompressed)?;
 
-        let signing_key = SigningKey::from_pkcs8_der(&v.signing_key).map_err(Error::msg)?;
-        let sig = ed25519_dalek::Signature::from_slice(&v.sig).map_err(Error::msg)?;
+        let signing_key = SigningKey::from_pkcs8_der(&v.signing_key).map_err(Error::from)?;
+        let sig = ed25519_dalek::Signature::from_slice(&v.sig)
+            .map_err(|_| Error::Malformed("Invalid signature"))?;
         let ca_verifying_key =
-            VerifyingKey::from_public_key_der(&v.ca_verifying_key).map_err(Error::msg)?;
+            VerifyingKey::from_public_key_der(&v.ca_verifying_key).map_err(Error::from)?;
+        let minisign_key = match (v.minisign_key, v.minisign_key_sig) {
+            (Some(key), Some(key_sig)) => {
+                let key = VerifyingKey::try_from(key.as_slice())
+                    .map_err(|_| Error::Malformed("Invalid verifying key"))?;
+                let key_sig = ed25519_dalek::Signature::from_slice(&key_sig)
+                    .map_err(|_| Error::Malformed("Invalid signature"))?;
+                ca_verifying_key
+                    .verify(&minisign::certified_bytes(&key), &key_sig)
+                    .map_err(|_| Error::UntrustedKey("Minisign key is not certified by the CA"))?;
+                Some(key)
+            }
+            _ => None,
+        };
 
         Ok(Self {
             signing_key,
@@ -231,30 +504,95 @@ impl Client {
             ca_data: CAData {
                 secret_key: None,
                 verifying_key: ca_verifying_key,
+                minisign_key,
             },
             kex_map: hashbrown::HashMap::new(),
+            psks: hashbrown::HashMap::new(),
             shared_keys: hashbrown::HashMap::new(),
+            groups: hashbrown::HashMap::new(),
+            rekey_policy: RekeyPolicy::default(),
+            limits,
+            cipher_suites: suite::ALL_SUITES.to_vec(),
+            now: None,
+            message_ttl: None,
             csprng: rand_chacha::ChaChaRng::from_entropy(),
         })
     }
 
-    pub fn init_dh_kex(&mut self, recipient: &str) -> anyhow::Result<(PublicKey, Signature)> {
+    // Exports are bound to the identity they were made by
+    pub(crate) fn owner(&self) -> [u8; 32] {
+        self.signing_key.verifying_key().to_bytes()
+    }
+
+    pub(crate) fn csprng(&mut self) -> &mut rand_chacha::ChaChaRng {
+        &mut self.csprng
+    }
+
+    pub(crate) fn signing_key(&self) -> &SigningKey {
+        &self.signing_key
+    }
+
+    pub(crate) fn signing_key_signature(&self) -> &Signature {
+        &self.signing_key_signature
+    }
+
+    pub(crate) fn ca_verifying_key(&self) -> &VerifyingKey {
+        &self.ca_data.verifying_key
+    }
+
+    /// Add or replace a pre-shared key usable with init_dh_kex and complete_dh_kex
+    pub fn add_psk(&mut self, psk_id: &str, psk: [u8; 32]) {
+        self.psks.insert(psk_id.to_string(), psk);
+    }
+
+    pub fn remove_psk(&mut self, psk_id: &str) {
+        self.psks.remove(psk_id);
+    }
+
+    fn psk(&self, psk_id: &str) -> Result<&[u8; 32]> {
+        match self.psks.get(psk_id) {
+            None => Err(Error::NotFound("Unknown PSK identifier")),
+            Some(v) => Ok(v),
+        }
+    }
+
+    /// Start a key exchange, optionally bound to the pre-shared key `psk_id` (see add_psk).
+    /// The peer has to call complete_dh_kex with the same PSK identifier.
+    pub fn init_dh_kex(
+        &mut self,
+        recipient: &str,
+        psk_id: Option<&str>,
+    ) -> Result<(PublicKey, Signature)> {
         let csprng = rand_chacha::ChaChaRng::from_entropy();
         let ephemeral_key = StaticSecret::random_from_rng(csprng);
         let pubkey = PublicKey::from(&ephemeral_key);
-        self.kex_map.insert(recipient.to_string(), ephemeral_key);
+
+        let transcript = match psk_id {
+            None => pubkey.as_bytes().to_vec(),
+            Some(id) => psk_transcript(self.psk(id)?, &pubkey)?,
+        };
+
+        self.kex_map
+            .entry(recipient.to_string())
+            .or_default()
+            .push(PendingKex {
+                ephemeral_key,
+                mode: KexMode::X25519,
+                kem_key: None,
+                psk_id: psk_id.map(|v| v.to_string()),
+            });
 
         // Sign pubkey
-        let sig = self.signing_key.sign(pubkey.as_bytes());
+        let sig = self.signing_key.sign(&transcript);
 
         // Self validate signature
         if self
             .signing_key
             .verifying_key()
-            .verify(pubkey.as_bytes(), &sig)
+            .verify(&transcript, &sig)
             .is_err()
         {
-            return Err(Error::msg("Signature validation failed"));
+            return Err(Error::Authentication("Signature validation failed"));
         }
 
         Ok((pubkey, sig))
@@ -267,43 +605,371 @@ impl Client {
         pubkey_sig: Signature,
         sender_verifing_key: VerifyingKey,
         sender_verifing_key_sig: Signature,
-    ) -> anyhow::Result<[u8; 32]> {
-        let ephemeral_key = match self.kex_map.remove(recipient) {
+        psk_id: Option<&str>,
+    ) -> Result<[u8; 32]> {
+        // Peers using this call do not say which exchange they answer, take the newest one.
+        // It is only removed once the exchange succeeded, a bad packet must not abort it.
+        let pending = match self.kex_map.get(recipient).and_then(|v| v.last()) {
             None => {
-                return Err(Error::msg("No ephemeral key found"));
+                return Err(Error::InvalidState("No ephemeral key found"));
             }
             Some(v) => v,
         };
-
-        let shared_secret = ephemeral_key.diffie_hellman(pubkey);
-        self.shared_keys
-            .insert(recipient.to_string(), shared_secret.to_bytes());
+        if pending.mode != KexMode::X25519 {
+            return Err(Error::InvalidArgument(
+                "Hybrid key exchange must be finished with finish_kex",
+            ));
+        }
+        if pending.psk_id.as_deref() != psk_id {
+            return Err(Error::InvalidArgument(
+                "PSK identifier differs from init_dh_kex",
+            ));
+        }
+        let psk = match psk_id {
+            None => None,
+            Some(id) => Some(*self.psk(id)?),
+        };
 
         // Verify the signature (first check if CA signed)
+        let transcript = match &psk {
+            None => pubkey.as_bytes().to_vec(),
+            Some(psk) => psk_transcript(psk, pubkey)?,
+        };
+        self.verify_kex_signatures(
+            &transcript,
+            &pubkey_sig,
+            &sender_verifing_key,
+            &sender_verifing_key_sig,
+        )
+        .map_err(|e| match psk {
+            // The peer signed a different HMAC, or none at all
+            Some(_)
+                if sender_verifing_key
+                    .verify(&transcript, &pubkey_sig)
+                    .is_err() =>
+            {
+                Error::Authentication("Pubkey not signed by sender or PSK mismatch")
+            }
+            _ => e,
+        })?;
+
+        let shared_secret = pending.ephemeral_key.diffie_hellman(pubkey);
+        let shared_key = match &psk {
+            None => shared_secret.to_bytes(),
+            Some(psk) => combine_psk_secret(
+                psk,
+                shared_secret.as_bytes(),
+                &PublicKey::from(&pending.ephemeral_key),
+                pubkey,
+            ),
+        };
+        if let Some(entries) = self.kex_map.get_mut(recipient) {
+            entries.pop();
+        }
+        self.insert_session(
+            recipient,
+            &sender_verifing_key,
+            shared_key,
+            CipherSuite::XChaCha20Poly1305,
+        );
+
+        Ok(shared_key)
+    }
+
+    /// Start a key exchange as initiator, the returned kex packet offers `mode`.
+    /// Finish it with the responder's packet using finish_kex.
+    pub fn init_kex(&mut self, recipient: &str, mode: KexMode) -> Result<Vec<u8>> {
+        let (pending, packet) = self.new_kex_offer(mode)?;
+        self.kex_map
+            .entry(recipient.to_string())
+            .or_default()
+            .push(pending);
+
+        Ok(packet)
+    }
+
+    /// Answer the kex packet of an initiator and complete the exchange on our side.
+    /// Offers with a weaker mode than `min_mode` are rejected.
+    pub fn respond_kex(
+        &mut self,
+        recipient: &str,
+        packet: &[u8],
+        min_mode: KexMode,
+    ) -> Result<Vec<u8>> {
+        let kex_packet: KexPacket = self.limits.parse_bson(packet)?;
+        let (outcome, response) = self.answer_kex_offer(&kex_packet, min_mode)?;
+        self.insert_session(
+            recipient,
+            &outcome.peer_key,
+            outcome.shared_key,
+            outcome.suite,
+        );
+
+        Ok(response)
+    }
+
+    /// Finish a key exchange started with init_kex or init_dh_kex using the peer's kex packet
+    pub fn finish_kex(&mut self, recipient: &str, packet: &[u8]) -> Result<[u8; 32]> {
+        let kex_packet: KexPacket = self.limits.parse_bson(packet)?;
+
+        // Responses name the offer they answer, packets from init_dh_kex peers do not
+        let entries = match self.kex_map.get(recipient) {
+            None => {
+                return Err(Error::InvalidState("No ephemeral key found"));
+            }
+            Some(v) => v,
+        };
+        let index = match kex_packet.in_reply_to {
+            None => entries.len().checked_sub(1),
+            Some(offer) => entries
+                .iter()
+                .position(|p| PublicKey::from(&p.ephemeral_key).to_bytes() == offer),
+        };
+        let (index, pending) = match index.and_then(|i| Some((i, entries.get(i)?))) {
+            None => {
+                return Err(Error::InvalidState("No ephemeral key found"));
+            }
+            Some(v) => v,
+        };
+
+        // Only a successful exchange consumes the offer
+        let outcome = self.complete_kex_offer(pending, &kex_packet)?;
+        if let Some(entries) = self.kex_map.get_mut(recipient) {
+            entries.remove(index);
+        }
+        self.insert_session(
+            recipient,
+            &outcome.peer_key,
+            outcome.shared_key,
+            outcome.suite,
+        );
+        Ok(outcome.shared_key)
+    }
+
+    pub(crate) fn new_kex_offer(&mut self, mode: KexMode) -> Result<(PendingKex, Vec<u8>)> {
+        let ephemeral_key = StaticSecret::random_from_rng(&mut self.csprng);
+        let pubkey = PublicKey::from(&ephemeral_key);
+
+        let kem_key = match mode {
+            KexMode::X25519 => None,
+            KexMode::HybridMlKem768 => Some(mlkem::generate(&mut self.csprng).1),
+        };
+        let kem_data = kem_key
+            .as_ref()
+            .map(|dk| dk.encapsulation_key().as_bytes().to_vec());
+
+        let suites = self.cipher_suites.iter().map(|v| v.id()).collect();
+        let packet = self.build_kex_packet(mode, pubkey, kem_data, Some(suites), None)?;
+        let pending = PendingKex {
+            ephemeral_key,
+            mode,
+            kem_key,
+            psk_id: None,
+        };
+
+        Ok((pending, packet))
+    }
+
+    // Returns the outcome with the mode of the offer and the negotiated suite, and the response
+    pub(crate) fn answer_kex_offer(
+        &mut self,
+        kex_packet: &KexPacket,
+        min_mode: KexMode,
+    ) -> Result<(KexOutcome, Vec<u8>)> {
+        let (mode, peer_pubkey, peer_key) = self.verify_kex_packet(kex_packet)?;
+        if mode < min_mode {
+            return Err(Error::Unsupported(
+                "Key exchange mode below required minimum",
+            ));
+        }
+        let offered = match &kex_packet.suites {
+            None => alloc::vec![CipherSuite::XChaCha20Poly1305.id()],
+            Some(v) => v.clone(),
+        };
+        let suite = suite::negotiate(&self.cipher_suites, &offered)?;
+
+        let ephemeral_key = StaticSecret::random_from_rng(&mut self.csprng);
+        let pubkey = PublicKey::from(&ephemeral_key);
+        let shared_secret = ephemeral_key.diffie_hellman(&peer_pubkey);
+
+        let (shared_key, kem_data) = match mode {
+            KexMode::X25519 => (shared_secret.to_bytes(), None),
+            KexMode::HybridMlKem768 => {
+                if !shared_secret.was_contributory() {
+                    return Err(Error::Malformed("Non-contributory Diffie-Hellman result"));
+                }
+                let ek = match &kex_packet.kem_data {
+                    None => return Err(Error::Malformed("ML-KEM encapsulation key missing")),
+                    Some(v) => mlkem::EncapsulationKey::from_bytes(v)?,
+                };
+                let (kem_secret, ciphertext) = mlkem::encapsulate(&ek, &mut self.csprng)?;
+                let shared_key = combine_hybrid_secrets(
+                    shared_secret.as_bytes(),
+                    &kem_secret,
+                    &peer_pubkey,
+                    &pubkey,
+                    ek.as_bytes(),
+                    &ciphertext,
+                );
+                (shared_key, Some(ciphertext))
+            }
+        };
+
+        let response = self.build_kex_packet(
+            mode,
+            pubkey,
+            kem_data,
+            Some(alloc::vec![suite.id()]),
+            Some(peer_pubkey),
+        )?;
+
+        let outcome = KexOutcome {
+            mode,
+            peer_key,
+            shared_key,
+            suite,
+        };
+        Ok((outcome, response))
+    }
+
+    // Returns the outcome with the suite the responder picked
+    pub(crate) fn complete_kex_offer(
+        &self,
+        pending: &PendingKex,
+        kex_packet: &KexPacket,
+    ) -> Result<KexOutcome> {
+        if pending.psk_id.is_some() {
+            return Err(Error::InvalidArgument(
+                "Key exchange with a PSK must be finished with complete_dh_kex",
+            ));
+        }
+        let (mode, peer_pubkey, peer_key) = self.verify_kex_packet(kex_packet)?;
+        if mode != pending.mode {
+            return Err(Error::Malformed("Key exchange mode mismatch"));
+        }
+        let suite = match kex_packet.suites.as_deref() {
+            None => CipherSuite::XChaCha20Poly1305,
+            Some([id]) => CipherSuite::from_id(*id)?,
+            Some(_) => return Err(Error::Malformed("Response must pick one cipher suite")),
+        };
+        if !self.cipher_suites.contains(&suite) {
+            return Err(Error::Unsupported("Cipher suite not allowed"));
+        }
+
+        let shared_secret = pending.ephemeral_key.diffie_hellman(&peer_pubkey);
+        let shared_key = match (mode, &pending.kem_key) {
+            (KexMode::X25519, _) => shared_secret.to_bytes(),
+            (KexMode::HybridMlKem768, Some(dk)) => {
+                if !shared_secret.was_contributory() {
+                    return Err(Error::Malformed("Non-contributory Diffie-Hellman result"));
+                }
+                let ciphertext = match &kex_packet.kem_data {
+                    None => return Err(Error::Malformed("ML-KEM ciphertext missing")),
+                    Some(v) => v,
+                };
+                let kem_secret = mlkem::decapsulate(dk, ciphertext)?;
+                combine_hybrid_secrets(
+                    shared_secret.as_bytes(),
+                    &kem_secret,
+                    &PublicKey::from(&pending.ephemeral_key),
+                    &peer_pubkey,
+                    dk.encapsulation_key().as_bytes(),
+                    ciphertext,
+                )
+            }
+            (KexMode::HybridMlKem768, None) => {
+                return Err(Error::Malformed("ML-KEM decapsulation key missing"));
+            }
+        };
+
+        Ok(KexOutcome {
+            mode,
+            peer_key,
+            shared_key,
+            suite,
+        })
+    }
+
+    fn build_kex_packet(
+        &self,
+        mode: KexMode,
+        public_key: PublicKey,
+        kem_data: Option<Vec<u8>>,
+        suites: Option<Vec<u8>>,
+        in_reply_to: Option<PublicKey>,
+    ) -> Result<Vec<u8>> {
+        let transcript = kex_transcript(mode, &public_key, kem_data.as_deref(), suites.as_deref());
+        let sig = self.signing_key.sign(&transcript);
+
+        let kex_packet = KexPacket {
+            public_key: public_key.to_bytes(),
+            sig: sig.to_bytes().to_vec(),
+            verifying_key: self
+                .signing_key
+                .verifying_key()
+                .to_public_key_der()
+                .map_err(|_| Error::Internal("Could not encode key"))?
+                .as_bytes()
+                .to_vec(),
+            signing_key_sig: self.signing_key_signature.to_bytes().to_vec(),
+            mode: mode.id(),
+            kem_data,
+            in_reply_to: in_reply_to.map(|v| v.to_bytes()),
+            suites,
+        };
+
+        bson::to_vec(&kex_packet).map_err(Error::from)
+    }
+
+    // Returns the mode, the peer's public key and its certified identity
+    fn verify_kex_packet(
+        &self,
+        kex_packet: &KexPacket,
+    ) -> Result<(KexMode, PublicKey, VerifyingKey)> {
+        let mode = KexMode::from_id(kex_packet.mode)?;
+        let public_key = PublicKey::from(kex_packet.public_key);
+        let sig = Signature::from_slice(&kex_packet.sig)
+            .map_err(|_| Error::Malformed("Invalid signature"))?;
+        let verifying_key =
+            VerifyingKey::from_public_key_der(&kex_packet.verifying_key).map_err(Error::from)?;
+        let signing_key_sig = Signature::from_slice(&kex_packet.signing_key_sig)
+            .map_err(|_| Error::Malformed("Invalid signature"))?;
+
+        let transcript = kex_transcript(
+            mode,
+            &public_key,
+            kex_packet.kem_data.as_deref(),
+            kex_packet.suites.as_deref(),
+        );
+        self.verify_kex_signatures(&transcript, &sig, &verifying_key, &signing_key_sig)?;
+
+        Ok((mode, public_key, verifying_key))
+    }
+
+    fn verify_kex_signatures(
+        &self,
+        transcript: &[u8],
+        sig: &Signature,
+        sender_verifing_key: &VerifyingKey,
+        sender_verifing_key_sig: &Signature,
+    ) -> Result<()> {
         if self
             .ca_data
             .verifying_key
-            .verify(sender_verifing_key.as_bytes(), &sender_verifing_key_sig)
+            .verify(sender_verifing_key.as_bytes(), sender_verifing_key_sig)
             .is_err()
         {
-            return Err(Error::msg("Sender key not signed by CA"));
+            return Err(Error::UntrustedKey("Sender key not signed by CA"));
         }
 
-        if sender_verifing_key
-            .verify(pubkey.as_bytes(), &pubkey_sig)
-            .is_err()
-        {
-            return Err(Error::msg("Pubkey not signed by sender"));
+        if sender_verifing_key.verify(transcript, sig).is_err() {
+            return Err(Error::Authentication("Pubkey not signed by sender"));
         }
 
-        Ok(shared_secret.to_bytes())
+        Ok(())
     }
 
-    pub fn generate_kex_packet(
-        &self,
-        public_key: PublicKey,
-        sig: Signature,
-    ) -> anyhow::Result<Vec<u8>> {
+    pub fn generate_kex_packet(&self, public_key: PublicKey, sig: Signature) -> Result<Vec<u8>> {
         let kex_packet = KexPacket {
             public_key: public_key.to_bytes(),
             sig: sig.to_bytes().to_vec(),
@@ -311,124 +977,1602 @@ impl Client {
                 .signing_key
                 .verifying_key()
                 .to_public_key_der()
-                .map_err(Error::msg)?
+                .map_err(|_| Error::Internal("Could not encode key"))?
                 .as_bytes()
                 .to_vec(),
             signing_key_sig: self.signing_key_signature.to_bytes().to_vec(),
+            mode: KexMode::X25519.id(),
+            kem_data: None,
+            in_reply_to: None,
+            suites: None,
         };
 
-        let serialized = bson::to_vec(&kex_packet).map_err(Error::msg)?;
+        let serialized = bson::to_vec(&kex_packet).map_err(Error::from)?;
 
         Ok(serialized)
     }
 
     pub fn unpack_kex_packet(
         data: &[u8],
-    ) -> anyhow::Result<(PublicKey, Signature, VerifyingKey, Signature)> {
-        let kex_packet: KexPacket = bson::from_slice(data).map_err(Error::msg)?;
+    ) -> Result<(PublicKey, Signature, VerifyingKey, Signature)> {
+        let kex_packet: KexPacket = Limits::default().parse_bson(data)?;
 
         let public_key = PublicKey::from(kex_packet.public_key);
-        let sig = Signature::from_slice(&kex_packet.sig).map_err(Error::msg)?;
+        let sig = Signature::from_slice(&kex_packet.sig)
+            .map_err(|_| Error::Malformed("Invalid signature"))?;
         let verifying_key =
-            VerifyingKey::from_public_key_der(&kex_packet.verifying_key).map_err(Error::msg)?;
-        let signing_key_sig =
-            Signature::from_slice(&kex_packet.signing_key_sig).map_err(Error::msg)?;
+            VerifyingKey::from_public_key_der(&kex_packet.verifying_key).map_err(Error::from)?;
+        let signing_key_sig = Signature::from_slice(&kex_packet.signing_key_sig)
+            .map_err(|_| Error::Malformed("Invalid signature"))?;
 
         Ok((public_key, sig, verifying_key, signing_key_sig))
     }
 
-    pub fn encrypt_message_for_recipient(
+    /// The X25519 static key used for Noise handshakes, HPKE and sealed sender, see identity
+    pub fn noise_static_public_key(&self) -> PublicKey {
+        PublicKey::from(&identity::static_secret(&self.signing_key))
+    }
+
+    /// Our identity and static key with their signatures, for peers that want to encrypt to us
+    /// offline
+    pub fn certificate(&self) -> Certificate {
+        let static_key = self.noise_static_public_key();
+        Certificate {
+            verifying_key: self.signing_key.verifying_key(),
+            signature: self.signing_key_signature,
+            static_key,
+            static_key_signature: identity::sign_static_key(&self.signing_key, &static_key),
+        }
+    }
+
+    /// HPKE (Auth mode) to the static key of a CA certified identity, see certificate.
+    /// The recipient does not need to be online, output is enc (32 bytes) || ciphertext.
+    pub fn hpke_seal(
         &mut self,
-        recipient: &str,
+        recipient: &Certificate,
+        info: &[u8],
+        aad: &[u8],
         message: &[u8],
-    ) -> anyhow::Result<Vec<u8>> {
-        let shared_key = match self.shared_keys.get(recipient) {
-            None => {
-                return Err(Error::msg("No shared key found"));
-            }
-            Some(v) => v,
-        };
+    ) -> Result<Vec<u8>> {
+        recipient.verify(&self.ca_data.verifying_key)?;
+        let own_secret = identity::static_secret(&self.signing_key);
+        hpke::seal(
+            &mut self.csprng,
+            &recipient.static_key,
+            info,
+            aad,
+            message,
+            None,
+            Some(&own_secret),
+        )
+    }
 
-        let mut nonce = [0u8; 24];
-        self.csprng.fill_bytes(&mut nonce);
+    /// Open the output of hpke_seal from a CA certified sender
+    pub fn hpke_open(
+        &self,
+        sender: &Certificate,
+        info: &[u8],
+        aad: &[u8],
+        data: &[u8],
+    ) -> Result<Vec<u8>> {
+        sender.verify(&self.ca_data.verifying_key)?;
+        let own_secret = identity::static_secret(&self.signing_key);
+        hpke::open(&own_secret, info, aad, data, None, Some(&sender.static_key))
+    }
 
-        let key = GenericArray::from_slice(shared_key);
-        let cipher = XChaCha20Poly1305::new(key);
-        let nonce = GenericArray::from_slice(&nonce);
+    // Dedicated X25519 key for age, so a shared age identity reveals nothing about the signing key
+    fn age_secret(&self) -> StaticSecret {
+        let mut hasher = Sha3_256::new();
+        hasher.update(b"CosmicCipher age identity");
+        hasher.update(self.signing_key.to_bytes());
+        let mut secret = [0u8; 32];
+        secret.copy_from_slice(&hasher.finalize());
+        StaticSecret::from(secret)
+    }
 
-        let mut aead_data = nonce.to_vec();
+    /// Our `age1...` recipient, files encrypted to it with age can be opened with age_decrypt
+    pub fn age_recipient(&self) -> Result<String> {
+        age::encode_recipient(&PublicKey::from(&self.age_secret()))
+    }
 
-        // Compression step
-        let compressed = compress_prepend_size(message);
+    /// Our `AGE-SECRET-KEY-1...` identity for the age tool, handle it like the exported user
+    pub fn age_identity(&self) -> Result<String> {
+        age::encode_identity(&self.age_secret())
+    }
 
-        let mut buffer = compressed;
+    /// Decrypt an age file (binary or armored) encrypted to age_recipient
+    pub fn age_decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        age::decrypt(
+            alloc::vec![age::AgeIdentity::X25519(self.age_secret())],
+            data,
+        )
+    }
 
-        cipher
-            .encrypt_in_place(nonce, &aead_data, &mut buffer)
-            .map_err(Error::msg)?;
+    /// Start signing data under `context`, which separates signatures for different purposes.
+    /// The signature is timestamped if the time is set, see set_time.
+    pub fn sign_stream(&self, context: &str) -> Result<SignStream<'_>> {
+        SignStream::new(
+            &self.signing_key,
+            &self.signing_key_signature,
+            &self.ca_data.verifying_key,
+            context,
+            self.now,
+        )
+    }
 
-        aead_data.extend_from_slice(&buffer);
+    /// Detached signature over `data`, see sign_stream
+    pub fn sign_detached(&self, context: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let mut stream = self.sign_stream(context)?;
+        stream.update(data);
+        stream.finish()
+    }
 
-        Ok(aead_data)
+    /// `data` together with its signature, see sign_stream
+    pub fn sign_attached(&self, context: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let mut stream = self.sign_stream(context)?;
+        stream.update(data);
+        signing::attach(stream.finish_block(), data)
+    }
+
+    /// Start verifying a detached signature made under `context` by a signer of our CA
+    pub fn verify_stream(&self, context: &str, signature: &[u8]) -> Result<VerifyStream<'_>> {
+        self.limits.check_document(signature)?;
+        VerifyStream::new(&self.ca_data.verifying_key, self.now, context, signature)
     }
 
-    pub fn decrypt_message_from_sender(
+    /// Verify a detached signature over `data`, see verify_stream
+    pub fn verify_detached(
+        &self,
+        context: &str,
+        data: &[u8],
+        signature: &[u8],
+    ) -> Result<SignatureInfo> {
+        let mut stream = self.verify_stream(context, signature)?;
+        stream.update(data);
+        stream.finish()
+    }
+
+    /// Verify the output of sign_attached and return the signed data
+    pub fn verify_attached(
+        &self,
+        context: &str,
+        signed: &[u8],
+    ) -> Result<(SignatureInfo, Vec<u8>)> {
+        self.limits.check_document(signed)?;
+        signing::verify_attached(&self.ca_data.verifying_key, self.now, context, signed)
+    }
+
+    /// Minisign public key of the CA, for verifying release signatures with `minisign -V`.
+    /// It is derived from the CA key and differs from the CA verifying key.
+    pub fn minisign_public_key(&self) -> Result<String> {
+        Ok(minisign::encode_public_key(&self.minisign_key()?))
+    }
+
+    fn minisign_key(&self) -> Result<VerifyingKey> {
+        self.ca_data
+            .minisign_key
+            .ok_or(Error::NoCa("Instance has no minisign key, export it again"))
+    }
+
+    /// Minisign signature file for `data` made with the key derived from the CA key, only the user has it
+    pub fn minisign_sign(
         &self,
-        sender: &str,
         data: &[u8],
-    ) -> anyhow::Result<Vec<u8>> {
-        let shared_key = match self.shared_keys.get(sender) {
+        trusted_comment: &str,
+        prehashed: bool,
+    ) -> Result<String> {
+        let ca_key = match self.ca_data.secret_key {
             None => {
-                return Err(Error::msg("No shared key found"));
+                return Err(Error::NoCa("CA data is missing"));
             }
-            Some(v) => v,
+            Some(v) => SigningKey::from_bytes(&v),
         };
+        minisign::sign(
+            &minisign::derive_signing_key(&ca_key),
+            data,
+            trusted_comment,
+            minisign::DEFAULT_UNTRUSTED_COMMENT,
+            prehashed,
+        )
+    }
 
-        let nonce = &data[0..24];
-        let mut buffer = data[24..].to_vec();
+    /// Verify a minisign signature made by the user, returns the trusted comment
+    pub fn minisign_verify(&self, data: &[u8], signature: &str) -> Result<String> {
+        let minisign_key = self.minisign_key()?;
+        minisign::verify_with_key(
+            &minisign::key_id(&minisign_key),
+            &minisign_key,
+            data,
+            signature,
+        )
+    }
 
-        let key = GenericArray::from_slice(shared_key);
-        let cipher = XChaCha20Poly1305::new(key);
-        let nonce = GenericArray::from_slice(nonce);
+    /// Certificate naming us `username` for sealed sender messages, valid until `expires_at` (unix seconds)
+    pub fn sender_certificate(&self, username: &str, expires_at: u64) -> Result<Vec<u8>> {
+        let certificate = SenderCertificate::new(
+            &self.signing_key,
+            &self.signing_key_signature,
+            username,
+            expires_at,
+        )?;
+        bson::to_vec(&certificate).map_err(Error::from)
+    }
 
-        let associated_data = &data[0..24];
+    /// Encrypt for the newest session with `recipient` and hide who we are from the relay.
+    /// `certificate` comes from sender_certificate, `recipient_certificate` from the recipient's
+    /// Client::certificate and must be signed by the CA.
+    pub fn seal_sender(
+        &mut self,
+        recipient: &str,
+        recipient_certificate: &Certificate,
+        certificate: &[u8],
+        message: &[u8],
+    ) -> Result<Vec<u8>> {
+        recipient_certificate.verify(&self.ca_data.verifying_key)?;
+        let parsed: SenderCertificate = self.limits.parse_bson(certificate)?;
+        if parsed.verifying_key != self.signing_key.verifying_key().to_bytes() {
+            return Err(Error::UntrustedKey("Sender certificate is not ours"));
+        }
+        let content = SealedContent {
+            certificate: certificate.to_vec(),
+            message: self.encrypt_message_for_recipient(recipient, message)?,
+        };
+        let content = bson::to_vec(&content).map_err(Error::from)?;
+        // Base mode, the sender is only named inside
+        hpke::seal(
+            &mut self.csprng,
+            &recipient_certificate.static_key,
+            sealed_sender::HPKE_INFO,
+            recipient_certificate.verifying_key.as_bytes(),
+            &content,
+            None,
+            None,
+        )
+    }
 
-        cipher
-            .decrypt_in_place(nonce, associated_data, &mut buffer)
-            .map_err(Error::msg)?;
+    /// Open the output of seal_sender, the sender certificate is checked against the CA
+    /// and the message must decrypt with a session of the certified username
+    pub fn open_sealed_sender(&mut self, data: &[u8]) -> Result<SealedSenderMessage> {
+        let own_secret = identity::static_secret(&self.signing_key);
+        let content = hpke::open(
+            &own_secret,
+            sealed_sender::HPKE_INFO,
+            self.signing_key.verifying_key().as_bytes(),
+            data,
+            None,
+            None,
+        )?;
+        let content: SealedContent = self.limits.parse_bson(&content)?;
+        let certificate: SenderCertificate = self.limits.parse_bson(&content.certificate)?;
+        let sender_key = certificate.validate(&self.ca_data.verifying_key, self.now)?;
+        let message = self.decrypt_message_from_sender(&certificate.username, &content.message)?;
+        Ok(SealedSenderMessage {
+            sender: certificate.username,
+            sender_key,
+            message,
+        })
+    }
 
-        // Decompression step
-        let decompressed = lz4_flex::decompress_size_prepended(&buffer).map_err(Error::msg)?;
+    /// Token to hand to contacts so they can deliver sealed sender messages to us through a relay.
+    /// Pick a new `epoch` to rotate it, e.g. a counter or the current week.
+    pub fn delivery_token(&self, epoch: u64) -> [u8; 32] {
+        sealed_sender::delivery_token(&self.signing_key, epoch)
+    }
 
-        Ok(decompressed)
+    /// Verifier for delivery_token of `epoch` to register with a relay,
+    /// see sealed_sender::check_delivery_token
+    pub fn delivery_token_verifier(&self, epoch: u64) -> [u8; 32] {
+        sealed_sender::delivery_token_verifier(&self.delivery_token(epoch))
     }
-}
 
-#[derive(Serialize, Deserialize)]
-struct KexPacket {
-    public_key: [u8; 32],
-    sig: Vec<u8>,
-    verifying_key: Vec<u8>,
-    signing_key_sig: Vec<u8>,
-}
+    /// Start a Noise handshake bound to this identity.
+    /// `remote_static` is required for the initiator of IK and NK, see `noise_static_public_key`.
+    pub fn noise_handshake(
+        &self,
+        params: noise::NoiseParams,
+        initiator: bool,
+        remote_static: Option<PublicKey>,
+    ) -> Result<noise::IdentityHandshake> {
+        let state = noise::HandshakeState::new(
+            params,
+            initiator,
+            params.name().as_bytes(),
+            Some(identity::static_secret(&self.signing_key)),
+            remote_static,
+        )?;
+
+        Ok(noise::IdentityHandshake::new(
+            state,
+            self.certificate(),
+            self.ca_data.verifying_key,
+            self.limits,
+        ))
+    }
 
-#[derive(Serialize, Deserialize)]
-struct UserForExport {
-    signing_key: Vec<u8>,
-    ca_key: Vec<u8>,
-}
+    /// Use a completed Noise handshake as the shared key for `recipient`,
+    /// so encrypt_message_for_recipient and decrypt_message_from_sender work as after complete_dh_kex.
+    /// Sessions only accept messages from the peer's certified identity, so the peer must have
+    /// authenticated itself. The NK initiator stays anonymous: the responder cannot install the
+    /// session and both sides keep using the TransportState instead.
+    pub fn install_noise_session(
+        &mut self,
+        recipient: &str,
+        transport: &noise::TransportState,
+    ) -> Result<()> {
+        let peer_key = match transport.remote_verifying_key() {
+            None => return Err(Error::Authentication("Noise peer is not authenticated")),
+            Some(v) => v,
+        };
+        self.insert_session(
+            recipient,
+            &peer_key,
+            transport.session_key(),
+            CipherSuite::XChaCha20Poly1305,
+        );
+        Ok(())
+    }
 
-#[derive(Serialize, Deserialize)]
-struct InstanceForExport {
-    signing_key: Vec<u8>,
-    sig: Vec<u8>,
-    ca_verifying_key: Vec<u8>,
-}
+    pub(crate) fn insert_session(
+        &mut self,
+        peer: &str,
+        peer_key: &VerifyingKey,
+        key: [u8; 32],
+        suite: CipherSuite,
+    ) -> SessionId {
+        let mut session = Session::new(key);
+        session.suite = suite;
+        session.peer_key_id = Some(message::key_id(peer_key));
+        self.add_session(peer, session)
+    }
+
+    fn add_session(&mut self, peer: &str, session: Session) -> SessionId {
+        let id = session.id;
+
+        let sessions = self.shared_keys.entry(peer.to_string()).or_default();
+        sessions.retain(|v| v.id != id);
+        sessions.push(session);
+        if sessions.len() > MAX_SESSIONS_PER_PEER {
+            sessions.remove(0);
+        }
+
+        id
+    }
+
+    /// Limits after which session keys are ratcheted, applies to all sessions
+    pub fn set_rekey_policy(&mut self, policy: RekeyPolicy) {
+        self.rekey_policy = policy;
+    }
+
+    /// Cipher suites to offer and accept in key exchanges, most preferred first.
+    /// The first one also protects exports and envelopes.
+    pub fn set_cipher_suites(&mut self, suites: &[CipherSuite]) -> Result<()> {
+        if suites.is_empty() {
+            return Err(Error::InvalidArgument("No cipher suite"));
+        }
+        self.cipher_suites = suites.to_vec();
+        Ok(())
+    }
+
+    pub fn cipher_suites(&self) -> &[CipherSuite] {
+        &self.cipher_suites
+    }
+
+    pub(crate) fn preferred_suite(&self) -> CipherSuite {
+        self.cipher_suites[0]
+    }
+
+    /// Size limits for messages and documents, see limits::Limits
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Tell the client the current unix time in seconds.
+    /// The library has no clock of its own, time based limits are only checked while this is set.
+    pub fn set_time(&mut self, unix_secs: u64) {
+        self.now = Some(unix_secs);
+    }
+
+    /// Time-to-live in seconds for messages from encrypt_message_for_recipient and
+    /// encrypt_message_for_session, None for messages that do not expire.
+    /// Receivers refuse expired messages, and any expiring message while their clock is not set,
+    /// see set_time.
+    pub fn set_message_ttl(&mut self, ttl_secs: Option<u64>) {
+        self.message_ttl = ttl_secs;
+    }
+
+    /// Ratchet a session key now instead of waiting for the rekey policy.
+    /// The peer follows with the next message it receives.
+    pub fn rekey_session(&mut self, peer: &str, session_id: &SessionId) -> Result<()> {
+        let now = self.now;
+        match self
+            .shared_keys
+            .get_mut(peer)
+            .and_then(|v| v.iter_mut().find(|s| &s.id == session_id))
+        {
+            None => Err(Error::NoSession("No shared key found")),
+            Some(v) => v.ratchet(now),
+        }
+    }
+
+    /// Ids of the sessions with `peer`, oldest first.
+    /// encrypt_message_for_recipient uses the last one.
+    pub fn sessions(&self, peer: &str) -> Vec<SessionId> {
+        self.shared_keys
+            .get(peer)
+            .map(|v| v.iter().map(|s| s.id).collect())
+            .unwrap_or_default()
+    }
+
+    /// Forget a session, messages encrypted in it can no longer be decrypted
+    pub fn close_session(&mut self, peer: &str, session_id: &SessionId) -> Result<()> {
+        let sessions = match self.shared_keys.get_mut(peer) {
+            None => {
+                return Err(Error::NoSession("No shared key found"));
+            }
+            Some(v) => v,
+        };
+        let len = sessions.len();
+        sessions.retain(|v| &v.id != session_id);
+        if sessions.len() == len {
+            return Err(Error::NoSession("No shared key found"));
+        }
+        if sessions.is_empty() {
+            self.shared_keys.remove(peer);
+        }
+        Ok(())
+    }
+
+    /// Encrypt for the newest session with `recipient`
+    pub fn encrypt_message_for_recipient(
+        &mut self,
+        recipient: &str,
+        message: &[u8],
+    ) -> Result<Vec<u8>> {
+        let session_id = match self.shared_keys.get(recipient).and_then(|v| v.last()) {
+            None => {
+                return Err(Error::NoSession("No shared key found"));
+            }
+            Some(v) => v.id,
+        };
+        self.encrypt_message_for_session(recipient, &session_id, message)
+    }
+
+    /// Encrypt for a specific session with `recipient`, see sessions.
+    /// Output is header || nonce (24 bytes) || ciphertext, see message::MessageHeader,
+    /// header and nonce are associated data.
+    /// The session key is ratcheted first if the rekey policy says so.
+    /// The message is compressed as set with set_session_compression, LZ4 by default,
+    /// and padded as set with set_session_padding. It expires as set with set_message_ttl.
+    pub fn encrypt_message_for_session(
+        &mut self,
+        recipient: &str,
+        session_id: &SessionId,
+        message: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.encrypt_message_with_options(
+            recipient,
+            session_id,
+            message,
+            &MessageOptions::default(),
+        )
+    }
+
+    /// Like encrypt_message_for_session with the codec chosen for this message.
+    /// Compression is skipped if it does not make the message smaller.
+    pub fn encrypt_message_with_compression(
+        &mut self,
+        recipient: &str,
+        session_id: &SessionId,
+        message: &[u8],
+        compression: Compression,
+    ) -> Result<Vec<u8>> {
+        let options = MessageOptions {
+            compression: Some(compression),
+            ..Default::default()
+        };
+        self.encrypt_message_with_options(recipient, session_id, message, &options)
+    }
+
+    /// Like encrypt_message_for_session with settings for this message only.
+    /// Padding is applied after compression, sensitive messages are never compressed.
+    pub fn encrypt_message_with_options(
+        &mut self,
+        recipient: &str,
+        session_id: &SessionId,
+        message: &[u8],
+        options: &MessageOptions,
+    ) -> Result<Vec<u8>> {
+        let (compression, padding) = match self
+            .shared_keys
+            .get(recipient)
+            .and_then(|v| v.iter().find(|s| &s.id == session_id))
+        {
+            None => {
+                return Err(Error::NoSession("No shared key found"));
+            }
+            Some(v) => (
+                options.compression.unwrap_or(v.compression),
+                options.padding.unwrap_or(v.padding),
+            ),
+        };
+        self.limits.check_plaintext(message.len())?;
+
+        let (mut flags, mut buffer) = message::encode_payload(
+            compression,
+            padding,
+            options.sensitive,
+            &mut self.csprng,
+            message,
+        )?;
+        // The receiver checks the payload against its limit before decrypting it
+        self.limits.check_plaintext(buffer.len())?;
+        if !options.aad.is_empty() {
+            flags |= message::FLAG_AAD;
+            if options.aad_hash {
+                flags |= message::FLAG_AAD_HASH;
+            }
+        }
+        let expires_at = match options.ttl_secs.or(self.message_ttl) {
+            None => None,
+            Some(ttl) => {
+                let now = self
+                    .now
+                    .ok_or(Error::InvalidState("Time-to-live needs Client::set_time"))?;
+                flags |= message::FLAG_EXPIRES;
+                Some(now.saturating_add(ttl))
+            }
+        };
+
+        let (mut header, session_key) =
+            self.next_message_header(recipient, session_id, message.len(), flags)?;
+        if flags & message::FLAG_AAD_HASH != 0 {
+            header.aad_hash = Some(message::aad_hash(options.aad));
+        }
+        header.expires_at = expires_at;
+
+        let mut nonce = alloc::vec![0u8; header.suite.nonce_len()];
+        self.csprng.fill_bytes(&mut nonce);
+
+        let mut aead_data = header.encode();
+        aead_data.extend_from_slice(&nonce);
+        let prefix_len = aead_data.len();
+        aead_data.extend_from_slice(options.aad);
+
+        header
+            .suite
+            .seal(&session_key, &nonce, &aead_data, &mut buffer)?;
+
+        aead_data.truncate(prefix_len);
+        aead_data.extend_from_slice(&buffer);
+
+        Ok(aead_data)
+    }
+
+    /// Codec for messages sent in this session, see encrypt_message_for_session
+    pub fn set_session_compression(
+        &mut self,
+        peer: &str,
+        session_id: &SessionId,
+        compression: Compression,
+    ) -> Result<()> {
+        match self
+            .shared_keys
+            .get_mut(peer)
+            .and_then(|v| v.iter_mut().find(|s| &s.id == session_id))
+        {
+            None => Err(Error::NoSession("No shared key found")),
+            Some(session) => {
+                session.compression = compression;
+                Ok(())
+            }
+        }
+    }
+
+    /// Padding for messages sent in this session, none by default
+    pub fn set_session_padding(
+        &mut self,
+        peer: &str,
+        session_id: &SessionId,
+        padding: Padding,
+    ) -> Result<()> {
+        padding::padded_len(padding, 0)?;
+        match self
+            .shared_keys
+            .get_mut(peer)
+            .and_then(|v| v.iter_mut().find(|s| &s.id == session_id))
+        {
+            None => Err(Error::NoSession("No shared key found")),
+            Some(session) => {
+                session.padding = padding;
+                Ok(())
+            }
+        }
+    }
+
+    // Header and key for the next message of a session, counts the message as sent
+    fn next_message_header(
+        &mut self,
+        recipient: &str,
+        session_id: &SessionId,
+        len: usize,
+        flags: u16,
+    ) -> Result<(MessageHeader, [u8; 32])> {
+        let sender_key_id = message::key_id(&self.signing_key.verifying_key());
+        let session = match self
+            .shared_keys
+            .get_mut(recipient)
+            .and_then(|v| v.iter_mut().find(|s| &s.id == session_id))
+        {
+            None => {
+                return Err(Error::NoSession("No shared key found"));
+            }
+            Some(v) => v,
+        };
+        session.prepare_send(&self.rekey_policy, self.now, len)?;
+
+        let header = MessageHeader {
+            version: message::VERSION_1,
+            suite: session.suite,
+            flags,
+            session_id: session.id,
+            epoch: session.epoch,
+            sender_key_id,
+            seq: session.send_seq,
+            aad_hash: None,
+            expires_at: None,
+        };
+        session.record_send(len);
+        Ok((header, direction_key(&session.key, &sender_key_id)))
+    }
+
+    /// Start encrypting a large message for the newest session with `recipient` in chunks.
+    /// Returns the encryptor and the stream header, which goes in front of the chunks.
+    /// The plaintext is not compressed and does not count towards the rekey byte limits.
+    pub fn encrypt_stream_for_recipient(
+        &mut self,
+        recipient: &str,
+    ) -> Result<(StreamEncryptor, Vec<u8>)> {
+        let session_id = match self.shared_keys.get(recipient).and_then(|v| v.last()) {
+            None => {
+                return Err(Error::NoSession("No shared key found"));
+            }
+            Some(v) => v.id,
+        };
+        let (header, session_key) =
+            self.next_message_header(recipient, &session_id, 0, message::FLAG_STREAM)?;
+        let mut salt = [0u8; stream::SALT_LEN];
+        self.csprng.fill_bytes(&mut salt);
+        StreamEncryptor::new(header.suite, &session_key, &header.encode(), &salt)
+    }
+
+    /// Start decrypting a stream from `sender`, `stream_header` is the first
+    /// stream::STREAM_HEADER_LEN bytes. The header is authenticated and checked
+    /// against the replay window before any chunk is decrypted.
+    pub fn decrypt_stream_from_sender(
+        &mut self,
+        sender: &str,
+        stream_header: &[u8],
+    ) -> Result<(StreamDecryptor, Delivery)> {
+        let header = message::parse_header(stream_header)?;
+        if header.flags & message::FLAG_STREAM == 0 {
+            return Err(Error::Malformed("Message is not a stream"));
+        }
+        let own_key_id = message::key_id(&self.signing_key.verifying_key());
+        let session = match self
+            .shared_keys
+            .get_mut(sender)
+            .and_then(|v| v.iter_mut().find(|s| s.id == header.session_id))
+        {
+            None => {
+                return Err(Error::NoSession("No shared key found"));
+            }
+            Some(v) => v,
+        };
+        // Only the suite negotiated for the session, so it cannot be downgraded
+        if header.suite != session.suite {
+            return Err(Error::Authentication(
+                "Cipher suite does not match the session",
+            ));
+        }
+        let session_key = session.receive_key(header.epoch, &header.sender_key_id, &own_key_id)?;
+        let decryptor = StreamDecryptor::new(session.suite, &session_key, stream_header)?;
+        let delivery = session.check_seq(header.seq)?;
+        session.advance_to(header.epoch, self.now)?;
+        session.record_seq(header.seq);
+        Ok((decryptor, delivery))
+    }
+
+    /// Decrypt a message from `sender`, the session is taken from the ciphertext.
+    /// A message from a newer epoch moves the session forward to it.
+    /// Replayed messages are rejected, see receive_message_from_sender.
+    pub fn decrypt_message_from_sender(&mut self, sender: &str, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(self.receive_message_from_sender(sender, data)?.message)
+    }
+
+    /// Like decrypt_message_from_sender, also reports gaps and late messages.
+    /// Sequence numbers that were already received or are older than the replay window fail.
+    pub fn receive_message_from_sender(
+        &mut self,
+        sender: &str,
+        data: &[u8],
+    ) -> Result<ReceivedMessage> {
+        self.receive_message_with_aad(sender, data, &[])
+    }
+
+    /// Encrypt for the newest session with `recipient`, bound to `aad`.
+    /// It only decrypts with the same `aad`, see MessageOptions::aad.
+    pub fn encrypt_message_with_aad(
+        &mut self,
+        recipient: &str,
+        message: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>> {
+        let session_id = match self.shared_keys.get(recipient).and_then(|v| v.last()) {
+            None => {
+                return Err(Error::NoSession("No shared key found"));
+            }
+            Some(v) => v.id,
+        };
+        let options = MessageOptions {
+            aad,
+            ..Default::default()
+        };
+        self.encrypt_message_with_options(recipient, &session_id, message, &options)
+    }
+
+    /// Encrypt for the newest session with `recipient`, refused `ttl_secs` after now.
+    /// Needs Client::set_time, receivers check the expiry against their own clock.
+    pub fn encrypt_message_with_ttl(
+        &mut self,
+        recipient: &str,
+        message: &[u8],
+        ttl_secs: u64,
+    ) -> Result<Vec<u8>> {
+        let session_id = match self.shared_keys.get(recipient).and_then(|v| v.last()) {
+            None => {
+                return Err(Error::NoSession("No shared key found"));
+            }
+            Some(v) => v.id,
+        };
+        let options = MessageOptions {
+            ttl_secs: Some(ttl_secs),
+            ..Default::default()
+        };
+        self.encrypt_message_with_options(recipient, &session_id, message, &options)
+    }
+
+    /// Decrypt a message that was encrypted with `aad`, see encrypt_message_with_aad
+    pub fn decrypt_message_with_aad(
+        &mut self,
+        sender: &str,
+        data: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>> {
+        Ok(self.receive_message_with_aad(sender, data, aad)?.message)
+    }
+
+    /// Like receive_message_from_sender for a message encrypted with `aad`.
+    /// Fails if the message was encrypted with other or without associated data.
+    pub fn receive_message_with_aad(
+        &mut self,
+        sender: &str,
+        data: &[u8],
+        aad: &[u8],
+    ) -> Result<ReceivedMessage> {
+        let header = message::parse_header(data)?;
+        if header.flags & message::FLAG_STREAM != 0 {
+            return Err(Error::InvalidArgument(
+                "Message is a stream, see decrypt_stream_from_sender",
+            ));
+        }
+        if header.flags & message::FLAG_AAD == 0 {
+            if !aad.is_empty() {
+                return Err(Error::Authentication("Message has no associated data"));
+            }
+        } else if aad.is_empty() {
+            return Err(Error::Authentication("Message needs associated data"));
+        }
+        if let Some(hash) = header.aad_hash {
+            if hash != message::aad_hash(aad) {
+                return Err(Error::Authentication("Associated data does not match"));
+            }
+        }
+        // Without a clock we cannot tell, so expiring messages are refused rather than read late
+        if header.expires_at.is_some() {
+            let now = self.now.ok_or(Error::InvalidState(
+                "Expiring messages need Client::set_time",
+            ))?;
+            if header.is_expired(now) {
+                return Err(Error::Expired("Message has expired"));
+            }
+        }
+        let own_key_id = message::key_id(&self.signing_key.verifying_key());
+        let session = match self
+            .shared_keys
+            .get_mut(sender)
+            .and_then(|v| v.iter_mut().find(|s| s.id == header.session_id))
+        {
+            None => {
+                return Err(Error::NoSession("No shared key found"));
+            }
+            Some(v) => v,
+        };
+        // Only the suite negotiated for the session, so it cannot be downgraded
+        if header.suite != session.suite {
+            return Err(Error::Authentication(
+                "Cipher suite does not match the session",
+            ));
+        }
+        let session_key = session.receive_key(header.epoch, &header.sender_key_id, &own_key_id)?;
+
+        let prefix_len = header.encoded_len() + header.suite.nonce_len();
+        let nonce = &data[header.encoded_len()..prefix_len];
+        self.limits
+            .check_plaintext((data.len() - prefix_len).saturating_sub(header.suite.overhead()))?;
+        let mut buffer = data[prefix_len..].to_vec();
+        let mut associated_data = data[..prefix_len].to_vec();
+        associated_data.extend_from_slice(aad);
+
+        header
+            .suite
+            .open(&session_key, nonce, &associated_data, &mut buffer)?;
+        let delivery = session.check_seq(header.seq)?;
+
+        // Only authentic messages may move the session forward
+        session.advance_to(header.epoch, self.now)?;
+        session.record_seq(header.seq);
+
+        let message =
+            message::decode_payload(header.flags, buffer, self.limits.max_decompressed_size)?;
+        self.limits.check_plaintext(message.len())?;
+        Ok(ReceivedMessage {
+            message,
+            seq: header.seq,
+            delivery,
+            expires_at: header.expires_at,
+        })
+    }
+
+    /// Only accept messages of this session in order, without gaps. For control channels.
+    pub fn set_strict_order(
+        &mut self,
+        peer: &str,
+        session_id: &SessionId,
+        strict: bool,
+    ) -> Result<()> {
+        match self
+            .shared_keys
+            .get_mut(peer)
+            .and_then(|v| v.iter_mut().find(|s| &s.id == session_id))
+        {
+            None => Err(Error::NoSession("No shared key found")),
+            Some(session) => {
+                session.strict_order = strict;
+                Ok(())
+            }
+        }
+    }
+
+    /// Decrypt a message without knowing the sender, it is looked up from the session id.
+    /// Returns sender and message.
+    pub fn decrypt_message(&mut self, data: &[u8]) -> Result<(String, Vec<u8>)> {
+        let session_id = message::parse_header(data)?.session_id;
+        let sender = match self
+            .shared_keys
+            .iter()
+            .find(|(_, sessions)| sessions.iter().any(|s| s.id == session_id))
+        {
+            None => {
+                return Err(Error::NoSession("No shared key found"));
+            }
+            Some((peer, _)) => peer.clone(),
+        };
+        let message = self.decrypt_message_from_sender(&sender, data)?;
+        Ok((sender, message))
+    }
+
+    /// Encrypt `message` once for several recipients, see EnvelopeRecipient.
+    /// The content key is wrapped per recipient, the envelope is signed with our identity key.
+    pub fn encrypt_envelope(
+        &mut self,
+        recipients: &[EnvelopeRecipient],
+        message: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.encrypt_envelope_with_options(recipients, message, &MessageOptions::default())
+    }
+
+    /// Like encrypt_envelope with compression, padding and sensitive set for this envelope.
+    /// Envelopes are LZ4 compressed and not padded by default.
+    pub fn encrypt_envelope_with_options(
+        &mut self,
+        recipients: &[EnvelopeRecipient],
+        message: &[u8],
+        options: &MessageOptions,
+    ) -> Result<Vec<u8>> {
+        if recipients.is_empty() {
+            return Err(Error::InvalidArgument("No recipients"));
+        }
+        options.check_payload_only()?;
+        self.limits.check_plaintext(message.len())?;
+        let (flags, payload) = message::encode_payload(
+            options.compression.unwrap_or(Compression::Lz4),
+            options.padding.unwrap_or(Padding::None),
+            options.sensitive,
+            &mut self.csprng,
+            message,
+        )?;
+        // The recipients check the payload against their limit before decrypting it
+        self.limits.check_plaintext(payload.len())?;
+        let mut content_key = [0u8; 32];
+        self.csprng.fill_bytes(&mut content_key);
+        let mut salt = [0u8; 32];
+        self.csprng.fill_bytes(&mut salt);
+
+        let signer = self.signing_key.verifying_key();
+        let mut stanzas = Vec::new();
+        for recipient in recipients {
+            let stanza = match recipient {
+                EnvelopeRecipient::Peer(peer) => {
+                    let session = match self.shared_keys.get_mut(*peer).and_then(|v| v.last_mut()) {
+                        None => return Err(Error::NoSession("No shared key found")),
+                        Some(v) => v,
+                    };
+                    session.prepare_send(&self.rekey_policy, self.now, content_key.len())?;
+                    let stanza = envelope::session_stanza(
+                        &session.key,
+                        session.epoch,
+                        &salt,
+                        &signer,
+                        &content_key,
+                    )?;
+                    session.record_send(content_key.len());
+                    stanza
+                }
+                EnvelopeRecipient::PublicKey(public_key) => envelope::public_key_stanza(
+                    &mut self.csprng,
+                    public_key,
+                    &salt,
+                    &signer,
+                    &content_key,
+                )?,
+            };
+            stanzas.push(stanza);
+        }
+        envelope::shuffle(&mut self.csprng, &mut stanzas);
+
+        let suite = self.preferred_suite();
+        let mut nonce = alloc::vec![0u8; suite.nonce_len()];
+        self.csprng.fill_bytes(&mut nonce);
+        let mut envelope = Envelope {
+            verifying_key: signer
+                .to_public_key_der()
+                .map_err(|_| Error::Internal("Could not encode key"))?
+                .to_vec(),
+            signing_key_sig: self.signing_key_signature.to_bytes().to_vec(),
+            salt: salt.to_vec(),
+            stanzas,
+            ciphertext: envelope::encrypt_payload(suite, &content_key, &nonce, &salt, payload)?,
+            nonce,
+            suite: Some(suite.id()),
+            flags: Some(flags),
+            signature: Vec::new(),
+        };
+        envelope.signature = self.signing_key.sign(&envelope.tbs()?).to_bytes().to_vec();
+
+        bson::to_vec(&envelope).map_err(Error::from)
+    }
+
+    /// Decrypt an envelope addressed to one of our sessions or to our noise_static_public_key.
+    /// The sender has to be certified by our CA.
+    pub fn decrypt_envelope(&mut self, data: &[u8]) -> Result<OpenedEnvelope> {
+        let envelope: Envelope = self.limits.parse_bson(data)?;
+
+        let sender =
+            VerifyingKey::from_public_key_der(&envelope.verifying_key).map_err(Error::from)?;
+        let signing_key_sig = Signature::from_slice(&envelope.signing_key_sig)
+            .map_err(|_| Error::Malformed("Invalid signature"))?;
+        if self
+            .ca_data
+            .verifying_key
+            .verify(sender.as_bytes(), &signing_key_sig)
+            .is_err()
+        {
+            return Err(Error::UntrustedKey("Sender not signed by CA"));
+        }
+        let signature = Signature::from_slice(&envelope.signature)
+            .map_err(|_| Error::Malformed("Invalid signature"))?;
+        if sender.verify(&envelope.tbs()?, &signature).is_err() {
+            return Err(Error::Authentication("Envelope not signed by sender"));
+        }
+
+        let own_secret = identity::static_secret(&self.signing_key);
+        let sender_key_id = message::key_id(&sender);
+        // (peer, session id, epoch) to move forward once the payload is authentic
+        let mut matched = None;
+        let mut content_key = None;
+        'stanzas: for stanza in &envelope.stanzas {
+            match stanza {
+                Stanza::Session {
+                    epoch,
+                    hint,
+                    wrapped_key,
+                } => {
+                    for (peer, sessions) in self.shared_keys.iter() {
+                        for session in sessions {
+                            let key = match session.key_for_epoch(*epoch) {
+                                Err(_) => continue,
+                                Ok(v) => v,
+                            };
+                            if &envelope::session_hint(&key, &envelope.salt)? != hint {
+                                continue;
+                            }
+                            // Only the session partner may use the session
+                            if session.peer_key_id != Some(sender_key_id) {
+                                return Err(Error::Authentication(
+                                    "Session stanza not from the session partner",
+                                ));
+                            }
+                            if let Some(v) = envelope::open_session_stanza(
+                                &key,
+                                &envelope.salt,
+                                &sender,
+                                wrapped_key,
+                            )? {
+                                matched = Some((peer.clone(), session.id, *epoch));
+                                content_key = Some(v);
+                                break 'stanzas;
+                            }
+                        }
+                    }
+                }
+                Stanza::PublicKey {
+                    ephemeral_key,
+                    wrapped_key,
+                } => {
+                    if let Some(v) = envelope::open_public_key_stanza(
+                        &own_secret,
+                        ephemeral_key,
+                        &envelope.salt,
+                        &sender,
+                        wrapped_key,
+                    )? {
+                        content_key = Some(v);
+                        break 'stanzas;
+                    }
+                }
+            }
+        }
+        let content_key = match content_key {
+            None => return Err(Error::NotFound("Envelope is not addressed to us")),
+            Some(v) => v,
+        };
+        if !self.cipher_suites.contains(&envelope.suite()?) {
+            return Err(Error::Unsupported("Cipher suite not allowed"));
+        }
+        let message = envelope::decrypt_payload(&content_key, &envelope, &self.limits)?;
+
+        let peer = match matched {
+            None => None,
+            Some((peer, session_id, epoch)) => {
+                if let Some(session) = self
+                    .shared_keys
+                    .get_mut(&peer)
+                    .and_then(|v| v.iter_mut().find(|s| s.id == session_id))
+                {
+                    session.advance_to(epoch, self.now)?;
+                }
+                Some(peer)
+            }
+        };
+
+        Ok(OpenedEnvelope {
+            sender,
+            peer,
+            message,
+        })
+    }
+
+    /// Create a group with `members`, each needs an established session.
+    /// Returns the group id and our sender key for every member, deliver them to the members
+    /// who pass them to process_group_key. We are the group admin, only we can change the members.
+    pub fn create_group(&mut self, members: &[&str]) -> Result<(GroupId, SenderKeyDistribution)> {
+        let mut members: Vec<String> = members.iter().map(|v| v.to_string()).collect();
+        members.sort();
+        members.dedup();
+        if members.iter().any(|v| !self.shared_keys.contains_key(v)) {
+            return Err(Error::NoSession("No shared key found for a member"));
+        }
+
+        let mut id = [0u8; 16];
+        self.csprng.fill_bytes(&mut id);
+        let suite = self.preferred_suite();
+        let group = Group::new(id, members.clone(), None, suite, &mut self.csprng);
+        self.groups.insert(id, group);
+
+        let distribution = self.distribute_group_key(&id, &members)?;
+        Ok((id, distribution))
+    }
+
+    /// Add `member` to a group we are the admin of. Our sender key is renewed and sent to every
+    /// member, so the new member cannot read earlier messages.
+    pub fn add_group_member(
+        &mut self,
+        group_id: &GroupId,
+        member: &str,
+    ) -> Result<SenderKeyDistribution> {
+        if !self.shared_keys.contains_key(member) {
+            return Err(Error::NoSession("No shared key found"));
+        }
+        let group = self.group_mut(group_id)?;
+        if group.admin.is_some() {
+            return Err(Error::InvalidState(
+                "Only the group admin can change the members",
+            ));
+        }
+        if group.members.iter().any(|v| v == member) {
+            return Err(Error::InvalidArgument("Already a group member"));
+        }
+        let mut members = group.members.clone();
+        members.push(member.to_string());
+        members.sort();
+        group.set_members(members);
+
+        self.rekey_group(group_id)
+    }
+
+    /// Remove `member` from a group we are the admin of. Our sender key is renewed and sent to
+    /// the remaining members, who renew theirs in turn once they process it.
+    pub fn remove_group_member(
+        &mut self,
+        group_id: &GroupId,
+        member: &str,
+    ) -> Result<SenderKeyDistribution> {
+        let group = self.group_mut(group_id)?;
+        if group.admin.is_some() {
+            return Err(Error::InvalidState(
+                "Only the group admin can change the members",
+            ));
+        }
+        if !group.members.iter().any(|v| v == member) {
+            return Err(Error::InvalidArgument("Not a group member"));
+        }
+        let members = group
+            .members
+            .iter()
+            .filter(|v| v.as_str() != member)
+            .cloned()
+            .collect();
+        group.set_members(members);
+
+        self.rekey_group(group_id)
+    }
+
+    /// Our current sender key for one member, e.g. once a session with it exists
+    pub fn group_key_for_member(&mut self, group_id: &GroupId, member: &str) -> Result<Vec<u8>> {
+        let group = self.group_mut(group_id)?;
+        if !group.members.iter().any(|v| v == member) {
+            return Err(Error::InvalidArgument("Not a group member"));
+        }
+        let message = group.sender_key_message(member);
+        let serialized = bson::to_vec(&message).map_err(Error::from)?;
+        self.encrypt_message_for_recipient(member, &serialized)
+    }
+
+    /// Process a sender key from create_group, add_group_member or remove_group_member of `sender`.
+    /// The member list is only taken over from the group admin, a group is joined with the admin's
+    /// sender key. If we joined the group or the members changed, our own sender key is renewed
+    /// and returned for the members we have a session with.
+    pub fn process_group_key(&mut self, sender: &str, data: &[u8]) -> Result<GroupKeyUpdate> {
+        let serialized = self.decrypt_message_from_sender(sender, data)?;
+        let message: SenderKeyMessage = self.limits.parse_bson(&serialized)?;
+        let group_id: GroupId = message
+            .group_id
+            .as_slice()
+            .try_into()
+            .map_err(|_| Error::Malformed("Invalid group id length"))?;
+
+        let mut members = message.members.clone();
+        members.push(sender.to_string());
+        members.sort();
+        members.dedup();
+
+        let changed = match self.groups.get_mut(&group_id) {
+            None => {
+                if message.admin.is_some() {
+                    return Err(Error::InvalidState(
+                        "Join the group with the sender key of its admin first",
+                    ));
+                }
+                let suite = self.preferred_suite();
+                let admin = Some(sender.to_string());
+                let mut group = Group::new(group_id, members, admin, suite, &mut self.csprng);
+                group.install_sender_key(sender, &message, &self.cipher_suites)?;
+                self.groups.insert(group_id, group);
+                true
+            }
+            Some(group) => {
+                if !group.members.iter().any(|v| v == sender) {
+                    return Err(Error::UntrustedKey("Sender is not a group member"));
+                }
+                group.install_sender_key(sender, &message, &self.cipher_suites)?;
+                // Other members only hand out their chain
+                if group.admin.as_deref() != Some(sender) {
+                    return Ok(GroupKeyUpdate {
+                        group_id,
+                        distribution: Vec::new(),
+                    });
+                }
+                let changed = group.members != members;
+                group.set_members(members);
+                if changed {
+                    group.rekey(&mut self.csprng)?;
+                }
+                changed
+            }
+        };
+
+        let distribution = match changed {
+            false => Vec::new(),
+            true => {
+                let members: Vec<String> = self
+                    .group_members(&group_id)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|v| self.shared_keys.contains_key(v))
+                    .collect();
+                self.distribute_group_key(&group_id, &members)?
+            }
+        };
+
+        Ok(GroupKeyUpdate {
+            group_id,
+            distribution,
+        })
+    }
+
+    /// Encrypt once for all members of a group
+    pub fn encrypt_group_message(&mut self, group_id: &GroupId, message: &[u8]) -> Result<Vec<u8>> {
+        self.encrypt_group_message_with_options(group_id, message, &MessageOptions::default())
+    }
+
+    /// Like encrypt_group_message with compression, padding and sensitive set for this message.
+    /// Group messages are LZ4 compressed and not padded by default.
+    pub fn encrypt_group_message_with_options(
+        &mut self,
+        group_id: &GroupId,
+        message: &[u8],
+        options: &MessageOptions,
+    ) -> Result<Vec<u8>> {
+        let group = match self.groups.get_mut(group_id) {
+            None => return Err(Error::NotFound("Group not found")),
+            Some(v) => v,
+        };
+        group.encrypt(&mut self.csprng, message, options, &self.limits)
+    }
+
+    /// Decrypt a group message from `sender`, the group is taken from the message
+    pub fn decrypt_group_message(&mut self, sender: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let group_id: GroupId = match data.get(..16).and_then(|v| v.try_into().ok()) {
+            None => return Err(Error::Malformed("Group message is too short")),
+            Some(v) => v,
+        };
+        let limits = self.limits;
+        self.group_mut(&group_id)?.decrypt(sender, data, &limits)
+    }
+
+    /// The other members of a group
+    pub fn group_members(&self, group_id: &GroupId) -> Option<Vec<String>> {
+        self.groups.get(group_id).map(|v| v.members.clone())
+    }
+
+    /// Forget a group, the other members should remove us with remove_group_member
+    pub fn leave_group(&mut self, group_id: &GroupId) -> Result<()> {
+        match self.groups.remove(group_id) {
+            None => Err(Error::NotFound("Group not found")),
+            Some(_) => Ok(()),
+        }
+    }
+
+    fn group_mut(&mut self, group_id: &GroupId) -> Result<&mut Group> {
+        match self.groups.get_mut(group_id) {
+            None => Err(Error::NotFound("Group not found")),
+            Some(v) => Ok(v),
+        }
+    }
+
+    fn rekey_group(&mut self, group_id: &GroupId) -> Result<SenderKeyDistribution> {
+        let group = match self.groups.get_mut(group_id) {
+            None => return Err(Error::NotFound("Group not found")),
+            Some(v) => v,
+        };
+        group.rekey(&mut self.csprng)?;
+        let members = group.members.clone();
+        self.distribute_group_key(group_id, &members)
+    }
+
+    // Our sender key encrypted for each of `members` over the pairwise sessions
+    fn distribute_group_key(
+        &mut self,
+        group_id: &GroupId,
+        members: &[String],
+    ) -> Result<SenderKeyDistribution> {
+        let mut distribution = Vec::new();
+        for member in members {
+            distribution.push((member.clone(), self.group_key_for_member(group_id, member)?));
+        }
+        Ok(distribution)
+    }
+}
+
+/// Result of process_group_key
+pub struct GroupKeyUpdate {
+    pub group_id: GroupId,
+    /// Our renewed sender key for the other members, empty if nothing changed
+    pub distribution: SenderKeyDistribution,
+}
+
+// Encrypt exported data with a password
+// Output is salt (16 bytes) || nonce (24 bytes) || ciphertext, salt and nonce are the associated data
+pub(crate) fn seal_with_password(
+    csprng: &mut rand_chacha::ChaChaRng,
+    suite: CipherSuite,
+    password: &[u8],
+    mut serialized: Vec<u8>,
+) -> Result<Vec<u8>> {
+    // Hash the password to expand it to a 32 byte key
+    let mut salt = [0u8; 16];
+    csprng.fill_bytes(&mut salt);
+
+    let mut output_key_material = [0u8; 32];
+    let argon2 = Argon2::default();
+    argon2
+        .hash_password_into(password, &salt, &mut output_key_material)
+        .map_err(Error::from)?;
+
+    let mut nonce = alloc::vec![0u8; suite.nonce_len()];
+    csprng.fill_bytes(&mut nonce);
+
+    // aead_data is magic + suite + salt + nonce
+    let mut aead_data = PASSWORD_MAGIC.to_vec();
+    aead_data.push(suite.id());
+    aead_data.extend_from_slice(&salt);
+    aead_data.extend_from_slice(&nonce);
+
+    suite.seal(&output_key_material, &nonce, &aead_data, &mut serialized)?;
+
+    aead_data.extend_from_slice(&serialized);
+
+    Ok(aead_data)
+}
+
+pub(crate) fn open_with_password(password: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    // Exports without the magic are salt (16 bytes) || nonce (24 bytes) || ciphertext
+    // with XChaCha20-Poly1305
+    let (suite, prefix) = match data.strip_prefix(&PASSWORD_MAGIC) {
+        Some([id, ..]) => (CipherSuite::from_id(*id)?, PASSWORD_MAGIC.len() + 1),
+        _ => (CipherSuite::XChaCha20Poly1305, 0),
+    };
+    let header_len = prefix + 16 + suite.nonce_len();
+    if data.len() < header_len {
+        return Err(Error::Malformed("Exported data is too short"));
+    }
+    let salt = &data[prefix..prefix + 16];
+    let nonce = &data[prefix + 16..header_len];
+
+    let mut serialized = data[header_len..].to_vec();
+
+    // Hash the password to expand it to a 32 byte key
+    let mut output_key_material = [0u8; 32];
+    let argon2 = Argon2::default();
+    argon2
+        .hash_password_into(password, salt, &mut output_key_material)
+        .map_err(Error::from)?;
+
+    suite.open(
+        &output_key_material,
+        nonce,
+        &data[..header_len],
+        &mut serialized,
+    )?;
+
+    Ok(serialized)
+}
+
+/// Derive the session key of a hybrid key exchange from both shared secrets.
+/// The key is bound to both X25519 public keys, the ML-KEM encapsulation key and the ciphertext.
+pub fn combine_hybrid_secrets(
+    x25519_secret: &[u8; 32],
+    mlkem_secret: &[u8; 32],
+    initiator_public_key: &PublicKey,
+    responder_public_key: &PublicKey,
+    encapsulation_key: &[u8],
+    ciphertext: &[u8],
+) -> [u8; 32] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"CosmicCipher hybrid kex v1");
+    hasher.update(mlkem_secret);
+    hasher.update(x25519_secret);
+    hasher.update(initiator_public_key.as_bytes());
+    hasher.update(responder_public_key.as_bytes());
+    hasher.update(Sha3_256::digest(encapsulation_key));
+    hasher.update(ciphertext);
+
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&hasher.finalize());
+    key
+}
+
+// The X25519 mode signs only the public key, so packets stay compatible with unpack_kex_packet
+// Signed instead of the bare public key when a PSK is used
+fn psk_transcript(psk: &[u8; 32], public_key: &PublicKey) -> Result<Vec<u8>> {
+    let mut mac = <Hmac<Sha3_256> as Mac>::new_from_slice(psk).map_err(Error::from)?;
+    mac.update(public_key.as_bytes());
+
+    let mut transcript = b"CosmicCipher psk kex v1".to_vec();
+    transcript.extend_from_slice(public_key.as_bytes());
+    transcript.extend_from_slice(&mac.finalize().into_bytes());
+    Ok(transcript)
+}
+
+// Both sides call this with their own key first, so the public keys are sorted
+fn combine_psk_secret(
+    psk: &[u8; 32],
+    x25519_secret: &[u8; 32],
+    own_pk: &PublicKey,
+    peer_pk: &PublicKey,
+) -> [u8; 32] {
+    let (first, second) = if own_pk.as_bytes() <= peer_pk.as_bytes() {
+        (own_pk, peer_pk)
+    } else {
+        (peer_pk, own_pk)
+    };
+
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"CosmicCipher psk kex v1");
+    hasher.update(psk);
+    hasher.update(x25519_secret);
+    hasher.update(first.as_bytes());
+    hasher.update(second.as_bytes());
+
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&hasher.finalize());
+    key
+}
+
+fn kex_transcript(
+    mode: KexMode,
+    public_key: &PublicKey,
+    kem_data: Option<&[u8]>,
+    suites: Option<&[u8]>,
+) -> Vec<u8> {
+    let mut transcript = match mode {
+        KexMode::X25519 => public_key.as_bytes().to_vec(),
+        KexMode::HybridMlKem768 => {
+            let mut transcript = b"CosmicCipher kex v1".to_vec();
+            transcript.push(mode.id());
+            transcript.extend_from_slice(public_key.as_bytes());
+            transcript.extend_from_slice(kem_data.unwrap_or_default());
+            transcript
+        }
+    };
+    // Packets from clients without cipher suites have none, so their transcript is unchanged
+    if let Some(suites) = suites {
+        transcript.extend_from_slice(b"CosmicCipher suites");
+        transcript.extend_from_slice(suites);
+    }
+    transcript
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct KexPacket {
+    public_key: [u8; 32],
+    sig: Vec<u8>,
+    verifying_key: Vec<u8>,
+    signing_key_sig: Vec<u8>,
+    // Missing in packets from clients that only know the X25519 mode
+    #[serde(default)]
+    mode: u8,
+    // ML-KEM encapsulation key in an offer, ciphertext in a response
+    #[serde(default)]
+    kem_data: Option<Vec<u8>>,
+    // Ephemeral public key of the offer a response answers.
+    // Only used to find the pending exchange, a wrong value makes the keys differ.
+    #[serde(default)]
+    pub(crate) in_reply_to: Option<[u8; 32]>,
+    // Suite ids the offer supports, most preferred first, or the one the response picked.
+    // Missing in packets from clients that only know XChaCha20-Poly1305.
+    #[serde(default)]
+    suites: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UserForExport {
+    signing_key: Vec<u8>,
+    ca_key: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SessionsForExport {
+    // Verifying key of the identity the sessions belong to
+    owner: Vec<u8>,
+    shared_keys: Vec<SharedKeyForExport>,
+    pending: Vec<PendingKexForExport>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SharedKeyForExport {
+    peer: String,
+    // Key of the current epoch
+    key: Vec<u8>,
+    #[serde(default)]
+    id: Option<Vec<u8>>,
+    #[serde(default)]
+    epoch: u32,
+    #[serde(default)]
+    previous_keys: Vec<EpochKeyForExport>,
+    #[serde(default)]
+    messages: u64,
+    #[serde(default)]
+    bytes: u64,
+    #[serde(default)]
+    epoch_started_at: Option<u64>,
+    #[serde(default)]
+    send_seq: u64,
+    #[serde(default)]
+    recv_highest: Option<u64>,
+    #[serde(default)]
+    recv_window: u64,
+    #[serde(default)]
+    strict_order: bool,
+    #[serde(default)]
+    compression: Option<u8>,
+    #[serde(default)]
+    padding: Padding,
+    #[serde(default)]
+    suite: Option<u8>,
+    #[serde(default)]
+    peer_key_id: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EpochKeyForExport {
+    epoch: u32,
+    key: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PendingKexForExport {
+    peer: String,
+    ephemeral_key: Vec<u8>,
+    mode: u8,
+    kem_key: Option<Vec<u8>>,
+    // Only the identifier, the PSK itself has to be provisioned again after an import
+    #[serde(default)]
+    psk_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct InstanceForExport {
+    signing_key: Vec<u8>,
+    sig: Vec<u8>,
+    ca_verifying_key: Vec<u8>,
+    // The minisign key and the CA signature certifying it
+    #[serde(default)]
+    minisign_key: Option<Vec<u8>>,
+    #[serde(default)]
+    minisign_key_sig: Option<Vec<u8>>,
+}
 
 #[cfg(test)]
 #[allow(clippy::unwrap_used)]
 mod tests {
     use super::*;
+    use chacha20poly1305::aead::generic_array::GenericArray;
+    use chacha20poly1305::{AeadInPlace, KeyInit, XChaCha20Poly1305};
 
     #[test]
     fn test_client() {
@@ -460,6 +2604,240 @@ mod tests {
         let _ = Client::import_instance(&exported).unwrap();
     }
 
+    #[test]
+    fn test_minisign_key_is_not_ca_key() {
+        use base64::prelude::*;
+
+        let user = Client::new_user();
+        // A legacy signature covers the raw data, a chosen verifying key must not get certified
+        let attacker = SigningKey::generate(&mut rand_chacha::ChaChaRng::from_entropy());
+        let signature_file = user
+            .minisign_sign(attacker.verifying_key().as_bytes(), "", false)
+            .unwrap();
+        let line = signature_file.lines().nth(1).unwrap();
+        let signature_bytes = BASE64_STANDARD.decode(line).unwrap();
+        let signature = Signature::from_slice(&signature_bytes[10..]).unwrap();
+
+        let static_key = PublicKey::from(&identity::static_secret(&attacker));
+        let forged = Certificate {
+            verifying_key: attacker.verifying_key(),
+            signature,
+            static_key,
+            static_key_signature: identity::sign_static_key(&attacker, &static_key),
+        };
+        assert!(matches!(
+            forged.verify(&user.ca_data.verifying_key),
+            Err(Error::UntrustedKey(_))
+        ));
+        assert!(user
+            .ca_data
+            .verifying_key
+            .verify(attacker.verifying_key().as_bytes(), &signature)
+            .is_err());
+        assert_ne!(user.ca_data.minisign_key, Some(user.ca_data.verifying_key));
+    }
+
+    #[test]
+    fn test_export_import_sessions() {
+        let mut client1 = Client::new_user();
+        let instance_data = client1.generate_instance().unwrap();
+        let mut client2 = Client::import_instance(instance_data.as_slice()).unwrap();
+        let password = b"password";
+
+        let offer = client1
+            .init_kex("client2", KexMode::HybridMlKem768)
+            .unwrap();
+        let response = client2
+            .respond_kex("client1", &offer, KexMode::X25519)
+            .unwrap();
+
+        // Restart client1 while its key exchange is pending
+        let user_export = client1.export_user(password).unwrap();
+        let sessions_export = client1.export_sessions(password).unwrap();
+        let mut client1 = Client::import_user(password, &user_export).unwrap();
+        client1.import_sessions(password, &sessions_export).unwrap();
+        client1.finish_kex("client2", &response).unwrap();
+
+        // Restart client2 with a single peer export
+        let peer_export = client2.export_session("client1", password).unwrap();
+        let mut client2 = Client::import_instance(instance_data.as_slice()).unwrap();
+        client2.import_sessions(password, &peer_export).unwrap();
+
+        let encrypted = client1
+            .encrypt_message_for_recipient("client2", b"Hello again")
+            .unwrap();
+        let decrypted = client2
+            .decrypt_message_from_sender("client1", &encrypted)
+            .unwrap();
+        assert_eq!(decrypted, b"Hello again");
+
+        assert!(client2.export_session("client3", password).is_err());
+        assert!(client2.import_sessions(b"wrong", &peer_export).is_err());
+        // Sessions of client2 cannot be imported into client1
+        assert!(client1.import_sessions(password, &peer_export).is_err());
+    }
+
+    #[test]
+    fn test_rekeying() {
+        let mut client1 = Client::new_user();
+        let instance_data = client1.generate_instance().unwrap();
+        let mut client2 = Client::import_instance(instance_data.as_slice()).unwrap();
+        let password = b"password";
+
+        let offer = client1.init_kex("client2", KexMode::X25519).unwrap();
+        let response = client2
+            .respond_kex("client1", &offer, KexMode::X25519)
+            .unwrap();
+        client1.finish_kex("client2", &response).unwrap();
+
+        client1.set_rekey_policy(RekeyPolicy {
+            max_messages: Some(2),
+            hard_max_messages: 3,
+            ..Default::default()
+        });
+        let messages: Vec<Vec<u8>> = (0..5u8)
+            .map(|i| {
+                client1
+                    .encrypt_message_for_recipient("client2", &[i])
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(message::parse_header(&messages[4]).unwrap().epoch, 2);
+
+        // Newer epochs first, older keys are still around for late messages
+        for i in [4, 2, 0, 3, 1] {
+            let decrypted = client2
+                .decrypt_message_from_sender("client1", &messages[i])
+                .unwrap();
+            assert_eq!(decrypted, [i as u8]);
+        }
+
+        // A forged epoch must not move the session forward
+        let mut forged = messages[4].clone();
+        forged[27] = 9;
+        assert!(client2
+            .decrypt_message_from_sender("client1", &forged)
+            .is_err());
+        assert_eq!(client2.shared_keys["client1"][0].epoch, 2);
+
+        // The ratchet state survives an export
+        let sessions_export = client2.export_sessions(password).unwrap();
+        let mut client2 = Client::import_instance(instance_data.as_slice()).unwrap();
+        client2.import_sessions(password, &sessions_export).unwrap();
+        let id = client1.sessions("client2")[0];
+        client1.rekey_session("client2", &id).unwrap();
+        let encrypted = client1
+            .encrypt_message_for_recipient("client2", b"after rekey")
+            .unwrap();
+        assert_eq!(
+            client2
+                .decrypt_message_from_sender("client1", &encrypted)
+                .unwrap(),
+            b"after rekey"
+        );
+
+        // Time based rekeying and the hard limit
+        client1.set_rekey_policy(RekeyPolicy {
+            max_age_secs: Some(3600),
+            hard_max_messages: 1,
+            ..Default::default()
+        });
+        client1.set_time(1_700_000_000);
+        assert!(client1
+            .encrypt_message_for_recipient("client2", b"limit")
+            .is_err());
+        client1.set_time(1_700_003_600);
+        assert!(client1
+            .encrypt_message_for_recipient("client2", b"new epoch")
+            .is_ok());
+        assert!(client1
+            .encrypt_message_for_recipient("client2", b"limit")
+            .is_err());
+    }
+
+    #[test]
+    fn test_multiple_sessions() {
+        let mut client1 = Client::new_user();
+        let instance_data = client1.generate_instance().unwrap();
+        let mut laptop = Client::import_instance(instance_data.as_slice()).unwrap();
+        let mut phone = Client::import_instance(instance_data.as_slice()).unwrap();
+
+        // Two offers to the same peer are pending at once, answered by different devices
+        let offer1 = client1.init_kex("client2", KexMode::X25519).unwrap();
+        let offer2 = client1
+            .init_kex("client2", KexMode::HybridMlKem768)
+            .unwrap();
+        let response2 = phone
+            .respond_kex("client1", &offer2, KexMode::X25519)
+            .unwrap();
+        let response1 = laptop
+            .respond_kex("client1", &offer1, KexMode::X25519)
+            .unwrap();
+
+        // A forged response does not use up the offer it names
+        let mut forged: KexPacket = bson::from_slice(&response1).unwrap();
+        forged.sig[0] ^= 1;
+        let forged = bson::to_vec(&forged).unwrap();
+        assert!(client1.finish_kex("client2", &forged).is_err());
+        assert_eq!(client1.kex_map["client2"].len(), 2);
+
+        client1.finish_kex("client2", &response1).unwrap();
+        client1.finish_kex("client2", &response2).unwrap();
+
+        let sessions = client1.sessions("client2");
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(laptop.sessions("client1"), sessions[..1]);
+        assert_eq!(phone.sessions("client1"), sessions[1..]);
+
+        // Both sides pick the session from the ciphertext
+        let to_laptop = client1
+            .encrypt_message_for_session("client2", &sessions[0], b"laptop")
+            .unwrap();
+        let to_phone = client1
+            .encrypt_message_for_recipient("client2", b"phone")
+            .unwrap();
+        assert_eq!(
+            laptop
+                .decrypt_message_from_sender("client1", &to_laptop)
+                .unwrap(),
+            b"laptop"
+        );
+        assert_eq!(
+            phone
+                .decrypt_message_from_sender("client1", &to_phone)
+                .unwrap(),
+            b"phone"
+        );
+        assert!(laptop
+            .decrypt_message_from_sender("client1", &to_phone)
+            .is_err());
+
+        let from_laptop = laptop
+            .encrypt_message_for_recipient("client1", b"from laptop")
+            .unwrap();
+        let from_phone = phone
+            .encrypt_message_for_recipient("client1", b"from phone")
+            .unwrap();
+        assert_eq!(
+            client1
+                .decrypt_message_from_sender("client2", &from_laptop)
+                .unwrap(),
+            b"from laptop"
+        );
+        assert_eq!(
+            client1
+                .decrypt_message_from_sender("client2", &from_phone)
+                .unwrap(),
+            b"from phone"
+        );
+
+        client1.close_session("client2", &sessions[0]).unwrap();
+        assert!(client1
+            .decrypt_message_from_sender("client2", &from_laptop)
+            .is_err());
+        assert!(client1.close_session("client2", &sessions[0]).is_err());
+    }
+
     #[test]
     fn test_dh_kex() {
         let mut client1 = Client::new_user();
@@ -473,27 +2851,66 @@ mod tests {
         let kexpacket1;
 
         {
-            let pubkey1 = client1.init_dh_kex(rcpt2).unwrap();
+            let pubkey1 = client1.init_dh_kex(rcpt2, None).unwrap();
             kexpacket1 = client1.generate_kex_packet(pubkey1.0, pubkey1.1).unwrap();
         }
 
         let kexpacket2;
         {
-            let pubkey2 = client2.init_dh_kex(rcpt1).unwrap();
+            let pubkey2 = client2.init_dh_kex(rcpt1, None).unwrap();
             kexpacket2 = client2.generate_kex_packet(pubkey2.0, pubkey2.1).unwrap();
         }
 
         {
             let (pubkey1, pubkey1_sig, sender_vk1, sender_vk1_sig) =
                 Client::unpack_kex_packet(kexpacket2.as_slice()).unwrap();
+
+            // Rejected packets leave the pending exchange in place
+            let forged = PublicKey::from([9u8; 32]);
+            assert!(client1
+                .complete_dh_kex(
+                    rcpt2,
+                    &forged,
+                    pubkey1_sig,
+                    sender_vk1,
+                    sender_vk1_sig,
+                    None
+                )
+                .is_err());
+            assert!(client1
+                .complete_dh_kex(
+                    rcpt2,
+                    &pubkey1,
+                    pubkey1_sig,
+                    sender_vk1,
+                    sender_vk1_sig,
+                    Some("site")
+                )
+                .is_err());
+            assert_eq!(client1.kex_map[rcpt2].len(), 1);
+
             let shared_secret1 = client1
-                .complete_dh_kex(rcpt2, &pubkey1, pubkey1_sig, sender_vk1, sender_vk1_sig)
+                .complete_dh_kex(
+                    rcpt2,
+                    &pubkey1,
+                    pubkey1_sig,
+                    sender_vk1,
+                    sender_vk1_sig,
+                    None,
+                )
                 .unwrap();
 
             let (pubkey2, pubkey2_sig, sender_vk2, sender_vk2_sig) =
                 Client::unpack_kex_packet(kexpacket1.as_slice()).unwrap();
             let shared_secret2 = client2
-                .complete_dh_kex(rcpt1, &pubkey2, pubkey2_sig, sender_vk2, sender_vk2_sig)
+                .complete_dh_kex(
+                    rcpt1,
+                    &pubkey2,
+                    pubkey2_sig,
+                    sender_vk2,
+                    sender_vk2_sig,
+                    None,
+                )
                 .unwrap();
 
             assert_eq!(shared_secret1, shared_secret2);
@@ -509,4 +2926,239 @@ mod tests {
 
         assert_eq!(message, decrypted.as_slice());
     }
+
+    #[test]
+    fn test_psk_kex() {
+        let mut client1 = Client::new_user();
+        let instance_data = client1.generate_instance().unwrap();
+        let mut client2 = Client::import_instance(instance_data.as_slice()).unwrap();
+        client1.add_psk("site", [7u8; 32]);
+        client2.add_psk("site", [7u8; 32]);
+        client2.add_psk("other", [8u8; 32]);
+
+        let run = |client1: &mut Client, client2: &mut Client, psk1, psk2| {
+            let (pk1, sig1) = client1.init_dh_kex("client2", psk1).unwrap();
+            let packet1 = client1.generate_kex_packet(pk1, sig1).unwrap();
+            let (pk2, sig2) = client2.init_dh_kex("client1", psk2).unwrap();
+            let packet2 = client2.generate_kex_packet(pk2, sig2).unwrap();
+
+            let (pk, sig, vk, vk_sig) = Client::unpack_kex_packet(&packet2).unwrap();
+            let key1 = client1.complete_dh_kex("client2", &pk, sig, vk, vk_sig, psk1);
+            let (pk, sig, vk, vk_sig) = Client::unpack_kex_packet(&packet1).unwrap();
+            let key2 = client2.complete_dh_kex("client1", &pk, sig, vk, vk_sig, psk2);
+            (key1, key2)
+        };
+
+        let (key1, key2) = run(&mut client1, &mut client2, Some("site"), Some("site"));
+        assert_eq!(key1.unwrap(), key2.unwrap());
+
+        let encrypted = client1
+            .encrypt_message_for_recipient("client2", b"Hello World!")
+            .unwrap();
+        let decrypted = client2
+            .decrypt_message_from_sender("client1", &encrypted)
+            .unwrap();
+        assert_eq!(decrypted, b"Hello World!");
+
+        // Different PSKs, or a PSK on one side only, fail on both sides
+        let (key1, key2) = run(&mut client1, &mut client2, Some("site"), Some("other"));
+        assert!(key1.is_err() && key2.is_err());
+        let (key1, key2) = run(&mut client1, &mut client2, None, Some("site"));
+        assert!(key1.is_err() && key2.is_err());
+
+        assert!(client1.init_dh_kex("client2", Some("unknown")).is_err());
+    }
+
+    #[test]
+    fn test_hybrid_kex() {
+        let mut client1 = Client::new_user();
+        let instance_data = client1.generate_instance().unwrap();
+        let mut client2 = Client::import_instance(instance_data.as_slice()).unwrap();
+
+        let offer = client1
+            .init_kex("client2", KexMode::HybridMlKem768)
+            .unwrap();
+        let response = client2
+            .respond_kex("client1", &offer, KexMode::HybridMlKem768)
+            .unwrap();
+        let shared_key = client1.finish_kex("client2", &response).unwrap();
+        assert_eq!(client2.shared_keys["client1"][0].key, shared_key);
+
+        let encrypted = client1
+            .encrypt_message_for_recipient("client2", b"Hello World!")
+            .unwrap();
+        let decrypted = client2
+            .decrypt_message_from_sender("client1", &encrypted)
+            .unwrap();
+        assert_eq!(decrypted, b"Hello World!");
+
+        // The legacy path does not accept hybrid packets
+        assert!(Client::unpack_kex_packet(&response)
+            .and_then(|(pk, sig, vk, vk_sig)| {
+                client1.init_dh_kex("client2", None)?;
+                client1.complete_dh_kex("client2", &pk, sig, vk, vk_sig, None)
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn test_hybrid_kex_downgrade() {
+        let mut client1 = Client::new_user();
+        let instance_data = client1.generate_instance().unwrap();
+        let mut client2 = Client::import_instance(instance_data.as_slice()).unwrap();
+
+        // Responder requires the hybrid mode
+        let offer = client1.init_kex("client2", KexMode::X25519).unwrap();
+        assert!(client2
+            .respond_kex("client1", &offer, KexMode::HybridMlKem768)
+            .is_err());
+
+        // The mode is covered by the signature
+        let offer = client1
+            .init_kex("client2", KexMode::HybridMlKem768)
+            .unwrap();
+        let mut kex_packet: KexPacket = bson::from_slice(&offer).unwrap();
+        kex_packet.mode = KexMode::X25519.id();
+        kex_packet.kem_data = None;
+        let tampered = bson::to_vec(&kex_packet).unwrap();
+        assert!(client2
+            .respond_kex("client1", &tampered, KexMode::X25519)
+            .is_err());
+
+        // The initiator rejects a response in a different mode
+        let response = {
+            let mut client3 = Client::import_instance(instance_data.as_slice()).unwrap();
+            let offer = client3.init_kex("client1", KexMode::X25519).unwrap();
+            client2
+                .respond_kex("client3", &offer, KexMode::X25519)
+                .unwrap()
+        };
+        assert!(client1.finish_kex("client2", &response).is_err());
+    }
+
+    #[test]
+    fn test_noise_session() {
+        let mut client1 = Client::new_user();
+        let instance_data = client1.generate_instance().unwrap();
+        let mut client2 = Client::import_instance(instance_data.as_slice()).unwrap();
+
+        let params =
+            noise::NoiseParams::new(noise::HandshakePattern::IK, noise::NoiseHash::Blake2s);
+        let mut initiator = client1
+            .noise_handshake(params, true, Some(client2.noise_static_public_key()))
+            .unwrap();
+        let mut responder = client2.noise_handshake(params, false, None).unwrap();
+
+        let msg1 = initiator.write_message(b"").unwrap();
+        responder.read_message(&msg1).unwrap();
+        let msg2 = responder.write_message(b"").unwrap();
+        initiator.read_message(&msg2).unwrap();
+
+        assert_eq!(
+            initiator.remote_verifying_key(),
+            Some(client2.signing_key.verifying_key())
+        );
+        assert_eq!(
+            responder.remote_verifying_key(),
+            Some(client1.signing_key.verifying_key())
+        );
+
+        let transport1 = initiator.into_transport().unwrap();
+        let transport2 = responder.into_transport().unwrap();
+        client1
+            .install_noise_session("client2", &transport1)
+            .unwrap();
+        client2
+            .install_noise_session("client1", &transport2)
+            .unwrap();
+
+        let encrypted = client1
+            .encrypt_message_for_recipient("client2", b"Hello Noise")
+            .unwrap();
+        let decrypted = client2
+            .decrypt_message_from_sender("client1", &encrypted)
+            .unwrap();
+        assert_eq!(decrypted, b"Hello Noise");
+    }
+
+    #[test]
+    fn test_noise_nk_anonymous_initiator() {
+        let mut client1 = Client::new_user();
+        let mut client2 = Client::import_instance(&client1.generate_instance().unwrap()).unwrap();
+
+        let params = noise::NoiseParams::new(noise::HandshakePattern::NK, noise::NoiseHash::Sha256);
+        let mut initiator = client1
+            .noise_handshake(params, true, Some(client2.noise_static_public_key()))
+            .unwrap();
+        let mut responder = client2.noise_handshake(params, false, None).unwrap();
+
+        let msg1 = initiator.write_message(b"").unwrap();
+        responder.read_message(&msg1).unwrap();
+        let msg2 = responder.write_message(b"").unwrap();
+        initiator.read_message(&msg2).unwrap();
+        assert_eq!(
+            initiator.remote_verifying_key(),
+            Some(client2.signing_key.verifying_key())
+        );
+        assert_eq!(responder.remote_verifying_key(), None);
+
+        // The responder does not know who it talks to, there is no session to install
+        let mut transport1 = initiator.into_transport().unwrap();
+        let mut transport2 = responder.into_transport().unwrap();
+        assert_eq!(
+            client2.install_noise_session("client1", &transport2),
+            Err(Error::Authentication("Noise peer is not authenticated"))
+        );
+        assert!(client2.sessions("client1").is_empty());
+
+        let ct = transport1.write_message(b"anonymous").unwrap();
+        assert_eq!(transport2.read_message(&ct).unwrap(), b"anonymous");
+    }
+
+    #[test]
+    fn test_noise_rejects_foreign_ca() {
+        let client1 = Client::new_user();
+        let client2 = Client::new_user();
+
+        let params = noise::NoiseParams::new(noise::HandshakePattern::XX, noise::NoiseHash::Sha256);
+        let mut initiator = client1.noise_handshake(params, true, None).unwrap();
+        let mut responder = client2.noise_handshake(params, false, None).unwrap();
+
+        let msg1 = initiator.write_message(b"").unwrap();
+        responder.read_message(&msg1).unwrap();
+        let msg2 = responder.write_message(b"").unwrap();
+        assert!(initiator.read_message(&msg2).is_err());
+    }
+
+    #[test]
+    fn test_headerless_message() {
+        let mut client1 = Client::new_user();
+        let mut client2 = Client::import_instance(&client1.generate_instance().unwrap()).unwrap();
+        let offer = client1.init_kex("client2", KexMode::X25519).unwrap();
+        let response = client2
+            .respond_kex("client1", &offer, KexMode::X25519)
+            .unwrap();
+        client1.finish_kex("client2", &response).unwrap();
+
+        // Session id || epoch || nonce || ciphertext, without sequence number to check replays
+        let session = &client1.shared_keys["client2"][0];
+        let mut data = session.id.to_vec();
+        data.extend_from_slice(&0u32.to_be_bytes());
+        data.extend_from_slice(&[5u8; 24]);
+        let mut buffer = compress_prepend_size(b"no header");
+        XChaCha20Poly1305::new(GenericArray::from_slice(&session.key))
+            .encrypt_in_place(GenericArray::from_slice(&[5u8; 24]), &data, &mut buffer)
+            .unwrap();
+        data.extend_from_slice(&buffer);
+
+        assert!(!message::has_header(&data));
+        assert_eq!(
+            client2.decrypt_message_from_sender("client1", &data),
+            Err(Error::Malformed("Message has no header"))
+        );
+        assert_eq!(
+            client2.decrypt_message(&data),
+            Err(Error::Malformed("Message has no header"))
+        );
+    }
 }
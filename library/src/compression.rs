@@ -0,0 +1,222 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// Compression of message payloads before encryption
+//
+// The codec is a flag in the authenticated message header, at most one is set:
+// FLAG_COMPRESSED is LZ4 with the size prepended, FLAG_ZSTD a zstd frame,
+// FLAG_DEFLATE raw deflate. Payloads that do not get smaller are sent without compression.
+
+use alloc::vec::Vec;
+use ruzstd::io::Read;
+
+use miniz_oxide::inflate::TINFLStatus;
+
+use crate::error::{Error, Result};
+use crate::limits::DECOMPRESSED_TOO_LARGE;
+use crate::message::{FLAG_COMPRESSED, FLAG_DEFLATE, FLAG_ZSTD};
+
+const DEFLATE_LEVEL: u8 = 6;
+
+/// Codec for message payloads, set per session or per message
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    #[default]
+    Lz4,
+    Zstd,
+    Deflate,
+}
+
+impl Compression {
+    pub(crate) fn id(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+            Compression::Deflate => 3,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Result<Self> {
+        match id {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Zstd),
+            3 => Ok(Compression::Deflate),
+            _ => Err(Error::Unsupported("Unknown compression")),
+        }
+    }
+
+    pub(crate) fn flag(&self) -> u16 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => FLAG_COMPRESSED,
+            Compression::Zstd => FLAG_ZSTD,
+            Compression::Deflate => FLAG_DEFLATE,
+        }
+    }
+
+    pub(crate) fn from_flags(flags: u16) -> Result<Self> {
+        match flags & (FLAG_COMPRESSED | FLAG_ZSTD | FLAG_DEFLATE) {
+            0 => Ok(Compression::None),
+            FLAG_COMPRESSED => Ok(Compression::Lz4),
+            FLAG_ZSTD => Ok(Compression::Zstd),
+            FLAG_DEFLATE => Ok(Compression::Deflate),
+            _ => Err(Error::Malformed("More than one compression flag set")),
+        }
+    }
+}
+
+pub fn compress(compression: Compression, data: &[u8]) -> Vec<u8> {
+    match compression {
+        Compression::None => data.to_vec(),
+        Compression::Lz4 => lz4_flex::compress_prepend_size(data),
+        Compression::Zstd => {
+            ruzstd::encoding::compress_to_vec(data, ruzstd::encoding::CompressionLevel::Fastest)
+        }
+        Compression::Deflate => miniz_oxide::deflate::compress_to_vec(data, DEFLATE_LEVEL),
+    }
+}
+
+/// Compress with `compression` unless that does not make `data` smaller.
+/// Returns the codec that was used.
+pub fn compress_adaptive(compression: Compression, data: &[u8]) -> (Compression, Vec<u8>) {
+    let compressed = compress(compression, data);
+    if compression != Compression::None && compressed.len() >= data.len() {
+        return (Compression::None, data.to_vec());
+    }
+    (compression, compressed)
+}
+
+/// Decompress `data`, failing before more than `max_size` bytes are produced
+pub fn decompress(compression: Compression, data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Lz4 => {
+            // The size prefix is chosen by the sender
+            let size = data
+                .get(..4)
+                .ok_or(Error::Decompression("Invalid LZ4 data"))?;
+            if u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize > max_size {
+                return Err(Error::TooLarge(DECOMPRESSED_TOO_LARGE));
+            }
+            lz4_flex::decompress_size_prepended(data).map_err(Error::from)
+        }
+        Compression::Zstd => {
+            let mut decoder = ruzstd::decoding::StreamingDecoder::new(data)
+                .map_err(|_| Error::Decompression("Invalid zstd frame"))?;
+            let mut output = Vec::new();
+            let mut chunk = [0u8; 4096];
+            loop {
+                let len = decoder
+                    .read(&mut chunk)
+                    .map_err(|_| Error::Decompression("Invalid zstd frame"))?;
+                if len == 0 {
+                    return Ok(output);
+                }
+                if output.len() + len > max_size {
+                    return Err(Error::TooLarge(DECOMPRESSED_TOO_LARGE));
+                }
+                output.extend_from_slice(&chunk[..len]);
+            }
+        }
+        Compression::Deflate => miniz_oxide::inflate::decompress_to_vec_with_limit(data, max_size)
+            .map_err(|e| match e.status {
+                TINFLStatus::HasMoreOutput => Error::TooLarge(DECOMPRESSED_TOO_LARGE),
+                _ => Error::Decompression("Invalid deflate data"),
+            }),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, KexMode};
+    use crate::message::parse_header;
+
+    #[test]
+    fn test_compression_flags() {
+        for compression in [
+            Compression::None,
+            Compression::Lz4,
+            Compression::Zstd,
+            Compression::Deflate,
+        ] {
+            assert_eq!(Compression::from_id(compression.id()).unwrap(), compression);
+            assert_eq!(
+                Compression::from_flags(compression.flag()).unwrap(),
+                compression
+            );
+        }
+        assert!(Compression::from_flags(FLAG_ZSTD | FLAG_DEFLATE).is_err());
+        assert!(Compression::from_id(4).is_err());
+    }
+
+    #[test]
+    fn test_session_compression() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let offer = alice.init_kex("bob", KexMode::X25519).unwrap();
+        let response = bob.respond_kex("alice", &offer, KexMode::X25519).unwrap();
+        alice.finish_kex("bob", &response).unwrap();
+        let session_id = alice.sessions("bob")[0];
+        let message = b"compressible ".repeat(100);
+
+        // LZ4 unless the session says otherwise
+        let data = alice
+            .encrypt_message_for_session("bob", &session_id, &message)
+            .unwrap();
+        assert_eq!(parse_header(&data).unwrap().flags, FLAG_COMPRESSED);
+        assert_eq!(
+            bob.decrypt_message_from_sender("alice", &data).unwrap(),
+            message
+        );
+
+        alice
+            .set_session_compression("bob", &session_id, Compression::Zstd)
+            .unwrap();
+        let data = alice
+            .encrypt_message_for_session("bob", &session_id, &message)
+            .unwrap();
+        assert_eq!(parse_header(&data).unwrap().flags, FLAG_ZSTD);
+        assert_eq!(
+            bob.decrypt_message_from_sender("alice", &data).unwrap(),
+            message
+        );
+
+        // Per message, and skipped when it does not help
+        let data = alice
+            .encrypt_message_with_compression("bob", &session_id, &message, Compression::Deflate)
+            .unwrap();
+        assert_eq!(parse_header(&data).unwrap().flags, FLAG_DEFLATE);
+        assert_eq!(
+            bob.decrypt_message_from_sender("alice", &data).unwrap(),
+            message
+        );
+        let data = alice
+            .encrypt_message_with_compression("bob", &session_id, b"short", Compression::Zstd)
+            .unwrap();
+        assert_eq!(parse_header(&data).unwrap().flags, 0);
+        assert_eq!(
+            bob.decrypt_message_from_sender("alice", &data).unwrap(),
+            b"short"
+        );
+
+        // The setting survives an export
+        let export = alice.export_sessions(b"password").unwrap();
+        alice.close_session("bob", &session_id).unwrap();
+        alice.import_sessions(b"password", &export).unwrap();
+        let data = alice
+            .encrypt_message_for_session("bob", &session_id, &message)
+            .unwrap();
+        assert_eq!(parse_header(&data).unwrap().flags, FLAG_ZSTD);
+    }
+}
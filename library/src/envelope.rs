@@ -0,0 +1,417 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// Multi-recipient envelopes
+//
+// The payload is compressed, optionally padded and encrypted once under a random content key.
+// The flags say how, as in message headers; envelopes without them are LZ4 compressed.
+// The content key is wrapped once per recipient, either with the key of an established session
+// or with an ephemeral X25519 exchange against the recipient's public key.
+// Stanzas carry no recipient identifiers and are shuffled, a recipient finds its own by trial:
+// session stanzas have a short hint only the session partners can compute, public key stanzas
+// are tried with one DH each. Other recipients only learn how many stanzas of each kind there are.
+// The sender signs the whole envelope with its CA certified key, so a recipient cannot reuse the
+// content key to forge a payload for the others under the sender's name. Wrapped keys are bound
+// to the signing key and session stanzas are only accepted from the session partner, so a
+// recipient that copies the stanzas into an envelope signed with its own key gets rejected too.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::{AeadInPlace, KeyInit, XChaCha20Poly1305};
+use ed25519_dalek::VerifyingKey;
+use hmac::{Hmac, Mac};
+use rand_chacha::rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+use sha3::Sha3_256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::error::{Error, Result};
+use crate::limits::Limits;
+use crate::message::{self, FLAG_COMPRESSED};
+use crate::suite::CipherSuite;
+
+// Session stanza hints are this long, enough to skip almost all foreign stanzas
+const HINT_LEN: usize = 8;
+
+/// A recipient of Client::encrypt_envelope
+#[derive(Clone, Copy, Debug)]
+pub enum EnvelopeRecipient<'a> {
+    /// A peer with an established session, the newest session is used
+    Peer(&'a str),
+    /// Anyone with the X25519 key, e.g. Client::noise_static_public_key of the recipient
+    PublicKey(PublicKey),
+}
+
+/// A decrypted envelope
+#[derive(Clone, Debug)]
+pub struct OpenedEnvelope {
+    /// Identity key of the sender, certified by our CA
+    pub sender: VerifyingKey,
+    /// The peer whose session unwrapped the content key, None for a public key stanza
+    pub peer: Option<String>,
+    pub message: Vec<u8>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) enum Stanza {
+    Session {
+        epoch: u32,
+        hint: Vec<u8>,
+        wrapped_key: Vec<u8>,
+    },
+    PublicKey {
+        ephemeral_key: [u8; 32],
+        wrapped_key: Vec<u8>,
+    },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Envelope {
+    pub(crate) verifying_key: Vec<u8>,
+    pub(crate) signing_key_sig: Vec<u8>,
+    // Random per envelope, makes every wrapping key unique
+    pub(crate) salt: Vec<u8>,
+    pub(crate) stanzas: Vec<Stanza>,
+    pub(crate) nonce: Vec<u8>,
+    pub(crate) ciphertext: Vec<u8>,
+    pub(crate) signature: Vec<u8>,
+    // Suite of the payload, missing in envelopes that predate suites (XChaCha20-Poly1305).
+    // Skipped when missing so the signed bytes of those stay the same.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) suite: Option<u8>,
+    // Payload flags, see message::encode_payload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) flags: Option<u16>,
+}
+
+impl Envelope {
+    pub(crate) fn suite(&self) -> Result<CipherSuite> {
+        match self.suite {
+            None => Ok(CipherSuite::XChaCha20Poly1305),
+            Some(id) => CipherSuite::from_id(id),
+        }
+    }
+
+    pub(crate) fn tbs(&self) -> Result<Vec<u8>> {
+        let mut tbs = self.clone();
+        tbs.signature = Vec::new();
+        bson::to_vec(&tbs).map_err(Error::from)
+    }
+}
+
+type HmacSha3 = Hmac<Sha3_256>;
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> Result<[u8; 32]> {
+    let mut mac = <HmacSha3 as Mac>::new_from_slice(key).map_err(Error::from)?;
+    for part in parts {
+        mac.update(part);
+    }
+    let mut output = [0u8; 32];
+    output.copy_from_slice(&mac.finalize().into_bytes());
+    Ok(output)
+}
+
+/// Hint by which the session partner recognizes its stanza
+pub(crate) fn session_hint(session_key: &[u8; 32], salt: &[u8]) -> Result<Vec<u8>> {
+    let hint = hmac(session_key, &[b"CosmicCipher envelope hint", salt])?;
+    Ok(hint[..HINT_LEN].to_vec())
+}
+
+fn session_wrap_key(session_key: &[u8; 32], salt: &[u8]) -> Result<[u8; 32]> {
+    hmac(session_key, &[b"CosmicCipher envelope session", salt])
+}
+
+fn public_key_wrap_key(
+    shared_secret: &[u8; 32],
+    ephemeral_key: &[u8; 32],
+    public_key: &[u8; 32],
+    salt: &[u8],
+) -> Result<[u8; 32]> {
+    hmac(
+        shared_secret,
+        &[
+            b"CosmicCipher envelope public key",
+            ephemeral_key,
+            public_key,
+            salt,
+        ],
+    )
+}
+
+// Wrapped keys only open in envelopes signed by the same key
+fn wrap_aad(salt: &[u8], signer: &VerifyingKey) -> Vec<u8> {
+    let mut aad = salt.to_vec();
+    aad.extend_from_slice(signer.as_bytes());
+    aad
+}
+
+// Wrapping keys are used once, the nonce can be fixed
+fn wrap(
+    key: &[u8; 32],
+    salt: &[u8],
+    signer: &VerifyingKey,
+    content_key: &[u8; 32],
+) -> Result<Vec<u8>> {
+    let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(key));
+    let mut buffer = content_key.to_vec();
+    cipher
+        .encrypt_in_place(
+            GenericArray::from_slice(&[0u8; 24]),
+            &wrap_aad(salt, signer),
+            &mut buffer,
+        )
+        .map_err(|_| Error::Internal("Encryption failed"))?;
+    Ok(buffer)
+}
+
+fn unwrap(
+    key: &[u8; 32],
+    salt: &[u8],
+    signer: &VerifyingKey,
+    wrapped_key: &[u8],
+) -> Option<[u8; 32]> {
+    let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(key));
+    let mut buffer = wrapped_key.to_vec();
+    cipher
+        .decrypt_in_place(
+            GenericArray::from_slice(&[0u8; 24]),
+            &wrap_aad(salt, signer),
+            &mut buffer,
+        )
+        .ok()?;
+    buffer.as_slice().try_into().ok()
+}
+
+pub(crate) fn session_stanza(
+    session_key: &[u8; 32],
+    epoch: u32,
+    salt: &[u8],
+    signer: &VerifyingKey,
+    content_key: &[u8; 32],
+) -> Result<Stanza> {
+    Ok(Stanza::Session {
+        epoch,
+        hint: session_hint(session_key, salt)?,
+        wrapped_key: wrap(
+            &session_wrap_key(session_key, salt)?,
+            salt,
+            signer,
+            content_key,
+        )?,
+    })
+}
+
+pub(crate) fn public_key_stanza(
+    csprng: &mut rand_chacha::ChaChaRng,
+    public_key: &PublicKey,
+    salt: &[u8],
+    signer: &VerifyingKey,
+    content_key: &[u8; 32],
+) -> Result<Stanza> {
+    let ephemeral_secret = StaticSecret::random_from_rng(csprng);
+    let ephemeral_key = PublicKey::from(&ephemeral_secret).to_bytes();
+    let shared_secret = ephemeral_secret.diffie_hellman(public_key);
+    if !shared_secret.was_contributory() {
+        return Err(Error::Malformed("Non-contributory Diffie-Hellman result"));
+    }
+    let key = public_key_wrap_key(
+        shared_secret.as_bytes(),
+        &ephemeral_key,
+        public_key.as_bytes(),
+        salt,
+    )?;
+
+    Ok(Stanza::PublicKey {
+        ephemeral_key,
+        wrapped_key: wrap(&key, salt, signer, content_key)?,
+    })
+}
+
+/// Try to unwrap a session stanza, None if it belongs to someone else
+pub(crate) fn open_session_stanza(
+    session_key: &[u8; 32],
+    salt: &[u8],
+    signer: &VerifyingKey,
+    wrapped_key: &[u8],
+) -> Result<Option<[u8; 32]>> {
+    Ok(unwrap(
+        &session_wrap_key(session_key, salt)?,
+        salt,
+        signer,
+        wrapped_key,
+    ))
+}
+
+/// Try to unwrap a public key stanza, None if it belongs to someone else
+pub(crate) fn open_public_key_stanza(
+    secret: &StaticSecret,
+    ephemeral_key: &[u8; 32],
+    salt: &[u8],
+    signer: &VerifyingKey,
+    wrapped_key: &[u8],
+) -> Result<Option<[u8; 32]>> {
+    let shared_secret = secret.diffie_hellman(&PublicKey::from(*ephemeral_key));
+    if !shared_secret.was_contributory() {
+        return Ok(None);
+    }
+    let key = public_key_wrap_key(
+        shared_secret.as_bytes(),
+        ephemeral_key,
+        PublicKey::from(secret).as_bytes(),
+        salt,
+    )?;
+    Ok(unwrap(&key, salt, signer, wrapped_key))
+}
+
+pub(crate) fn shuffle<T>(csprng: &mut rand_chacha::ChaChaRng, items: &mut [T]) {
+    for i in (1..items.len()).rev() {
+        let j = (csprng.next_u64() % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+pub(crate) fn encrypt_payload(
+    suite: CipherSuite,
+    content_key: &[u8; 32],
+    nonce: &[u8],
+    salt: &[u8],
+    mut payload: Vec<u8>,
+) -> Result<Vec<u8>> {
+    suite.seal(content_key, nonce, salt, &mut payload)?;
+    Ok(payload)
+}
+
+pub(crate) fn decrypt_payload(
+    content_key: &[u8; 32],
+    envelope: &Envelope,
+    limits: &Limits,
+) -> Result<Vec<u8>> {
+    let suite = envelope.suite()?;
+    limits.check_plaintext(envelope.ciphertext.len().saturating_sub(suite.overhead()))?;
+    let mut buffer = envelope.ciphertext.clone();
+    suite.open(content_key, &envelope.nonce, &envelope.salt, &mut buffer)?;
+    let message = message::decode_payload(
+        envelope.flags.unwrap_or(FLAG_COMPRESSED),
+        buffer,
+        limits.max_decompressed_size,
+    )?;
+    limits.check_plaintext(message.len())?;
+    Ok(message)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, KexMode};
+    use alloc::string::ToString;
+
+    #[test]
+    fn test_envelope() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let mut carol = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let mut dave = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let offer = alice.init_kex("bob", KexMode::X25519).unwrap();
+        let response = bob.respond_kex("alice", &offer, KexMode::X25519).unwrap();
+        alice.finish_kex("bob", &response).unwrap();
+
+        let recipients = [
+            EnvelopeRecipient::Peer("bob"),
+            EnvelopeRecipient::PublicKey(carol.noise_static_public_key()),
+        ];
+        let data = alice.encrypt_envelope(&recipients, b"Hello all").unwrap();
+
+        let opened = bob.decrypt_envelope(&data).unwrap();
+        assert_eq!(opened.message, b"Hello all");
+        assert_eq!(opened.peer, Some("alice".to_string()));
+        let opened = carol.decrypt_envelope(&data).unwrap();
+        assert_eq!(opened.message, b"Hello all");
+        assert_eq!(opened.peer, None);
+        assert_eq!(opened.sender, bob.decrypt_envelope(&data).unwrap().sender);
+        assert!(dave.decrypt_envelope(&data).is_err());
+
+        // Stanzas do not name their recipients
+        let envelope: Envelope = bson::from_slice(&data).unwrap();
+        assert_eq!(envelope.stanzas.len(), 2);
+        let carol_key = carol.noise_static_public_key().to_bytes();
+        assert!(!data.windows(32).any(|w| w == carol_key));
+
+        // A recipient cannot swap the payload for the others
+        let mut forged = envelope.clone();
+        forged.ciphertext = encrypt_payload(
+            CipherSuite::XChaCha20Poly1305,
+            &[0u8; 32],
+            &[0u8; 24],
+            &forged.salt,
+            b"Forged".to_vec(),
+        )
+        .unwrap();
+        assert!(carol
+            .decrypt_envelope(&bson::to_vec(&forged).unwrap())
+            .is_err());
+
+        assert!(alice
+            .encrypt_envelope(&[EnvelopeRecipient::Peer("dave")], b"")
+            .is_err());
+    }
+
+    // A recipient re-signs the envelope with its own certified key, reusing the sender's stanzas
+    fn re_sign(data: &[u8], signer: &Client) -> Vec<u8> {
+        use ed25519_dalek::pkcs8::EncodePublicKey;
+        use ed25519_dalek::Signer;
+
+        let mut forged: Envelope = bson::from_slice(data).unwrap();
+        forged.verifying_key = signer
+            .signing_key()
+            .verifying_key()
+            .to_public_key_der()
+            .unwrap()
+            .to_vec();
+        forged.signing_key_sig = signer.signing_key_signature().to_bytes().to_vec();
+        forged.signature = signer
+            .signing_key()
+            .sign(&forged.tbs().unwrap())
+            .to_bytes()
+            .to_vec();
+        bson::to_vec(&forged).unwrap()
+    }
+
+    #[test]
+    fn test_envelope_re_signed() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let mut carol = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        for (name, peer) in [("bob", &mut bob), ("carol", &mut carol)] {
+            let offer = alice.init_kex(name, KexMode::X25519).unwrap();
+            let response = peer.respond_kex("alice", &offer, KexMode::X25519).unwrap();
+            alice.finish_kex(name, &response).unwrap();
+        }
+
+        let recipients = [
+            EnvelopeRecipient::Peer("bob"),
+            EnvelopeRecipient::Peer("carol"),
+        ];
+        let data = alice.encrypt_envelope(&recipients, b"Hello all").unwrap();
+        assert!(matches!(
+            bob.decrypt_envelope(&re_sign(&data, &carol)),
+            Err(Error::Authentication(_))
+        ));
+        assert_eq!(bob.decrypt_envelope(&data).unwrap().message, b"Hello all");
+
+        let recipients = [
+            EnvelopeRecipient::Peer("bob"),
+            EnvelopeRecipient::PublicKey(carol.noise_static_public_key()),
+        ];
+        let data = alice.encrypt_envelope(&recipients, b"Hello all").unwrap();
+        assert!(carol.decrypt_envelope(&re_sign(&data, &bob)).is_err());
+        assert_eq!(carol.decrypt_envelope(&data).unwrap().message, b"Hello all");
+    }
+}
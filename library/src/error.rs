@@ -0,0 +1,265 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// Errors of the library
+//
+// Every variant carries a short message for logs, callers should decide on the variant or its
+// code. Codes are stable: new variants get new codes, existing ones are never renamed.
+
+use core::fmt;
+
+/// Result with the library's Error
+pub type Result<T, E = Error> = core::result::Result<T, E>;
+
+/// What went wrong, see code for a stable identifier
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// A tag, MAC, signature or key commitment did not verify, or the data was bound to a
+    /// different context. The input may have been tampered with.
+    Authentication(&'static str),
+    /// Input that cannot be parsed or violates its format
+    Malformed(&'static str),
+    /// Input uses a version, suite, mode or algorithm we do not support or allow
+    Unsupported(&'static str),
+    /// No session with the peer, see Client::init_kex
+    NoSession(&'static str),
+    /// The client has no CA, e.g. an instance whose CA data was not imported
+    NoCa(&'static str),
+    /// A key is not certified by our CA, or not the key we expected
+    UntrustedKey(&'static str),
+    /// A group, sender key, identity or other item is not known
+    NotFound(&'static str),
+    /// Compressed data is invalid
+    Decompression(&'static str),
+    /// Input exceeds a size limit, see limits::Limits
+    TooLarge(&'static str),
+    /// The message was received before or is too old to tell
+    Replay(&'static str),
+    /// The message or certificate has expired
+    Expired(&'static str),
+    /// The caller passed an argument that cannot be used
+    InvalidArgument(&'static str),
+    /// The operation is not possible in the current state, e.g. a key is exhausted
+    InvalidState(&'static str),
+    /// Encoding or a primitive failed unexpectedly
+    Internal(&'static str),
+}
+
+impl Error {
+    /// Stable identifier of the variant, for mapping to HTTP statuses or JS error classes
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Authentication(_) => "authentication_failed",
+            Self::Malformed(_) => "malformed_input",
+            Self::Unsupported(_) => "unsupported",
+            Self::NoSession(_) => "no_session",
+            Self::NoCa(_) => "no_ca",
+            Self::UntrustedKey(_) => "untrusted_key",
+            Self::NotFound(_) => "not_found",
+            Self::Decompression(_) => "decompression_failed",
+            Self::TooLarge(_) => "too_large",
+            Self::Replay(_) => "replay",
+            Self::Expired(_) => "expired",
+            Self::InvalidArgument(_) => "invalid_argument",
+            Self::InvalidState(_) => "invalid_state",
+            Self::Internal(_) => "internal",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::Authentication(message)
+            | Self::Malformed(message)
+            | Self::Unsupported(message)
+            | Self::NoSession(message)
+            | Self::NoCa(message)
+            | Self::UntrustedKey(message)
+            | Self::NotFound(message)
+            | Self::Decompression(message)
+            | Self::TooLarge(message)
+            | Self::Replay(message)
+            | Self::Expired(message)
+            | Self::InvalidArgument(message)
+            | Self::InvalidState(message)
+            | Self::Internal(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl core::error::Error for Error {}
+
+// Foreign errors by what they mean where they occur, sites where that differs map explicitly
+
+impl From<bson::de::Error> for Error {
+    fn from(_: bson::de::Error) -> Self {
+        Self::Malformed("Invalid BSON document")
+    }
+}
+
+impl From<bson::ser::Error> for Error {
+    fn from(_: bson::ser::Error) -> Self {
+        Self::Internal("Could not encode BSON document")
+    }
+}
+
+impl From<chacha20poly1305::aead::Error> for Error {
+    fn from(_: chacha20poly1305::aead::Error) -> Self {
+        Self::Authentication("Decryption failed")
+    }
+}
+
+impl From<ed25519_dalek::SignatureError> for Error {
+    fn from(_: ed25519_dalek::SignatureError) -> Self {
+        Self::Authentication("Signature invalid")
+    }
+}
+
+impl From<ed25519_dalek::pkcs8::Error> for Error {
+    fn from(_: ed25519_dalek::pkcs8::Error) -> Self {
+        Self::Malformed("Invalid key encoding")
+    }
+}
+
+impl From<ed25519_dalek::pkcs8::spki::Error> for Error {
+    fn from(_: ed25519_dalek::pkcs8::spki::Error) -> Self {
+        Self::Malformed("Invalid key encoding")
+    }
+}
+
+impl From<hmac::digest::InvalidLength> for Error {
+    fn from(_: hmac::digest::InvalidLength) -> Self {
+        Self::Internal("Invalid HMAC key length")
+    }
+}
+
+impl From<base64::DecodeError> for Error {
+    fn from(_: base64::DecodeError) -> Self {
+        Self::Malformed("Invalid base64")
+    }
+}
+
+impl From<bech32::Error> for Error {
+    fn from(_: bech32::Error) -> Self {
+        Self::Malformed("Invalid bech32")
+    }
+}
+
+impl From<lz4_flex::block::DecompressError> for Error {
+    fn from(_: lz4_flex::block::DecompressError) -> Self {
+        Self::Decompression("Invalid LZ4 data")
+    }
+}
+
+impl From<argon2::Error> for Error {
+    fn from(_: argon2::Error) -> Self {
+        Self::Internal("Password hashing failed")
+    }
+}
+
+impl From<scrypt::errors::InvalidParams> for Error {
+    fn from(_: scrypt::errors::InvalidParams) -> Self {
+        Self::Malformed("Invalid scrypt parameters")
+    }
+}
+
+impl From<scrypt::errors::InvalidOutputLen> for Error {
+    fn from(_: scrypt::errors::InvalidOutputLen) -> Self {
+        Self::Internal("Invalid scrypt output length")
+    }
+}
+
+impl From<core::str::Utf8Error> for Error {
+    fn from(_: core::str::Utf8Error) -> Self {
+        Self::Malformed("Invalid UTF-8")
+    }
+}
+
+impl From<core::num::ParseIntError> for Error {
+    fn from(_: core::num::ParseIntError) -> Self {
+        Self::Malformed("Invalid number")
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, KexMode};
+    use crate::limits::Limits;
+
+    #[test]
+    fn test_error_kinds() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        assert!(matches!(
+            bob.encrypt_message_for_recipient("alice", b"hi"),
+            Err(Error::NoSession(_))
+        ));
+        assert!(matches!(bob.generate_instance(), Err(Error::NoCa(_))));
+
+        // A stranger's identity is not certified by our CA
+        let mut mallory = Client::new_user();
+        let offer = mallory.init_kex("bob", KexMode::X25519).unwrap();
+        assert!(matches!(
+            bob.respond_kex("mallory", &offer, KexMode::X25519),
+            Err(Error::UntrustedKey(_))
+        ));
+        assert!(matches!(
+            bob.respond_kex("mallory", &offer[..offer.len() - 1], KexMode::X25519),
+            Err(Error::Malformed(_))
+        ));
+
+        let offer = alice.init_kex("bob", KexMode::X25519).unwrap();
+        let response = bob.respond_kex("alice", &offer, KexMode::X25519).unwrap();
+        alice.finish_kex("bob", &response).unwrap();
+        let data = alice
+            .encrypt_message_for_recipient("bob", b"hello")
+            .unwrap();
+        let mut tampered = data.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        let error = bob
+            .decrypt_message_from_sender("alice", &tampered)
+            .unwrap_err();
+        assert_eq!(error, Error::Authentication("Decryption failed"));
+        assert_eq!(error.code(), "authentication_failed");
+
+        bob.decrypt_message_from_sender("alice", &data).unwrap();
+        let error = bob.decrypt_message_from_sender("alice", &data).unwrap_err();
+        assert!(matches!(error, Error::Replay(_)));
+        assert_eq!(error.code(), "replay");
+
+        alice.set_time(100);
+        bob.set_time(200);
+        let data = alice.encrypt_message_with_ttl("bob", b"late", 10).unwrap();
+        assert_eq!(
+            bob.decrypt_message_from_sender("alice", &data),
+            Err(Error::Expired("Message has expired"))
+        );
+
+        bob.set_limits(Limits {
+            max_plaintext_size: 4,
+            ..Default::default()
+        });
+        let data = alice
+            .encrypt_message_for_recipient("bob", b"too long")
+            .unwrap();
+        assert!(matches!(
+            bob.decrypt_message_from_sender("alice", &data),
+            Err(Error::TooLarge(_))
+        ));
+    }
+}
@@ -0,0 +1,622 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// Group messaging with sender keys
+//
+// Every member has its own sender chain per group: a chain key, an iteration counter and an ed25519 key.
+// The chain is sent to the other members over the pairwise sessions, after that a message is
+// encrypted once and every member can decrypt it.
+// message key = SHA3-256("CosmicCipher group message key" || chain key)
+// next chain key = SHA3-256("CosmicCipher group chain key" || chain key)
+// Messages are signed with the chain's ed25519 key, so members cannot forge messages of each other.
+// Each chain is encrypted with the preferred suite of its owner, receivers only install chains
+// with a suite they accept. Sender keys without a suite are XChaCha20-Poly1305.
+// A member change starts a new generation of the sender chain, removed members cannot read it.
+// Only the admin, the member who created the group, changes the members. Sender keys of other
+// members install their chain but never change the member list, and a group is only joined
+// with the admin's sender key.
+// The header flags say how the payload was compressed and padded, like in message headers.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand_chacha::rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+
+use crate::compression::Compression;
+use crate::error::{Error, Result};
+use crate::limits::Limits;
+use crate::message::{self, MessageOptions};
+use crate::padding::Padding;
+use crate::suite::CipherSuite;
+
+/// Random identifier of a group, sent in front of every group message
+pub type GroupId = [u8; 16];
+
+/// Our sender key encrypted over the pairwise session of each member, as (member, data)
+pub type SenderKeyDistribution = Vec<(String, Vec<u8>)>;
+
+// Group id (16) || generation (4) || iteration (4) || flags (2), followed by the nonce of the
+// chain's suite
+const PREFIX_LEN: usize = 26;
+const SIGNATURE_LEN: usize = 64;
+// Receivers do not advance a chain further than this in one go
+const MAX_SKIP: u32 = 1000;
+// Message keys kept for messages that arrive out of order
+const MAX_SKIPPED_KEYS: usize = 1000;
+// Chains of older generations are kept for messages in transit
+const RETAINED_GENERATIONS: usize = 2;
+
+struct SenderChain {
+    generation: u32,
+    chain_key: [u8; 32],
+    iteration: u32,
+    signing_key: SigningKey,
+    suite: CipherSuite,
+}
+
+#[derive(Clone)]
+struct ReceiverChain {
+    generation: u32,
+    chain_key: [u8; 32],
+    // Iteration of chain_key, the next expected message
+    iteration: u32,
+    verifying_key: VerifyingKey,
+    suite: CipherSuite,
+    skipped: Vec<(u32, [u8; 32])>,
+}
+
+impl ReceiverChain {
+    // Message key for `iteration`, and the chain as it is once that message is accepted
+    fn message_key(&self, iteration: u32) -> Result<([u8; 32], ReceiverChain)> {
+        let mut chain = self.clone();
+
+        if iteration < chain.iteration {
+            let index = match chain.skipped.iter().position(|(i, _)| *i == iteration) {
+                None => return Err(Error::Replay("Group message key already used or discarded")),
+                Some(v) => v,
+            };
+            let (_, key) = chain.skipped.remove(index);
+            return Ok((key, chain));
+        }
+        if iteration - chain.iteration > MAX_SKIP {
+            return Err(Error::Malformed("Group message too far ahead"));
+        }
+
+        while chain.iteration < iteration {
+            chain
+                .skipped
+                .push((chain.iteration, message_key(&chain.chain_key)));
+            chain.chain_key = next_chain_key(&chain.chain_key);
+            chain.iteration += 1;
+        }
+        if chain.skipped.len() > MAX_SKIPPED_KEYS {
+            let excess = chain.skipped.len() - MAX_SKIPPED_KEYS;
+            chain.skipped.drain(..excess);
+        }
+
+        let key = message_key(&chain.chain_key);
+        chain.chain_key = next_chain_key(&chain.chain_key);
+        chain.iteration = match chain.iteration.checked_add(1) {
+            None => return Err(Error::InvalidState("Group chain exhausted")),
+            Some(v) => v,
+        };
+
+        Ok((key, chain))
+    }
+}
+
+pub(crate) struct Group {
+    pub(crate) id: GroupId,
+    // Other members, without ourselves
+    pub(crate) members: Vec<String>,
+    // The member who may change the members, None if that is us
+    pub(crate) admin: Option<String>,
+    own: SenderChain,
+    // Chains of the other members, newest generation last
+    receivers: hashbrown::HashMap<String, Vec<ReceiverChain>>,
+}
+
+impl Group {
+    pub(crate) fn new(
+        id: GroupId,
+        members: Vec<String>,
+        admin: Option<String>,
+        suite: CipherSuite,
+        csprng: &mut rand_chacha::ChaChaRng,
+    ) -> Self {
+        Self {
+            id,
+            members,
+            admin,
+            own: new_sender_chain(csprng, 0, suite),
+            receivers: hashbrown::HashMap::new(),
+        }
+    }
+
+    /// Start a new generation of our sender chain
+    pub(crate) fn rekey(&mut self, csprng: &mut rand_chacha::ChaChaRng) -> Result<()> {
+        let generation = match self.own.generation.checked_add(1) {
+            None => return Err(Error::InvalidState("Group generations exhausted")),
+            Some(v) => v,
+        };
+        self.own = new_sender_chain(csprng, generation, self.own.suite);
+        Ok(())
+    }
+
+    pub(crate) fn set_members(&mut self, members: Vec<String>) {
+        self.receivers.retain(|k, _| members.contains(k));
+        self.members = members;
+    }
+
+    /// Our current sender chain for `recipient`, with the member list as the recipient sees it
+    pub(crate) fn sender_key_message(&self, recipient: &str) -> SenderKeyMessage {
+        SenderKeyMessage {
+            group_id: self.id.to_vec(),
+            generation: self.own.generation,
+            chain_key: self.own.chain_key.to_vec(),
+            iteration: self.own.iteration,
+            verifying_key: self.own.signing_key.verifying_key().to_bytes().to_vec(),
+            suite: Some(self.own.suite.id()),
+            admin: self.admin.clone(),
+            members: self
+                .members
+                .iter()
+                .filter(|v| v.as_str() != recipient)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Install the sender chain of `sender`, if its suite is one of `suites`
+    pub(crate) fn install_sender_key(
+        &mut self,
+        sender: &str,
+        message: &SenderKeyMessage,
+        suites: &[CipherSuite],
+    ) -> Result<()> {
+        let suite = match message.suite {
+            None => CipherSuite::XChaCha20Poly1305,
+            Some(id) => CipherSuite::from_id(id)?,
+        };
+        if !suites.contains(&suite) {
+            return Err(Error::Unsupported("Cipher suite not allowed"));
+        }
+        let chain_key: [u8; 32] = message
+            .chain_key
+            .as_slice()
+            .try_into()
+            .map_err(|_| Error::Malformed("Invalid chain key length"))?;
+        let verifying_key: [u8; 32] = message
+            .verifying_key
+            .as_slice()
+            .try_into()
+            .map_err(|_| Error::Malformed("Invalid verifying key length"))?;
+        let chain = ReceiverChain {
+            generation: message.generation,
+            chain_key,
+            iteration: message.iteration,
+            verifying_key: VerifyingKey::from_bytes(&verifying_key)
+                .map_err(|_| Error::Malformed("Invalid verifying key"))?,
+            suite,
+            skipped: Vec::new(),
+        };
+
+        let chains = self.receivers.entry(sender.into()).or_default();
+        if let Some(newest) = chains.last() {
+            if newest.generation >= chain.generation {
+                return Err(Error::Replay("Sender key generation is not newer"));
+            }
+        }
+        chains.push(chain);
+        if chains.len() > RETAINED_GENERATIONS {
+            chains.remove(0);
+        }
+
+        Ok(())
+    }
+
+    /// Output is group id || generation || iteration || flags || nonce || ciphertext || signature,
+    /// the header is associated data and the signature covers everything before it.
+    /// Payloads are LZ4 compressed and not padded unless `options` say otherwise.
+    pub(crate) fn encrypt(
+        &mut self,
+        csprng: &mut rand_chacha::ChaChaRng,
+        message: &[u8],
+        options: &MessageOptions,
+        limits: &Limits,
+    ) -> Result<Vec<u8>> {
+        options.check_payload_only()?;
+        limits.check_plaintext(message.len())?;
+        let (flags, mut buffer) = message::encode_payload(
+            options.compression.unwrap_or(Compression::Lz4),
+            options.padding.unwrap_or(Padding::None),
+            options.sensitive,
+            csprng,
+            message,
+        )?;
+        // The receivers check the payload against their limit before decrypting it
+        limits.check_plaintext(buffer.len())?;
+
+        let key = message_key(&self.own.chain_key);
+        let iteration = self.own.iteration;
+        self.own.iteration = match iteration.checked_add(1) {
+            None => return Err(Error::InvalidState("Group chain exhausted")),
+            Some(v) => v,
+        };
+        self.own.chain_key = next_chain_key(&self.own.chain_key);
+
+        let suite = self.own.suite;
+        let mut nonce = vec![0u8; suite.nonce_len()];
+        csprng.fill_bytes(&mut nonce);
+
+        let mut output = self.id.to_vec();
+        output.extend_from_slice(&self.own.generation.to_be_bytes());
+        output.extend_from_slice(&iteration.to_be_bytes());
+        output.extend_from_slice(&flags.to_be_bytes());
+        output.extend_from_slice(&nonce);
+
+        suite.seal(&key, &nonce, &output, &mut buffer)?;
+        output.extend_from_slice(&buffer);
+
+        let sig = self.own.signing_key.sign(&output);
+        output.extend_from_slice(&sig.to_bytes());
+
+        Ok(output)
+    }
+
+    pub(crate) fn decrypt(
+        &mut self,
+        sender: &str,
+        data: &[u8],
+        limits: &Limits,
+    ) -> Result<Vec<u8>> {
+        if data.len() < PREFIX_LEN + SIGNATURE_LEN {
+            return Err(Error::Malformed("Group message is too short"));
+        }
+        let (signed, sig) = data.split_at(data.len() - SIGNATURE_LEN);
+        let generation = u32::from_be_bytes([data[16], data[17], data[18], data[19]]);
+        let iteration = u32::from_be_bytes([data[20], data[21], data[22], data[23]]);
+        let flags = u16::from_be_bytes([data[24], data[25]]);
+
+        let chains = match self.receivers.get_mut(sender) {
+            None => return Err(Error::NotFound("No sender key found")),
+            Some(v) => v,
+        };
+        let chain = match chains.iter_mut().find(|c| c.generation == generation) {
+            None => return Err(Error::NotFound("No sender key found for this generation")),
+            Some(v) => v,
+        };
+
+        let header_len = PREFIX_LEN + chain.suite.nonce_len();
+        if signed.len() < header_len + chain.suite.overhead() {
+            return Err(Error::Malformed("Group message is too short"));
+        }
+
+        let sig = Signature::from_slice(sig).map_err(|_| Error::Malformed("Invalid signature"))?;
+        if chain.verifying_key.verify(signed, &sig).is_err() {
+            return Err(Error::Authentication("Group message not signed by sender"));
+        }
+
+        let (key, advanced) = chain.message_key(iteration)?;
+        limits.check_plaintext(signed.len() - header_len - chain.suite.overhead())?;
+        let mut buffer = signed[header_len..].to_vec();
+        chain.suite.open(
+            &key,
+            &signed[PREFIX_LEN..header_len],
+            &signed[..header_len],
+            &mut buffer,
+        )?;
+        *chain = advanced;
+
+        let message = message::decode_payload(flags, buffer, limits.max_decompressed_size)?;
+        limits.check_plaintext(message.len())?;
+        Ok(message)
+    }
+}
+
+/// Sent over a pairwise session to hand out a sender chain
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SenderKeyMessage {
+    pub(crate) group_id: Vec<u8>,
+    generation: u32,
+    chain_key: Vec<u8>,
+    iteration: u32,
+    verifying_key: Vec<u8>,
+    // Id of the chain's cipher suite
+    #[serde(default)]
+    suite: Option<u8>,
+    // The other members without the recipient and the sender
+    pub(crate) members: Vec<String>,
+    // The group admin as the sender knows it, None if that is the sender
+    #[serde(default)]
+    pub(crate) admin: Option<String>,
+}
+
+fn new_sender_chain(
+    csprng: &mut rand_chacha::ChaChaRng,
+    generation: u32,
+    suite: CipherSuite,
+) -> SenderChain {
+    let mut chain_key = [0u8; 32];
+    csprng.fill_bytes(&mut chain_key);
+    SenderChain {
+        generation,
+        chain_key,
+        iteration: 0,
+        signing_key: SigningKey::generate(csprng),
+        suite,
+    }
+}
+
+fn message_key(chain_key: &[u8; 32]) -> [u8; 32] {
+    derive(b"CosmicCipher group message key", chain_key)
+}
+
+fn next_chain_key(chain_key: &[u8; 32]) -> [u8; 32] {
+    derive(b"CosmicCipher group chain key", chain_key)
+}
+
+fn derive(label: &[u8], chain_key: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(label);
+    hasher.update(chain_key);
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&hasher.finalize());
+    key
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::SenderKeyMessage;
+    use crate::client::{Client, KexMode};
+    use crate::error::Error;
+    use crate::suite::CipherSuite;
+    use alloc::string::ToString;
+    use alloc::vec::Vec;
+
+    fn connect(a: &mut Client, a_name: &str, b: &mut Client, b_name: &str) {
+        let offer = a.init_kex(b_name, KexMode::X25519).unwrap();
+        let response = b.respond_kex(a_name, &offer, KexMode::X25519).unwrap();
+        a.finish_kex(b_name, &response).unwrap();
+    }
+
+    #[test]
+    fn test_group_messages() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let mut carol = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        connect(&mut alice, "alice", &mut bob, "bob");
+        connect(&mut alice, "alice", &mut carol, "carol");
+        connect(&mut bob, "bob", &mut carol, "carol");
+
+        let (group_id, distribution) = alice.create_group(&["bob", "carol"]).unwrap();
+        assert_eq!(distribution.len(), 2);
+        let mut replies = Vec::new();
+        for (member, data) in distribution {
+            let client = if member == "bob" {
+                &mut bob
+            } else {
+                &mut carol
+            };
+            let update = client.process_group_key("alice", &data).unwrap();
+            assert_eq!(update.group_id, group_id);
+            for (to, data) in update.distribution {
+                replies.push((member.clone(), to, data));
+            }
+        }
+        for (from, to, data) in replies {
+            let client = match to.as_str() {
+                "alice" => &mut alice,
+                "bob" => &mut bob,
+                _ => &mut carol,
+            };
+            // Everybody agrees on the members, nobody renews again
+            let update = client.process_group_key(&from, &data).unwrap();
+            assert!(update.distribution.is_empty());
+        }
+        assert_eq!(
+            carol.group_members(&group_id).unwrap(),
+            ["alice".to_string(), "bob".to_string()]
+        );
+
+        let first = alice.encrypt_group_message(&group_id, b"first").unwrap();
+        let second = alice.encrypt_group_message(&group_id, b"second").unwrap();
+        assert_eq!(
+            carol.decrypt_group_message("alice", &first).unwrap(),
+            b"first"
+        );
+        // Out of order, but every message only once
+        assert_eq!(
+            bob.decrypt_group_message("alice", &second).unwrap(),
+            b"second"
+        );
+        assert_eq!(
+            bob.decrypt_group_message("alice", &first).unwrap(),
+            b"first"
+        );
+        assert!(bob.decrypt_group_message("alice", &first).is_err());
+
+        let from_bob = bob.encrypt_group_message(&group_id, b"from bob").unwrap();
+        assert_eq!(
+            carol.decrypt_group_message("bob", &from_bob).unwrap(),
+            b"from bob"
+        );
+        // Only bob's key verifies bob's messages
+        assert!(alice.decrypt_group_message("carol", &from_bob).is_err());
+        let mut forged = from_bob.clone();
+        let len = forged.len();
+        forged[len - 70] ^= 1;
+        assert!(alice.decrypt_group_message("bob", &forged).is_err());
+        assert_eq!(
+            alice.decrypt_group_message("bob", &from_bob).unwrap(),
+            b"from bob"
+        );
+
+        // Removing carol renews the keys of alice and bob
+        let distribution = alice.remove_group_member(&group_id, "carol").unwrap();
+        assert_eq!(distribution.len(), 1);
+        let update = bob.process_group_key("alice", &distribution[0].1).unwrap();
+        assert_eq!(update.distribution.len(), 1);
+        assert_eq!(bob.group_members(&group_id).unwrap(), ["alice".to_string()]);
+        let update = alice
+            .process_group_key("bob", &update.distribution[0].1)
+            .unwrap();
+        assert!(update.distribution.is_empty());
+
+        let secret = alice.encrypt_group_message(&group_id, b"secret").unwrap();
+        assert!(carol.decrypt_group_message("alice", &secret).is_err());
+        assert_eq!(
+            bob.decrypt_group_message("alice", &secret).unwrap(),
+            b"secret"
+        );
+        let secret = bob.encrypt_group_message(&group_id, b"secret").unwrap();
+        assert!(carol.decrypt_group_message("bob", &secret).is_err());
+        assert_eq!(
+            alice.decrypt_group_message("bob", &secret).unwrap(),
+            b"secret"
+        );
+
+        // A member without a session cannot be added
+        assert!(alice.add_group_member(&group_id, "dave").is_err());
+        alice.leave_group(&group_id).unwrap();
+        assert!(alice.encrypt_group_message(&group_id, b"gone").is_err());
+    }
+
+    #[test]
+    fn test_group_suites() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let mut carol = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        alice
+            .set_cipher_suites(&[CipherSuite::Aes256GcmSiv, CipherSuite::XChaCha20Poly1305])
+            .unwrap();
+        carol
+            .set_cipher_suites(&[CipherSuite::XChaCha20Poly1305])
+            .unwrap();
+        connect(&mut alice, "alice", &mut bob, "bob");
+        connect(&mut alice, "alice", &mut carol, "carol");
+
+        // Alice's chain uses her preferred suite, bob's his own
+        let (group_id, distribution) = alice.create_group(&["bob", "carol"]).unwrap();
+        let (_, data) = distribution.iter().find(|(v, _)| v == "bob").unwrap();
+        let update = bob.process_group_key("alice", data).unwrap();
+        alice
+            .process_group_key("bob", &update.distribution[0].1)
+            .unwrap();
+        let message = alice.encrypt_group_message(&group_id, b"siv").unwrap();
+        assert_eq!(
+            bob.decrypt_group_message("alice", &message).unwrap(),
+            b"siv"
+        );
+        let message = bob.encrypt_group_message(&group_id, b"chacha").unwrap();
+        assert_eq!(
+            alice.decrypt_group_message("bob", &message).unwrap(),
+            b"chacha"
+        );
+
+        // Carol does not accept the suite of alice's chain
+        let (_, data) = distribution.iter().find(|(v, _)| v == "carol").unwrap();
+        assert_eq!(
+            carol.process_group_key("alice", data).err(),
+            Some(Error::Unsupported("Cipher suite not allowed"))
+        );
+    }
+
+    #[test]
+    fn test_group_admin() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let mut carol = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        connect(&mut alice, "alice", &mut bob, "bob");
+        connect(&mut alice, "alice", &mut carol, "carol");
+        connect(&mut bob, "bob", &mut carol, "carol");
+
+        let (group_id, distribution) = alice.create_group(&["bob", "carol"]).unwrap();
+        let mut from_bob = Vec::new();
+        for (member, data) in distribution {
+            let client = if member == "bob" {
+                &mut bob
+            } else {
+                &mut carol
+            };
+            let update = client.process_group_key("alice", &data).unwrap();
+            if member == "bob" {
+                from_bob = update.distribution;
+            }
+        }
+
+        // Only alice changes the members
+        assert_eq!(
+            bob.remove_group_member(&group_id, "carol").err(),
+            Some(Error::InvalidState(
+                "Only the group admin can change the members"
+            ))
+        );
+        assert!(bob.add_group_member(&group_id, "alice").is_err());
+
+        // Bob's sender key installs his chain, but his member list is ignored
+        let (_, data) = from_bob.iter().find(|(v, _)| v == "carol").unwrap();
+        carol.process_group_key("bob", data).unwrap();
+        let forged = SenderKeyMessage {
+            group_id: group_id.to_vec(),
+            generation: 7,
+            chain_key: [1u8; 32].to_vec(),
+            iteration: 0,
+            verifying_key: ed25519_dalek::SigningKey::from_bytes(&[2u8; 32])
+                .verifying_key()
+                .to_bytes()
+                .to_vec(),
+            suite: None,
+            members: Vec::new(),
+            admin: None,
+        };
+        let data = bob
+            .encrypt_message_for_recipient("carol", &bson::to_vec(&forged).unwrap())
+            .unwrap();
+        let update = carol.process_group_key("bob", &data).unwrap();
+        assert!(update.distribution.is_empty());
+        assert_eq!(
+            carol.group_members(&group_id).unwrap(),
+            ["alice".to_string(), "bob".to_string()]
+        );
+
+        // A group is not joined with the sender key of another member
+        let mut dave = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        connect(&mut alice, "alice", &mut dave, "dave");
+        connect(&mut bob, "bob", &mut dave, "dave");
+        let distribution = alice.add_group_member(&group_id, "dave").unwrap();
+        let update = bob
+            .process_group_key(
+                "alice",
+                &distribution.iter().find(|(v, _)| v == "bob").unwrap().1,
+            )
+            .unwrap();
+        let (_, data) = update
+            .distribution
+            .iter()
+            .find(|(v, _)| v == "dave")
+            .unwrap();
+        assert_eq!(
+            dave.process_group_key("bob", data).err(),
+            Some(Error::InvalidState(
+                "Join the group with the sender key of its admin first"
+            ))
+        );
+        let (_, data) = distribution.iter().find(|(v, _)| v == "dave").unwrap();
+        dave.process_group_key("alice", data).unwrap();
+        assert_eq!(
+            dave.group_members(&group_id).unwrap(),
+            ["alice".to_string(), "bob".to_string(), "carol".to_string()]
+        );
+    }
+}
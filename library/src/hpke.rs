@@ -0,0 +1,431 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// Hybrid Public Key Encryption (RFC 9180)
+//
+// Only one suite: DHKEM(X25519, HKDF-SHA256), HKDF-SHA256, ChaCha20Poly1305.
+// All four modes are supported, the mode follows from whether a PSK and a sender key are given.
+// Client::hpke_seal and Client::hpke_open use it with the static keys of certified identities.
+
+use alloc::vec::Vec;
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, KeyInit};
+use hmac::{Hmac, Mac};
+use rand_chacha::rand_core::{CryptoRng, RngCore};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::error::{Error, Result};
+
+const KEM_ID: u16 = 0x0020;
+const KDF_ID: u16 = 0x0001;
+const AEAD_ID: u16 = 0x0003;
+
+const HASH_LEN: usize = 32;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+/// Length of the encapsulated key
+pub const ENC_LEN: usize = 32;
+const MIN_PSK_LEN: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HpkeMode {
+    Base,
+    Psk,
+    Auth,
+    AuthPsk,
+}
+
+impl HpkeMode {
+    fn id(&self) -> u8 {
+        match self {
+            HpkeMode::Base => 0,
+            HpkeMode::Psk => 1,
+            HpkeMode::Auth => 2,
+            HpkeMode::AuthPsk => 3,
+        }
+    }
+}
+
+/// Pre-shared key for the PSK modes, both sides need the same key and id
+#[derive(Clone, Copy, Debug)]
+pub struct HpkePsk<'a> {
+    pub psk: &'a [u8],
+    pub psk_id: &'a [u8],
+}
+
+fn kem_suite_id() -> Vec<u8> {
+    let mut suite_id = b"KEM".to_vec();
+    suite_id.extend_from_slice(&KEM_ID.to_be_bytes());
+    suite_id
+}
+
+fn hpke_suite_id() -> Vec<u8> {
+    let mut suite_id = b"HPKE".to_vec();
+    suite_id.extend_from_slice(&KEM_ID.to_be_bytes());
+    suite_id.extend_from_slice(&KDF_ID.to_be_bytes());
+    suite_id.extend_from_slice(&AEAD_ID.to_be_bytes());
+    suite_id
+}
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> Result<[u8; HASH_LEN]> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).map_err(Error::from)?;
+    for part in parts {
+        mac.update(part);
+    }
+    let mut output = [0u8; HASH_LEN];
+    output.copy_from_slice(&mac.finalize().into_bytes());
+    Ok(output)
+}
+
+fn labeled_extract(
+    suite_id: &[u8],
+    salt: &[u8],
+    label: &[u8],
+    ikm: &[u8],
+) -> Result<[u8; HASH_LEN]> {
+    // HKDF uses a zero key for an empty salt, HMAC pads short keys with zeros anyway
+    hmac(salt, &[b"HPKE-v1", suite_id, label, ikm])
+}
+
+fn labeled_expand(
+    suite_id: &[u8],
+    prk: &[u8],
+    label: &[u8],
+    info: &[u8],
+    len: usize,
+) -> Result<Vec<u8>> {
+    if len > 255 * HASH_LEN {
+        return Err(Error::TooLarge("Requested output is too long"));
+    }
+    let len_prefix = (len as u16).to_be_bytes();
+
+    let mut output = Vec::with_capacity(len);
+    let mut block: Vec<u8> = Vec::new();
+    let mut counter = 1u8;
+    while output.len() < len {
+        block = hmac(
+            prk,
+            &[
+                &block,
+                &len_prefix,
+                b"HPKE-v1",
+                suite_id,
+                label,
+                info,
+                &[counter],
+            ],
+        )?
+        .to_vec();
+        output.extend_from_slice(&block);
+        counter = counter.wrapping_add(1);
+    }
+    output.truncate(len);
+    Ok(output)
+}
+
+fn to_key(data: &[u8]) -> Result<[u8; 32]> {
+    data.try_into()
+        .map_err(|_| Error::Malformed("Invalid key length"))
+}
+
+/// DeriveKeyPair of DHKEM(X25519, HKDF-SHA256)
+pub fn derive_key_pair(ikm: &[u8]) -> Result<(StaticSecret, PublicKey)> {
+    let suite_id = kem_suite_id();
+    let dkp_prk = labeled_extract(&suite_id, b"", b"dkp_prk", ikm)?;
+    let sk = to_key(&labeled_expand(&suite_id, &dkp_prk, b"sk", b"", 32)?)?;
+    let secret = StaticSecret::from(sk);
+    let public = PublicKey::from(&secret);
+    Ok((secret, public))
+}
+
+fn dh(secret: &StaticSecret, public: &PublicKey) -> Result<[u8; 32]> {
+    let shared = secret.diffie_hellman(public);
+    if !shared.was_contributory() {
+        return Err(Error::Malformed("Non-contributory Diffie-Hellman result"));
+    }
+    Ok(shared.to_bytes())
+}
+
+fn extract_and_expand(dh: &[u8], kem_context: &[u8]) -> Result<[u8; 32]> {
+    let suite_id = kem_suite_id();
+    let eae_prk = labeled_extract(&suite_id, b"", b"eae_prk", dh)?;
+    to_key(&labeled_expand(
+        &suite_id,
+        &eae_prk,
+        b"shared_secret",
+        kem_context,
+        32,
+    )?)
+}
+
+// Encap and AuthEncap
+fn encap(
+    ephemeral: &StaticSecret,
+    pk_r: &PublicKey,
+    sk_s: Option<&StaticSecret>,
+) -> Result<([u8; 32], [u8; ENC_LEN])> {
+    let enc = PublicKey::from(ephemeral).to_bytes();
+    let mut dh_value = dh(ephemeral, pk_r)?.to_vec();
+    let mut kem_context = enc.to_vec();
+    kem_context.extend_from_slice(pk_r.as_bytes());
+    if let Some(sk_s) = sk_s {
+        dh_value.extend_from_slice(&dh(sk_s, pk_r)?);
+        kem_context.extend_from_slice(PublicKey::from(sk_s).as_bytes());
+    }
+    Ok((extract_and_expand(&dh_value, &kem_context)?, enc))
+}
+
+// Decap and AuthDecap
+fn decap(enc: &[u8], sk_r: &StaticSecret, pk_s: Option<&PublicKey>) -> Result<[u8; 32]> {
+    let pk_e = PublicKey::from(to_key(enc)?);
+    let mut dh_value = dh(sk_r, &pk_e)?.to_vec();
+    let mut kem_context = enc.to_vec();
+    kem_context.extend_from_slice(PublicKey::from(sk_r).as_bytes());
+    if let Some(pk_s) = pk_s {
+        dh_value.extend_from_slice(&dh(sk_r, pk_s)?);
+        kem_context.extend_from_slice(pk_s.as_bytes());
+    }
+    extract_and_expand(&dh_value, &kem_context)
+}
+
+struct Context {
+    key: [u8; KEY_LEN],
+    base_nonce: [u8; NONCE_LEN],
+    exporter_secret: [u8; HASH_LEN],
+    seq: u64,
+}
+
+impl Context {
+    fn new(
+        mode: HpkeMode,
+        shared_secret: &[u8; 32],
+        info: &[u8],
+        psk: Option<HpkePsk>,
+    ) -> Result<Self> {
+        let (psk, psk_id) = match psk {
+            None => (&[][..], &[][..]),
+            Some(v) => {
+                if v.psk.len() < MIN_PSK_LEN {
+                    return Err(Error::InvalidArgument("PSK is too short"));
+                }
+                if v.psk_id.is_empty() {
+                    return Err(Error::InvalidArgument("PSK id is empty"));
+                }
+                (v.psk, v.psk_id)
+            }
+        };
+
+        let suite_id = hpke_suite_id();
+        let psk_id_hash = labeled_extract(&suite_id, b"", b"psk_id_hash", psk_id)?;
+        let info_hash = labeled_extract(&suite_id, b"", b"info_hash", info)?;
+        let mut key_schedule_context = [mode.id()].to_vec();
+        key_schedule_context.extend_from_slice(&psk_id_hash);
+        key_schedule_context.extend_from_slice(&info_hash);
+
+        let secret = labeled_extract(&suite_id, shared_secret, b"secret", psk)?;
+        let key = labeled_expand(&suite_id, &secret, b"key", &key_schedule_context, KEY_LEN)?;
+        let base_nonce = labeled_expand(
+            &suite_id,
+            &secret,
+            b"base_nonce",
+            &key_schedule_context,
+            NONCE_LEN,
+        )?;
+        let exporter_secret =
+            labeled_expand(&suite_id, &secret, b"exp", &key_schedule_context, HASH_LEN)?;
+
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&base_nonce);
+        Ok(Self {
+            key: to_key(&key)?,
+            base_nonce: nonce,
+            exporter_secret: to_key(&exporter_secret)?,
+            seq: 0,
+        })
+    }
+
+    // base_nonce XOR seq
+    fn nonce(&self) -> [u8; NONCE_LEN] {
+        let mut nonce = self.base_nonce;
+        for (n, s) in nonce[NONCE_LEN - 8..]
+            .iter_mut()
+            .zip(self.seq.to_be_bytes().iter())
+        {
+            *n ^= s;
+        }
+        nonce
+    }
+
+    fn increment_seq(&mut self) -> Result<()> {
+        self.seq = match self.seq.checked_add(1) {
+            None => return Err(Error::InvalidState("Message limit reached")),
+            Some(v) => v,
+        };
+        Ok(())
+    }
+
+    fn export(&self, exporter_context: &[u8], len: usize) -> Result<Vec<u8>> {
+        labeled_expand(
+            &hpke_suite_id(),
+            &self.exporter_secret,
+            b"sec",
+            exporter_context,
+            len,
+        )
+    }
+}
+
+fn mode(psk: &Option<HpkePsk>, authenticated: bool) -> HpkeMode {
+    match (psk.is_some(), authenticated) {
+        (false, false) => HpkeMode::Base,
+        (true, false) => HpkeMode::Psk,
+        (false, true) => HpkeMode::Auth,
+        (true, true) => HpkeMode::AuthPsk,
+    }
+}
+
+/// Encryption context of the sender, messages have to be opened in the order they were sealed
+pub struct SenderContext {
+    mode: HpkeMode,
+    context: Context,
+}
+
+impl SenderContext {
+    pub fn mode(&self) -> HpkeMode {
+        self.mode
+    }
+
+    pub fn seal(&mut self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.context.nonce();
+        let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(&self.context.key));
+        let mut buffer = plaintext.to_vec();
+        cipher
+            .encrypt_in_place(GenericArray::from_slice(&nonce), aad, &mut buffer)
+            .map_err(|_| Error::Internal("Encryption failed"))?;
+        self.context.increment_seq()?;
+        Ok(buffer)
+    }
+
+    pub fn export(&self, exporter_context: &[u8], len: usize) -> Result<Vec<u8>> {
+        self.context.export(exporter_context, len)
+    }
+}
+
+/// Decryption context of the recipient
+pub struct RecipientContext {
+    mode: HpkeMode,
+    context: Context,
+}
+
+impl RecipientContext {
+    pub fn mode(&self) -> HpkeMode {
+        self.mode
+    }
+
+    pub fn open(&mut self, aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(&self.context.key));
+        let mut buffer = ciphertext.to_vec();
+        cipher
+            .decrypt_in_place(
+                GenericArray::from_slice(&self.context.nonce()),
+                aad,
+                &mut buffer,
+            )
+            .map_err(Error::from)?;
+        // Only a successful open moves to the next nonce
+        self.context.increment_seq()?;
+        Ok(buffer)
+    }
+
+    pub fn export(&self, exporter_context: &[u8], len: usize) -> Result<Vec<u8>> {
+        self.context.export(exporter_context, len)
+    }
+}
+
+/// Set up a sender context for `pk_r`. Returns the encapsulated key for the recipient.
+/// With `sk_s` the recipient can tell the message came from the owner of that key (Auth modes).
+pub fn setup_sender<R: CryptoRng + RngCore>(
+    csprng: &mut R,
+    pk_r: &PublicKey,
+    info: &[u8],
+    psk: Option<HpkePsk>,
+    sk_s: Option<&StaticSecret>,
+) -> Result<([u8; ENC_LEN], SenderContext)> {
+    let ephemeral = StaticSecret::random_from_rng(csprng);
+    setup_sender_with_ephemeral(&ephemeral, pk_r, info, psk, sk_s)
+}
+
+/// setup_sender with a given ephemeral key, only for test vectors
+pub fn setup_sender_with_ephemeral(
+    ephemeral: &StaticSecret,
+    pk_r: &PublicKey,
+    info: &[u8],
+    psk: Option<HpkePsk>,
+    sk_s: Option<&StaticSecret>,
+) -> Result<([u8; ENC_LEN], SenderContext)> {
+    let mode = mode(&psk, sk_s.is_some());
+    let (shared_secret, enc) = encap(ephemeral, pk_r, sk_s)?;
+    Ok((
+        enc,
+        SenderContext {
+            mode,
+            context: Context::new(mode, &shared_secret, info, psk)?,
+        },
+    ))
+}
+
+/// Set up a recipient context from the encapsulated key.
+/// `pk_s` has to be given exactly when the sender used a sender key.
+pub fn setup_recipient(
+    enc: &[u8],
+    sk_r: &StaticSecret,
+    info: &[u8],
+    psk: Option<HpkePsk>,
+    pk_s: Option<&PublicKey>,
+) -> Result<RecipientContext> {
+    let mode = mode(&psk, pk_s.is_some());
+    let shared_secret = decap(enc, sk_r, pk_s)?;
+    Ok(RecipientContext {
+        mode,
+        context: Context::new(mode, &shared_secret, info, psk)?,
+    })
+}
+
+/// Single-shot encryption, output is enc (32 bytes) || ciphertext
+pub fn seal<R: CryptoRng + RngCore>(
+    csprng: &mut R,
+    pk_r: &PublicKey,
+    info: &[u8],
+    aad: &[u8],
+    plaintext: &[u8],
+    psk: Option<HpkePsk>,
+    sk_s: Option<&StaticSecret>,
+) -> Result<Vec<u8>> {
+    let (enc, mut context) = setup_sender(csprng, pk_r, info, psk, sk_s)?;
+    let mut output = enc.to_vec();
+    output.extend_from_slice(&context.seal(aad, plaintext)?);
+    Ok(output)
+}
+
+/// Single-shot decryption of the output of seal
+pub fn open(
+    sk_r: &StaticSecret,
+    info: &[u8],
+    aad: &[u8],
+    data: &[u8],
+    psk: Option<HpkePsk>,
+    pk_s: Option<&PublicKey>,
+) -> Result<Vec<u8>> {
+    if data.len() < ENC_LEN {
+        return Err(Error::Malformed("Ciphertext is too short"));
+    }
+    let mut context = setup_recipient(&data[..ENC_LEN], sk_r, info, psk, pk_s)?;
+    context.open(aad, &data[ENC_LEN..])
+}
@@ -0,0 +1,121 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// Certified identities
+//
+// An identity is an Ed25519 key signed by the CA, it only ever signs. Noise, HPKE and sealed
+// sender use a dedicated X25519 static key instead: SHA3-256(label || signing key), which the
+// identity signs as label || static key. Peers holding the Certificate can encrypt to the
+// static key without a round trip.
+
+use alloc::vec::Vec;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha3::{Digest, Sha3_256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::error::{Error, Result};
+
+const STATIC_KEY_LABEL: &[u8] = b"CosmicCipher static key";
+
+/// A CA certified identity with its X25519 static key, see Client::certificate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Certificate {
+    pub verifying_key: VerifyingKey,
+    /// CA signature over verifying_key
+    pub signature: Signature,
+    /// X25519 key for Noise, HPKE and sealed sender
+    pub static_key: PublicKey,
+    /// Signature of verifying_key over static_key
+    pub static_key_signature: Signature,
+}
+
+impl Certificate {
+    /// Check the CA signature over the identity and the identity's signature over the static key
+    pub fn verify(&self, ca_verifying_key: &VerifyingKey) -> Result<()> {
+        if ca_verifying_key
+            .verify(self.verifying_key.as_bytes(), &self.signature)
+            .is_err()
+        {
+            return Err(Error::UntrustedKey("Identity not signed by CA"));
+        }
+        verify_static_key(
+            &self.verifying_key,
+            &self.static_key,
+            &self.static_key_signature,
+        )
+    }
+}
+
+/// The X25519 static secret of an identity
+pub(crate) fn static_secret(signing_key: &SigningKey) -> StaticSecret {
+    let mut hasher = Sha3_256::new();
+    hasher.update(STATIC_KEY_LABEL);
+    hasher.update(signing_key.to_bytes());
+    let mut secret = [0u8; 32];
+    secret.copy_from_slice(&hasher.finalize());
+    StaticSecret::from(secret)
+}
+
+pub(crate) fn sign_static_key(signing_key: &SigningKey, static_key: &PublicKey) -> Signature {
+    signing_key.sign(&static_key_transcript(static_key))
+}
+
+pub(crate) fn verify_static_key(
+    verifying_key: &VerifyingKey,
+    static_key: &PublicKey,
+    signature: &Signature,
+) -> Result<()> {
+    if verifying_key
+        .verify(&static_key_transcript(static_key), signature)
+        .is_err()
+    {
+        return Err(Error::Authentication("Static key not signed by identity"));
+    }
+    Ok(())
+}
+
+fn static_key_transcript(static_key: &PublicKey) -> Vec<u8> {
+    let mut transcript = STATIC_KEY_LABEL.to_vec();
+    transcript.extend_from_slice(static_key.as_bytes());
+    transcript
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+
+    #[test]
+    fn test_certificate() {
+        let mut alice = Client::new_user();
+        let bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let alice_ca = *alice.ca_verifying_key();
+        let certificate = bob.certificate();
+        certificate.verify(&alice_ca).unwrap();
+
+        // Not the Ed25519 key converted to X25519
+        assert_ne!(
+            certificate.static_key.to_bytes(),
+            certificate.verifying_key.to_montgomery().to_bytes()
+        );
+
+        let mut swapped = certificate;
+        swapped.static_key = alice.certificate().static_key;
+        assert_eq!(
+            swapped.verify(&alice_ca),
+            Err(Error::Authentication("Static key not signed by identity"))
+        );
+        let mallory = Client::new_user();
+        assert_eq!(
+            mallory.certificate().verify(&alice_ca),
+            Err(Error::UntrustedKey("Identity not signed by CA"))
+        );
+    }
+}
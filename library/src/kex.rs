@@ -0,0 +1,384 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// Sans-IO state machine for the key exchange of Client::init_kex, respond_kex and finish_kex
+//
+// A KexMachine holds one exchange with one peer. Packets from the peer go into handle_input,
+// packets for the peer come out of poll_transmit and the established session is reported by poll_event.
+// The machine does no IO itself and can be exported and imported between any two steps.
+//
+//   initiator                          responder
+//   AwaitingResponse  --- offer --->   AwaitingOffer
+//   Established       <-- response --  Established
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::client::{
+    open_with_password, seal_with_password, Client, KexMode, KexPacket, PendingKex, SessionId,
+};
+use crate::error::{Error, Result};
+use crate::mlkem;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KexRole {
+    Initiator,
+    Responder,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KexEvent {
+    /// The session is installed in the client and used by encrypt_message_for_recipient
+    Established {
+        session_id: SessionId,
+        mode: KexMode,
+    },
+}
+
+enum KexState {
+    AwaitingOffer {
+        min_mode: KexMode,
+    },
+    AwaitingResponse {
+        pending: Box<PendingKex>,
+    },
+    Established {
+        session_id: SessionId,
+        mode: KexMode,
+    },
+}
+
+pub struct KexMachine {
+    peer: String,
+    role: KexRole,
+    state: KexState,
+    transmit: VecDeque<Vec<u8>>,
+    events: VecDeque<KexEvent>,
+}
+
+impl KexMachine {
+    /// Start an exchange with `peer` offering `mode`, the offer is queued for poll_transmit
+    pub fn initiator(client: &mut Client, peer: &str, mode: KexMode) -> Result<Self> {
+        let (pending, offer) = client.new_kex_offer(mode)?;
+
+        let mut transmit = VecDeque::new();
+        transmit.push_back(offer);
+
+        Ok(Self {
+            peer: peer.to_string(),
+            role: KexRole::Initiator,
+            state: KexState::AwaitingResponse {
+                pending: Box::new(pending),
+            },
+            transmit,
+            events: VecDeque::new(),
+        })
+    }
+
+    /// Wait for an offer from `peer`, offers with a weaker mode than `min_mode` are rejected
+    pub fn responder(peer: &str, min_mode: KexMode) -> Self {
+        Self {
+            peer: peer.to_string(),
+            role: KexRole::Responder,
+            state: KexState::AwaitingOffer { min_mode },
+            transmit: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    pub fn role(&self) -> KexRole {
+        self.role
+    }
+
+    pub fn is_established(&self) -> bool {
+        matches!(self.state, KexState::Established { .. })
+    }
+
+    /// Process a packet from the peer.
+    /// Input the current state does not expect is rejected and leaves the machine unchanged,
+    /// so a forged or replayed packet cannot abort the exchange.
+    pub fn handle_input(&mut self, client: &mut Client, data: &[u8]) -> Result<()> {
+        let kex_packet: KexPacket = client.limits().parse_bson(data)?;
+
+        let (outcome, response) = match &self.state {
+            KexState::AwaitingOffer { min_mode } => {
+                if kex_packet.in_reply_to.is_some() {
+                    return Err(Error::Malformed("Expected a key exchange offer"));
+                }
+                let (outcome, response) = client.answer_kex_offer(&kex_packet, *min_mode)?;
+                (outcome, Some(response))
+            }
+            KexState::AwaitingResponse { pending } => {
+                let offer = PublicKey::from(&pending.ephemeral_key).to_bytes();
+                if kex_packet.in_reply_to != Some(offer) {
+                    return Err(Error::Malformed(
+                        "Expected a response to our key exchange offer",
+                    ));
+                }
+                (client.complete_kex_offer(pending, &kex_packet)?, None)
+            }
+            KexState::Established { .. } => {
+                return Err(Error::InvalidState("Key exchange already finished"));
+            }
+        };
+
+        let session_id = client.insert_session(
+            &self.peer,
+            &outcome.peer_key,
+            outcome.shared_key,
+            outcome.suite,
+        );
+        let mode = outcome.mode;
+        self.state = KexState::Established { session_id, mode };
+        self.transmit.extend(response);
+        self.events
+            .push_back(KexEvent::Established { session_id, mode });
+
+        Ok(())
+    }
+
+    /// Next packet to send to the peer
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        self.transmit.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<KexEvent> {
+        self.events.pop_front()
+    }
+
+    /// Export the machine protected like Client::export_sessions, it contains the ephemeral keys.
+    /// An established session lives in the client and is exported with export_sessions.
+    pub fn export(&self, client: &mut Client, password: &[u8]) -> Result<Vec<u8>> {
+        let mut export = KexMachineForExport {
+            owner: client.owner().to_vec(),
+            peer: self.peer.clone(),
+            initiator: self.role == KexRole::Initiator,
+            state: 0,
+            mode: 0,
+            ephemeral_key: None,
+            kem_key: None,
+            session_id: None,
+            transmit: self.transmit.iter().cloned().collect(),
+            events: self
+                .events
+                .iter()
+                .map(|v| match v {
+                    KexEvent::Established { session_id, mode } => EventForExport {
+                        session_id: session_id.to_vec(),
+                        mode: mode.id(),
+                    },
+                })
+                .collect(),
+        };
+        match &self.state {
+            KexState::AwaitingOffer { min_mode } => {
+                export.state = 0;
+                export.mode = min_mode.id();
+            }
+            KexState::AwaitingResponse { pending } => {
+                export.state = 1;
+                export.mode = pending.mode.id();
+                export.ephemeral_key = Some(pending.ephemeral_key.to_bytes().to_vec());
+                export.kem_key = pending.kem_key.as_ref().map(|dk| dk.as_bytes().to_vec());
+            }
+            KexState::Established { session_id, mode } => {
+                export.state = 2;
+                export.mode = mode.id();
+                export.session_id = Some(session_id.to_vec());
+            }
+        }
+
+        let serialized = bson::to_vec(&export).map_err(Error::from)?;
+
+        let suite = client.preferred_suite();
+        seal_with_password(client.csprng(), suite, password, serialized)
+    }
+
+    /// Import a machine from export, only into the identity that exported it
+    pub fn import(client: &Client, password: &[u8], data: &[u8]) -> Result<Self> {
+        let serialized = open_with_password(password, data)?;
+        let export: KexMachineForExport = client.limits().parse_bson(&serialized)?;
+
+        if export.owner != client.owner() {
+            return Err(Error::InvalidArgument(
+                "Key exchange belongs to a different identity",
+            ));
+        }
+
+        let mode = KexMode::from_id(export.mode)?;
+        let state = match export.state {
+            0 => KexState::AwaitingOffer { min_mode: mode },
+            1 => {
+                let ephemeral_key: [u8; 32] = export
+                    .ephemeral_key
+                    .as_deref()
+                    .and_then(|v| v.try_into().ok())
+                    .ok_or(Error::Malformed("Invalid ephemeral key length"))?;
+                let kem_key = match export.kem_key {
+                    None => None,
+                    Some(dk) => Some(mlkem::DecapsulationKey::from_bytes(&dk)?),
+                };
+                KexState::AwaitingResponse {
+                    pending: Box::new(PendingKex {
+                        ephemeral_key: StaticSecret::from(ephemeral_key),
+                        mode,
+                        kem_key,
+                        psk_id: None,
+                    }),
+                }
+            }
+            2 => KexState::Established {
+                session_id: session_id_from_slice(export.session_id.as_deref())?,
+                mode,
+            },
+            _ => return Err(Error::Malformed("Unknown key exchange state")),
+        };
+
+        let mut events = VecDeque::new();
+        for v in export.events {
+            events.push_back(KexEvent::Established {
+                session_id: session_id_from_slice(Some(&v.session_id))?,
+                mode: KexMode::from_id(v.mode)?,
+            });
+        }
+
+        Ok(Self {
+            peer: export.peer,
+            role: if export.initiator {
+                KexRole::Initiator
+            } else {
+                KexRole::Responder
+            },
+            state,
+            transmit: export.transmit.into_iter().collect(),
+            events,
+        })
+    }
+}
+
+fn session_id_from_slice(data: Option<&[u8]>) -> Result<SessionId> {
+    data.and_then(|v| v.try_into().ok())
+        .ok_or(Error::Malformed("Invalid session id length"))
+}
+
+#[derive(Serialize, Deserialize)]
+struct KexMachineForExport {
+    owner: Vec<u8>,
+    peer: String,
+    initiator: bool,
+    // 0 awaiting offer, 1 awaiting response, 2 established
+    state: u8,
+    // Minimum mode while awaiting an offer, the negotiated mode otherwise
+    mode: u8,
+    ephemeral_key: Option<Vec<u8>>,
+    kem_key: Option<Vec<u8>>,
+    session_id: Option<Vec<u8>>,
+    transmit: Vec<Vec<u8>>,
+    events: Vec<EventForExport>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EventForExport {
+    session_id: Vec<u8>,
+    mode: u8,
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kex_machine() {
+        let mut client1 = Client::new_user();
+        let instance_data = client1.generate_instance().unwrap();
+        let mut client2 = Client::import_instance(instance_data.as_slice()).unwrap();
+
+        let mut initiator =
+            KexMachine::initiator(&mut client1, "client2", KexMode::HybridMlKem768).unwrap();
+        let mut responder = KexMachine::responder("client1", KexMode::HybridMlKem768);
+        assert_eq!(initiator.role(), KexRole::Initiator);
+        assert!(responder.poll_transmit().is_none());
+
+        let offer = initiator.poll_transmit().unwrap();
+        assert!(initiator.poll_transmit().is_none());
+
+        // An offer is not a valid response
+        assert!(initiator.handle_input(&mut client1, &offer).is_err());
+
+        responder.handle_input(&mut client2, &offer).unwrap();
+        assert!(responder.is_established());
+        let response = responder.poll_transmit().unwrap();
+
+        // A response is not a valid offer, replays are rejected once established
+        let mut other = KexMachine::responder("client1", KexMode::X25519);
+        assert!(other.handle_input(&mut client2, &response).is_err());
+        assert!(other.poll_event().is_none());
+        assert!(responder.handle_input(&mut client2, &offer).is_err());
+
+        initiator.handle_input(&mut client1, &response).unwrap();
+        let event1 = initiator.poll_event().unwrap();
+        let event2 = responder.poll_event().unwrap();
+        assert_eq!(event1, event2);
+        assert_eq!(
+            event1,
+            KexEvent::Established {
+                session_id: client1.sessions("client2")[0],
+                mode: KexMode::HybridMlKem768,
+            }
+        );
+
+        let encrypted = client1
+            .encrypt_message_for_recipient("client2", b"Hello World!")
+            .unwrap();
+        let decrypted = client2
+            .decrypt_message_from_sender("client1", &encrypted)
+            .unwrap();
+        assert_eq!(decrypted, b"Hello World!");
+    }
+
+    #[test]
+    fn test_kex_machine_export() {
+        let mut client1 = Client::new_user();
+        let instance_data = client1.generate_instance().unwrap();
+        let mut client2 = Client::import_instance(instance_data.as_slice()).unwrap();
+        let password = b"password";
+
+        let initiator =
+            KexMachine::initiator(&mut client1, "client2", KexMode::HybridMlKem768).unwrap();
+        let export = initiator.export(&mut client1, password).unwrap();
+        assert!(KexMachine::import(&client2, password, &export).is_err());
+        let mut initiator = KexMachine::import(&client1, password, &export).unwrap();
+        let offer = initiator.poll_transmit().unwrap();
+
+        // A responder with a lower minimum still gets the offered mode
+        let responder = KexMachine::responder("client1", KexMode::X25519);
+        let export = responder.export(&mut client2, password).unwrap();
+        let mut responder = KexMachine::import(&client2, password, &export).unwrap();
+        responder.handle_input(&mut client2, &offer).unwrap();
+        let response = responder.poll_transmit().unwrap();
+
+        initiator.handle_input(&mut client1, &response).unwrap();
+        let export = initiator.export(&mut client1, password).unwrap();
+        let mut initiator = KexMachine::import(&client1, password, &export).unwrap();
+        assert!(initiator.is_established());
+        assert_eq!(initiator.peer(), "client2");
+        assert_eq!(initiator.poll_event(), responder.poll_event());
+        assert_eq!(client1.sessions("client2"), client2.sessions("client1"));
+    }
+}
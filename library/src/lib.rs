@@ -17,5 +17,28 @@
 #![allow(dead_code)]
 
 extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
 
+pub mod age;
 pub mod client;
+pub mod compression;
+pub mod envelope;
+pub mod error;
+pub mod group;
+pub mod hpke;
+pub mod identity;
+pub mod kex;
+pub mod limits;
+pub mod message;
+pub mod minisign;
+pub mod mlkem;
+pub mod mls;
+pub mod noise;
+pub mod padding;
+pub mod sealed_sender;
+pub mod session;
+pub mod signing;
+pub mod store;
+pub mod stream;
+pub mod suite;
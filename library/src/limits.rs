@@ -0,0 +1,253 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// Size limits for data from other parties
+//
+// Compressed payloads carry their size or expand without bound, and BSON documents declare
+// their own length, so every size is checked before anything is allocated for it.
+
+use serde::de::DeserializeOwned;
+
+use crate::error::{Error, Result};
+
+pub const PLAINTEXT_TOO_LARGE: &str = "Plaintext exceeds the size limit";
+pub const DECOMPRESSED_TOO_LARGE: &str = "Decompressed data exceeds the size limit";
+pub const DOCUMENT_TOO_LARGE: &str = "Document exceeds the size limit";
+
+/// Limits for a Client, see Client::set_limits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// Largest message to encrypt or decrypt, before compression and padding
+    pub max_plaintext_size: usize,
+    /// Largest output of decompressing a payload or an instance
+    pub max_decompressed_size: usize,
+    /// Largest BSON document to parse: exports, key exchange packets, envelopes, signatures
+    pub max_document_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_plaintext_size: 64 << 20,
+            max_decompressed_size: 64 << 20,
+            // The largest document BSON allows
+            max_document_size: 16 << 20,
+        }
+    }
+}
+
+impl Limits {
+    pub(crate) fn check_plaintext(&self, len: usize) -> Result<()> {
+        if len > self.max_plaintext_size {
+            return Err(Error::TooLarge(PLAINTEXT_TOO_LARGE));
+        }
+        Ok(())
+    }
+
+    /// Checks the input and the length the document declares for itself
+    pub(crate) fn check_document(&self, data: &[u8]) -> Result<()> {
+        if data.len() > self.max_document_size {
+            return Err(Error::TooLarge(DOCUMENT_TOO_LARGE));
+        }
+        if let Some(declared) = data.get(..4) {
+            let declared = i32::from_le_bytes([declared[0], declared[1], declared[2], declared[3]]);
+            if declared < 0 || declared as usize > self.max_document_size {
+                return Err(Error::TooLarge(DOCUMENT_TOO_LARGE));
+            }
+        }
+        Ok(())
+    }
+
+    /// bson::from_slice after check_document
+    pub(crate) fn parse_bson<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        self.check_document(data)?;
+        bson::from_slice(data).map_err(Error::from)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, KexMode};
+    use crate::compression::{compress, decompress, Compression};
+    use crate::envelope::EnvelopeRecipient;
+    use crate::mls::{MlsEvent, MlsGroup, MlsKeyPackageBundle};
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    #[test]
+    fn test_decompression_limits() {
+        let bomb = vec![0u8; 1 << 20];
+        for compression in [Compression::Lz4, Compression::Zstd, Compression::Deflate] {
+            let compressed = compress(compression, &bomb);
+            assert_eq!(
+                decompress(compression, &compressed, 1 << 16)
+                    .unwrap_err()
+                    .to_string(),
+                DECOMPRESSED_TOO_LARGE
+            );
+        }
+
+        // A forged size prefix is refused before allocating
+        let mut forged = u32::MAX.to_le_bytes().to_vec();
+        forged.extend_from_slice(&[0u8; 8]);
+        assert_eq!(
+            decompress(Compression::Lz4, &forged, 1 << 20)
+                .unwrap_err()
+                .to_string(),
+            DECOMPRESSED_TOO_LARGE
+        );
+        assert_eq!(
+            Client::import_instance(&forged).err().unwrap().to_string(),
+            DECOMPRESSED_TOO_LARGE
+        );
+
+        let limits = Limits::default();
+        let mut document = bson::to_vec(&bson::doc! { "a": 1 }).unwrap();
+        assert!(limits.check_document(&document).is_ok());
+        document[..4].copy_from_slice(&i32::MAX.to_le_bytes());
+        assert_eq!(
+            limits.check_document(&document).unwrap_err().to_string(),
+            DOCUMENT_TOO_LARGE
+        );
+    }
+
+    #[test]
+    fn test_message_limits() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let offer = alice.init_kex("bob", KexMode::X25519).unwrap();
+        let response = bob.respond_kex("alice", &offer, KexMode::X25519).unwrap();
+        alice.finish_kex("bob", &response).unwrap();
+
+        let limits = Limits {
+            max_plaintext_size: 1024,
+            max_decompressed_size: 4096,
+            max_document_size: 256,
+        };
+        bob.set_limits(limits);
+        let small = alice
+            .encrypt_message_for_recipient("bob", &[1u8; 1024])
+            .unwrap();
+        assert_eq!(
+            bob.decrypt_message_from_sender("alice", &small).unwrap(),
+            [1u8; 1024]
+        );
+
+        // Compresses to less than the limit but expands beyond it
+        let large = alice
+            .encrypt_message_for_recipient("bob", &[1u8; 2048])
+            .unwrap();
+        assert_eq!(
+            bob.decrypt_message_from_sender("alice", &large)
+                .unwrap_err()
+                .to_string(),
+            PLAINTEXT_TOO_LARGE
+        );
+        let bomb = alice
+            .encrypt_message_for_recipient("bob", &[1u8; 8192])
+            .unwrap();
+        assert_eq!(
+            bob.decrypt_message_from_sender("alice", &bomb)
+                .unwrap_err()
+                .to_string(),
+            DECOMPRESSED_TOO_LARGE
+        );
+
+        alice.set_limits(limits);
+        assert_eq!(
+            alice
+                .encrypt_message_for_recipient("bob", &[1u8; 2048])
+                .unwrap_err()
+                .to_string(),
+            PLAINTEXT_TOO_LARGE
+        );
+        let export = alice.export_sessions(b"password").unwrap();
+        assert_eq!(
+            alice
+                .import_sessions(b"password", &export)
+                .unwrap_err()
+                .to_string(),
+            DOCUMENT_TOO_LARGE
+        );
+
+        // Envelopes, sender key groups and MLS groups are limited the same way
+        let limits = Limits {
+            max_plaintext_size: 1024,
+            ..Default::default()
+        };
+        alice.set_limits(Limits::default());
+        bob.set_limits(limits);
+        let (group_id, distribution) = alice.create_group(&["bob"]).unwrap();
+        for (_, data) in distribution {
+            bob.process_group_key("alice", &data).unwrap();
+        }
+        let mut mls_a = MlsGroup::create(&mut alice).unwrap();
+        let bob_kp = MlsKeyPackageBundle::new(&mut bob).unwrap();
+        mls_a
+            .propose_add(&alice, &bob_kp.key_package().unwrap())
+            .unwrap();
+        let output = mls_a.commit(&mut alice).unwrap();
+        let mut mls_b = MlsGroup::join(&bob, &bob_kp, &output.welcomes[0]).unwrap();
+
+        let envelope = alice
+            .encrypt_envelope(&[EnvelopeRecipient::Peer("bob")], &[1u8; 2048])
+            .unwrap();
+        assert_eq!(
+            bob.decrypt_envelope(&envelope).unwrap_err().to_string(),
+            PLAINTEXT_TOO_LARGE
+        );
+        let group_message = alice
+            .encrypt_group_message(&group_id, &[1u8; 2048])
+            .unwrap();
+        assert_eq!(
+            bob.decrypt_group_message("alice", &group_message)
+                .unwrap_err()
+                .to_string(),
+            PLAINTEXT_TOO_LARGE
+        );
+        let mls_message = mls_a.encrypt(&mut alice, &[1u8; 2048]).unwrap();
+        assert_eq!(
+            mls_b
+                .process_message(&bob, &mls_message)
+                .unwrap_err()
+                .to_string(),
+            PLAINTEXT_TOO_LARGE
+        );
+        let mls_message = mls_a.encrypt(&mut alice, &[1u8; 1024]).unwrap();
+        assert!(matches!(
+            mls_b.process_message(&bob, &mls_message).unwrap(),
+            MlsEvent::Application { message, .. } if message == [1u8; 1024]
+        ));
+
+        alice.set_limits(limits);
+        assert_eq!(
+            alice
+                .encrypt_envelope(&[EnvelopeRecipient::Peer("bob")], &[1u8; 2048])
+                .unwrap_err()
+                .to_string(),
+            PLAINTEXT_TOO_LARGE
+        );
+        assert_eq!(
+            alice
+                .encrypt_group_message(&group_id, &[1u8; 2048])
+                .unwrap_err()
+                .to_string(),
+            PLAINTEXT_TOO_LARGE
+        );
+        assert_eq!(
+            mls_a
+                .encrypt(&mut alice, &[1u8; 2048])
+                .unwrap_err()
+                .to_string(),
+            PLAINTEXT_TOO_LARGE
+        );
+    }
+}
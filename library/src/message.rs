@@ -0,0 +1,528 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// Header of session messages
+//
+// magic (4) || version (1) || suite (1) || flags (2) || session id (16) || epoch (4)
+// || sender key id (8) || sequence number (8), integers big endian, followed by nonce and ciphertext.
+// The suite is the one negotiated for the session and sets the nonce length, see suite.
+// Header and nonce are the associated data, so none of it can be changed in transit.
+//
+// Messages with caller associated data (FLAG_AAD) append it to the associated data, with
+// FLAG_AAD_HASH a 16 byte hash of it follows the header, so receivers can match a message to
+// its context before decrypting.
+// With FLAG_EXPIRES the unix time in seconds after which the message must not be read follows
+// as a big endian u64, after the hash if there is one.
+//
+// The sender key id must be the session peer's certified identity, each direction is encrypted
+// with its own key derived from it, see session.
+//
+// Data without the magic is rejected, there is no headerless format. New versions and suites get
+// their own numbers, the header is parsed before anything else so decryption can dispatch on them.
+
+use alloc::vec::Vec;
+use ed25519_dalek::VerifyingKey;
+use rand_chacha::rand_core::RngCore;
+use sha3::{Digest, Sha3_256};
+
+use crate::compression::{self, Compression};
+use crate::error::{Error, Result};
+use crate::padding::{self, Padding};
+use crate::session::{Delivery, SessionId};
+use crate::suite::CipherSuite;
+
+pub const MAGIC: [u8; 4] = *b"CCMS";
+pub const VERSION_1: u8 = 1;
+pub const HEADER_LEN: usize = 44;
+
+/// The payload was compressed with LZ4 before encryption, see compression
+pub const FLAG_COMPRESSED: u16 = 1;
+/// The header starts a chunked stream, see stream
+pub const FLAG_STREAM: u16 = 2;
+/// The payload is a zstd frame
+pub const FLAG_ZSTD: u16 = 4;
+/// The payload is raw deflate
+pub const FLAG_DEFLATE: u16 = 8;
+/// The payload is padded, see padding
+pub const FLAG_PADDED: u16 = 16;
+/// The message was encrypted with caller associated data
+pub const FLAG_AAD: u16 = 32;
+/// A hash of the caller associated data follows the header
+pub const FLAG_AAD_HASH: u16 = 64;
+/// An expiry time follows the header
+pub const FLAG_EXPIRES: u16 = 128;
+const KNOWN_FLAGS: u16 = FLAG_COMPRESSED
+    | FLAG_STREAM
+    | FLAG_ZSTD
+    | FLAG_DEFLATE
+    | FLAG_PADDED
+    | FLAG_AAD
+    | FLAG_AAD_HASH
+    | FLAG_EXPIRES;
+pub const AAD_HASH_LEN: usize = 16;
+const EXPIRES_LEN: usize = 8;
+
+/// Parsed header of a session message, see parse_header
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessageHeader {
+    pub version: u8,
+    pub suite: CipherSuite,
+    pub flags: u16,
+    pub session_id: SessionId,
+    pub epoch: u32,
+    /// key_id of the sender's identity key
+    pub sender_key_id: [u8; 8],
+    pub seq: u64,
+    /// Set with FLAG_AAD_HASH, see aad_hash
+    pub aad_hash: Option<[u8; AAD_HASH_LEN]>,
+    /// Set with FLAG_EXPIRES, unix time in seconds
+    pub expires_at: Option<u64>,
+}
+
+impl MessageHeader {
+    /// Encoded length, HEADER_LEN plus the associated data hash and expiry if there are any
+    pub fn encoded_len(&self) -> usize {
+        let mut len = HEADER_LEN;
+        if self.aad_hash.is_some() {
+            len += AAD_HASH_LEN;
+        }
+        if self.expires_at.is_some() {
+            len += EXPIRES_LEN;
+        }
+        len
+    }
+
+    /// Whether the message must no longer be read at unix time `now`
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut header = alloc::vec![0u8; HEADER_LEN];
+        header[0..4].copy_from_slice(&MAGIC);
+        header[4] = self.version;
+        header[5] = self.suite as u8;
+        header[6..8].copy_from_slice(&self.flags.to_be_bytes());
+        header[8..24].copy_from_slice(&self.session_id);
+        header[24..28].copy_from_slice(&self.epoch.to_be_bytes());
+        header[28..36].copy_from_slice(&self.sender_key_id);
+        header[36..44].copy_from_slice(&self.seq.to_be_bytes());
+        if let Some(hash) = &self.aad_hash {
+            header.extend_from_slice(hash);
+        }
+        if let Some(expires_at) = self.expires_at {
+            header.extend_from_slice(&expires_at.to_be_bytes());
+        }
+        header
+    }
+}
+
+/// Settings for a single message, unset fields use the session's settings.
+/// Group messages and envelopes only take compression, padding and sensitive.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MessageOptions<'a> {
+    pub compression: Option<Compression>,
+    pub padding: Option<Padding>,
+    /// Never compress, for secrets that may be sent together with attacker-controlled data
+    pub sensitive: bool,
+    /// Context the message is bound to, like a record id or channel name.
+    /// Not sent, the receiver has to pass the same bytes to decrypt.
+    pub aad: &'a [u8],
+    /// Put a hash of `aad` in the header
+    pub aad_hash: bool,
+    /// Receivers refuse the message this many seconds after it was sent, needs Client::set_time
+    pub ttl_secs: Option<u64>,
+}
+
+impl MessageOptions<'_> {
+    // For messages without a session header, which cannot carry associated data or an expiry
+    pub(crate) fn check_payload_only(&self) -> Result<()> {
+        if !self.aad.is_empty() || self.aad_hash || self.ttl_secs.is_some() {
+            return Err(Error::Unsupported(
+                "Only compression, padding and sensitive apply here",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A decrypted message with its place in the session
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReceivedMessage {
+    pub message: Vec<u8>,
+    pub seq: u64,
+    pub delivery: Delivery,
+    /// Unix time after which the message should be deleted, see store::MessageStore
+    pub expires_at: Option<u64>,
+}
+
+/// Short identifier of an identity key, carried in message headers
+pub fn key_id(verifying_key: &VerifyingKey) -> [u8; 8] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"CosmicCipher key id");
+    hasher.update(verifying_key.as_bytes());
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&hasher.finalize()[..8]);
+    id
+}
+
+/// Hash of caller associated data as carried in the header, see MessageOptions::aad_hash
+pub fn aad_hash(aad: &[u8]) -> [u8; AAD_HASH_LEN] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"CosmicCipher associated data");
+    hasher.update(aad);
+    let mut hash = [0u8; AAD_HASH_LEN];
+    hash.copy_from_slice(&hasher.finalize()[..AAD_HASH_LEN]);
+    hash
+}
+
+/// Compress and pad `message` for encryption, returns the payload flags.
+/// Sensitive messages are never compressed, padding is applied after compression.
+pub(crate) fn encode_payload<R: RngCore>(
+    compression: Compression,
+    padding: Padding,
+    sensitive: bool,
+    rng: &mut R,
+    message: &[u8],
+) -> Result<(u16, Vec<u8>)> {
+    let compression = if sensitive {
+        Compression::None
+    } else {
+        compression
+    };
+    let (compression, mut buffer) = compression::compress_adaptive(compression, message);
+    let mut flags = compression.flag();
+    if padding != Padding::None {
+        padding::pad(padding, rng, &mut buffer)?;
+        flags |= FLAG_PADDED;
+    }
+    Ok((flags, buffer))
+}
+
+/// Undo encode_payload for a decrypted payload with `flags`
+pub(crate) fn decode_payload(
+    flags: u16,
+    mut buffer: Vec<u8>,
+    max_decompressed_size: usize,
+) -> Result<Vec<u8>> {
+    if flags & FLAG_PADDED != 0 {
+        padding::unpad(&mut buffer)?;
+    }
+    compression::decompress(
+        Compression::from_flags(flags)?,
+        &buffer,
+        max_decompressed_size,
+    )
+}
+
+/// Whether `data` starts with the magic of a message header
+pub fn has_header(data: &[u8]) -> bool {
+    data.len() >= MAGIC.len() && data[..MAGIC.len()] == MAGIC
+}
+
+/// Parse the header of a message, without decrypting it
+pub fn parse_header(data: &[u8]) -> Result<MessageHeader> {
+    if !has_header(data) {
+        return Err(Error::Malformed("Message has no header"));
+    }
+    if data.len() < HEADER_LEN {
+        return Err(Error::Malformed("Ciphertext is too short"));
+    }
+    if data[4] != VERSION_1 {
+        return Err(Error::Unsupported("Unsupported message version"));
+    }
+    let suite = CipherSuite::from_id(data[5])?;
+    let flags = u16::from_be_bytes([data[6], data[7]]);
+    if flags & !KNOWN_FLAGS != 0 {
+        return Err(Error::Unsupported("Unknown message flags"));
+    }
+    let aad_hash = if flags & FLAG_AAD_HASH == 0 {
+        None
+    } else if flags & FLAG_AAD == 0 {
+        return Err(Error::Malformed(
+            "Associated data hash without associated data",
+        ));
+    } else if data.len() < HEADER_LEN + AAD_HASH_LEN {
+        return Err(Error::Malformed("Ciphertext is too short"));
+    } else {
+        let mut hash = [0u8; AAD_HASH_LEN];
+        hash.copy_from_slice(&data[HEADER_LEN..HEADER_LEN + AAD_HASH_LEN]);
+        Some(hash)
+    };
+    let offset = HEADER_LEN + aad_hash.map_or(0, |_| AAD_HASH_LEN);
+    let expires_at = if flags & FLAG_EXPIRES == 0 {
+        None
+    } else if data.len() < offset + EXPIRES_LEN {
+        return Err(Error::Malformed("Ciphertext is too short"));
+    } else {
+        let mut expires_at = [0u8; EXPIRES_LEN];
+        expires_at.copy_from_slice(&data[offset..offset + EXPIRES_LEN]);
+        Some(u64::from_be_bytes(expires_at))
+    };
+
+    let mut session_id = [0u8; 16];
+    session_id.copy_from_slice(&data[8..24]);
+    let mut epoch = [0u8; 4];
+    epoch.copy_from_slice(&data[24..28]);
+    let mut sender_key_id = [0u8; 8];
+    sender_key_id.copy_from_slice(&data[28..36]);
+    let mut seq = [0u8; 8];
+    seq.copy_from_slice(&data[36..44]);
+
+    let header = MessageHeader {
+        version: data[4],
+        suite,
+        flags,
+        session_id,
+        epoch: u32::from_be_bytes(epoch),
+        sender_key_id,
+        seq: u64::from_be_bytes(seq),
+        aad_hash,
+        expires_at,
+    };
+    if data.len() < header.encoded_len() + suite.nonce_len() {
+        return Err(Error::Malformed("Ciphertext is too short"));
+    }
+    Ok(header)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, KexMode};
+    use alloc::string::ToString;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_header() {
+        let header = MessageHeader {
+            version: VERSION_1,
+            suite: CipherSuite::XChaCha20Poly1305,
+            flags: FLAG_COMPRESSED,
+            session_id: [7u8; 16],
+            epoch: 3,
+            sender_key_id: [9u8; 8],
+            seq: 1 << 40,
+            aad_hash: None,
+            expires_at: None,
+        };
+        let mut data = header.encode();
+        data.extend_from_slice(&[0u8; 24]);
+        assert_eq!(parse_header(&data).unwrap(), header);
+
+        let mut unknown = data.clone();
+        unknown[4] = 2;
+        assert!(parse_header(&unknown).is_err());
+        let mut unknown = data.clone();
+        unknown[5] = 0;
+        assert!(parse_header(&unknown).is_err());
+        let mut unknown = data.clone();
+        unknown[6] = 0x80;
+        assert!(parse_header(&unknown).is_err());
+        assert!(parse_header(&data[..HEADER_LEN]).is_err());
+    }
+
+    #[test]
+    fn test_header_dispatch() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let offer = alice.init_kex("bob", KexMode::X25519).unwrap();
+        let response = bob.respond_kex("alice", &offer, KexMode::X25519).unwrap();
+        alice.finish_kex("bob", &response).unwrap();
+
+        let first = alice
+            .encrypt_message_for_recipient("bob", b"first")
+            .unwrap();
+        let second = alice
+            .encrypt_message_for_recipient("bob", b"second")
+            .unwrap();
+        let header = parse_header(&second).unwrap();
+        assert_eq!(header.session_id, alice.sessions("bob")[0]);
+        assert_eq!(
+            header.sender_key_id,
+            key_id(&alice.certificate().verifying_key)
+        );
+        assert_eq!(parse_header(&first).unwrap().seq + 1, header.seq);
+
+        // The sender is found from the session id
+        let (sender, message) = bob.decrypt_message(&second).unwrap();
+        assert_eq!(sender, "alice");
+        assert_eq!(message, b"second");
+        assert_eq!(
+            bob.decrypt_message_from_sender("alice", &first).unwrap(),
+            b"first"
+        );
+
+        // The header is authenticated
+        let mut tampered = first.clone();
+        tampered[43] ^= 1;
+        assert!(bob.decrypt_message_from_sender("alice", &tampered).is_err());
+        let mut tampered = first.clone();
+        tampered[4] = 2;
+        assert!(bob.decrypt_message(&tampered).is_err());
+    }
+
+    #[test]
+    fn test_reflected_message() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let offer = alice.init_kex("bob", KexMode::X25519).unwrap();
+        let response = bob.respond_kex("alice", &offer, KexMode::X25519).unwrap();
+        alice.finish_kex("bob", &response).unwrap();
+
+        // Our own message handed back to us as if bob had sent it
+        let data = alice
+            .encrypt_message_for_recipient("bob", b"pay 5")
+            .unwrap();
+        assert_eq!(
+            alice.decrypt_message_from_sender("bob", &data),
+            Err(Error::Authentication("Message was sent with our own key"))
+        );
+
+        // Only the peer's certified key, also after an export
+        let export = bob.export_sessions(b"password").unwrap();
+        bob.close_session("alice", &bob.sessions("alice")[0])
+            .unwrap();
+        bob.import_sessions(b"password", &export).unwrap();
+        let mut forged = data.clone();
+        forged[28..36].copy_from_slice(&[9u8; 8]);
+        assert_eq!(
+            bob.decrypt_message_from_sender("alice", &forged),
+            Err(Error::UntrustedKey(
+                "Message is not from the session's peer"
+            ))
+        );
+        assert_eq!(
+            bob.decrypt_message_from_sender("alice", &data).unwrap(),
+            b"pay 5"
+        );
+
+        // Each direction has its own key
+        let reply = bob.encrypt_message_for_recipient("alice", b"paid").unwrap();
+        assert_eq!(
+            alice.decrypt_message_from_sender("bob", &reply).unwrap(),
+            b"paid"
+        );
+    }
+
+    #[test]
+    fn test_replay_and_order() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let offer = alice.init_kex("bob", KexMode::X25519).unwrap();
+        let response = bob.respond_kex("alice", &offer, KexMode::X25519).unwrap();
+        alice.finish_kex("bob", &response).unwrap();
+
+        let messages: Vec<Vec<u8>> = (0..4u8)
+            .map(|i| alice.encrypt_message_for_recipient("bob", &[i]).unwrap())
+            .collect();
+        let received = bob
+            .receive_message_from_sender("alice", &messages[0])
+            .unwrap();
+        assert_eq!(received.seq, 0);
+        assert_eq!(received.delivery, Delivery::InOrder);
+        let received = bob
+            .receive_message_from_sender("alice", &messages[2])
+            .unwrap();
+        assert_eq!(received.delivery, Delivery::AfterGap { missing: 1 });
+        assert!(bob
+            .decrypt_message_from_sender("alice", &messages[2])
+            .is_err());
+
+        // The window survives an export
+        let export = bob.export_sessions(b"password").unwrap();
+        bob.close_session("alice", &bob.sessions("alice")[0])
+            .unwrap();
+        bob.import_sessions(b"password", &export).unwrap();
+        assert!(bob
+            .decrypt_message_from_sender("alice", &messages[0])
+            .is_err());
+        let received = bob
+            .receive_message_from_sender("alice", &messages[1])
+            .unwrap();
+        assert_eq!(received.delivery, Delivery::Late);
+
+        // Control channels take the next message only
+        let session_id = bob.sessions("alice")[0];
+        bob.set_strict_order("alice", &session_id, true).unwrap();
+        let next = alice.encrypt_message_for_recipient("bob", b"next").unwrap();
+        assert!(bob.decrypt_message_from_sender("alice", &next).is_err());
+        assert_eq!(
+            bob.decrypt_message_from_sender("alice", &messages[3])
+                .unwrap(),
+            [3]
+        );
+        assert_eq!(
+            bob.decrypt_message_from_sender("alice", &next).unwrap(),
+            b"next"
+        );
+    }
+
+    #[test]
+    fn test_caller_aad() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let offer = alice.init_kex("bob", KexMode::X25519).unwrap();
+        let response = bob.respond_kex("alice", &offer, KexMode::X25519).unwrap();
+        alice.finish_kex("bob", &response).unwrap();
+
+        let row_1 = alice
+            .encrypt_message_with_aad("bob", b"balance=100", b"accounts/1")
+            .unwrap();
+        let row_2 = alice
+            .encrypt_message_with_aad("bob", b"balance=5", b"accounts/2")
+            .unwrap();
+        assert_ne!(parse_header(&row_1).unwrap().flags & FLAG_AAD, 0);
+
+        // Swapped between records, or without the context
+        assert!(bob
+            .decrypt_message_with_aad("alice", &row_2, b"accounts/1")
+            .is_err());
+        assert!(bob.decrypt_message_from_sender("alice", &row_1).is_err());
+        assert_eq!(
+            bob.decrypt_message_with_aad("alice", &row_1, b"accounts/1")
+                .unwrap(),
+            b"balance=100"
+        );
+
+        // With the hash the mismatch is found before decrypting
+        let session_id = alice.sessions("bob")[0];
+        let options = MessageOptions {
+            aad: b"channel:general",
+            aad_hash: true,
+            ..Default::default()
+        };
+        let data = alice
+            .encrypt_message_with_options("bob", &session_id, b"hi", &options)
+            .unwrap();
+        let header = parse_header(&data).unwrap();
+        assert_eq!(header.aad_hash, Some(aad_hash(b"channel:general")));
+        assert_eq!(header.encoded_len(), HEADER_LEN + AAD_HASH_LEN);
+        assert_eq!(
+            bob.decrypt_message_with_aad("alice", &data, b"channel:random")
+                .unwrap_err()
+                .to_string(),
+            "Associated data does not match"
+        );
+        assert_eq!(
+            bob.decrypt_message_with_aad("alice", &data, b"channel:general")
+                .unwrap(),
+            b"hi"
+        );
+
+        // Messages without associated data do not take any
+        let plain = alice
+            .encrypt_message_for_recipient("bob", b"plain")
+            .unwrap();
+        assert!(bob
+            .decrypt_message_with_aad("alice", &plain, b"accounts/1")
+            .is_err());
+    }
+}
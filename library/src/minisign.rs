@@ -0,0 +1,243 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// Minisign signatures, https://jedisct1.github.io/minisign/
+//
+// Public key: "Ed" || key id (8 bytes) || Ed25519 public key
+// Signature:  "ED" || key id || Ed25519(BLAKE2b-512(data)) for prehashed signatures,
+//             "Ed" || key id || Ed25519(data) for legacy ones
+// Global signature: Ed25519(signature || trusted comment), so the trusted comment is signed too
+//
+// Files are base64 lines below an untrusted comment, the signature file adds the trusted comment
+// and the global signature. Minisign picks the key id at random, ours is derived from the key
+// so every instance exports the same public key for the CA.
+//
+// Legacy signatures cover the raw data, so the CA key itself must never make them: a signature
+// over 32 chosen bytes would be an identity certificate. Signatures are made with a key derived
+// from the CA key instead, which the CA certifies for instances with a domain separated signature.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use base64::prelude::*;
+use blake2::{Blake2b512, Digest};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha3::Sha3_256;
+
+use crate::error::{Error, Result};
+
+const ALG_PUBLIC_KEY: &[u8; 2] = b"Ed";
+const ALG_LEGACY: &[u8; 2] = b"Ed";
+const ALG_PREHASHED: &[u8; 2] = b"ED";
+const UNTRUSTED_PREFIX: &str = "untrusted comment: ";
+const TRUSTED_PREFIX: &str = "trusted comment: ";
+
+pub const DEFAULT_UNTRUSTED_COMMENT: &str = "signature from CosmicCipher CA key";
+
+/// The minisign signing key belonging to a CA key
+pub fn derive_signing_key(ca_key: &SigningKey) -> SigningKey {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"CosmicCipher minisign key");
+    hasher.update(ca_key.as_bytes());
+    SigningKey::from_bytes(&hasher.finalize().into())
+}
+
+/// What the CA signs to certify its minisign key
+pub fn certified_bytes(verifying_key: &VerifyingKey) -> Vec<u8> {
+    let mut bytes = b"CosmicCipher minisign key certificate".to_vec();
+    bytes.extend_from_slice(verifying_key.as_bytes());
+    bytes
+}
+
+/// Key id as used in minisign, derived from the public key
+pub fn key_id(verifying_key: &VerifyingKey) -> [u8; 8] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"CosmicCipher minisign key id");
+    hasher.update(verifying_key.as_bytes());
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&hasher.finalize()[..8]);
+    id
+}
+
+// Minisign prints the key id as a little endian number
+fn key_id_hex(key_id: &[u8; 8]) -> String {
+    format!("{:016X}", u64::from_le_bytes(*key_id))
+}
+
+/// The public key file for `minisign -V -p`
+pub fn encode_public_key(verifying_key: &VerifyingKey) -> String {
+    let key_id = key_id(verifying_key);
+    let mut bytes = ALG_PUBLIC_KEY.to_vec();
+    bytes.extend_from_slice(&key_id);
+    bytes.extend_from_slice(verifying_key.as_bytes());
+    format!(
+        "{}minisign public key {}\n{}\n",
+        UNTRUSTED_PREFIX,
+        key_id_hex(&key_id),
+        BASE64_STANDARD.encode(bytes)
+    )
+}
+
+/// Parse a public key file or just its base64 line, returns key id and key
+pub fn parse_public_key(public_key: &str) -> Result<([u8; 8], VerifyingKey)> {
+    let line = public_key
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with(UNTRUSTED_PREFIX))
+        .ok_or(Error::InvalidArgument("Public key is empty"))?;
+    let bytes = BASE64_STANDARD.decode(line).map_err(Error::from)?;
+    if bytes.len() != 42 || &bytes[..2] != ALG_PUBLIC_KEY {
+        return Err(Error::Unsupported("Not a minisign Ed25519 public key"));
+    }
+    let mut key_id = [0u8; 8];
+    key_id.copy_from_slice(&bytes[2..10]);
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&bytes[10..]);
+    let verifying_key =
+        VerifyingKey::from_bytes(&key).map_err(|_| Error::Malformed("Invalid verifying key"))?;
+    Ok((key_id, verifying_key))
+}
+
+fn check_comment(comment: &str) -> Result<()> {
+    if comment.contains(['\r', '\n']) {
+        return Err(Error::InvalidArgument("Comments must be a single line"));
+    }
+    Ok(())
+}
+
+fn prehash(data: &[u8]) -> [u8; 64] {
+    let mut digest = [0u8; 64];
+    digest.copy_from_slice(&Blake2b512::digest(data));
+    digest
+}
+
+/// Signature file for `data`. Prehashed signatures are what minisign makes by default,
+/// legacy ones sign the data directly.
+pub fn sign(
+    signing_key: &SigningKey,
+    data: &[u8],
+    trusted_comment: &str,
+    untrusted_comment: &str,
+    prehashed: bool,
+) -> Result<String> {
+    check_comment(trusted_comment)?;
+    check_comment(untrusted_comment)?;
+    let (alg, signature) = if prehashed {
+        (ALG_PREHASHED, signing_key.sign(&prehash(data)))
+    } else {
+        (ALG_LEGACY, signing_key.sign(data))
+    };
+    let mut signature_bytes = alg.to_vec();
+    signature_bytes.extend_from_slice(&key_id(&signing_key.verifying_key()));
+    signature_bytes.extend_from_slice(&signature.to_bytes());
+
+    let mut global = signature.to_bytes().to_vec();
+    global.extend_from_slice(trusted_comment.as_bytes());
+    let global = signing_key.sign(&global);
+
+    Ok(format!(
+        "{}{}\n{}\n{}{}\n{}\n",
+        UNTRUSTED_PREFIX,
+        untrusted_comment,
+        BASE64_STANDARD.encode(signature_bytes),
+        TRUSTED_PREFIX,
+        trusted_comment,
+        BASE64_STANDARD.encode(global.to_bytes())
+    ))
+}
+
+/// Verify a signature file over `data` with a public key file (or its base64 line),
+/// returns the trusted comment
+pub fn verify(public_key: &str, data: &[u8], signature: &str) -> Result<String> {
+    let (key_id, verifying_key) = parse_public_key(public_key)?;
+    verify_with_key(&key_id, &verifying_key, data, signature)
+}
+
+/// Like verify, for a key that is already parsed
+pub fn verify_with_key(
+    key_id: &[u8; 8],
+    verifying_key: &VerifyingKey,
+    data: &[u8],
+    signature: &str,
+) -> Result<String> {
+    let mut lines = signature.lines().map(|line| line.trim_end_matches('\r'));
+    let mut next = || {
+        lines
+            .next()
+            .ok_or(Error::Malformed("Signature is truncated"))
+    };
+    if !next()?.starts_with(UNTRUSTED_PREFIX) {
+        return Err(Error::Malformed("Missing untrusted comment"));
+    }
+    let signature_bytes = BASE64_STANDARD.decode(next()?).map_err(Error::from)?;
+    let trusted_comment = next()?
+        .strip_prefix(TRUSTED_PREFIX)
+        .ok_or(Error::Malformed("Missing trusted comment"))?;
+    let global = BASE64_STANDARD.decode(next()?).map_err(Error::from)?;
+
+    if signature_bytes.len() != 74 {
+        return Err(Error::Malformed("Signature has the wrong length"));
+    }
+    if signature_bytes[2..10] != key_id[..] {
+        return Err(Error::Authentication("Signed with a different key"));
+    }
+    let sig = Signature::from_slice(&signature_bytes[10..])
+        .map_err(|_| Error::Malformed("Invalid signature"))?;
+    let valid = match &signature_bytes[..2] {
+        alg if alg == ALG_PREHASHED => verifying_key.verify(&prehash(data), &sig),
+        alg if alg == ALG_LEGACY => verifying_key.verify(data, &sig),
+        _ => return Err(Error::Unsupported("Unknown signature algorithm")),
+    };
+    if valid.is_err() {
+        return Err(Error::Authentication("Signature invalid"));
+    }
+
+    let mut signed = signature_bytes[10..].to_vec();
+    signed.extend_from_slice(trusted_comment.as_bytes());
+    let global =
+        Signature::from_slice(&global).map_err(|_| Error::Malformed("Invalid signature"))?;
+    if verifying_key.verify(&signed, &global).is_err() {
+        return Err(Error::Authentication("Trusted comment signature invalid"));
+    }
+    Ok(trusted_comment.into())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    // From minisign-verify, made with the minisign tool
+    const PUBLIC_KEY: &str = "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3";
+    const LEGACY_SIGNATURE: &str = "untrusted comment: signature from minisign secret key
+RWQf6LRCGA9i59SLOFxz6NxvASXDJeRtuZykwQepbDEGt87ig1BNpWaVWuNrm73YiIiJbq71Wi+dP9eKL8OC351vwIasSSbXxwA=
+trusted comment: timestamp:1555779966\tfile:test
+QtKMXWyYcwdpZAlPF7tE2ENJkRd1ujvKjlj1m9RtHTBnZPa5WKU5uWRs5GoP5M/VqE81QFuMKI5k/SfNQUaOAA==
+";
+    const PREHASHED_SIGNATURE: &str = "untrusted comment: signature from minisign secret key
+RUQf6LRCGA9i559r3g7V1qNyJDApGip8MfqcadIgT9CuhV3EMhHoN1mGTkUidF/z7SrlQgXdy8ofjb7bNJJylDOocrCo8KLzZwo=
+trusted comment: timestamp:1556193335\tfile:test
+y/rUw2y8/hOUYjZU71eHp/Wo1KZ40fGy2VJEDl34XMJM+TX48Ss/17u3IvIfbVR1FkZZSNCisQbuQY+bHwhEBg==
+";
+
+    #[test]
+    fn test_minisign_vectors() {
+        assert_eq!(
+            verify(PUBLIC_KEY, b"test", LEGACY_SIGNATURE).unwrap(),
+            "timestamp:1555779966\tfile:test"
+        );
+        assert_eq!(
+            verify(PUBLIC_KEY, b"test", PREHASHED_SIGNATURE).unwrap(),
+            "timestamp:1556193335\tfile:test"
+        );
+        assert!(verify(PUBLIC_KEY, b"tset", PREHASHED_SIGNATURE).is_err());
+        let forged = PREHASHED_SIGNATURE.replace("file:test", "file:other");
+        assert!(verify(PUBLIC_KEY, b"test", &forged).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// ML-KEM-768 as specified in FIPS 203
+// https://csrc.nist.gov/pubs/fips/203/final
+//
+// A thin wrapper around the ml-kem crate with the key checks of FIPS 203 section 7,
+// which the crate leaves to the caller. Keys keep their encoding, the key exchange hashes it.
+// Function names follow the algorithm names of the standard.
+
+use alloc::vec::Vec;
+use ml_kem::array::Array;
+use ml_kem::kem::{Decapsulate, Encapsulate};
+use ml_kem::{EncapsulateDeterministic, EncodedSizeUser, KemCore, MlKem768, B32};
+use rand_chacha::rand_core::CryptoRngCore;
+use sha3::{Digest, Sha3_256};
+
+use crate::error::{Error, Result};
+
+const K: usize = 3;
+
+pub const ENCAPSULATION_KEY_LEN: usize = 384 * K + 32;
+pub const DECAPSULATION_KEY_LEN: usize = 768 * K + 96;
+pub const CIPHERTEXT_LEN: usize = 32 * (10 * K + 4);
+pub const SHARED_SECRET_LEN: usize = 32;
+pub const SEED_LEN: usize = 64;
+
+type InnerEncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+type InnerDecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+
+pub struct EncapsulationKey {
+    key: InnerEncapsulationKey,
+    bytes: [u8; ENCAPSULATION_KEY_LEN],
+}
+
+pub struct DecapsulationKey {
+    key: InnerDecapsulationKey,
+    bytes: [u8; DECAPSULATION_KEY_LEN],
+}
+
+impl EncapsulationKey {
+    /// Parse an encapsulation key, including the modulus check of FIPS 203 section 7.2
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let encoded = Array::try_from(bytes)
+            .map_err(|_| Error::Malformed("Invalid ML-KEM encapsulation key length"))?;
+        // Decoding reduces mod q, only keys that were reduced already encode the same again
+        let key = InnerEncapsulationKey::from_bytes(&encoded);
+        if key.as_bytes() != encoded {
+            return Err(Error::Malformed("Invalid ML-KEM encapsulation key"));
+        }
+        Ok(Self::new(key))
+    }
+
+    fn new(key: InnerEncapsulationKey) -> Self {
+        let mut bytes = [0u8; ENCAPSULATION_KEY_LEN];
+        bytes.copy_from_slice(&key.as_bytes());
+        Self { key, bytes }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl DecapsulationKey {
+    /// Parse a decapsulation key, including the hash check of FIPS 203 section 7.3
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let encoded = Array::try_from(bytes)
+            .map_err(|_| Error::Malformed("Invalid ML-KEM decapsulation key length"))?;
+        let ek = &bytes[384 * K..768 * K + 32];
+        if Sha3_256::digest(ek).as_slice() != &bytes[768 * K + 32..768 * K + 64] {
+            return Err(Error::Malformed("Invalid ML-KEM decapsulation key"));
+        }
+        Ok(Self::new(InnerDecapsulationKey::from_bytes(&encoded)))
+    }
+
+    fn new(key: InnerDecapsulationKey) -> Self {
+        let mut bytes = [0u8; DECAPSULATION_KEY_LEN];
+        bytes.copy_from_slice(&key.as_bytes());
+        Self { key, bytes }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn encapsulation_key(&self) -> EncapsulationKey {
+        EncapsulationKey::new(self.key.encapsulation_key().clone())
+    }
+}
+
+/// ML-KEM.KeyGen
+pub fn generate<R: CryptoRngCore>(rng: &mut R) -> (EncapsulationKey, DecapsulationKey) {
+    let (dk, ek) = MlKem768::generate(rng);
+    (EncapsulationKey::new(ek), DecapsulationKey::new(dk))
+}
+
+/// ML-KEM.KeyGen_internal, the seed is d || z
+pub fn generate_from_seed(seed: &[u8; SEED_LEN]) -> (EncapsulationKey, DecapsulationKey) {
+    let (d, z) = seed.split_at(32);
+    let d = B32::from_fn(|i| d[i]);
+    let z = B32::from_fn(|i| z[i]);
+    let (dk, ek) = MlKem768::generate_deterministic(&d, &z);
+    (EncapsulationKey::new(ek), DecapsulationKey::new(dk))
+}
+
+/// ML-KEM.Encaps, returns the shared secret and the ciphertext
+pub fn encapsulate<R: CryptoRngCore>(
+    ek: &EncapsulationKey,
+    rng: &mut R,
+) -> Result<([u8; SHARED_SECRET_LEN], Vec<u8>)> {
+    let (c, shared_secret) = ek
+        .key
+        .encapsulate(rng)
+        .map_err(|_| Error::Internal("ML-KEM encapsulation failed"))?;
+    Ok((shared_secret.into(), c.to_vec()))
+}
+
+/// ML-KEM.Encaps_internal
+pub fn encapsulate_deterministic(
+    ek: &EncapsulationKey,
+    m: &[u8; 32],
+) -> Result<([u8; SHARED_SECRET_LEN], Vec<u8>)> {
+    let (c, shared_secret) = ek
+        .key
+        .encapsulate_deterministic(&B32::from(*m))
+        .map_err(|_| Error::Internal("ML-KEM encapsulation failed"))?;
+    Ok((shared_secret.into(), c.to_vec()))
+}
+
+/// ML-KEM.Decaps, with implicit rejection
+pub fn decapsulate(dk: &DecapsulationKey, c: &[u8]) -> Result<[u8; SHARED_SECRET_LEN]> {
+    let c = Array::try_from(c).map_err(|_| Error::Malformed("Invalid ML-KEM ciphertext length"))?;
+    let shared_secret = dk
+        .key
+        .decapsulate(&c)
+        .map_err(|_| Error::Internal("ML-KEM decapsulation failed"))?;
+    Ok(shared_secret.into())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use rand_chacha::rand_core::SeedableRng;
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = rand_chacha::ChaChaRng::from_entropy();
+        let (ek, dk) = generate(&mut rng);
+        let (ss1, c) = encapsulate(&ek, &mut rng).unwrap();
+        assert_eq!(c.len(), CIPHERTEXT_LEN);
+        let ss2 = decapsulate(&dk, &c).unwrap();
+        assert_eq!(ss1, ss2);
+        assert!(decapsulate(&dk, &c[1..]).is_err());
+    }
+
+    #[test]
+    fn test_implicit_rejection() {
+        let mut rng = rand_chacha::ChaChaRng::from_entropy();
+        let (ek, dk) = generate(&mut rng);
+        let (ss1, mut c) = encapsulate(&ek, &mut rng).unwrap();
+        c[0] ^= 1;
+        let ss2 = decapsulate(&dk, &c).unwrap();
+        assert_ne!(ss1, ss2);
+        assert_eq!(ss2, decapsulate(&dk, &c).unwrap());
+    }
+
+    #[test]
+    fn test_key_checks() {
+        let (ek, dk) = generate_from_seed(&[7u8; SEED_LEN]);
+        assert!(EncapsulationKey::from_bytes(ek.as_bytes()).is_ok());
+        assert!(DecapsulationKey::from_bytes(dk.as_bytes()).is_ok());
+        assert_eq!(dk.encapsulation_key().as_bytes(), ek.as_bytes());
+
+        // A coefficient of 0xfff is not reduced mod q
+        let mut bad_ek = ek.as_bytes().to_vec();
+        bad_ek[0] = 0xff;
+        bad_ek[1] |= 0x0f;
+        assert!(EncapsulationKey::from_bytes(&bad_ek).is_err());
+
+        let mut bad_dk = dk.as_bytes().to_vec();
+        bad_dk[384 * K] ^= 1;
+        assert!(DecapsulationKey::from_bytes(&bad_dk).is_err());
+    }
+}
@@ -0,0 +1,1788 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// Tree based group key agreement in the style of MLS (RFC 9420)
+//
+// Members sit at the leaves of a binary ratchet tree, every node may hold an X25519 key pair.
+// A member knows the private keys of the nodes on the path from its leaf to the root.
+// A commit applies Add, Remove and Update proposals and replaces the keys on the committer's path:
+// the path secret of each node is encrypted to the other subtree, so a commit costs O(log n)
+// encryptions instead of one per member. The path secret above the root is the commit secret,
+// which is mixed into the key schedule of the next epoch.
+//
+// Differences to RFC 9420, all on the side of simplicity:
+// - Add, Update and Remove blank the direct path of the leaf instead of tracking unmerged leaves
+// - every commit carries an update path, proposals are included by value
+// - the wire format is bson, the KDF is HMAC-SHA3-256, the AEAD is the cipher suite the creator
+//   prefers; joiners only accept suites of their own, groups without one are XChaCha20-Poly1305
+// - application messages are only accepted in the current epoch
+// Credentials are the Client identities: the ed25519 key of each member has to be signed by our CA.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use ed25519_dalek::{Signature, Signer, Verifier, VerifyingKey};
+use hmac::{Hmac, Mac};
+use rand_chacha::rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::client::{open_with_password, seal_with_password, Client};
+use crate::compression::Compression;
+use crate::error::{Error, Result};
+use crate::limits::Limits;
+use crate::message::{self, MessageOptions, FLAG_COMPRESSED};
+use crate::padding::Padding;
+use crate::suite::CipherSuite;
+
+/// Random identifier of an MLS group
+pub type MlsGroupId = [u8; 16];
+
+/// Key package of a client that wants to join groups, keep it until the Welcome arrives.
+/// Publish the bytes of key_package, they are single use.
+pub struct MlsKeyPackageBundle {
+    init_key: StaticSecret,
+    leaf_secret: [u8; 32],
+    key_package: KeyPackage,
+}
+
+impl MlsKeyPackageBundle {
+    pub fn new(client: &mut Client) -> Result<Self> {
+        let init_key = StaticSecret::random_from_rng(&mut *client.csprng());
+        let mut leaf_secret = [0u8; 32];
+        client.csprng().fill_bytes(&mut leaf_secret);
+
+        let leaf_node = LeafNode::new(client, &leaf_secret);
+        let mut key_package = KeyPackage {
+            init_key: PublicKey::from(&init_key).to_bytes(),
+            leaf_node,
+            signature: Vec::new(),
+        };
+        key_package.signature = client
+            .signing_key()
+            .sign(&key_package.tbs()?)
+            .to_bytes()
+            .to_vec();
+
+        Ok(Self {
+            init_key,
+            leaf_secret,
+            key_package,
+        })
+    }
+
+    pub fn key_package(&self) -> Result<Vec<u8>> {
+        bson::to_vec(&self.key_package).map_err(Error::from)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MlsEvent {
+    /// A proposal was stored, it becomes effective with the next commit
+    Proposal {
+        sender: u32,
+    },
+    /// A commit was applied, the group is in a new epoch
+    Commit {
+        epoch: u64,
+    },
+    /// We were removed from the group, it cannot be used anymore
+    Removed,
+    Application {
+        sender: u32,
+        message: Vec<u8>,
+    },
+}
+
+/// Result of MlsGroup::commit
+pub struct MlsCommitOutput {
+    /// Send to all members of the previous epoch
+    pub commit: Vec<u8>,
+    /// One per added member, send to whoever published the key package
+    pub welcomes: Vec<Vec<u8>>,
+}
+
+struct EpochSecrets {
+    init: [u8; 32],
+    encryption: [u8; 32],
+    confirmation: [u8; 32],
+    exporter: [u8; 32],
+}
+
+impl EpochSecrets {
+    fn derive(joiner_secret: &[u8; 32], context: &[u8; 32]) -> Result<Self> {
+        let epoch_secret = expand_label(&extract(joiner_secret, &[0u8; 32])?, "epoch", context)?;
+        Ok(Self {
+            init: derive_secret(&epoch_secret, "init")?,
+            encryption: derive_secret(&epoch_secret, "encryption")?,
+            confirmation: derive_secret(&epoch_secret, "confirm")?,
+            exporter: derive_secret(&epoch_secret, "exporter")?,
+        })
+    }
+}
+
+pub struct MlsGroup {
+    group_id: MlsGroupId,
+    suite: CipherSuite,
+    epoch: u64,
+    own_leaf: u32,
+    tree: RatchetTree,
+    // X25519 private keys by node index, only for nodes whose public key they match
+    node_secrets: hashbrown::HashMap<u32, [u8; 32]>,
+    confirmed_transcript_hash: [u8; 32],
+    interim_transcript_hash: [u8; 32],
+    secrets: EpochSecrets,
+    // Proposals of this epoch, they go into the next commit
+    pending_proposals: Vec<SignedProposal>,
+    // Leaf secrets of our Update proposals of this epoch
+    pending_updates: Vec<[u8; 32]>,
+    // Next generation of our application messages
+    generation: u32,
+    // (sender, generation) of the application messages received in this epoch
+    seen: hashbrown::HashSet<(u32, u32)>,
+    removed: bool,
+}
+
+impl MlsGroup {
+    /// Create a group with ourselves as the only member
+    pub fn create(client: &mut Client) -> Result<Self> {
+        let mut group_id = [0u8; 16];
+        client.csprng().fill_bytes(&mut group_id);
+        let mut leaf_secret = [0u8; 32];
+        client.csprng().fill_bytes(&mut leaf_secret);
+        let mut joiner_secret = [0u8; 32];
+        client.csprng().fill_bytes(&mut joiner_secret);
+
+        let tree = RatchetTree::new(LeafNode::new(client, &leaf_secret));
+        let confirmed_transcript_hash = [0u8; 32];
+        let context = group_context(&group_id, 0, &tree.hash()?, &confirmed_transcript_hash);
+
+        let mut node_secrets = hashbrown::HashMap::new();
+        node_secrets.insert(0, leaf_secret);
+
+        Ok(Self {
+            group_id,
+            suite: client.preferred_suite(),
+            epoch: 0,
+            own_leaf: 0,
+            tree,
+            node_secrets,
+            confirmed_transcript_hash,
+            interim_transcript_hash: [0u8; 32],
+            secrets: EpochSecrets::derive(&joiner_secret, &context)?,
+            pending_proposals: Vec::new(),
+            pending_updates: Vec::new(),
+            generation: 0,
+            seen: hashbrown::HashSet::new(),
+            removed: false,
+        })
+    }
+
+    /// Join a group with the Welcome from the commit that added our key package
+    pub fn join(client: &Client, bundle: &MlsKeyPackageBundle, welcome: &[u8]) -> Result<Self> {
+        let welcome: Welcome = client.limits().parse_bson(welcome)?;
+        if welcome.key_package_ref != hash(&[&bundle.key_package()?]) {
+            return Err(Error::InvalidArgument(
+                "Welcome is for a different key package",
+            ));
+        }
+        let suite = suite_from_id(welcome.suite)?;
+        if !client.cipher_suites().contains(&suite) {
+            return Err(Error::Unsupported("Cipher suite not allowed"));
+        }
+        let serialized = open(suite, &bundle.init_key, b"welcome", &welcome.ciphertext)?;
+        let secrets: WelcomeSecrets = client.limits().parse_bson(&serialized)?;
+        let group_info = secrets.group_info;
+        if group_info.suite != welcome.suite {
+            return Err(Error::Authentication(
+                "Cipher suite does not match the group",
+            ));
+        }
+        let tree = group_info.tree.clone();
+
+        let ca = client.ca_verifying_key();
+        for leaf in tree.leaves.iter().flatten() {
+            leaf.verify(ca)?;
+        }
+        let signer = tree.leaf(group_info.signer)?.verifying_key()?;
+        let sig = Signature::from_slice(&group_info.signature)
+            .map_err(|_| Error::Malformed("Invalid signature"))?;
+        if signer.verify(&group_info.tbs()?, &sig).is_err() {
+            return Err(Error::Authentication("Group info not signed by committer"));
+        }
+
+        let own_leaf = match tree
+            .leaves
+            .iter()
+            .position(|v| v.as_ref() == Some(&bundle.key_package.leaf_node))
+        {
+            None => return Err(Error::InvalidState("Own leaf not found in the group")),
+            Some(v) => v as u32,
+        };
+
+        let mut node_secrets = hashbrown::HashMap::new();
+        node_secrets.insert(2 * own_leaf, bundle.leaf_secret);
+        if let Some(path_secret) = secrets.path_secret {
+            let path_secret = to_array(&path_secret)?;
+            let path = direct_path(2 * group_info.signer, tree.leaf_count());
+            let start = match path.iter().position(|n| in_subtree(2 * own_leaf, *n)) {
+                None => return Err(Error::Malformed("Committer path does not reach our leaf")),
+                Some(v) => v,
+            };
+            derive_path(&tree, &path[start..], path_secret, &mut node_secrets)?;
+        }
+
+        let confirmed_transcript_hash = to_array(&group_info.confirmed_transcript_hash)?;
+        let context = group_context(
+            &to_array(&group_info.group_id)?,
+            group_info.epoch,
+            &tree.hash()?,
+            &confirmed_transcript_hash,
+        );
+        let epoch_secrets = EpochSecrets::derive(&to_array(&secrets.joiner_secret)?, &context)?;
+        verify_confirmation_tag(
+            &epoch_secrets.confirmation,
+            &confirmed_transcript_hash,
+            &group_info.confirmation_tag,
+        )?;
+
+        Ok(Self {
+            group_id: to_array(&group_info.group_id)?,
+            suite,
+            epoch: group_info.epoch,
+            own_leaf,
+            tree,
+            node_secrets,
+            confirmed_transcript_hash,
+            interim_transcript_hash: hash(&[
+                &confirmed_transcript_hash,
+                &group_info.confirmation_tag,
+            ]),
+            secrets: epoch_secrets,
+            pending_proposals: Vec::new(),
+            pending_updates: Vec::new(),
+            generation: 0,
+            seen: hashbrown::HashSet::new(),
+            removed: false,
+        })
+    }
+
+    pub fn group_id(&self) -> MlsGroupId {
+        self.group_id
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn own_leaf_index(&self) -> u32 {
+        self.own_leaf
+    }
+
+    /// Leaf index and identity of every member, including ourselves
+    pub fn members(&self) -> Vec<(u32, VerifyingKey)> {
+        self.tree
+            .leaves
+            .iter()
+            .enumerate()
+            .filter_map(|(i, v)| {
+                v.as_ref()
+                    .and_then(|leaf| leaf.verifying_key().ok())
+                    .map(|vk| (i as u32, vk))
+            })
+            .collect()
+    }
+
+    /// A secret all members of the current epoch share, for use outside of the group
+    pub fn export_secret(&self, label: &str, context: &[u8]) -> Result<[u8; 32]> {
+        expand_label(&self.secrets.exporter, label, context)
+    }
+
+    /// Propose to add the owner of `key_package`
+    pub fn propose_add(&mut self, client: &Client, key_package: &[u8]) -> Result<Vec<u8>> {
+        let key_package: KeyPackage = client.limits().parse_bson(key_package)?;
+        key_package.verify(client.ca_verifying_key())?;
+        self.propose(client, Proposal::Add { key_package })
+    }
+
+    /// Propose to remove the member at `leaf`
+    pub fn propose_remove(&mut self, client: &Client, leaf: u32) -> Result<Vec<u8>> {
+        self.tree.leaf(leaf)?;
+        if leaf == self.own_leaf {
+            return Err(Error::InvalidArgument("Cannot remove ourselves"));
+        }
+        self.propose(client, Proposal::Remove { leaf })
+    }
+
+    /// Propose a new leaf key for ourselves
+    pub fn propose_update(&mut self, client: &mut Client) -> Result<Vec<u8>> {
+        let mut leaf_secret = [0u8; 32];
+        client.csprng().fill_bytes(&mut leaf_secret);
+        let leaf_node = LeafNode::new(client, &leaf_secret);
+        let message = self.propose(client, Proposal::Update { leaf_node })?;
+        self.pending_updates.push(leaf_secret);
+        Ok(message)
+    }
+
+    fn propose(&mut self, client: &Client, proposal: Proposal) -> Result<Vec<u8>> {
+        self.check_active()?;
+        let mut signed = SignedProposal {
+            group_id: self.group_id.to_vec(),
+            epoch: self.epoch,
+            sender: self.own_leaf,
+            proposal,
+            signature: Vec::new(),
+        };
+        signed.signature = client
+            .signing_key()
+            .sign(&signed.tbs()?)
+            .to_bytes()
+            .to_vec();
+        self.pending_proposals.push(signed.clone());
+
+        bson::to_vec(&MlsMessage::Proposal(signed)).map_err(Error::from)
+    }
+
+    /// Commit the pending proposals and renew our path, the group moves to the next epoch
+    pub fn commit(&mut self, client: &mut Client) -> Result<MlsCommitOutput> {
+        self.check_active()?;
+
+        // Our own path replaces our leaf anyway
+        let proposals: Vec<SignedProposal> = self
+            .pending_proposals
+            .iter()
+            .filter(|p| {
+                !(p.sender == self.own_leaf && matches!(p.proposal, Proposal::Update { .. }))
+            })
+            .cloned()
+            .collect();
+        let mut tree = self.tree.clone();
+        let added = apply_proposals(
+            &self.tree,
+            &mut tree,
+            &proposals,
+            (&self.group_id, self.epoch),
+            self.own_leaf,
+            client.ca_verifying_key(),
+        )?;
+
+        let mut leaf_secret = [0u8; 32];
+        client.csprng().fill_bytes(&mut leaf_secret);
+        let leaf_node = LeafNode::new(client, &leaf_secret);
+        tree.set_leaf(self.own_leaf, Some(leaf_node.clone()));
+
+        let path = direct_path(2 * self.own_leaf, tree.leaf_count());
+        let mut path_secrets = Vec::new();
+        let mut path_secret = [0u8; 32];
+        client.csprng().fill_bytes(&mut path_secret);
+        let mut node_secrets = hashbrown::HashMap::new();
+        node_secrets.insert(2 * self.own_leaf, leaf_secret);
+        for node in &path {
+            let node_secret = derive_secret(&path_secret, "node")?;
+            tree.nodes[*node as usize] = Some(public_key(&node_secret));
+            node_secrets.insert(*node, node_secret);
+            path_secrets.push(path_secret);
+            path_secret = derive_secret(&path_secret, "path")?;
+        }
+        let commit_secret = path_secret;
+
+        let epoch = self.epoch + 1;
+        let provisional_context = provisional_context(&self.group_id, epoch, &tree.hash()?);
+        let excluded: Vec<u32> = added.iter().map(|(leaf, _)| *leaf).collect();
+        let mut nodes = Vec::new();
+        for (node, path_secret) in path.iter().zip(path_secrets.iter()) {
+            let child = copath_child(*node, 2 * self.own_leaf);
+            let mut encrypted_path_secrets = Vec::new();
+            for recipient in tree.resolution(child, &excluded) {
+                let public_key = match tree.nodes[recipient as usize] {
+                    None => return Err(Error::Malformed("Blank node in resolution")),
+                    Some(v) => v,
+                };
+                encrypted_path_secrets.push(seal(
+                    self.suite,
+                    client.csprng(),
+                    &public_key,
+                    &provisional_context,
+                    path_secret,
+                )?);
+            }
+            nodes.push(UpdatePathNode {
+                public_key: public_key(&derive_secret(path_secret, "node")?),
+                encrypted_path_secrets,
+            });
+        }
+
+        let mut commit = Commit {
+            group_id: self.group_id.to_vec(),
+            epoch: self.epoch,
+            sender: self.own_leaf,
+            proposals,
+            path: UpdatePath { leaf_node, nodes },
+            signature: Vec::new(),
+            confirmation_tag: Vec::new(),
+        };
+        let tbs = commit.tbs()?;
+        commit.signature = client.signing_key().sign(&tbs).to_bytes().to_vec();
+
+        let confirmed_transcript_hash =
+            hash(&[&self.interim_transcript_hash, &tbs, &commit.signature]);
+        let context = group_context(
+            &self.group_id,
+            epoch,
+            &tree.hash()?,
+            &confirmed_transcript_hash,
+        );
+        let joiner_secret = expand_label(
+            &extract(&self.secrets.init, &commit_secret)?,
+            "joiner",
+            &context,
+        )?;
+        let secrets = EpochSecrets::derive(&joiner_secret, &context)?;
+        commit.confirmation_tag =
+            confirmation_tag(&secrets.confirmation, &confirmed_transcript_hash)?;
+
+        let mut welcomes = Vec::new();
+        for (leaf, key_package) in &added {
+            let path_secret = path
+                .iter()
+                .position(|n| in_subtree(2 * leaf, *n))
+                .map(|i| path_secrets[i].to_vec());
+            let mut group_info = GroupInfo {
+                group_id: self.group_id.to_vec(),
+                suite: Some(self.suite.id()),
+                epoch,
+                tree: tree.clone(),
+                confirmed_transcript_hash: confirmed_transcript_hash.to_vec(),
+                confirmation_tag: commit.confirmation_tag.clone(),
+                signer: self.own_leaf,
+                signature: Vec::new(),
+            };
+            group_info.signature = client
+                .signing_key()
+                .sign(&group_info.tbs()?)
+                .to_bytes()
+                .to_vec();
+            let secrets = WelcomeSecrets {
+                joiner_secret: joiner_secret.to_vec(),
+                path_secret,
+                group_info,
+            };
+            let serialized = bson::to_vec(&secrets).map_err(Error::from)?;
+            let welcome = Welcome {
+                key_package_ref: hash(&[&bson::to_vec(key_package).map_err(Error::from)?]).to_vec(),
+                suite: Some(self.suite.id()),
+                ciphertext: seal(
+                    self.suite,
+                    client.csprng(),
+                    &key_package.init_key,
+                    b"welcome",
+                    &serialized,
+                )?,
+            };
+            welcomes.push(bson::to_vec(&welcome).map_err(Error::from)?);
+        }
+
+        let interim_transcript_hash = hash(&[&confirmed_transcript_hash, &commit.confirmation_tag]);
+        let commit = bson::to_vec(&MlsMessage::Commit(commit)).map_err(Error::from)?;
+
+        self.next_epoch(
+            tree,
+            node_secrets,
+            confirmed_transcript_hash,
+            interim_transcript_hash,
+            secrets,
+        );
+
+        Ok(MlsCommitOutput { commit, welcomes })
+    }
+
+    /// Process a proposal, commit or application message of another member
+    pub fn process_message(&mut self, client: &Client, data: &[u8]) -> Result<MlsEvent> {
+        self.check_active()?;
+        let message: MlsMessage = client.limits().parse_bson(data)?;
+
+        match message {
+            MlsMessage::Proposal(proposal) => {
+                self.verify_proposal(&self.tree, &proposal, client.ca_verifying_key())?;
+                if proposal.sender == self.own_leaf {
+                    return Err(Error::InvalidArgument("Own proposal"));
+                }
+                let sender = proposal.sender;
+                if !self
+                    .pending_proposals
+                    .iter()
+                    .any(|p| p.signature == proposal.signature)
+                {
+                    self.pending_proposals.push(proposal);
+                }
+                Ok(MlsEvent::Proposal { sender })
+            }
+            MlsMessage::Commit(commit) => self.process_commit(client, commit),
+            MlsMessage::Application(message) => self.decrypt(message, client.limits()),
+        }
+    }
+
+    fn process_commit(&mut self, client: &Client, commit: Commit) -> Result<MlsEvent> {
+        self.check_epoch(&commit.group_id, commit.epoch)?;
+        if commit.sender == self.own_leaf {
+            return Err(Error::InvalidArgument("Own commit"));
+        }
+        let sender_key = self.tree.leaf(commit.sender)?.verifying_key()?;
+        let tbs = commit.tbs()?;
+        let sig = Signature::from_slice(&commit.signature)
+            .map_err(|_| Error::Malformed("Invalid signature"))?;
+        if sender_key.verify(&tbs, &sig).is_err() {
+            return Err(Error::Authentication("Commit not signed by sender"));
+        }
+
+        let ca = client.ca_verifying_key();
+        let mut tree = self.tree.clone();
+        let added = apply_proposals(
+            &self.tree,
+            &mut tree,
+            &commit.proposals,
+            (&commit.group_id, commit.epoch),
+            commit.sender,
+            ca,
+        )?;
+        if tree.leaves[self.own_leaf as usize].is_none() {
+            self.removed = true;
+            return Ok(MlsEvent::Removed);
+        }
+
+        let leaf_node = &commit.path.leaf_node;
+        leaf_node.verify(ca)?;
+        if leaf_node.verifying_key != sender_key.to_bytes() {
+            return Err(Error::UntrustedKey(
+                "Commit changes the identity of the committer",
+            ));
+        }
+        tree.set_leaf(commit.sender, Some(leaf_node.clone()));
+        let path = direct_path(2 * commit.sender, tree.leaf_count());
+        if path.len() != commit.path.nodes.len() {
+            return Err(Error::Malformed("Update path has the wrong length"));
+        }
+        for (node, update) in path.iter().zip(commit.path.nodes.iter()) {
+            tree.nodes[*node as usize] = Some(update.public_key);
+        }
+
+        // Our own Update proposal may have been committed
+        let mut node_secrets = self.node_secrets.clone();
+        let own_node = 2 * self.own_leaf;
+        if let Some(own_key) = tree.nodes[own_node as usize] {
+            if let Some(secret) = self
+                .pending_updates
+                .iter()
+                .find(|s| public_key(s) == own_key)
+            {
+                node_secrets.insert(own_node, *secret);
+            }
+        }
+        node_secrets.retain(|node, secret| tree.nodes[*node as usize] == Some(public_key(secret)));
+
+        let epoch = self.epoch + 1;
+        let provisional_context = provisional_context(&self.group_id, epoch, &tree.hash()?);
+        let excluded: Vec<u32> = added.iter().map(|(leaf, _)| *leaf).collect();
+        let start = match path.iter().position(|n| in_subtree(own_node, *n)) {
+            None => return Err(Error::Malformed("Committer path does not reach our leaf")),
+            Some(v) => v,
+        };
+        let child = copath_child(path[start], 2 * commit.sender);
+        let resolution = tree.resolution(child, &excluded);
+        let (index, secret) = match resolution
+            .iter()
+            .enumerate()
+            .find_map(|(i, n)| node_secrets.get(n).map(|s| (i, *s)))
+        {
+            None => return Err(Error::NotFound("No key to decrypt the path secret")),
+            Some(v) => v,
+        };
+        let encrypted = match commit.path.nodes[start].encrypted_path_secrets.get(index) {
+            None => return Err(Error::Malformed("Path secret missing")),
+            Some(v) => v,
+        };
+        let path_secret = to_array(&open(
+            self.suite,
+            &StaticSecret::from(secret),
+            &provisional_context,
+            encrypted,
+        )?)?;
+        let commit_secret = derive_path(&tree, &path[start..], path_secret, &mut node_secrets)?;
+
+        let confirmed_transcript_hash =
+            hash(&[&self.interim_transcript_hash, &tbs, &commit.signature]);
+        let context = group_context(
+            &self.group_id,
+            epoch,
+            &tree.hash()?,
+            &confirmed_transcript_hash,
+        );
+        let joiner_secret = expand_label(
+            &extract(&self.secrets.init, &commit_secret)?,
+            "joiner",
+            &context,
+        )?;
+        let secrets = EpochSecrets::derive(&joiner_secret, &context)?;
+        verify_confirmation_tag(
+            &secrets.confirmation,
+            &confirmed_transcript_hash,
+            &commit.confirmation_tag,
+        )?;
+
+        let interim_transcript_hash = hash(&[&confirmed_transcript_hash, &commit.confirmation_tag]);
+        self.next_epoch(
+            tree,
+            node_secrets,
+            confirmed_transcript_hash,
+            interim_transcript_hash,
+            secrets,
+        );
+
+        Ok(MlsEvent::Commit { epoch })
+    }
+
+    fn next_epoch(
+        &mut self,
+        tree: RatchetTree,
+        node_secrets: hashbrown::HashMap<u32, [u8; 32]>,
+        confirmed_transcript_hash: [u8; 32],
+        interim_transcript_hash: [u8; 32],
+        secrets: EpochSecrets,
+    ) {
+        self.epoch += 1;
+        self.tree = tree;
+        self.node_secrets = node_secrets;
+        self.confirmed_transcript_hash = confirmed_transcript_hash;
+        self.interim_transcript_hash = interim_transcript_hash;
+        self.secrets = secrets;
+        self.pending_proposals.clear();
+        self.pending_updates.clear();
+        self.generation = 0;
+        self.seen.clear();
+    }
+
+    /// Encrypt an application message for all members of the current epoch
+    pub fn encrypt(&mut self, client: &mut Client, message: &[u8]) -> Result<Vec<u8>> {
+        self.encrypt_with_options(client, message, &MessageOptions::default())
+    }
+
+    /// Like encrypt with compression, padding and sensitive set for this message.
+    /// Application messages are LZ4 compressed and not padded by default.
+    pub fn encrypt_with_options(
+        &mut self,
+        client: &mut Client,
+        message: &[u8],
+        options: &MessageOptions,
+    ) -> Result<Vec<u8>> {
+        self.check_active()?;
+        options.check_payload_only()?;
+        let limits = *client.limits();
+        limits.check_plaintext(message.len())?;
+        let (flags, mut buffer) = message::encode_payload(
+            options.compression.unwrap_or(Compression::Lz4),
+            options.padding.unwrap_or(Padding::None),
+            options.sensitive,
+            client.csprng(),
+            message,
+        )?;
+        // The receivers check the payload against their limit before decrypting it
+        limits.check_plaintext(buffer.len())?;
+        let generation = self.generation;
+        self.generation = match generation.checked_add(1) {
+            None => {
+                return Err(Error::InvalidState(
+                    "Epoch message limit reached, commit first",
+                ))
+            }
+            Some(v) => v,
+        };
+
+        let mut nonce = vec![0u8; self.suite.nonce_len()];
+        client.csprng().fill_bytes(&mut nonce);
+        let mut application = ApplicationMessage {
+            group_id: self.group_id.to_vec(),
+            epoch: self.epoch,
+            sender: self.own_leaf,
+            generation,
+            flags: Some(flags),
+            nonce: nonce.clone(),
+            ciphertext: Vec::new(),
+            signature: Vec::new(),
+        };
+        let associated_data = bson::to_vec(&application).map_err(Error::from)?;
+
+        let key = self.application_key(self.own_leaf, generation)?;
+        self.suite
+            .seal(&key, &nonce, &associated_data, &mut buffer)?;
+        application.ciphertext = buffer;
+        application.signature = client
+            .signing_key()
+            .sign(&application.tbs()?)
+            .to_bytes()
+            .to_vec();
+
+        bson::to_vec(&MlsMessage::Application(application)).map_err(Error::from)
+    }
+
+    fn decrypt(&mut self, message: ApplicationMessage, limits: &Limits) -> Result<MlsEvent> {
+        self.check_epoch(&message.group_id, message.epoch)?;
+        let sender_key = self.tree.leaf(message.sender)?.verifying_key()?;
+        let sig = Signature::from_slice(&message.signature)
+            .map_err(|_| Error::Malformed("Invalid signature"))?;
+        if sender_key.verify(&message.tbs()?, &sig).is_err() {
+            return Err(Error::Authentication(
+                "Application message not signed by sender",
+            ));
+        }
+        if self.seen.contains(&(message.sender, message.generation)) {
+            return Err(Error::Replay("Replayed application message"));
+        }
+        if message.nonce.len() != self.suite.nonce_len() {
+            return Err(Error::Malformed("Invalid nonce length"));
+        }
+
+        let mut associated = message.clone();
+        associated.ciphertext = Vec::new();
+        associated.signature = Vec::new();
+        let associated_data = bson::to_vec(&associated).map_err(Error::from)?;
+
+        limits.check_plaintext(
+            message
+                .ciphertext
+                .len()
+                .saturating_sub(self.suite.overhead()),
+        )?;
+        let key = self.application_key(message.sender, message.generation)?;
+        let mut buffer = message.ciphertext;
+        self.suite
+            .open(&key, &message.nonce, &associated_data, &mut buffer)?;
+        self.seen.insert((message.sender, message.generation));
+
+        let plaintext = message::decode_payload(
+            message.flags.unwrap_or(FLAG_COMPRESSED),
+            buffer,
+            limits.max_decompressed_size,
+        )?;
+        limits.check_plaintext(plaintext.len())?;
+        Ok(MlsEvent::Application {
+            sender: message.sender,
+            message: plaintext,
+        })
+    }
+
+    fn application_key(&self, sender: u32, generation: u32) -> Result<[u8; 32]> {
+        let mut context = sender.to_be_bytes().to_vec();
+        context.extend_from_slice(&generation.to_be_bytes());
+        expand_label(&self.secrets.encryption, "application", &context)
+    }
+
+    fn verify_proposal(
+        &self,
+        tree: &RatchetTree,
+        proposal: &SignedProposal,
+        ca: &VerifyingKey,
+    ) -> Result<()> {
+        self.check_epoch(&proposal.group_id, proposal.epoch)?;
+        verify_proposal(tree, proposal, ca)
+    }
+
+    fn check_epoch(&self, group_id: &[u8], epoch: u64) -> Result<()> {
+        if group_id != self.group_id {
+            return Err(Error::Malformed("Message for a different group"));
+        }
+        if epoch != self.epoch {
+            return Err(Error::Malformed("Message for a different epoch"));
+        }
+        Ok(())
+    }
+
+    fn check_active(&self) -> Result<()> {
+        match self.removed {
+            true => Err(Error::InvalidState("Removed from the group")),
+            false => Ok(()),
+        }
+    }
+
+    /// Export the group state protected like Client::export_sessions
+    pub fn export(&self, client: &mut Client, password: &[u8]) -> Result<Vec<u8>> {
+        let mut node_secrets: Vec<(u32, Vec<u8>)> = self
+            .node_secrets
+            .iter()
+            .map(|(n, s)| (*n, s.to_vec()))
+            .collect();
+        node_secrets.sort();
+        let mut seen: Vec<(u32, u32)> = self.seen.iter().cloned().collect();
+        seen.sort();
+
+        let export = MlsGroupForExport {
+            owner: client.owner().to_vec(),
+            group_id: self.group_id.to_vec(),
+            suite: Some(self.suite.id()),
+            epoch: self.epoch,
+            own_leaf: self.own_leaf,
+            tree: self.tree.clone(),
+            node_secrets,
+            confirmed_transcript_hash: self.confirmed_transcript_hash.to_vec(),
+            interim_transcript_hash: self.interim_transcript_hash.to_vec(),
+            init_secret: self.secrets.init.to_vec(),
+            encryption_secret: self.secrets.encryption.to_vec(),
+            confirmation_key: self.secrets.confirmation.to_vec(),
+            exporter_secret: self.secrets.exporter.to_vec(),
+            pending_proposals: self.pending_proposals.clone(),
+            pending_updates: self.pending_updates.iter().map(|v| v.to_vec()).collect(),
+            generation: self.generation,
+            seen,
+            removed: self.removed,
+        };
+        let serialized = bson::to_vec(&export).map_err(Error::from)?;
+
+        let suite = client.preferred_suite();
+        seal_with_password(client.csprng(), suite, password, serialized)
+    }
+
+    /// Import a group from export, only into the identity that exported it
+    pub fn import(client: &Client, password: &[u8], data: &[u8]) -> Result<Self> {
+        let serialized = open_with_password(password, data)?;
+        let export: MlsGroupForExport = client.limits().parse_bson(&serialized)?;
+        if export.owner != client.owner() {
+            return Err(Error::InvalidArgument(
+                "Group belongs to a different identity",
+            ));
+        }
+
+        let mut node_secrets = hashbrown::HashMap::new();
+        for (node, secret) in export.node_secrets {
+            node_secrets.insert(node, to_array(&secret)?);
+        }
+        let mut pending_updates = Vec::new();
+        for secret in export.pending_updates {
+            pending_updates.push(to_array(&secret)?);
+        }
+
+        Ok(Self {
+            group_id: to_array(&export.group_id)?,
+            suite: suite_from_id(export.suite)?,
+            epoch: export.epoch,
+            own_leaf: export.own_leaf,
+            tree: export.tree,
+            node_secrets,
+            confirmed_transcript_hash: to_array(&export.confirmed_transcript_hash)?,
+            interim_transcript_hash: to_array(&export.interim_transcript_hash)?,
+            secrets: EpochSecrets {
+                init: to_array(&export.init_secret)?,
+                encryption: to_array(&export.encryption_secret)?,
+                confirmation: to_array(&export.confirmation_key)?,
+                exporter: to_array(&export.exporter_secret)?,
+            },
+            pending_proposals: export.pending_proposals,
+            pending_updates,
+            generation: export.generation,
+            seen: export.seen.into_iter().collect(),
+            removed: export.removed,
+        })
+    }
+}
+
+// Apply proposals in the order Update, Remove, Add. Returns the added leaves.
+// Every proposal has to be from the group and epoch of the commit.
+fn apply_proposals(
+    old_tree: &RatchetTree,
+    tree: &mut RatchetTree,
+    proposals: &[SignedProposal],
+    (group_id, epoch): (&[u8], u64),
+    committer: u32,
+    ca: &VerifyingKey,
+) -> Result<Vec<(u32, KeyPackage)>> {
+    for proposal in proposals {
+        if proposal.group_id != group_id {
+            return Err(Error::Malformed("Proposal for a different group"));
+        }
+        if proposal.epoch != epoch {
+            return Err(Error::Malformed("Proposal for a different epoch"));
+        }
+        verify_proposal(old_tree, proposal, ca)?;
+    }
+
+    for proposal in proposals {
+        if let Proposal::Update { leaf_node } = &proposal.proposal {
+            if proposal.sender == committer {
+                return Err(Error::Malformed(
+                    "Committer cannot commit its own Update proposal",
+                ));
+            }
+            tree.set_leaf(proposal.sender, Some(leaf_node.clone()));
+            tree.blank_path(proposal.sender);
+        }
+    }
+    for proposal in proposals {
+        if let Proposal::Remove { leaf } = &proposal.proposal {
+            if *leaf == committer {
+                return Err(Error::Malformed("Committer cannot remove itself"));
+            }
+            tree.leaf(*leaf)?;
+            tree.set_leaf(*leaf, None);
+            tree.blank_path(*leaf);
+        }
+    }
+    let mut added = Vec::new();
+    for proposal in proposals {
+        if let Proposal::Add { key_package } = &proposal.proposal {
+            let leaf = tree.add_leaf(key_package.leaf_node.clone());
+            added.push((leaf, key_package.clone()));
+        }
+    }
+
+    Ok(added)
+}
+
+// Check the signature of a proposal against the tree of its epoch
+fn verify_proposal(tree: &RatchetTree, proposal: &SignedProposal, ca: &VerifyingKey) -> Result<()> {
+    let sender_key = tree.leaf(proposal.sender)?.verifying_key()?;
+    let sig = Signature::from_slice(&proposal.signature)
+        .map_err(|_| Error::Malformed("Invalid signature"))?;
+    if sender_key.verify(&proposal.tbs()?, &sig).is_err() {
+        return Err(Error::Authentication("Proposal not signed by sender"));
+    }
+
+    match &proposal.proposal {
+        Proposal::Add { key_package } => key_package.verify(ca),
+        Proposal::Remove { leaf } => tree.leaf(*leaf).map(|_| ()),
+        Proposal::Update { leaf_node } => {
+            leaf_node.verify(ca)?;
+            if leaf_node.verifying_key != sender_key.to_bytes() {
+                return Err(Error::UntrustedKey(
+                    "Update changes the identity of the member",
+                ));
+            }
+            Ok(())
+        }
+    }
+}
+
+// Derive the node keys of `path` from the path secret of its first node, check them against the tree
+// and store them. Returns the commit secret.
+fn derive_path(
+    tree: &RatchetTree,
+    path: &[u32],
+    mut path_secret: [u8; 32],
+    node_secrets: &mut hashbrown::HashMap<u32, [u8; 32]>,
+) -> Result<[u8; 32]> {
+    for node in path {
+        let node_secret = derive_secret(&path_secret, "node")?;
+        if tree.nodes[*node as usize] != Some(public_key(&node_secret)) {
+            return Err(Error::Authentication(
+                "Path secret does not match the public key",
+            ));
+        }
+        node_secrets.insert(*node, node_secret);
+        path_secret = derive_secret(&path_secret, "path")?;
+    }
+    Ok(path_secret)
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct RatchetTree {
+    // Public keys by node index, leaves at even indices, the leaf count is a power of two
+    nodes: Vec<Option<[u8; 32]>>,
+    // Credentials by leaf index
+    leaves: Vec<Option<LeafNode>>,
+}
+
+impl RatchetTree {
+    fn new(leaf: LeafNode) -> Self {
+        Self {
+            nodes: vec![Some(leaf.encryption_key)],
+            leaves: vec![Some(leaf)],
+        }
+    }
+
+    fn leaf_count(&self) -> u32 {
+        self.leaves.len() as u32
+    }
+
+    fn leaf(&self, index: u32) -> Result<&LeafNode> {
+        match self.leaves.get(index as usize) {
+            Some(Some(v)) => Ok(v),
+            _ => Err(Error::Malformed("No member at this leaf")),
+        }
+    }
+
+    fn set_leaf(&mut self, index: u32, leaf: Option<LeafNode>) {
+        self.nodes[2 * index as usize] = leaf.as_ref().map(|v| v.encryption_key);
+        self.leaves[index as usize] = leaf;
+    }
+
+    fn blank_path(&mut self, index: u32) {
+        for node in direct_path(2 * index, self.leaf_count()) {
+            self.nodes[node as usize] = None;
+        }
+    }
+
+    // Put a new member into the leftmost free leaf, the tree doubles if there is none
+    fn add_leaf(&mut self, leaf: LeafNode) -> u32 {
+        let index = match self.leaves.iter().position(|v| v.is_none()) {
+            Some(v) => v as u32,
+            None => {
+                let count = self.leaves.len();
+                self.leaves.resize(2 * count, None);
+                self.nodes.resize(4 * count - 1, None);
+                count as u32
+            }
+        };
+        self.set_leaf(index, Some(leaf));
+        self.blank_path(index);
+        index
+    }
+
+    // Nodes that cover the subtree of `node` with keys, without the leaves in `excluded`
+    fn resolution(&self, node: u32, excluded: &[u32]) -> Vec<u32> {
+        match self.nodes[node as usize] {
+            Some(_) if level(node) == 0 && excluded.contains(&(node / 2)) => Vec::new(),
+            Some(_) => vec![node],
+            None if level(node) == 0 => Vec::new(),
+            None => {
+                let mut resolution = self.resolution(left(node), excluded);
+                resolution.extend(self.resolution(right(node), excluded));
+                resolution
+            }
+        }
+    }
+
+    fn hash(&self) -> Result<[u8; 32]> {
+        Ok(hash(&[&bson::to_vec(self).map_err(Error::from)?]))
+    }
+}
+
+// Array representation of a left-balanced binary tree, see RFC 9420 appendix C
+
+fn level(node: u32) -> u32 {
+    node.trailing_ones()
+}
+
+fn left(node: u32) -> u32 {
+    node ^ (1 << (level(node) - 1))
+}
+
+fn right(node: u32) -> u32 {
+    node ^ (3 << (level(node) - 1))
+}
+
+fn parent(node: u32) -> u32 {
+    let k = level(node);
+    let b = (node >> (k + 1)) & 1;
+    (node | (1 << k)) ^ (b << (k + 1))
+}
+
+fn root(leaf_count: u32) -> u32 {
+    leaf_count - 1
+}
+
+// Parents of `node` up to and including the root
+fn direct_path(node: u32, leaf_count: u32) -> Vec<u32> {
+    let root = root(leaf_count);
+    let mut path = Vec::new();
+    let mut node = node;
+    while node != root {
+        node = parent(node);
+        path.push(node);
+    }
+    path
+}
+
+fn in_subtree(node: u32, ancestor: u32) -> bool {
+    let span = (1u32 << level(ancestor)) - 1;
+    node + span >= ancestor && node <= ancestor + span
+}
+
+// The child of `parent` that does not contain `node`
+fn copath_child(parent: u32, node: u32) -> u32 {
+    let left = left(parent);
+    match in_subtree(node, left) {
+        true => right(parent),
+        false => left,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct LeafNode {
+    encryption_key: [u8; 32],
+    verifying_key: [u8; 32],
+    // CA signature of the verifying key
+    signing_key_sig: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl LeafNode {
+    fn new(client: &Client, leaf_secret: &[u8; 32]) -> Self {
+        let encryption_key = public_key(leaf_secret);
+        let verifying_key = client.signing_key().verifying_key().to_bytes();
+        let signature = client
+            .signing_key()
+            .sign(&leaf_tbs(&encryption_key, &verifying_key))
+            .to_bytes()
+            .to_vec();
+
+        Self {
+            encryption_key,
+            verifying_key,
+            signing_key_sig: client.signing_key_signature().to_bytes().to_vec(),
+            signature,
+        }
+    }
+
+    fn verifying_key(&self) -> Result<VerifyingKey> {
+        VerifyingKey::from_bytes(&self.verifying_key)
+            .map_err(|_| Error::Malformed("Invalid verifying key"))
+    }
+
+    fn verify(&self, ca: &VerifyingKey) -> Result<()> {
+        let verifying_key = self.verifying_key()?;
+        let sig = Signature::from_slice(&self.signing_key_sig)
+            .map_err(|_| Error::Malformed("Invalid signature"))?;
+        if ca.verify(verifying_key.as_bytes(), &sig).is_err() {
+            return Err(Error::UntrustedKey("Member key not signed by CA"));
+        }
+        let sig = Signature::from_slice(&self.signature)
+            .map_err(|_| Error::Malformed("Invalid signature"))?;
+        if verifying_key
+            .verify(&leaf_tbs(&self.encryption_key, &self.verifying_key), &sig)
+            .is_err()
+        {
+            return Err(Error::Authentication("Leaf not signed by member"));
+        }
+        Ok(())
+    }
+}
+
+fn leaf_tbs(encryption_key: &[u8; 32], verifying_key: &[u8; 32]) -> Vec<u8> {
+    let mut tbs = b"CosmicCipher mls leaf".to_vec();
+    tbs.extend_from_slice(encryption_key);
+    tbs.extend_from_slice(verifying_key);
+    tbs
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct KeyPackage {
+    init_key: [u8; 32],
+    leaf_node: LeafNode,
+    signature: Vec<u8>,
+}
+
+impl KeyPackage {
+    fn tbs(&self) -> Result<Vec<u8>> {
+        let mut tbs = self.clone();
+        tbs.signature = Vec::new();
+        bson::to_vec(&tbs).map_err(Error::from)
+    }
+
+    fn verify(&self, ca: &VerifyingKey) -> Result<()> {
+        self.leaf_node.verify(ca)?;
+        let sig = Signature::from_slice(&self.signature)
+            .map_err(|_| Error::Malformed("Invalid signature"))?;
+        if self
+            .leaf_node
+            .verifying_key()?
+            .verify(&self.tbs()?, &sig)
+            .is_err()
+        {
+            return Err(Error::Authentication("Key package not signed by member"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+enum Proposal {
+    Add { key_package: KeyPackage },
+    Remove { leaf: u32 },
+    Update { leaf_node: LeafNode },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct SignedProposal {
+    group_id: Vec<u8>,
+    epoch: u64,
+    sender: u32,
+    proposal: Proposal,
+    signature: Vec<u8>,
+}
+
+impl SignedProposal {
+    fn tbs(&self) -> Result<Vec<u8>> {
+        let mut tbs = self.clone();
+        tbs.signature = Vec::new();
+        bson::to_vec(&tbs).map_err(Error::from)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct UpdatePathNode {
+    public_key: [u8; 32],
+    // Path secret for each node in the resolution of the copath child
+    encrypted_path_secrets: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct UpdatePath {
+    leaf_node: LeafNode,
+    nodes: Vec<UpdatePathNode>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Commit {
+    group_id: Vec<u8>,
+    // Epoch the commit was made in
+    epoch: u64,
+    sender: u32,
+    proposals: Vec<SignedProposal>,
+    path: UpdatePath,
+    signature: Vec<u8>,
+    confirmation_tag: Vec<u8>,
+}
+
+impl Commit {
+    fn tbs(&self) -> Result<Vec<u8>> {
+        let mut tbs = self.clone();
+        tbs.signature = Vec::new();
+        tbs.confirmation_tag = Vec::new();
+        bson::to_vec(&tbs).map_err(Error::from)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct ApplicationMessage {
+    group_id: Vec<u8>,
+    epoch: u64,
+    sender: u32,
+    generation: u32,
+    // Payload flags, see message::encode_payload. Missing means LZ4 compressed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    flags: Option<u16>,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl ApplicationMessage {
+    fn tbs(&self) -> Result<Vec<u8>> {
+        let mut tbs = self.clone();
+        tbs.signature = Vec::new();
+        bson::to_vec(&tbs).map_err(Error::from)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum MlsMessage {
+    Proposal(SignedProposal),
+    Commit(Commit),
+    Application(ApplicationMessage),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct GroupInfo {
+    group_id: Vec<u8>,
+    #[serde(default)]
+    suite: Option<u8>,
+    epoch: u64,
+    tree: RatchetTree,
+    confirmed_transcript_hash: Vec<u8>,
+    confirmation_tag: Vec<u8>,
+    signer: u32,
+    signature: Vec<u8>,
+}
+
+impl GroupInfo {
+    fn tbs(&self) -> Result<Vec<u8>> {
+        let mut tbs = self.clone();
+        tbs.signature = Vec::new();
+        bson::to_vec(&tbs).map_err(Error::from)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct WelcomeSecrets {
+    joiner_secret: Vec<u8>,
+    // Path secret of the lowest node the committer and the new member share
+    path_secret: Option<Vec<u8>>,
+    group_info: GroupInfo,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Welcome {
+    // Hash of the key package the welcome is encrypted to
+    key_package_ref: Vec<u8>,
+    // Suite of the group, the ciphertext is sealed with it
+    #[serde(default)]
+    suite: Option<u8>,
+    ciphertext: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MlsGroupForExport {
+    owner: Vec<u8>,
+    group_id: Vec<u8>,
+    #[serde(default)]
+    suite: Option<u8>,
+    epoch: u64,
+    own_leaf: u32,
+    tree: RatchetTree,
+    node_secrets: Vec<(u32, Vec<u8>)>,
+    confirmed_transcript_hash: Vec<u8>,
+    interim_transcript_hash: Vec<u8>,
+    init_secret: Vec<u8>,
+    encryption_secret: Vec<u8>,
+    confirmation_key: Vec<u8>,
+    exporter_secret: Vec<u8>,
+    pending_proposals: Vec<SignedProposal>,
+    pending_updates: Vec<Vec<u8>>,
+    generation: u32,
+    seen: Vec<(u32, u32)>,
+    removed: bool,
+}
+
+// Key schedule, HKDF with HMAC-SHA3-256. Labels are prefixed so they cannot collide with other protocols.
+
+type HmacSha3 = Hmac<Sha3_256>;
+
+fn extract(salt: &[u8], ikm: &[u8]) -> Result<[u8; 32]> {
+    let mut mac = <HmacSha3 as Mac>::new_from_slice(salt).map_err(Error::from)?;
+    mac.update(ikm);
+    to_array(&mac.finalize().into_bytes())
+}
+
+fn expand_label(secret: &[u8; 32], label: &str, context: &[u8]) -> Result<[u8; 32]> {
+    let mut mac = <HmacSha3 as Mac>::new_from_slice(secret).map_err(Error::from)?;
+    let label = [b"CosmicCipher mls ", label.as_bytes()].concat();
+    mac.update(&(label.len() as u32).to_be_bytes());
+    mac.update(&label);
+    mac.update(&(context.len() as u32).to_be_bytes());
+    mac.update(context);
+    mac.update(&[1]);
+    to_array(&mac.finalize().into_bytes())
+}
+
+fn derive_secret(secret: &[u8; 32], label: &str) -> Result<[u8; 32]> {
+    expand_label(secret, label, &[])
+}
+
+fn confirmation_tag(confirmation_key: &[u8; 32], transcript: &[u8; 32]) -> Result<Vec<u8>> {
+    let mut mac = <HmacSha3 as Mac>::new_from_slice(confirmation_key).map_err(Error::from)?;
+    mac.update(transcript);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+fn verify_confirmation_tag(
+    confirmation_key: &[u8; 32],
+    transcript: &[u8; 32],
+    tag: &[u8],
+) -> Result<()> {
+    let mut mac = <HmacSha3 as Mac>::new_from_slice(confirmation_key).map_err(Error::from)?;
+    mac.update(transcript);
+    mac.verify_slice(tag)
+        .map_err(|_| Error::Authentication("Confirmation tag mismatch"))
+}
+
+// Length prefixed hash of several parts
+fn hash(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha3_256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+    let mut output = [0u8; 32];
+    output.copy_from_slice(&hasher.finalize());
+    output
+}
+
+fn group_context(
+    group_id: &MlsGroupId,
+    epoch: u64,
+    tree_hash: &[u8; 32],
+    confirmed_transcript_hash: &[u8; 32],
+) -> [u8; 32] {
+    hash(&[
+        b"CosmicCipher mls group context",
+        group_id,
+        &epoch.to_be_bytes(),
+        tree_hash,
+        confirmed_transcript_hash,
+    ])
+}
+
+// Path secrets are encrypted before the transcript of the commit is known
+fn provisional_context(group_id: &MlsGroupId, epoch: u64, tree_hash: &[u8; 32]) -> [u8; 32] {
+    hash(&[
+        b"CosmicCipher mls provisional context",
+        group_id,
+        &epoch.to_be_bytes(),
+        tree_hash,
+    ])
+}
+
+fn public_key(secret: &[u8; 32]) -> [u8; 32] {
+    PublicKey::from(&StaticSecret::from(*secret)).to_bytes()
+}
+
+fn to_array<const N: usize>(data: &[u8]) -> Result<[u8; N]> {
+    data.try_into()
+        .map_err(|_| Error::Malformed("Invalid length"))
+}
+
+fn suite_from_id(id: Option<u8>) -> Result<CipherSuite> {
+    match id {
+        None => Ok(CipherSuite::XChaCha20Poly1305),
+        Some(v) => CipherSuite::from_id(v),
+    }
+}
+
+// Encrypt to an X25519 public key: ephemeral key (32 bytes) || ciphertext.
+// The key is only used once, so the nonce can be fixed.
+fn seal(
+    suite: CipherSuite,
+    csprng: &mut rand_chacha::ChaChaRng,
+    public_key: &[u8; 32],
+    info: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>> {
+    let ephemeral_key = StaticSecret::random_from_rng(csprng);
+    let ephemeral_public = PublicKey::from(&ephemeral_key).to_bytes();
+    let shared_secret = ephemeral_key.diffie_hellman(&PublicKey::from(*public_key));
+    if !shared_secret.was_contributory() {
+        return Err(Error::Malformed("Non-contributory Diffie-Hellman result"));
+    }
+    let key = expand_label(
+        &extract(
+            &[ephemeral_public, *public_key].concat(),
+            shared_secret.as_bytes(),
+        )?,
+        "seal",
+        info,
+    )?;
+
+    let mut buffer = plaintext.to_vec();
+    suite.seal(&key, &vec![0u8; suite.nonce_len()], info, &mut buffer)?;
+
+    let mut output = ephemeral_public.to_vec();
+    output.extend_from_slice(&buffer);
+    Ok(output)
+}
+
+fn open(suite: CipherSuite, secret: &StaticSecret, info: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < 32 {
+        return Err(Error::Malformed("Ciphertext is too short"));
+    }
+    let ephemeral_public = to_array(&data[..32])?;
+    let shared_secret = secret.diffie_hellman(&PublicKey::from(ephemeral_public));
+    if !shared_secret.was_contributory() {
+        return Err(Error::Malformed("Non-contributory Diffie-Hellman result"));
+    }
+    let own_public = PublicKey::from(secret).to_bytes();
+    let key = expand_label(
+        &extract(
+            &[ephemeral_public, own_public].concat(),
+            shared_secret.as_bytes(),
+        )?,
+        "seal",
+        info,
+    )?;
+
+    let mut buffer = data[32..].to_vec();
+    suite.open(&key, &vec![0u8; suite.nonce_len()], info, &mut buffer)?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn application(event: MlsEvent) -> Option<Vec<u8>> {
+        match event {
+            MlsEvent::Application { message, .. } => Some(message),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_tree_math() {
+        // 8 leaves, root 7
+        assert_eq!(direct_path(0, 8), [1, 3, 7]);
+        assert_eq!(direct_path(10, 8), [9, 11, 7]);
+        assert_eq!(copath_child(3, 0), 5);
+        assert_eq!(copath_child(7, 10), 3);
+        assert!(in_subtree(4, 3) && !in_subtree(8, 3));
+        assert_eq!(parent(left(11)), 11);
+        assert_eq!(parent(right(11)), 11);
+        assert!(direct_path(0, 1).is_empty());
+    }
+
+    #[test]
+    fn test_mls_group() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let mut carol = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let mut dave = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+
+        // Alice adds bob and carol in one commit
+        let mut group_a = MlsGroup::create(&mut alice).unwrap();
+        let bob_kp = MlsKeyPackageBundle::new(&mut bob).unwrap();
+        let carol_kp = MlsKeyPackageBundle::new(&mut carol).unwrap();
+        group_a
+            .propose_add(&alice, &bob_kp.key_package().unwrap())
+            .unwrap();
+        group_a
+            .propose_add(&alice, &carol_kp.key_package().unwrap())
+            .unwrap();
+        let output = group_a.commit(&mut alice).unwrap();
+        assert_eq!(output.welcomes.len(), 2);
+        assert!(MlsGroup::join(&carol, &carol_kp, &output.welcomes[0]).is_err());
+        let mut group_b = MlsGroup::join(&bob, &bob_kp, &output.welcomes[0]).unwrap();
+        let mut group_c = MlsGroup::join(&carol, &carol_kp, &output.welcomes[1]).unwrap();
+        assert_eq!(group_b.epoch(), 1);
+        assert_eq!(group_b.members().len(), 3);
+        let secret = group_a.export_secret("test", b"").unwrap();
+        assert_eq!(group_b.export_secret("test", b"").unwrap(), secret);
+        assert_eq!(group_c.export_secret("test", b"").unwrap(), secret);
+
+        let message = group_a.encrypt(&mut alice, b"Hello group").unwrap();
+        assert_eq!(
+            application(group_b.process_message(&bob, &message).unwrap()),
+            Some(b"Hello group".to_vec())
+        );
+        assert_eq!(
+            application(group_c.process_message(&carol, &message).unwrap()),
+            Some(b"Hello group".to_vec())
+        );
+        assert!(group_c.process_message(&carol, &message).is_err());
+
+        // Bob proposes an update and adds dave, carol commits both
+        let update = group_b.propose_update(&mut bob).unwrap();
+        let dave_kp = MlsKeyPackageBundle::new(&mut dave).unwrap();
+        let add = group_b
+            .propose_add(&bob, &dave_kp.key_package().unwrap())
+            .unwrap();
+        for proposal in [&update, &add] {
+            group_a.process_message(&alice, proposal).unwrap();
+            group_c.process_message(&carol, proposal).unwrap();
+        }
+        let output = group_c.commit(&mut carol).unwrap();
+        assert_eq!(
+            group_a.process_message(&alice, &output.commit).unwrap(),
+            MlsEvent::Commit { epoch: 2 }
+        );
+        group_b.process_message(&bob, &output.commit).unwrap();
+        let mut group_d = MlsGroup::join(&dave, &dave_kp, &output.welcomes[0]).unwrap();
+        let secret = group_a.export_secret("test", b"").unwrap();
+        for group in [&group_b, &group_c, &group_d] {
+            assert_eq!(group.export_secret("test", b"").unwrap(), secret);
+        }
+
+        // The state survives an export
+        let export = group_b.export(&mut bob, b"password").unwrap();
+        assert!(MlsGroup::import(&carol, b"password", &export).is_err());
+        let mut group_b = MlsGroup::import(&bob, b"password", &export).unwrap();
+
+        // Dave removes carol, she cannot follow
+        let remove = group_d
+            .propose_remove(&dave, group_c.own_leaf_index())
+            .unwrap();
+        group_a.process_message(&alice, &remove).unwrap();
+        let output = group_d.commit(&mut dave).unwrap();
+        group_a.process_message(&alice, &output.commit).unwrap();
+        group_b.process_message(&bob, &output.commit).unwrap();
+        assert_eq!(
+            group_c.process_message(&carol, &output.commit).unwrap(),
+            MlsEvent::Removed
+        );
+        assert!(group_c.encrypt(&mut carol, b"still here?").is_err());
+        assert_eq!(group_a.members().len(), 3);
+
+        let message = group_b.encrypt(&mut bob, b"Without carol").unwrap();
+        assert_eq!(
+            application(group_a.process_message(&alice, &message).unwrap()),
+            Some(b"Without carol".to_vec())
+        );
+        assert_eq!(
+            application(group_d.process_message(&dave, &message).unwrap()),
+            Some(b"Without carol".to_vec())
+        );
+        assert!(group_c.process_message(&carol, &message).is_err());
+
+        // A forged commit does not change the state
+        let output = group_a.commit(&mut alice).unwrap();
+        let mut forged: MlsMessage = bson::from_slice(&output.commit).unwrap();
+        if let MlsMessage::Commit(commit) = &mut forged {
+            commit.confirmation_tag[0] ^= 1;
+        }
+        let forged = bson::to_vec(&forged).unwrap();
+        assert!(group_b.process_message(&bob, &forged).is_err());
+        assert_eq!(group_b.epoch(), 3);
+        group_b.process_message(&bob, &output.commit).unwrap();
+        assert_eq!(
+            group_b.export_secret("test", b"").unwrap(),
+            group_a.export_secret("test", b"").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_mls_rejects_stale_proposal() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let mut group_a = MlsGroup::create(&mut alice).unwrap();
+        let bob_kp = MlsKeyPackageBundle::new(&mut bob).unwrap();
+        group_a
+            .propose_add(&alice, &bob_kp.key_package().unwrap())
+            .unwrap();
+        let output = group_a.commit(&mut alice).unwrap();
+        let mut group_b = MlsGroup::join(&bob, &bob_kp, &output.welcomes[0]).unwrap();
+
+        // Bob's proposal from epoch 1 still has a valid signature in epoch 2
+        let update = group_b.propose_update(&mut bob).unwrap();
+        let stale = match bson::from_slice(&update).unwrap() {
+            MlsMessage::Proposal(proposal) => Some(proposal),
+            _ => None,
+        }
+        .unwrap();
+        group_a.commit(&mut alice).unwrap();
+        assert_eq!(group_a.epoch(), 2);
+
+        let ca = alice.ca_verifying_key();
+        let mut tree = group_a.tree.clone();
+        let stale = [stale];
+        let own_leaf = group_a.own_leaf;
+        assert!(apply_proposals(
+            &group_a.tree,
+            &mut tree,
+            &stale,
+            (&group_a.group_id, 1),
+            own_leaf,
+            ca
+        )
+        .is_ok());
+        let mut tree = group_a.tree.clone();
+        assert!(matches!(
+            apply_proposals(
+                &group_a.tree,
+                &mut tree,
+                &stale,
+                (&group_a.group_id, 2),
+                own_leaf,
+                ca
+            ),
+            Err(Error::Malformed(_))
+        ));
+        assert!(matches!(
+            apply_proposals(
+                &group_a.tree,
+                &mut tree,
+                &stale,
+                (&[0u8; 16], 1),
+                own_leaf,
+                ca
+            ),
+            Err(Error::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_mls_rejects_foreign_ca() {
+        let mut alice = Client::new_user();
+        let mut mallory = Client::new_user();
+
+        let mut group = MlsGroup::create(&mut alice).unwrap();
+        let key_package = MlsKeyPackageBundle::new(&mut mallory).unwrap();
+        assert!(group
+            .propose_add(&alice, &key_package.key_package().unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn test_mls_limits() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let mut group_a = MlsGroup::create(&mut alice).unwrap();
+        let bob_kp = MlsKeyPackageBundle::new(&mut bob).unwrap();
+        group_a
+            .propose_add(&alice, &bob_kp.key_package().unwrap())
+            .unwrap();
+        let output = group_a.commit(&mut alice).unwrap();
+        let mut group_b = MlsGroup::join(&bob, &bob_kp, &output.welcomes[0]).unwrap();
+
+        bob.set_limits(Limits {
+            max_plaintext_size: 1024,
+            max_decompressed_size: 4096,
+            max_document_size: 1 << 20,
+        });
+        // Compresses to less than the limits but expands beyond them
+        let bomb = group_a.encrypt(&mut alice, &[0u8; 1 << 16]).unwrap();
+        assert!(matches!(
+            group_b.process_message(&bob, &bomb),
+            Err(Error::TooLarge(_))
+        ));
+
+        bob.set_limits(Limits {
+            max_document_size: 256,
+            ..Default::default()
+        });
+        let message = group_a.encrypt(&mut alice, b"hello").unwrap();
+        assert_eq!(
+            group_b.process_message(&bob, &message).unwrap_err(),
+            Error::TooLarge(crate::limits::DOCUMENT_TOO_LARGE)
+        );
+        assert!(matches!(
+            MlsGroup::join(&bob, &bob_kp, &output.welcomes[0]),
+            Err(Error::TooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_mls_suites() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let mut carol = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        alice
+            .set_cipher_suites(&[CipherSuite::Aes256GcmSiv])
+            .unwrap();
+        carol
+            .set_cipher_suites(&[CipherSuite::XChaCha20Poly1305])
+            .unwrap();
+
+        // The group keeps the creator's suite, whatever the members prefer
+        let mut group_a = MlsGroup::create(&mut alice).unwrap();
+        let bob_kp = MlsKeyPackageBundle::new(&mut bob).unwrap();
+        group_a
+            .propose_add(&alice, &bob_kp.key_package().unwrap())
+            .unwrap();
+        let output = group_a.commit(&mut alice).unwrap();
+        let mut group_b = MlsGroup::join(&bob, &bob_kp, &output.welcomes[0]).unwrap();
+        assert_eq!(group_b.suite, CipherSuite::Aes256GcmSiv);
+        let message = group_b.encrypt(&mut bob, b"siv").unwrap();
+        assert_eq!(
+            application(group_a.process_message(&alice, &message).unwrap()).unwrap(),
+            b"siv"
+        );
+
+        // Joiners only accept their own suites
+        let carol_kp = MlsKeyPackageBundle::new(&mut carol).unwrap();
+        group_b
+            .propose_add(&bob, &carol_kp.key_package().unwrap())
+            .unwrap();
+        let output = group_b.commit(&mut bob).unwrap();
+        assert_eq!(
+            MlsGroup::join(&carol, &carol_kp, &output.welcomes[0]).err(),
+            Some(Error::Unsupported("Cipher suite not allowed"))
+        );
+        group_a.process_message(&alice, &output.commit).unwrap();
+
+        // The suite survives an export
+        let export = group_a.export(&mut alice, b"password").unwrap();
+        let imported = MlsGroup::import(&alice, b"password", &export).unwrap();
+        assert_eq!(imported.suite, CipherSuite::Aes256GcmSiv);
+    }
+}
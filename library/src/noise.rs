@@ -0,0 +1,882 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// Noise Protocol Framework (revision 34) handshakes
+// https://noiseprotocol.org/noise.html
+//
+// Supported: the XX, IK and NK patterns with 25519, ChaChaPoly and BLAKE2s or SHA256.
+//
+// The static keys are the identity's dedicated X25519 keys, see identity.
+// IdentityHandshake sends the Ed25519 verifying key, its CA signature and its signature over the
+// static key inside the first encrypted handshake payload, so the peer can check that the static
+// key is certified.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use blake2::Blake2s256;
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, KeyInit};
+use ed25519_dalek::pkcs8::{DecodePublicKey, EncodePublicKey};
+use ed25519_dalek::{Signature, VerifyingKey};
+use hmac::{Mac, SimpleHmac};
+use rand_chacha::rand_core::SeedableRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::error::{Error, Result};
+use crate::identity::Certificate;
+use crate::limits::Limits;
+
+pub const MAX_MESSAGE_LEN: usize = 65535;
+const HASH_LEN: usize = 32;
+const DH_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandshakePattern {
+    XX,
+    IK,
+    NK,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoiseHash {
+    Blake2s,
+    Sha256,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Token {
+    E,
+    S,
+    EE,
+    ES,
+    SE,
+    SS,
+}
+
+impl HandshakePattern {
+    fn name(&self) -> &'static str {
+        match self {
+            HandshakePattern::XX => "XX",
+            HandshakePattern::IK => "IK",
+            HandshakePattern::NK => "NK",
+        }
+    }
+
+    // The responder static key is known in advance for IK and NK
+    fn responder_static_premessage(&self) -> bool {
+        matches!(self, HandshakePattern::IK | HandshakePattern::NK)
+    }
+
+    fn initiator_has_static(&self) -> bool {
+        matches!(self, HandshakePattern::XX | HandshakePattern::IK)
+    }
+
+    fn messages(&self) -> &'static [&'static [Token]] {
+        match self {
+            HandshakePattern::XX => &[
+                &[Token::E],
+                &[Token::E, Token::EE, Token::S, Token::ES],
+                &[Token::S, Token::SE],
+            ],
+            HandshakePattern::IK => &[
+                &[Token::E, Token::ES, Token::S, Token::SS],
+                &[Token::E, Token::EE, Token::SE],
+            ],
+            HandshakePattern::NK => &[&[Token::E, Token::ES], &[Token::E, Token::EE]],
+        }
+    }
+
+    /// Index of the first handshake message in which `initiator`'s payload is encrypted
+    /// and its static key is already known to the peer.
+    /// This is where an identity certificate can be sent without leaking it.
+    pub fn credential_message(&self, initiator: bool) -> Option<usize> {
+        match (self, initiator) {
+            (HandshakePattern::XX, true) => Some(2),
+            (HandshakePattern::XX, false) => Some(1),
+            (HandshakePattern::IK, true) => Some(0),
+            (HandshakePattern::IK, false) => Some(1),
+            (HandshakePattern::NK, true) => None,
+            (HandshakePattern::NK, false) => Some(1),
+        }
+    }
+}
+
+impl NoiseHash {
+    fn name(&self) -> &'static str {
+        match self {
+            NoiseHash::Blake2s => "BLAKE2s",
+            NoiseHash::Sha256 => "SHA256",
+        }
+    }
+
+    fn hash(&self, parts: &[&[u8]]) -> [u8; HASH_LEN] {
+        match self {
+            NoiseHash::Blake2s => digest_parts::<Blake2s256>(parts),
+            NoiseHash::Sha256 => digest_parts::<Sha256>(parts),
+        }
+    }
+
+    fn hmac(&self, key: &[u8], parts: &[&[u8]]) -> Result<[u8; HASH_LEN]> {
+        match self {
+            NoiseHash::Blake2s => hmac_parts::<Blake2s256>(key, parts),
+            NoiseHash::Sha256 => hmac_parts::<Sha256>(key, parts),
+        }
+    }
+
+    fn hkdf2(&self, ck: &[u8], ikm: &[u8]) -> Result<([u8; HASH_LEN], [u8; HASH_LEN])> {
+        let temp_key = self.hmac(ck, &[ikm])?;
+        let output1 = self.hmac(&temp_key, &[&[0x01]])?;
+        let output2 = self.hmac(&temp_key, &[&output1, &[0x02]])?;
+        Ok((output1, output2))
+    }
+}
+
+fn digest_parts<D: Digest>(parts: &[&[u8]]) -> [u8; HASH_LEN] {
+    let mut hasher = D::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    let mut out = [0u8; HASH_LEN];
+    out.copy_from_slice(&hasher.finalize()[..HASH_LEN]);
+    out
+}
+
+fn hmac_parts<D>(key: &[u8], parts: &[&[u8]]) -> Result<[u8; HASH_LEN]>
+where
+    D: Digest + hmac::digest::core_api::BlockSizeUser,
+{
+    let mut mac = <SimpleHmac<D> as Mac>::new_from_slice(key).map_err(Error::from)?;
+    for part in parts {
+        mac.update(part);
+    }
+    let mut out = [0u8; HASH_LEN];
+    out.copy_from_slice(&mac.finalize().into_bytes()[..HASH_LEN]);
+    Ok(out)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NoiseParams {
+    pub pattern: HandshakePattern,
+    pub hash: NoiseHash,
+}
+
+impl NoiseParams {
+    pub fn new(pattern: HandshakePattern, hash: NoiseHash) -> Self {
+        Self { pattern, hash }
+    }
+
+    /// The protocol name, e.g. `Noise_XX_25519_ChaChaPoly_BLAKE2s`
+    pub fn name(&self) -> String {
+        let mut name = String::from("Noise_");
+        name.push_str(self.pattern.name());
+        name.push_str("_25519_ChaChaPoly_");
+        name.push_str(self.hash.name());
+        name
+    }
+}
+
+fn dh(secret: &StaticSecret, public: &PublicKey) -> Result<[u8; DH_LEN]> {
+    let shared = secret.diffie_hellman(public);
+    if !shared.was_contributory() {
+        return Err(Error::Malformed("Non-contributory Diffie-Hellman result"));
+    }
+    Ok(shared.to_bytes())
+}
+
+struct CipherState {
+    k: Option<[u8; 32]>,
+    n: u64,
+}
+
+impl CipherState {
+    fn empty() -> Self {
+        Self { k: None, n: 0 }
+    }
+
+    fn with_key(k: [u8; 32]) -> Self {
+        Self { k: Some(k), n: 0 }
+    }
+
+    fn nonce(&self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.n.to_le_bytes());
+        nonce
+    }
+
+    fn encrypt_with_ad(&mut self, ad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        let k = match self.k {
+            None => return Ok(plaintext.to_vec()),
+            Some(v) => v,
+        };
+        // 2^64-1 is reserved
+        if self.n == u64::MAX {
+            return Err(Error::InvalidState("Noise nonce exhausted"));
+        }
+
+        let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(&k));
+        let nonce = self.nonce();
+        let mut buffer = plaintext.to_vec();
+        cipher
+            .encrypt_in_place(GenericArray::from_slice(&nonce), ad, &mut buffer)
+            .map_err(|_| Error::Internal("Encryption failed"))?;
+        self.n += 1;
+        Ok(buffer)
+    }
+
+    fn decrypt_with_ad(&mut self, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let k = match self.k {
+            None => return Ok(ciphertext.to_vec()),
+            Some(v) => v,
+        };
+        if self.n == u64::MAX {
+            return Err(Error::InvalidState("Noise nonce exhausted"));
+        }
+
+        let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(&k));
+        let nonce = self.nonce();
+        let mut buffer = ciphertext.to_vec();
+        cipher
+            .decrypt_in_place(GenericArray::from_slice(&nonce), ad, &mut buffer)
+            .map_err(Error::from)?;
+        self.n += 1;
+        Ok(buffer)
+    }
+}
+
+struct SymmetricState {
+    hash: NoiseHash,
+    cipher: CipherState,
+    ck: [u8; HASH_LEN],
+    h: [u8; HASH_LEN],
+}
+
+impl SymmetricState {
+    fn initialize(params: &NoiseParams) -> Self {
+        let name = params.name();
+        let h = if name.len() <= HASH_LEN {
+            let mut h = [0u8; HASH_LEN];
+            h[..name.len()].copy_from_slice(name.as_bytes());
+            h
+        } else {
+            params.hash.hash(&[name.as_bytes()])
+        };
+
+        Self {
+            hash: params.hash,
+            cipher: CipherState::empty(),
+            ck: h,
+            h,
+        }
+    }
+
+    fn mix_key(&mut self, ikm: &[u8]) -> Result<()> {
+        let (ck, temp_k) = self.hash.hkdf2(&self.ck, ikm)?;
+        self.ck = ck;
+        self.cipher = CipherState::with_key(temp_k);
+        Ok(())
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.h = self.hash.hash(&[&self.h, data]);
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let h = self.h;
+        let ciphertext = self.cipher.encrypt_with_ad(&h, plaintext)?;
+        self.mix_hash(&ciphertext);
+        Ok(ciphertext)
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let h = self.h;
+        let plaintext = self.cipher.decrypt_with_ad(&h, ciphertext)?;
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    fn split(&self) -> Result<(CipherState, CipherState)> {
+        let (k1, k2) = self.hash.hkdf2(&self.ck, &[])?;
+        Ok((CipherState::with_key(k1), CipherState::with_key(k2)))
+    }
+
+    // Not part of the Noise specification: an additional key derived from the final
+    // chaining key, used when a completed handshake is installed as a Client session
+    fn export_key(&self) -> Result<[u8; 32]> {
+        let temp_key = self.hash.hmac(&self.ck, &[b"CosmicCipher session key"])?;
+        self.hash.hmac(&temp_key, &[&[0x01]])
+    }
+}
+
+pub struct HandshakeState {
+    params: NoiseParams,
+    initiator: bool,
+    symmetric: SymmetricState,
+    s: Option<StaticSecret>,
+    e: Option<StaticSecret>,
+    rs: Option<PublicKey>,
+    re: Option<PublicKey>,
+    message_index: usize,
+}
+
+impl HandshakeState {
+    /// Create a handshake state.
+    /// `s` is our static key, `rs` the peer's static key if the pattern requires it up front.
+    pub fn new(
+        params: NoiseParams,
+        initiator: bool,
+        prologue: &[u8],
+        s: Option<StaticSecret>,
+        rs: Option<PublicKey>,
+    ) -> Result<Self> {
+        let needs_s = if initiator {
+            params.pattern.initiator_has_static()
+        } else {
+            true
+        };
+        if needs_s && s.is_none() {
+            return Err(Error::InvalidArgument(
+                "Static key required for this handshake pattern",
+            ));
+        }
+        if initiator && params.pattern.responder_static_premessage() && rs.is_none() {
+            return Err(Error::InvalidArgument(
+                "Remote static key required for this handshake pattern",
+            ));
+        }
+
+        let mut symmetric = SymmetricState::initialize(&params);
+        symmetric.mix_hash(prologue);
+
+        if params.pattern.responder_static_premessage() {
+            let responder_static = if initiator {
+                rs
+            } else {
+                s.as_ref().map(PublicKey::from)
+            };
+            match responder_static {
+                None => return Err(Error::Malformed("Responder static key missing")),
+                Some(v) => symmetric.mix_hash(v.as_bytes()),
+            }
+        }
+
+        Ok(Self {
+            params,
+            initiator,
+            symmetric,
+            s: if needs_s { s } else { None },
+            e: None,
+            rs,
+            re: None,
+            message_index: 0,
+        })
+    }
+
+    pub fn params(&self) -> NoiseParams {
+        self.params
+    }
+
+    pub fn is_initiator(&self) -> bool {
+        self.initiator
+    }
+
+    /// Index of the next handshake message to be written or read
+    pub fn message_index(&self) -> usize {
+        self.message_index
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.message_index >= self.params.pattern.messages().len()
+    }
+
+    /// True if it is our turn to call `write_message`
+    pub fn is_my_turn(&self) -> bool {
+        !self.is_finished() && self.message_index.is_multiple_of(2) == self.initiator
+    }
+
+    pub fn remote_static(&self) -> Option<PublicKey> {
+        self.rs
+    }
+
+    pub fn handshake_hash(&self) -> [u8; HASH_LEN] {
+        self.symmetric.h
+    }
+
+    fn tokens(&self) -> Result<&'static [Token]> {
+        match self.params.pattern.messages().get(self.message_index) {
+            None => Err(Error::InvalidState("Handshake already finished")),
+            Some(v) => Ok(v),
+        }
+    }
+
+    fn static_key(&self) -> Result<&StaticSecret> {
+        match &self.s {
+            None => Err(Error::Malformed("Static key missing")),
+            Some(v) => Ok(v),
+        }
+    }
+
+    fn ephemeral_key(&self) -> Result<&StaticSecret> {
+        match &self.e {
+            None => Err(Error::Malformed("Ephemeral key missing")),
+            Some(v) => Ok(v),
+        }
+    }
+
+    fn remote_static_key(&self) -> Result<&PublicKey> {
+        match &self.rs {
+            None => Err(Error::Malformed("Remote static key missing")),
+            Some(v) => Ok(v),
+        }
+    }
+
+    fn remote_ephemeral_key(&self) -> Result<&PublicKey> {
+        match &self.re {
+            None => Err(Error::Malformed("Remote ephemeral key missing")),
+            Some(v) => Ok(v),
+        }
+    }
+
+    fn mix_dh(&mut self, token: Token) -> Result<()> {
+        // es means initiator ephemeral with responder static, regardless of who computes it
+        let shared = match (token, self.initiator) {
+            (Token::EE, _) => dh(self.ephemeral_key()?, self.remote_ephemeral_key()?)?,
+            (Token::ES, true) => dh(self.ephemeral_key()?, self.remote_static_key()?)?,
+            (Token::ES, false) => dh(self.static_key()?, self.remote_ephemeral_key()?)?,
+            (Token::SE, true) => dh(self.static_key()?, self.remote_ephemeral_key()?)?,
+            (Token::SE, false) => dh(self.ephemeral_key()?, self.remote_static_key()?)?,
+            (Token::SS, _) => dh(self.static_key()?, self.remote_static_key()?)?,
+            _ => return Err(Error::Malformed("Not a Diffie-Hellman token")),
+        };
+        self.symmetric.mix_key(&shared)
+    }
+
+    pub fn write_message(&mut self, payload: &[u8]) -> Result<Vec<u8>> {
+        if !self.is_my_turn() {
+            return Err(Error::InvalidState(
+                "Not our turn to write a handshake message",
+            ));
+        }
+
+        let mut message = Vec::new();
+        for token in self.tokens()? {
+            match token {
+                Token::E => {
+                    let csprng = rand_chacha::ChaChaRng::from_entropy();
+                    let e = StaticSecret::random_from_rng(csprng);
+                    let e_pub = PublicKey::from(&e);
+                    message.extend_from_slice(e_pub.as_bytes());
+                    self.symmetric.mix_hash(e_pub.as_bytes());
+                    self.e = Some(e);
+                }
+                Token::S => {
+                    let s_pub = PublicKey::from(self.static_key()?);
+                    let encrypted = self.symmetric.encrypt_and_hash(s_pub.as_bytes())?;
+                    message.extend_from_slice(&encrypted);
+                }
+                dh_token => self.mix_dh(*dh_token)?,
+            }
+        }
+
+        let encrypted = self.symmetric.encrypt_and_hash(payload)?;
+        message.extend_from_slice(&encrypted);
+        if message.len() > MAX_MESSAGE_LEN {
+            return Err(Error::TooLarge("Noise message too long"));
+        }
+
+        self.message_index += 1;
+        Ok(message)
+    }
+
+    pub fn read_message(&mut self, message: &[u8]) -> Result<Vec<u8>> {
+        if self.is_finished() || self.is_my_turn() {
+            return Err(Error::InvalidState("Not expecting a handshake message"));
+        }
+        if message.len() > MAX_MESSAGE_LEN {
+            return Err(Error::TooLarge("Noise message too long"));
+        }
+
+        let mut rest = message;
+        for token in self.tokens()? {
+            match token {
+                Token::E => {
+                    if rest.len() < DH_LEN {
+                        return Err(Error::Malformed("Noise message truncated"));
+                    }
+                    let (re, tail) = rest.split_at(DH_LEN);
+                    let mut re_bytes = [0u8; DH_LEN];
+                    re_bytes.copy_from_slice(re);
+                    self.symmetric.mix_hash(&re_bytes);
+                    self.re = Some(PublicKey::from(re_bytes));
+                    rest = tail;
+                }
+                Token::S => {
+                    let len = if self.symmetric.cipher.k.is_some() {
+                        DH_LEN + TAG_LEN
+                    } else {
+                        DH_LEN
+                    };
+                    if rest.len() < len {
+                        return Err(Error::Malformed("Noise message truncated"));
+                    }
+                    let (encrypted, tail) = rest.split_at(len);
+                    let rs = self.symmetric.decrypt_and_hash(encrypted)?;
+                    let mut rs_bytes = [0u8; DH_LEN];
+                    rs_bytes.copy_from_slice(&rs);
+                    self.rs = Some(PublicKey::from(rs_bytes));
+                    rest = tail;
+                }
+                dh_token => self.mix_dh(*dh_token)?,
+            }
+        }
+
+        let payload = self.symmetric.decrypt_and_hash(rest)?;
+        self.message_index += 1;
+        Ok(payload)
+    }
+
+    pub fn into_transport(self) -> Result<TransportState> {
+        if !self.is_finished() {
+            return Err(Error::InvalidState("Handshake not finished"));
+        }
+
+        let (c1, c2) = self.symmetric.split()?;
+        let (send, recv) = if self.initiator { (c1, c2) } else { (c2, c1) };
+
+        Ok(TransportState {
+            send,
+            recv,
+            handshake_hash: self.symmetric.h,
+            session_key: self.symmetric.export_key()?,
+            remote_static: self.rs,
+            remote_verifying_key: None,
+            initiator: self.initiator,
+        })
+    }
+}
+
+pub struct TransportState {
+    send: CipherState,
+    recv: CipherState,
+    handshake_hash: [u8; HASH_LEN],
+    session_key: [u8; 32],
+    remote_static: Option<PublicKey>,
+    remote_verifying_key: Option<VerifyingKey>,
+    initiator: bool,
+}
+
+impl TransportState {
+    pub fn write_message(&mut self, payload: &[u8]) -> Result<Vec<u8>> {
+        if payload.len() + TAG_LEN > MAX_MESSAGE_LEN {
+            return Err(Error::TooLarge("Noise message too long"));
+        }
+        self.send.encrypt_with_ad(&[], payload)
+    }
+
+    pub fn read_message(&mut self, message: &[u8]) -> Result<Vec<u8>> {
+        if message.len() > MAX_MESSAGE_LEN {
+            return Err(Error::TooLarge("Noise message too long"));
+        }
+        self.recv.decrypt_with_ad(&[], message)
+    }
+
+    pub fn handshake_hash(&self) -> [u8; HASH_LEN] {
+        self.handshake_hash
+    }
+
+    pub fn remote_static(&self) -> Option<PublicKey> {
+        self.remote_static
+    }
+
+    /// The peer's CA certified identity, if the handshake was an IdentityHandshake
+    /// and the peer authenticated itself
+    pub fn remote_verifying_key(&self) -> Option<VerifyingKey> {
+        self.remote_verifying_key
+    }
+
+    pub fn is_initiator(&self) -> bool {
+        self.initiator
+    }
+
+    pub(crate) fn session_key(&self) -> [u8; 32] {
+        self.session_key
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct IdentityPayload {
+    verifying_key: Option<Vec<u8>>,
+    signing_key_sig: Option<Vec<u8>>,
+    static_key_sig: Option<Vec<u8>>,
+    payload: Vec<u8>,
+}
+
+pub struct IdentityHandshake {
+    state: HandshakeState,
+    // Only set if the pattern sends our credential
+    certificate: Option<Certificate>,
+    ca_verifying_key: VerifyingKey,
+    remote_verifying_key: Option<VerifyingKey>,
+    limits: Limits,
+}
+
+impl IdentityHandshake {
+    /// Use `Client::noise_handshake` to create one from an identity
+    pub(crate) fn new(
+        state: HandshakeState,
+        certificate: Certificate,
+        ca_verifying_key: VerifyingKey,
+        limits: Limits,
+    ) -> Self {
+        let sends_credential = state
+            .params
+            .pattern
+            .credential_message(state.initiator)
+            .is_some();
+        Self {
+            state,
+            certificate: sends_credential.then_some(certificate),
+            ca_verifying_key,
+            remote_verifying_key: None,
+            limits,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.is_finished()
+    }
+
+    pub fn is_my_turn(&self) -> bool {
+        self.state.is_my_turn()
+    }
+
+    pub fn remote_verifying_key(&self) -> Option<VerifyingKey> {
+        self.remote_verifying_key
+    }
+
+    pub fn write_message(&mut self, payload: &[u8]) -> Result<Vec<u8>> {
+        let credential_message = self
+            .state
+            .params
+            .pattern
+            .credential_message(self.state.initiator);
+
+        let mut identity_payload = IdentityPayload {
+            verifying_key: None,
+            signing_key_sig: None,
+            static_key_sig: None,
+            payload: payload.to_vec(),
+        };
+        if credential_message == Some(self.state.message_index) {
+            if let Some(certificate) = &self.certificate {
+                identity_payload.verifying_key = Some(
+                    certificate
+                        .verifying_key
+                        .to_public_key_der()
+                        .map_err(|_| Error::Internal("Could not encode key"))?
+                        .as_bytes()
+                        .to_vec(),
+                );
+                identity_payload.signing_key_sig = Some(certificate.signature.to_bytes().to_vec());
+                identity_payload.static_key_sig =
+                    Some(certificate.static_key_signature.to_bytes().to_vec());
+            }
+        }
+
+        let serialized = bson::to_vec(&identity_payload).map_err(Error::from)?;
+        self.state.write_message(&serialized)
+    }
+
+    pub fn read_message(&mut self, message: &[u8]) -> Result<Vec<u8>> {
+        let index = self.state.message_index;
+        let serialized = self.state.read_message(message)?;
+        let identity_payload: IdentityPayload = self.limits.parse_bson(&serialized)?;
+
+        let credential_message = self
+            .state
+            .params
+            .pattern
+            .credential_message(!self.state.initiator);
+        match (
+            credential_message == Some(index),
+            identity_payload.verifying_key,
+            identity_payload.signing_key_sig,
+            identity_payload.static_key_sig,
+        ) {
+            (true, Some(vk), Some(sig), Some(static_sig)) => {
+                let static_key = match self.state.remote_static() {
+                    None => return Err(Error::InvalidState("Peer static key not known yet")),
+                    Some(v) => v,
+                };
+                let certificate = Certificate {
+                    verifying_key: VerifyingKey::from_public_key_der(&vk).map_err(Error::from)?,
+                    signature: Signature::from_slice(&sig)
+                        .map_err(|_| Error::Malformed("Invalid signature"))?,
+                    static_key,
+                    static_key_signature: Signature::from_slice(&static_sig)
+                        .map_err(|_| Error::Malformed("Invalid signature"))?,
+                };
+                // The identity signature binds the Noise static key to the certified identity
+                certificate.verify(&self.ca_verifying_key)?;
+                self.remote_verifying_key = Some(certificate.verifying_key);
+            }
+            (true, _, _, _) => return Err(Error::Malformed("Peer credential missing")),
+            (false, None, None, None) => {}
+            (false, _, _, _) => return Err(Error::Malformed("Unexpected peer credential")),
+        }
+
+        Ok(identity_payload.payload)
+    }
+
+    pub fn into_transport(self) -> Result<TransportState> {
+        let mut transport = self.state.into_transport()?;
+        transport.remote_verifying_key = self.remote_verifying_key;
+        Ok(transport)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn handshake(
+        params: NoiseParams,
+        initiator_s: Option<StaticSecret>,
+        responder_s: StaticSecret,
+    ) -> (TransportState, TransportState) {
+        let rs = if params.pattern.responder_static_premessage() {
+            Some(PublicKey::from(&responder_s))
+        } else {
+            None
+        };
+        let mut initiator =
+            HandshakeState::new(params, true, b"prologue", initiator_s, rs).unwrap();
+        let mut responder =
+            HandshakeState::new(params, false, b"prologue", Some(responder_s), None).unwrap();
+
+        while !initiator.is_finished() {
+            let (writer, reader) = if initiator.is_my_turn() {
+                (&mut initiator, &mut responder)
+            } else {
+                (&mut responder, &mut initiator)
+            };
+            let message = writer.write_message(b"payload").unwrap();
+            assert_eq!(reader.read_message(&message).unwrap(), b"payload");
+        }
+        assert!(responder.is_finished());
+        assert_eq!(initiator.handshake_hash(), responder.handshake_hash());
+
+        (
+            initiator.into_transport().unwrap(),
+            responder.into_transport().unwrap(),
+        )
+    }
+
+    fn random_static() -> StaticSecret {
+        StaticSecret::random_from_rng(rand_chacha::ChaChaRng::from_entropy())
+    }
+
+    #[test]
+    fn test_patterns() {
+        for pattern in [
+            HandshakePattern::XX,
+            HandshakePattern::IK,
+            HandshakePattern::NK,
+        ] {
+            for hash in [NoiseHash::Blake2s, NoiseHash::Sha256] {
+                let params = NoiseParams::new(pattern, hash);
+                let initiator_s = if pattern.initiator_has_static() {
+                    Some(random_static())
+                } else {
+                    None
+                };
+                let (mut i, mut r) = handshake(params, initiator_s, random_static());
+
+                let ct = i.write_message(b"Hello World!").unwrap();
+                assert_eq!(r.read_message(&ct).unwrap(), b"Hello World!");
+                let ct = r.write_message(b"Hello back").unwrap();
+                assert_eq!(i.read_message(&ct).unwrap(), b"Hello back");
+                assert_eq!(i.session_key(), r.session_key());
+            }
+        }
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        let params = NoiseParams::new(HandshakePattern::XX, NoiseHash::Blake2s);
+        let mut responder =
+            HandshakeState::new(params, false, b"", Some(random_static()), None).unwrap();
+        assert!(responder.write_message(b"").is_err());
+
+        let mut initiator =
+            HandshakeState::new(params, true, b"", Some(random_static()), None).unwrap();
+        assert!(initiator.read_message(&[0u8; 32]).is_err());
+        assert!(initiator.into_transport().is_err());
+    }
+
+    #[test]
+    fn test_snow_interop() {
+        for (pattern, hash) in [
+            (HandshakePattern::XX, NoiseHash::Blake2s),
+            (HandshakePattern::IK, NoiseHash::Sha256),
+            (HandshakePattern::NK, NoiseHash::Blake2s),
+        ] {
+            let params = NoiseParams::new(pattern, hash);
+            let snow_params: snow::params::NoiseParams = params.name().parse().unwrap();
+
+            let responder_keys = snow::Builder::new(snow_params.clone())
+                .generate_keypair()
+                .unwrap();
+            let mut responder = snow::Builder::new(snow_params)
+                .local_private_key(&responder_keys.private)
+                .prologue(b"interop")
+                .build_responder()
+                .unwrap();
+
+            let mut rs = [0u8; 32];
+            rs.copy_from_slice(&responder_keys.public);
+            let mut initiator = HandshakeState::new(
+                params,
+                true,
+                b"interop",
+                Some(random_static()),
+                Some(PublicKey::from(rs)),
+            )
+            .unwrap();
+
+            let mut buf = [0u8; MAX_MESSAGE_LEN];
+            while !initiator.is_finished() {
+                if initiator.is_my_turn() {
+                    let message = initiator.write_message(b"from us").unwrap();
+                    let len = responder.read_message(&message, &mut buf).unwrap();
+                    assert_eq!(&buf[..len], b"from us");
+                } else {
+                    let len = responder.write_message(b"from snow", &mut buf).unwrap();
+                    assert_eq!(initiator.read_message(&buf[..len]).unwrap(), b"from snow");
+                }
+            }
+            assert!(responder.is_handshake_finished());
+            assert_eq!(
+                initiator.handshake_hash().as_slice(),
+                responder.get_handshake_hash()
+            );
+
+            let mut ours = initiator.into_transport().unwrap();
+            let mut theirs = responder.into_transport_mode().unwrap();
+            let ct = ours.write_message(b"transport").unwrap();
+            let len = theirs.read_message(&ct, &mut buf).unwrap();
+            assert_eq!(&buf[..len], b"transport");
+            let len = theirs.write_message(b"reply", &mut buf).unwrap();
+            assert_eq!(ours.read_message(&buf[..len]).unwrap(), b"reply");
+        }
+    }
+}
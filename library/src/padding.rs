@@ -0,0 +1,259 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// Padding of message payloads, applied after compression and before encryption
+//
+// payload || 0x80 || 0x00 ... (ISO/IEC 7816-4), so the padding is removed by dropping
+// trailing zeros and the marker. Padded messages set FLAG_PADDED in the message header.
+// Padmé is from "Reducing Metadata Leakage from Encrypted Files and Communication with PURBs",
+// it leaks O(log log n) bits of the length with at most 12% overhead.
+
+use alloc::vec::Vec;
+use rand_chacha::rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+const MARKER: u8 = 0x80;
+
+/// How payloads are padded before encryption
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Padding {
+    #[default]
+    None,
+    /// Round up to a length with few significant bits
+    Padme,
+    /// Round up to a multiple of this many bytes
+    Bucket(u32),
+    /// Add a random number of bytes, at most this many
+    Random(u32),
+}
+
+// Padmé length for `len` bytes
+fn padme(len: u64) -> u64 {
+    if len < 2 {
+        return len;
+    }
+    let e = 63 - len.leading_zeros() as u64;
+    let s = 64 - e.leading_zeros() as u64;
+    let mask = (1u64 << (e - s)) - 1;
+    (len + mask) & !mask
+}
+
+/// Length of `len` bytes of payload after padding, without the random part of Padding::Random
+pub fn padded_len(padding: Padding, len: usize) -> Result<usize> {
+    let len = len as u64 + 1;
+    let padded = match padding {
+        Padding::None => return Ok(len as usize - 1),
+        Padding::Padme => padme(len),
+        Padding::Bucket(0) => {
+            return Err(Error::InvalidArgument("Padding bucket must not be empty"))
+        }
+        Padding::Bucket(size) => len.div_ceil(size as u64) * size as u64,
+        Padding::Random(_) => len,
+    };
+    usize::try_from(padded).map_err(|_| Error::TooLarge("Padded message is too long"))
+}
+
+/// Pad `data` in place
+pub fn pad<R: RngCore>(padding: Padding, rng: &mut R, data: &mut Vec<u8>) -> Result<()> {
+    if padding == Padding::None {
+        return Ok(());
+    }
+    let mut len = padded_len(padding, data.len())?;
+    if let Padding::Random(max) = padding {
+        len += (rng.next_u64() % (max as u64 + 1)) as usize;
+    }
+    data.push(MARKER);
+    data.resize(len, 0);
+    Ok(())
+}
+
+/// Remove the padding added by pad, fails if it is malformed
+pub fn unpad(data: &mut Vec<u8>) -> Result<()> {
+    let end = data
+        .iter()
+        .rposition(|b| *b != 0)
+        .ok_or(Error::Malformed("Invalid padding"))?;
+    if data[end] != MARKER {
+        return Err(Error::Malformed("Invalid padding"));
+    }
+    data.truncate(end);
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, KexMode, MessageOptions};
+    use crate::message::{self, parse_header};
+    use rand_chacha::rand_core::SeedableRng;
+
+    #[test]
+    fn test_padding() {
+        assert_eq!(padme(1000), 1024);
+        assert_eq!(padme(1025), 1088);
+        assert_eq!(padme(9), 10);
+        assert_eq!(padded_len(Padding::Bucket(256), 255).unwrap(), 256);
+        assert_eq!(padded_len(Padding::Bucket(256), 256).unwrap(), 512);
+        assert!(padded_len(Padding::Bucket(0), 1).is_err());
+
+        let mut rng = rand_chacha::ChaChaRng::seed_from_u64(1);
+        for padding in [
+            Padding::None,
+            Padding::Padme,
+            Padding::Bucket(64),
+            Padding::Random(100),
+        ] {
+            for len in [0usize, 1, 63, 64, 1000] {
+                let message: Vec<u8> = (0..len).map(|i| i as u8 | 1).collect();
+                let mut data = message.clone();
+                pad(padding, &mut rng, &mut data).unwrap();
+                if let Padding::Bucket(size) = padding {
+                    assert_eq!(data.len() % size as usize, 0);
+                }
+                if padding != Padding::None {
+                    unpad(&mut data).unwrap();
+                }
+                assert_eq!(data, message);
+            }
+        }
+
+        assert!(unpad(&mut [1u8, 2, 0, 0].to_vec()).is_err());
+        assert!(unpad(&mut [0u8; 16].to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_session_padding() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let offer = alice.init_kex("bob", KexMode::X25519).unwrap();
+        let response = bob.respond_kex("alice", &offer, KexMode::X25519).unwrap();
+        alice.finish_kex("bob", &response).unwrap();
+        let session_id = alice.sessions("bob")[0];
+        let overhead = message::HEADER_LEN + 24 + 16;
+
+        alice
+            .set_session_padding("bob", &session_id, Padding::Bucket(256))
+            .unwrap();
+        assert!(alice
+            .set_session_padding("bob", &session_id, Padding::Bucket(0))
+            .is_err());
+        for message in [&b"yes"[..], b"no", &[7u8; 200]] {
+            let data = alice.encrypt_message_for_recipient("bob", message).unwrap();
+            assert_eq!(data.len(), overhead + 256);
+            assert_ne!(parse_header(&data).unwrap().flags & message::FLAG_PADDED, 0);
+            assert_eq!(
+                bob.decrypt_message_from_sender("alice", &data).unwrap(),
+                message
+            );
+        }
+
+        // Sensitive messages are padded but never compressed
+        let secret = b"password=hunter2 ".repeat(20);
+        let options = MessageOptions {
+            padding: Some(Padding::Padme),
+            sensitive: true,
+            ..Default::default()
+        };
+        let data = alice
+            .encrypt_message_with_options("bob", &session_id, &secret, &options)
+            .unwrap();
+        assert_eq!(parse_header(&data).unwrap().flags, message::FLAG_PADDED);
+        assert_eq!(
+            data.len(),
+            overhead + padme(secret.len() as u64 + 1) as usize
+        );
+        assert_eq!(
+            bob.decrypt_message_from_sender("alice", &data).unwrap(),
+            secret
+        );
+    }
+
+    #[test]
+    fn test_group_and_envelope_padding() {
+        use crate::client::EnvelopeRecipient;
+        use crate::error::Error;
+        use crate::mls::{MlsEvent, MlsGroup, MlsKeyPackageBundle};
+
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let offer = alice.init_kex("bob", KexMode::X25519).unwrap();
+        let response = bob.respond_kex("alice", &offer, KexMode::X25519).unwrap();
+        alice.finish_kex("bob", &response).unwrap();
+        let secret = b"password=hunter2 ".repeat(20);
+        let options = MessageOptions {
+            padding: Some(Padding::Bucket(512)),
+            sensitive: true,
+            ..Default::default()
+        };
+
+        // Sender key groups: prefix (26) || nonce || payload || tag || signature
+        let (group_id, distribution) = alice.create_group(&["bob"]).unwrap();
+        bob.process_group_key("alice", &distribution[0].1).unwrap();
+        let data = alice
+            .encrypt_group_message_with_options(&group_id, &secret, &options)
+            .unwrap();
+        assert_eq!(data.len(), 26 + 24 + 512 + 16 + 64);
+        assert_eq!(
+            u16::from_be_bytes([data[24], data[25]]),
+            message::FLAG_PADDED
+        );
+        assert_eq!(bob.decrypt_group_message("alice", &data).unwrap(), secret);
+        // Compressed by default
+        let data = alice.encrypt_group_message(&group_id, &secret).unwrap();
+        assert!(data.len() < 26 + 24 + secret.len());
+        assert_eq!(bob.decrypt_group_message("alice", &data).unwrap(), secret);
+
+        // Envelopes
+        let recipients = [EnvelopeRecipient::Peer("bob")];
+        let padded = alice
+            .encrypt_envelope_with_options(&recipients, &secret, &options)
+            .unwrap();
+        let compressed = alice.encrypt_envelope(&recipients, &secret).unwrap();
+        assert!(padded.len() > compressed.len() + 400);
+        assert_eq!(bob.decrypt_envelope(&padded).unwrap().message, secret);
+        assert_eq!(bob.decrypt_envelope(&compressed).unwrap().message, secret);
+
+        // MLS application messages
+        let mut group_a = MlsGroup::create(&mut alice).unwrap();
+        let bob_kp = MlsKeyPackageBundle::new(&mut bob).unwrap();
+        group_a
+            .propose_add(&alice, &bob_kp.key_package().unwrap())
+            .unwrap();
+        let output = group_a.commit(&mut alice).unwrap();
+        let mut group_b = MlsGroup::join(&bob, &bob_kp, &output.welcomes[0]).unwrap();
+        let data = group_a
+            .encrypt_with_options(&mut alice, &secret, &options)
+            .unwrap();
+        assert!(matches!(
+            group_b.process_message(&bob, &data).unwrap(),
+            MlsEvent::Application { message, .. } if message == secret
+        ));
+
+        // Associated data and expiry need a session header
+        let with_aad = MessageOptions {
+            aad: b"channel",
+            ..Default::default()
+        };
+        assert!(matches!(
+            alice.encrypt_group_message_with_options(&group_id, b"", &with_aad),
+            Err(Error::Unsupported(_))
+        ));
+        assert!(matches!(
+            alice.encrypt_envelope_with_options(&recipients, b"", &with_aad),
+            Err(Error::Unsupported(_))
+        ));
+        assert!(matches!(
+            group_a.encrypt_with_options(&mut alice, b"", &with_aad),
+            Err(Error::Unsupported(_))
+        ));
+    }
+}
@@ -0,0 +1,220 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// Sealed sender
+//
+// The message is encrypted with the session as usual, then the sender certificate and the session
+// ciphertext are sealed with HPKE (base mode, so without sender key) to the recipient's certified static key.
+// A relay only sees the recipient it delivers to. The recipient validates the certificate
+// against the CA and decrypts the inner message with the session of the certified username,
+// which only the real sender can have produced.
+//
+// Delivery tokens limit who can drop sealed messages into a mailbox: the recipient hands its token
+// to its contacts (e.g. inside a session message) and registers only the verifier with the relay.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+
+use crate::error::{Error, Result};
+
+pub(crate) const HPKE_INFO: &[u8] = b"CosmicCipher sealed sender v1";
+
+/// Who sent a sealed sender message, taken from its validated certificate
+#[derive(Clone, Debug)]
+pub struct SealedSenderMessage {
+    pub sender: String,
+    pub sender_key: VerifyingKey,
+    pub message: Vec<u8>,
+}
+
+/// Binds a username to an identity key, signed by that key. The key itself is signed by the CA.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct SenderCertificate {
+    pub(crate) username: String,
+    pub(crate) verifying_key: [u8; 32],
+    pub(crate) signing_key_sig: Vec<u8>,
+    // Unix seconds
+    pub(crate) expires_at: u64,
+    pub(crate) signature: Vec<u8>,
+}
+
+impl SenderCertificate {
+    pub(crate) fn new(
+        signing_key: &SigningKey,
+        signing_key_sig: &Signature,
+        username: &str,
+        expires_at: u64,
+    ) -> Result<Self> {
+        let mut certificate = Self {
+            username: username.into(),
+            verifying_key: signing_key.verifying_key().to_bytes(),
+            signing_key_sig: signing_key_sig.to_bytes().to_vec(),
+            expires_at,
+            signature: Vec::new(),
+        };
+        certificate.signature = signing_key.sign(&certificate.tbs()?).to_bytes().to_vec();
+        Ok(certificate)
+    }
+
+    fn tbs(&self) -> Result<Vec<u8>> {
+        let mut tbs = self.clone();
+        tbs.signature = Vec::new();
+        bson::to_vec(&tbs).map_err(Error::from)
+    }
+
+    /// Check the CA signature, the self signature and, if the time is known, the expiry
+    pub(crate) fn validate(&self, ca: &VerifyingKey, now: Option<u64>) -> Result<VerifyingKey> {
+        let verifying_key = VerifyingKey::from_bytes(&self.verifying_key)
+            .map_err(|_| Error::Malformed("Invalid verifying key"))?;
+        let sig = Signature::from_slice(&self.signing_key_sig)
+            .map_err(|_| Error::Malformed("Invalid signature"))?;
+        if ca.verify(verifying_key.as_bytes(), &sig).is_err() {
+            return Err(Error::UntrustedKey("Sender not signed by CA"));
+        }
+        let sig = Signature::from_slice(&self.signature)
+            .map_err(|_| Error::Malformed("Invalid signature"))?;
+        if verifying_key.verify(&self.tbs()?, &sig).is_err() {
+            return Err(Error::Authentication(
+                "Sender certificate not signed by sender",
+            ));
+        }
+        if let Some(now) = now {
+            if now >= self.expires_at {
+                return Err(Error::Expired("Sender certificate expired"));
+            }
+        }
+        Ok(verifying_key)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SealedContent {
+    pub(crate) certificate: Vec<u8>,
+    // Ciphertext of encrypt_message_for_recipient
+    pub(crate) message: Vec<u8>,
+}
+
+/// Token a recipient derives from its identity and hands to the contacts allowed to reach it.
+/// Moving to a new epoch rotates the token, contacts that only know the old one cannot deliver.
+pub(crate) fn delivery_token(signing_key: &SigningKey, epoch: u64) -> [u8; 32] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"CosmicCipher delivery token");
+    hasher.update(epoch.to_be_bytes());
+    hasher.update(signing_key.to_bytes());
+    let mut token = [0u8; 32];
+    token.copy_from_slice(&hasher.finalize());
+    token
+}
+
+/// What a relay stores to check delivery tokens without being able to hand them out
+pub fn delivery_token_verifier(token: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"CosmicCipher delivery token verifier");
+    hasher.update(token);
+    let mut verifier = [0u8; 32];
+    verifier.copy_from_slice(&hasher.finalize());
+    verifier
+}
+
+/// Check a delivery token presented to a relay
+pub fn check_delivery_token(verifier: &[u8; 32], token: &[u8]) -> bool {
+    // Comparing hashes does not reveal how much of the token was right
+    &delivery_token_verifier(token) == verifier
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, KexMode};
+
+    #[test]
+    fn test_sealed_sender() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let mut carol = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        for (peer, name) in [(&mut bob, "bob"), (&mut carol, "carol")] {
+            let offer = alice.init_kex(name, KexMode::X25519).unwrap();
+            let response = peer.respond_kex("alice", &offer, KexMode::X25519).unwrap();
+            alice.finish_kex(name, &response).unwrap();
+        }
+        let offer = carol.init_kex("bob", KexMode::X25519).unwrap();
+        let response = bob.respond_kex("carol", &offer, KexMode::X25519).unwrap();
+        carol.finish_kex("bob", &response).unwrap();
+
+        let bob_certificate = bob.certificate();
+        let certificate = alice.sender_certificate("alice", 2000).unwrap();
+        let data = alice
+            .seal_sender("bob", &bob_certificate, &certificate, b"Guess who")
+            .unwrap();
+        let opened = bob.open_sealed_sender(&data).unwrap();
+        assert_eq!(opened.sender, "alice");
+        assert_eq!(opened.sender_key, alice.certificate().verifying_key);
+        assert_eq!(opened.message, b"Guess who");
+
+        // The relay sees neither the sender key nor the certificate
+        let alice_key = alice.certificate().verifying_key.to_bytes();
+        assert!(!data.windows(32).any(|w| w == alice_key));
+        assert!(!data.windows(5).any(|w| w == b"alice"));
+
+        // Only the recipient can open it
+        assert!(carol.open_sealed_sender(&data).is_err());
+
+        // Carol cannot claim to be alice, her message does not decrypt with the alice session
+        let forged = carol.sender_certificate("alice", 2000).unwrap();
+        let data = carol
+            .seal_sender("bob", &bob_certificate, &forged, b"Not alice")
+            .unwrap();
+        assert!(bob.open_sealed_sender(&data).is_err());
+        assert!(carol
+            .seal_sender("bob", &bob_certificate, &certificate, b"")
+            .is_err());
+
+        // Expired certificates are rejected once the time is known
+        let data = alice
+            .seal_sender("bob", &bob_certificate, &certificate, b"Late")
+            .unwrap();
+        bob.set_time(2000);
+        assert!(bob.open_sealed_sender(&data).is_err());
+
+        // Senders from a foreign CA are rejected
+        let mut mallory = Client::new_user();
+        assert!(alice
+            .seal_sender("bob", &mallory.certificate(), &certificate, b"")
+            .is_err());
+        let mallory_certificate = mallory.sender_certificate("alice", 2000).unwrap();
+        let parsed: SenderCertificate = bson::from_slice(&mallory_certificate).unwrap();
+        assert!(parsed
+            .validate(&bob_certificate.verifying_key, None)
+            .is_err());
+        assert!(mallory
+            .seal_sender("bob", &bob_certificate, &mallory_certificate, b"")
+            .is_err());
+    }
+
+    #[test]
+    fn test_delivery_token() {
+        let mut alice = Client::new_user();
+        let bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let verifier = bob.delivery_token_verifier(1);
+        assert!(check_delivery_token(&verifier, &bob.delivery_token(1)));
+        assert!(!check_delivery_token(&verifier, &alice.delivery_token(1)));
+        // Rotated tokens do not verify against each other
+        assert!(!check_delivery_token(&verifier, &bob.delivery_token(0)));
+        assert!(!check_delivery_token(
+            &bob.delivery_token_verifier(2),
+            &bob.delivery_token(1)
+        ));
+        assert!(!check_delivery_token(&verifier, &verifier));
+        assert!(!check_delivery_token(&verifier, b""));
+    }
+}
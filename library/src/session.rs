@@ -0,0 +1,415 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// Session state for an established shared key
+//
+// The key is rekeyed with a hash ratchet: key(n+1) = SHA3-256(label || key(n)).
+// The sender ratchets when the RekeyPolicy says so and puts the epoch n in front of the ciphertext.
+// The receiver follows forward on the first authentic message of a newer epoch
+// and keeps a few older keys for messages that are still in transit.
+// Messages are encrypted with a key per direction, SHA3-256(label || key(n) || sender key id),
+// so a message cannot be reflected back to its sender.
+
+use alloc::vec::Vec;
+use sha3::{Digest, Sha3_256};
+
+use crate::compression::Compression;
+use crate::error::{Error, Result};
+use crate::padding::Padding;
+use crate::suite::CipherSuite;
+
+/// Identifies one of possibly several sessions with the same peer.
+/// Both sides derive it from the initial shared key, it is sent in front of every ciphertext.
+pub type SessionId = [u8; 16];
+
+// Keys of this many previous epochs are kept for messages in transit
+const RETAINED_EPOCHS: usize = 4;
+// Receivers do not ratchet further than this in one go
+const MAX_EPOCH_SKIP: u32 = 1024;
+/// Sequence numbers this far behind the highest one received are still accepted once
+pub const REPLAY_WINDOW: u64 = 64;
+
+/// How a received message relates to the ones before it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    /// The next message after the highest one received
+    InOrder,
+    /// Newer than expected, `missing` messages before it have not arrived (yet)
+    AfterGap { missing: u64 },
+    /// Older than the highest one received, fills an earlier gap
+    Late,
+}
+
+/// When to ratchet a session key, and when to stop using it.
+/// Limits count what this side encrypted since the last ratchet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RekeyPolicy {
+    /// Ratchet after this many messages
+    pub max_messages: Option<u64>,
+    /// Ratchet after this many plaintext bytes
+    pub max_bytes: Option<u64>,
+    /// Ratchet after this many seconds, needs Client::set_time
+    pub max_age_secs: Option<u64>,
+    /// Refuse to encrypt more messages than this under one key
+    pub hard_max_messages: u64,
+    /// Refuse to encrypt more plaintext bytes than this under one key
+    pub hard_max_bytes: u64,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        Self {
+            max_messages: None,
+            max_bytes: None,
+            max_age_secs: None,
+            // Random 192 bit nonces allow far more, this keeps a wide safety margin
+            hard_max_messages: 1 << 32,
+            hard_max_bytes: 1 << 40,
+        }
+    }
+}
+
+pub(crate) struct Session {
+    pub(crate) id: SessionId,
+    pub(crate) key: [u8; 32],
+    pub(crate) epoch: u32,
+    // (epoch, key) of earlier epochs, oldest first
+    pub(crate) previous_keys: Vec<(u32, [u8; 32])>,
+    // Usage in the current epoch
+    pub(crate) messages: u64,
+    pub(crate) bytes: u64,
+    pub(crate) epoch_started_at: Option<u64>,
+    // Sequence number of the next message we send, counts across epochs
+    pub(crate) send_seq: u64,
+    // Highest sequence number received, bit i of the window is set if highest - i was received
+    pub(crate) recv_highest: Option<u64>,
+    pub(crate) recv_window: u64,
+    // Only accept the next sequence number, for control channels
+    pub(crate) strict_order: bool,
+    // Codec for messages sent in this session
+    pub(crate) compression: Compression,
+    // Padding for messages sent in this session
+    pub(crate) padding: Padding,
+    // Negotiated in the key exchange
+    pub(crate) suite: CipherSuite,
+    // Key id of the peer's certified identity, None for sessions imported from older exports
+    pub(crate) peer_key_id: Option<[u8; 8]>,
+}
+
+impl Session {
+    pub(crate) fn new(key: [u8; 32]) -> Self {
+        let mut id = [0u8; 16];
+        let mut hasher = Sha3_256::new();
+        hasher.update(b"CosmicCipher session id");
+        hasher.update(key);
+        id.copy_from_slice(&hasher.finalize()[..16]);
+
+        Self {
+            id,
+            key,
+            epoch: 0,
+            previous_keys: Vec::new(),
+            messages: 0,
+            bytes: 0,
+            epoch_started_at: None,
+            send_seq: 0,
+            recv_highest: None,
+            recv_window: 0,
+            strict_order: false,
+            compression: Compression::default(),
+            padding: Padding::default(),
+            suite: CipherSuite::default(),
+            peer_key_id: None,
+        }
+    }
+
+    /// Ratchet if the policy asks for it and check the hard limits for a message of `len` bytes
+    pub(crate) fn prepare_send(
+        &mut self,
+        policy: &RekeyPolicy,
+        now: Option<u64>,
+        len: usize,
+    ) -> Result<()> {
+        if self.epoch_started_at.is_none() {
+            self.epoch_started_at = now;
+        }
+
+        let age = match (now, self.epoch_started_at) {
+            (Some(now), Some(started)) => Some(now.saturating_sub(started)),
+            _ => None,
+        };
+        let exceeded = |limit: Option<u64>, value: Option<u64>| match (limit, value) {
+            (Some(limit), Some(value)) => value >= limit,
+            _ => false,
+        };
+        if exceeded(policy.max_messages, Some(self.messages))
+            || exceeded(policy.max_bytes, Some(self.bytes))
+            || exceeded(policy.max_age_secs, age)
+        {
+            self.ratchet(now)?;
+        }
+
+        if self.messages >= policy.hard_max_messages
+            || self.bytes.saturating_add(len as u64) > policy.hard_max_bytes
+        {
+            return Err(Error::InvalidState(
+                "Session key usage limit reached, rekey or run a new key exchange",
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn record_send(&mut self, len: usize) {
+        self.messages += 1;
+        self.send_seq += 1;
+        self.bytes = self.bytes.saturating_add(len as u64);
+    }
+
+    pub(crate) fn ratchet(&mut self, now: Option<u64>) -> Result<()> {
+        let epoch = match self.epoch.checked_add(1) {
+            None => return Err(Error::InvalidState("Session epochs exhausted")),
+            Some(v) => v,
+        };
+
+        self.previous_keys.push((self.epoch, self.key));
+        if self.previous_keys.len() > RETAINED_EPOCHS {
+            self.previous_keys.remove(0);
+        }
+        self.key = ratchet_key(&self.key);
+        self.epoch = epoch;
+        self.messages = 0;
+        self.bytes = 0;
+        self.epoch_started_at = now;
+
+        Ok(())
+    }
+
+    /// The key for a received message, without changing the session
+    pub(crate) fn key_for_epoch(&self, epoch: u32) -> Result<[u8; 32]> {
+        if epoch == self.epoch {
+            return Ok(self.key);
+        }
+        if epoch < self.epoch {
+            return match self.previous_keys.iter().find(|(e, _)| *e == epoch) {
+                None => Err(Error::Replay("Session key of this epoch was discarded")),
+                Some((_, key)) => Ok(*key),
+            };
+        }
+        if epoch - self.epoch > MAX_EPOCH_SKIP {
+            return Err(Error::Malformed("Session epoch too far ahead"));
+        }
+
+        let mut key = self.key;
+        for _ in self.epoch..epoch {
+            key = ratchet_key(&key);
+        }
+        Ok(key)
+    }
+
+    /// The key for a message of `epoch` from the identity with `sender_key_id`.
+    /// Fails unless the sender is the peer, in particular for our own messages.
+    pub(crate) fn receive_key(
+        &self,
+        epoch: u32,
+        sender_key_id: &[u8; 8],
+        own_key_id: &[u8; 8],
+    ) -> Result<[u8; 32]> {
+        if sender_key_id == own_key_id {
+            return Err(Error::Authentication("Message was sent with our own key"));
+        }
+        if self.peer_key_id.is_some_and(|id| &id != sender_key_id) {
+            return Err(Error::UntrustedKey(
+                "Message is not from the session's peer",
+            ));
+        }
+        Ok(direction_key(&self.key_for_epoch(epoch)?, sender_key_id))
+    }
+
+    /// Follow the peer to a newer epoch, call only after a message of that epoch was authenticated
+    pub(crate) fn advance_to(&mut self, epoch: u32, now: Option<u64>) -> Result<()> {
+        if epoch > self.epoch && epoch - self.epoch > MAX_EPOCH_SKIP {
+            return Err(Error::Malformed("Session epoch too far ahead"));
+        }
+        while self.epoch < epoch {
+            self.ratchet(now)?;
+        }
+        Ok(())
+    }
+
+    /// Classify a received sequence number, without recording it
+    pub(crate) fn check_seq(&self, seq: u64) -> Result<Delivery> {
+        let expected = match self.recv_highest {
+            None => 0,
+            Some(highest) => highest.saturating_add(1),
+        };
+        if seq == expected {
+            return Ok(Delivery::InOrder);
+        }
+        if self.strict_order {
+            return Err(Error::Replay("Message out of order"));
+        }
+        let highest = match self.recv_highest {
+            Some(highest) if seq <= highest => highest,
+            _ => {
+                return Ok(Delivery::AfterGap {
+                    missing: seq - expected,
+                })
+            }
+        };
+        let behind = highest - seq;
+        if behind >= REPLAY_WINDOW {
+            return Err(Error::Replay("Message is older than the replay window"));
+        }
+        if self.recv_window & (1 << behind) != 0 {
+            return Err(Error::Replay("Message replayed"));
+        }
+        Ok(Delivery::Late)
+    }
+
+    /// Record an authenticated sequence number, after check_seq accepted it
+    pub(crate) fn record_seq(&mut self, seq: u64) {
+        match self.recv_highest {
+            Some(highest) if seq <= highest => {
+                self.recv_window |= 1 << (highest - seq);
+            }
+            Some(highest) => {
+                let shift = seq - highest;
+                self.recv_window = if shift >= REPLAY_WINDOW {
+                    0
+                } else {
+                    self.recv_window << shift
+                };
+                self.recv_window |= 1;
+                self.recv_highest = Some(seq);
+            }
+            None => {
+                self.recv_window = 1;
+                self.recv_highest = Some(seq);
+            }
+        }
+    }
+}
+
+fn ratchet_key(key: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"CosmicCipher ratchet");
+    hasher.update(key);
+    let mut next = [0u8; 32];
+    next.copy_from_slice(&hasher.finalize());
+    next
+}
+
+/// Key for the messages of one direction, `sender_key_id` is the sender's message::key_id
+pub(crate) fn direction_key(key: &[u8; 32], sender_key_id: &[u8; 8]) -> [u8; 32] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"CosmicCipher direction");
+    hasher.update(key);
+    hasher.update(sender_key_id);
+    let mut direction_key = [0u8; 32];
+    direction_key.copy_from_slice(&hasher.finalize());
+    direction_key
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ratchet_policy() {
+        let policy = RekeyPolicy {
+            max_messages: Some(2),
+            ..Default::default()
+        };
+        let mut session = Session::new([1u8; 32]);
+        let initial_key = session.key;
+
+        for _ in 0..5 {
+            session.prepare_send(&policy, None, 10).unwrap();
+            session.record_send(10);
+        }
+        assert_eq!(session.epoch, 2);
+        assert_eq!(session.messages, 1);
+        assert_ne!(session.key, initial_key);
+        assert_eq!(session.key_for_epoch(0).unwrap(), initial_key);
+
+        let mut receiver = Session::new(initial_key);
+        assert_eq!(receiver.key_for_epoch(2).unwrap(), session.key);
+        receiver.advance_to(2, None).unwrap();
+        assert_eq!(receiver.key, session.key);
+        assert_eq!(receiver.id, session.id);
+    }
+
+    #[test]
+    fn test_age_and_hard_limits() {
+        let policy = RekeyPolicy {
+            max_age_secs: Some(60),
+            hard_max_bytes: 100,
+            ..Default::default()
+        };
+        let mut session = Session::new([2u8; 32]);
+        session.prepare_send(&policy, Some(1000), 10).unwrap();
+        session.record_send(10);
+        session.prepare_send(&policy, Some(1059), 10).unwrap();
+        assert_eq!(session.epoch, 0);
+        session.prepare_send(&policy, Some(1060), 10).unwrap();
+        assert_eq!(session.epoch, 1);
+
+        assert!(session.prepare_send(&policy, Some(1060), 101).is_err());
+    }
+
+    #[test]
+    fn test_epoch_window() {
+        let mut session = Session::new([3u8; 32]);
+        for _ in 0..10 {
+            session.ratchet(None).unwrap();
+        }
+        assert!(session.key_for_epoch(5).is_err());
+        assert!(session.key_for_epoch(6).is_ok());
+        assert!(session.key_for_epoch(10 + MAX_EPOCH_SKIP + 1).is_err());
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut session = Session::new([4u8; 32]);
+        let receive = |session: &mut Session, seq| {
+            let delivery = session.check_seq(seq)?;
+            session.record_seq(seq);
+            Ok::<_, Error>(delivery)
+        };
+        assert_eq!(receive(&mut session, 0).unwrap(), Delivery::InOrder);
+        assert_eq!(receive(&mut session, 1).unwrap(), Delivery::InOrder);
+        assert_eq!(
+            receive(&mut session, 5).unwrap(),
+            Delivery::AfterGap { missing: 3 }
+        );
+        assert_eq!(receive(&mut session, 3).unwrap(), Delivery::Late);
+        assert!(receive(&mut session, 3).is_err());
+        assert!(receive(&mut session, 5).is_err());
+        assert_eq!(receive(&mut session, 6).unwrap(), Delivery::InOrder);
+
+        assert_eq!(
+            receive(&mut session, 6 + REPLAY_WINDOW).unwrap(),
+            Delivery::AfterGap {
+                missing: REPLAY_WINDOW - 1
+            }
+        );
+        assert!(receive(&mut session, 6).is_err());
+        assert_eq!(receive(&mut session, 7).unwrap(), Delivery::Late);
+
+        session.strict_order = true;
+        assert!(session.check_seq(6 + REPLAY_WINDOW + 2).is_err());
+        assert!(session.check_seq(8).is_err());
+        assert_eq!(
+            session.check_seq(6 + REPLAY_WINDOW + 1).unwrap(),
+            Delivery::InOrder
+        );
+    }
+}
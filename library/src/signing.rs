@@ -0,0 +1,309 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// Signatures over arbitrary data with the identity key
+//
+// The data is hashed with SHA3-256 and Ed25519 signs
+// domain || context || timestamp || signer key || CA key || digest,
+// so a signature made for one context (e.g. "release") never verifies in another.
+// Each signature carries the chain up to the trust anchor: the signer key and the CA
+// signature over it. A verifier only accepts chains ending in its own CA.
+//
+// Detached signatures are the bson signature block alone, attached ones wrap block and data.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+
+use crate::error::{Error, Result};
+
+const DOMAIN: &[u8] = b"CosmicCipher signature v1";
+
+// Signatures from further in the future than this are rejected when the time is known
+pub const MAX_CLOCK_SKEW: u64 = 300;
+
+/// Who signed, from a verified signature
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SignatureInfo {
+    pub signer: VerifyingKey,
+    // Unix seconds, if the signer knew the time
+    pub timestamp: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct SignatureBlock {
+    context: String,
+    verifying_key: [u8; 32],
+    signing_key_sig: Vec<u8>,
+    ca_key: [u8; 32],
+    timestamp: Option<u64>,
+    signature: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SignedMessage {
+    signature: SignatureBlock,
+    data: Vec<u8>,
+}
+
+fn tbs(
+    context: &str,
+    timestamp: Option<u64>,
+    verifying_key: &[u8; 32],
+    ca_key: &[u8; 32],
+    digest: &[u8],
+) -> Vec<u8> {
+    let mut tbs = DOMAIN.to_vec();
+    tbs.extend_from_slice(&(context.len() as u32).to_be_bytes());
+    tbs.extend_from_slice(context.as_bytes());
+    match timestamp {
+        None => tbs.push(0),
+        Some(timestamp) => {
+            tbs.push(1);
+            tbs.extend_from_slice(&timestamp.to_be_bytes());
+        }
+    }
+    tbs.extend_from_slice(verifying_key);
+    tbs.extend_from_slice(ca_key);
+    tbs.extend_from_slice(digest);
+    tbs
+}
+
+fn check_context(context: &str) -> Result<()> {
+    if context.is_empty() {
+        return Err(Error::InvalidArgument(
+            "Signature context must not be empty",
+        ));
+    }
+    Ok(())
+}
+
+/// Incremental signing, see Client::sign_stream
+pub struct SignStream<'a> {
+    signing_key: &'a SigningKey,
+    signing_key_sig: &'a Signature,
+    ca: &'a VerifyingKey,
+    context: String,
+    timestamp: Option<u64>,
+    hasher: Sha3_256,
+}
+
+impl<'a> SignStream<'a> {
+    pub(crate) fn new(
+        signing_key: &'a SigningKey,
+        signing_key_sig: &'a Signature,
+        ca: &'a VerifyingKey,
+        context: &str,
+        timestamp: Option<u64>,
+    ) -> Result<Self> {
+        check_context(context)?;
+        Ok(Self {
+            signing_key,
+            signing_key_sig,
+            ca,
+            context: context.into(),
+            timestamp,
+            hasher: Sha3_256::new(),
+        })
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    /// The detached signature over everything passed to update
+    pub fn finish(self) -> Result<Vec<u8>> {
+        bson::to_vec(&self.finish_block()).map_err(Error::from)
+    }
+
+    pub(crate) fn finish_block(self) -> SignatureBlock {
+        let verifying_key = self.signing_key.verifying_key().to_bytes();
+        let ca_key = self.ca.to_bytes();
+        let digest = self.hasher.finalize();
+        let tbs = tbs(
+            &self.context,
+            self.timestamp,
+            &verifying_key,
+            &ca_key,
+            &digest,
+        );
+        SignatureBlock {
+            context: self.context,
+            verifying_key,
+            signing_key_sig: self.signing_key_sig.to_bytes().to_vec(),
+            ca_key,
+            timestamp: self.timestamp,
+            signature: self.signing_key.sign(&tbs).to_bytes().to_vec(),
+        }
+    }
+}
+
+/// Incremental verification of a detached signature, see Client::verify_stream
+pub struct VerifyStream<'a> {
+    ca: &'a VerifyingKey,
+    now: Option<u64>,
+    context: String,
+    block: SignatureBlock,
+    hasher: Sha3_256,
+}
+
+impl<'a> VerifyStream<'a> {
+    pub(crate) fn new(
+        ca: &'a VerifyingKey,
+        now: Option<u64>,
+        context: &str,
+        signature: &[u8],
+    ) -> Result<Self> {
+        let block = bson::from_slice(signature).map_err(Error::from)?;
+        Ok(Self::from_block(ca, now, context, block))
+    }
+
+    fn from_block(
+        ca: &'a VerifyingKey,
+        now: Option<u64>,
+        context: &str,
+        block: SignatureBlock,
+    ) -> Self {
+        Self {
+            ca,
+            now,
+            context: context.into(),
+            block,
+            hasher: Sha3_256::new(),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    /// Check the signature over everything passed to update and the chain up to the CA
+    pub fn finish(self) -> Result<SignatureInfo> {
+        let block = self.block;
+        check_context(&self.context)?;
+        if block.context != self.context {
+            return Err(Error::Authentication(
+                "Signature made for a different context",
+            ));
+        }
+        if block.ca_key != self.ca.to_bytes() {
+            return Err(Error::UntrustedKey(
+                "Signature chain ends in a different CA",
+            ));
+        }
+        let signer = VerifyingKey::from_bytes(&block.verifying_key)
+            .map_err(|_| Error::Malformed("Invalid verifying key"))?;
+        let sig = Signature::from_slice(&block.signing_key_sig)
+            .map_err(|_| Error::Malformed("Invalid signature"))?;
+        if self.ca.verify(signer.as_bytes(), &sig).is_err() {
+            return Err(Error::UntrustedKey("Signer not signed by CA"));
+        }
+        let digest = self.hasher.finalize();
+        let tbs = tbs(
+            &block.context,
+            block.timestamp,
+            &block.verifying_key,
+            &block.ca_key,
+            &digest,
+        );
+        let sig = Signature::from_slice(&block.signature)
+            .map_err(|_| Error::Malformed("Invalid signature"))?;
+        if signer.verify(&tbs, &sig).is_err() {
+            return Err(Error::Authentication("Signature invalid"));
+        }
+        if let (Some(now), Some(timestamp)) = (self.now, block.timestamp) {
+            if timestamp > now.saturating_add(MAX_CLOCK_SKEW) {
+                return Err(Error::Malformed("Signature timestamp is in the future"));
+            }
+        }
+        Ok(SignatureInfo {
+            signer,
+            timestamp: block.timestamp,
+        })
+    }
+}
+
+pub(crate) fn attach(block: SignatureBlock, data: &[u8]) -> Result<Vec<u8>> {
+    bson::to_vec(&SignedMessage {
+        signature: block,
+        data: data.to_vec(),
+    })
+    .map_err(Error::from)
+}
+
+pub(crate) fn verify_attached(
+    ca: &VerifyingKey,
+    now: Option<u64>,
+    context: &str,
+    signed: &[u8],
+) -> Result<(SignatureInfo, Vec<u8>)> {
+    let signed: SignedMessage = bson::from_slice(signed).map_err(Error::from)?;
+    let mut stream = VerifyStream::from_block(ca, now, context, signed.signature);
+    stream.update(&signed.data);
+    Ok((stream.finish()?, signed.data))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use crate::client::Client;
+
+    #[test]
+    fn test_signatures() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let mallory = Client::new_user();
+        let data = b"Release 1.0";
+
+        let signature = alice.sign_detached("release", data).unwrap();
+        let info = bob.verify_detached("release", data, &signature).unwrap();
+        assert_eq!(info.signer, alice.certificate().verifying_key);
+        assert_eq!(info.timestamp, None);
+        assert!(bob.verify_detached("commit", data, &signature).is_err());
+        assert!(bob
+            .verify_detached("release", b"Release 1.1", &signature)
+            .is_err());
+        assert!(alice.sign_detached("", data).is_err());
+
+        // Streams produce the same signatures as whole buffers
+        let mut stream = alice.sign_stream("release").unwrap();
+        for piece in data.chunks(3) {
+            stream.update(piece);
+        }
+        let streamed = stream.finish().unwrap();
+        assert_eq!(streamed, signature);
+        let mut stream = bob.verify_stream("release", &signature).unwrap();
+        stream.update(&data[..4]);
+        stream.update(&data[4..]);
+        assert_eq!(stream.finish().unwrap(), info);
+
+        let signed = alice.sign_attached("release", data).unwrap();
+        let (attached_info, attached) = bob.verify_attached("release", &signed).unwrap();
+        assert_eq!(attached, data);
+        assert_eq!(attached_info, info);
+        let mut tampered = signed.clone();
+        let last = tampered.len() - 2;
+        tampered[last] ^= 1;
+        assert!(bob.verify_attached("release", &tampered).is_err());
+
+        // Only chains ending in our CA are trusted
+        let foreign = mallory.sign_detached("release", data).unwrap();
+        assert!(bob.verify_detached("release", data, &foreign).is_err());
+
+        // Timestamps are signed and must not lie in the future
+        alice.set_time(5000);
+        let signature = alice.sign_detached("release", data).unwrap();
+        let info = bob.verify_detached("release", data, &signature).unwrap();
+        assert_eq!(info.timestamp, Some(5000));
+        bob.set_time(5000 - super::MAX_CLOCK_SKEW - 1);
+        assert!(bob.verify_detached("release", data, &signature).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// Local store for decrypted messages
+//
+// Messages sent with a time-to-live carry their expiry in the authenticated header.
+// Client refuses them once expired, the store deletes the plaintext of those already read.
+// There is no clock in no_std, callers pass the time like they do to Client::set_time.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::message::ReceivedMessage;
+
+/// A decrypted message kept by MessageStore
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoredMessage {
+    pub sender: String,
+    pub seq: u64,
+    pub message: Vec<u8>,
+    /// Unix time in seconds from the message header, None if it does not expire
+    pub expires_at: Option<u64>,
+}
+
+impl StoredMessage {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+}
+
+/// Decrypted messages in the order they were added
+#[derive(Clone, Debug, Default)]
+pub struct MessageStore {
+    messages: Vec<StoredMessage>,
+}
+
+impl MessageStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, sender: &str, received: ReceivedMessage) {
+        self.messages.push(StoredMessage {
+            sender: String::from(sender),
+            seq: received.seq,
+            message: received.message,
+            expires_at: received.expires_at,
+        });
+    }
+
+    pub fn messages(&self) -> &[StoredMessage] {
+        &self.messages
+    }
+
+    /// Messages from `sender` that have not expired at unix time `now`
+    pub fn messages_from(&self, sender: &str, now: u64) -> Vec<&StoredMessage> {
+        self.messages
+            .iter()
+            .filter(|stored| stored.sender == sender && !stored.is_expired(now))
+            .collect()
+    }
+
+    /// Delete messages that have expired at unix time `now`, returns how many.
+    /// Their plaintext is overwritten before it is freed.
+    pub fn purge_expired(&mut self, now: u64) -> usize {
+        let before = self.messages.len();
+        self.messages.retain_mut(|stored| {
+            if stored.is_expired(now) {
+                stored.message.fill(0);
+                return false;
+            }
+            true
+        });
+        before - self.messages.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, KexMode, MessageOptions};
+    use crate::error::Error;
+    use crate::message::{parse_header, FLAG_EXPIRES, HEADER_LEN};
+    use alloc::string::ToString;
+
+    #[test]
+    fn test_message_expiry() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let offer = alice.init_kex("bob", KexMode::X25519).unwrap();
+        let response = bob.respond_kex("alice", &offer, KexMode::X25519).unwrap();
+        alice.finish_kex("bob", &response).unwrap();
+
+        // The sender needs a clock
+        assert_eq!(
+            alice
+                .encrypt_message_with_ttl("bob", b"soon gone", 60)
+                .unwrap_err()
+                .to_string(),
+            "Time-to-live needs Client::set_time"
+        );
+        alice.set_time(1_000);
+        let data = alice
+            .encrypt_message_with_ttl("bob", b"soon gone", 60)
+            .unwrap();
+        let header = parse_header(&data).unwrap();
+        assert_ne!(header.flags & FLAG_EXPIRES, 0);
+        assert_eq!(header.expires_at, Some(1_060));
+        assert_eq!(header.encoded_len(), HEADER_LEN + 8);
+
+        // Refused while the receiver has no clock, without touching the session
+        assert_eq!(
+            bob.decrypt_message_from_sender("alice", &data),
+            Err(Error::InvalidState(
+                "Expiring messages need Client::set_time"
+            ))
+        );
+
+        // Refused once expired, without touching the session
+        bob.set_time(1_060);
+        assert_eq!(
+            bob.decrypt_message_from_sender("alice", &data)
+                .unwrap_err()
+                .to_string(),
+            "Message has expired"
+        );
+        bob.set_time(1_059);
+        let received = bob.receive_message_from_sender("alice", &data).unwrap();
+        assert_eq!(received.message, b"soon gone");
+        assert_eq!(received.expires_at, Some(1_060));
+
+        // The expiry is authenticated
+        let data = alice
+            .encrypt_message_with_ttl("bob", b"extended", 60)
+            .unwrap();
+        let mut forged = data.clone();
+        let offset = HEADER_LEN;
+        forged[offset..offset + 8].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(bob.decrypt_message_from_sender("alice", &forged).is_err());
+        assert_eq!(
+            bob.decrypt_message_from_sender("alice", &data).unwrap(),
+            b"extended"
+        );
+
+        // A default for encrypt_message_for_recipient, options take precedence
+        alice.set_message_ttl(Some(10));
+        let data = alice.encrypt_message_for_recipient("bob", b"hi").unwrap();
+        assert_eq!(parse_header(&data).unwrap().expires_at, Some(1_010));
+        let session_id = alice.sessions("bob")[0];
+        let options = MessageOptions {
+            ttl_secs: Some(5),
+            ..Default::default()
+        };
+        let data = alice
+            .encrypt_message_with_options("bob", &session_id, b"hi", &options)
+            .unwrap();
+        assert_eq!(parse_header(&data).unwrap().expires_at, Some(1_005));
+        alice.set_message_ttl(None);
+        let data = alice.encrypt_message_for_recipient("bob", b"hi").unwrap();
+        assert_eq!(parse_header(&data).unwrap().expires_at, None);
+    }
+
+    #[test]
+    fn test_message_store() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let offer = alice.init_kex("bob", KexMode::X25519).unwrap();
+        let response = bob.respond_kex("alice", &offer, KexMode::X25519).unwrap();
+        alice.finish_kex("bob", &response).unwrap();
+        alice.set_time(1_000);
+        bob.set_time(1_000);
+
+        let mut store = MessageStore::new();
+        let short = alice.encrypt_message_with_ttl("bob", b"short", 10).unwrap();
+        let long = alice.encrypt_message_with_ttl("bob", b"long", 100).unwrap();
+        let kept = alice.encrypt_message_for_recipient("bob", b"kept").unwrap();
+        for data in [short, long, kept] {
+            let received = bob.receive_message_from_sender("alice", &data).unwrap();
+            store.push("alice", received);
+        }
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.messages_from("alice", 1_010).len(), 2);
+        assert!(store.messages_from("carol", 1_000).is_empty());
+
+        assert_eq!(store.purge_expired(1_009), 0);
+        assert_eq!(store.purge_expired(1_010), 1);
+        assert_eq!(store.messages()[0].message, b"long");
+        assert_eq!(store.purge_expired(u64::MAX), 1);
+        assert_eq!(store.messages()[0].message, b"kept");
+        assert_eq!(store.messages()[0].expires_at, None);
+    }
+}
@@ -0,0 +1,485 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// Chunked encryption of large messages (STREAM, Hoang et al. 2015)
+//
+// Stream header: message header (FLAG_STREAM) || salt (32 bytes) || header tag (16 bytes)
+// The stream key is SHA3-256(label || session key || salt), so every stream has its own key.
+// The plaintext is cut into chunks of CHUNK_SIZE bytes, each encrypted with the suite of the
+// session under nonce 0 || chunk counter (8 bytes) || last chunk flag (1 byte), padded to the
+// nonce length of the suite, and message header || salt as associated data. The header tag is
+// the last TAG_LEN bytes of an empty encryption under nonce ff..ff, so the header can be
+// checked before the first chunk.
+//
+// The counter stops reordering, the flag stops truncation: a stream cut at a chunk boundary
+// ends with a chunk that was not encrypted as the last one. Only an empty stream has an empty chunk.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use sha3::{Digest, Sha3_256};
+
+use crate::error::{Error, Result};
+use crate::message::HEADER_LEN;
+use crate::suite::CipherSuite;
+
+pub const CHUNK_SIZE: usize = 64 * 1024;
+pub const TAG_LEN: usize = crate::suite::TAG_LEN;
+pub const SALT_LEN: usize = 32;
+pub const STREAM_HEADER_LEN: usize = HEADER_LEN + SALT_LEN + TAG_LEN;
+
+pub(crate) fn stream_key(session_key: &[u8; 32], salt: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"CosmicCipher stream key");
+    hasher.update(session_key);
+    hasher.update(salt);
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&hasher.finalize());
+    key
+}
+
+fn chunk_nonce(suite: CipherSuite, counter: u64, last: bool) -> Vec<u8> {
+    let mut nonce = vec![0u8; suite.nonce_len()];
+    let len = nonce.len();
+    nonce[len - 9..len - 1].copy_from_slice(&counter.to_be_bytes());
+    nonce[len - 1] = last as u8;
+    nonce
+}
+
+struct ChunkCipher {
+    suite: CipherSuite,
+    key: [u8; 32],
+    // Message header and salt
+    associated_data: Vec<u8>,
+    counter: u64,
+}
+
+impl ChunkCipher {
+    fn new(suite: CipherSuite, key: [u8; 32], associated_data: &[u8]) -> Self {
+        Self {
+            suite,
+            key,
+            associated_data: associated_data.to_vec(),
+            counter: 0,
+        }
+    }
+
+    fn header_tag(&self) -> Result<[u8; TAG_LEN]> {
+        let nonce = vec![0xff; self.suite.nonce_len()];
+        let mut buffer = Vec::new();
+        self.suite
+            .seal(&self.key, &nonce, &self.associated_data, &mut buffer)?;
+        let mut tag = [0u8; TAG_LEN];
+        tag.copy_from_slice(&buffer[buffer.len() - TAG_LEN..]);
+        Ok(tag)
+    }
+
+    fn seal(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>> {
+        let nonce = chunk_nonce(self.suite, self.counter, last);
+        let mut buffer = chunk.to_vec();
+        self.suite
+            .seal(&self.key, &nonce, &self.associated_data, &mut buffer)?;
+        self.advance()?;
+        Ok(buffer)
+    }
+
+    fn open(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>> {
+        let nonce = chunk_nonce(self.suite, self.counter, last);
+        let mut buffer = chunk.to_vec();
+        self.suite
+            .open(&self.key, &nonce, &self.associated_data, &mut buffer)
+            .map_err(|_| {
+                Error::Authentication("Stream chunk is invalid, reordered or truncated")
+            })?;
+        self.advance()?;
+        Ok(buffer)
+    }
+
+    // Size of a sealed chunk that is not the last one
+    fn sealed_chunk_len(&self) -> usize {
+        CHUNK_SIZE + self.suite.overhead()
+    }
+
+    fn advance(&mut self) -> Result<()> {
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or(Error::TooLarge("Stream is too long"))?;
+        Ok(())
+    }
+}
+
+/// Encrypts a stream chunk by chunk, see Client::encrypt_stream_for_recipient
+pub struct StreamEncryptor {
+    cipher: ChunkCipher,
+    buffer: Vec<u8>,
+}
+
+impl StreamEncryptor {
+    // `header` is the message header, the returned stream header goes in front of the chunks
+    pub(crate) fn new(
+        suite: CipherSuite,
+        session_key: &[u8; 32],
+        header: &[u8],
+        salt: &[u8; SALT_LEN],
+    ) -> Result<(Self, Vec<u8>)> {
+        let mut associated_data = header.to_vec();
+        associated_data.extend_from_slice(salt);
+        let cipher = ChunkCipher::new(suite, stream_key(session_key, salt), &associated_data);
+        associated_data.extend_from_slice(&cipher.header_tag()?);
+        let encryptor = Self {
+            cipher,
+            buffer: Vec::new(),
+        };
+        Ok((encryptor, associated_data))
+    }
+
+    /// Encrypt the next part of the plaintext, returns the chunks that are complete
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.buffer.extend_from_slice(data);
+        let mut output = Vec::new();
+        // The last chunk is held back until finish, it has to carry the flag
+        let mut start = 0;
+        while self.buffer.len() - start > CHUNK_SIZE {
+            let chunk = &self.buffer[start..start + CHUNK_SIZE];
+            output.extend_from_slice(&self.cipher.seal(chunk, false)?);
+            start += CHUNK_SIZE;
+        }
+        self.buffer.drain(..start);
+        Ok(output)
+    }
+
+    /// Encrypt the last chunk
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        self.cipher.seal(&self.buffer, true)
+    }
+}
+
+/// Decrypts a stream chunk by chunk, see Client::decrypt_stream_from_sender
+pub struct StreamDecryptor {
+    cipher: ChunkCipher,
+    buffer: Vec<u8>,
+}
+
+impl StreamDecryptor {
+    // Checks the header tag of `stream_header`
+    pub(crate) fn new(
+        suite: CipherSuite,
+        session_key: &[u8; 32],
+        stream_header: &[u8],
+    ) -> Result<Self> {
+        if stream_header.len() != STREAM_HEADER_LEN {
+            return Err(Error::Malformed("Stream header has the wrong length"));
+        }
+        let (associated_data, tag) = stream_header.split_at(HEADER_LEN + SALT_LEN);
+        let salt = &associated_data[HEADER_LEN..];
+        let cipher = ChunkCipher::new(suite, stream_key(session_key, salt), associated_data);
+        // The empty encryption is deterministic, so recompute it and compare in constant time
+        let difference = cipher
+            .header_tag()?
+            .iter()
+            .zip(tag)
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        if difference != 0 {
+            return Err(Error::Authentication("Stream header is not authentic"));
+        }
+        Ok(Self {
+            cipher,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Decrypt the next part of the stream, returns the plaintext of the chunks that are complete
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.buffer.extend_from_slice(data);
+        let mut output = Vec::new();
+        let mut start = 0;
+        let sealed_chunk_len = self.cipher.sealed_chunk_len();
+        while self.buffer.len() - start > sealed_chunk_len {
+            let chunk = &self.buffer[start..start + sealed_chunk_len];
+            output.extend_from_slice(&self.cipher.open(chunk, false)?);
+            start += sealed_chunk_len;
+        }
+        self.buffer.drain(..start);
+        Ok(output)
+    }
+
+    /// Decrypt the last chunk, fails if the stream was truncated
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        let overhead = self.cipher.suite.overhead();
+        if self.buffer.len() < overhead {
+            return Err(Error::Authentication("Stream is truncated"));
+        }
+        if self.buffer.len() == overhead && self.cipher.counter != 0 {
+            return Err(Error::Malformed(
+                "Only an empty stream may end with an empty chunk",
+            ));
+        }
+        self.cipher.open(&self.buffer, true)
+    }
+}
+
+/// Writes the encrypted stream to `W`, for std users
+#[cfg(feature = "std")]
+pub struct StreamWriter<W: std::io::Write> {
+    inner: W,
+    encryptor: StreamEncryptor,
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write> StreamWriter<W> {
+    /// Writes the stream header right away
+    pub fn new(
+        mut inner: W,
+        encryptor: StreamEncryptor,
+        stream_header: &[u8],
+    ) -> std::io::Result<Self> {
+        inner.write_all(stream_header)?;
+        Ok(Self { inner, encryptor })
+    }
+
+    /// Write the last chunk and return the inner writer
+    pub fn finish(mut self) -> std::io::Result<W> {
+        let last = self.encryptor.finish().map_err(io_error)?;
+        self.inner.write_all(&last)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write> std::io::Write for StreamWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let output = self.encryptor.update(buf).map_err(io_error)?;
+        self.inner.write_all(&output)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads and decrypts the chunks of a stream from `R`, for std users.
+/// The stream header has to be read from `R` before, see Client::decrypt_stream_from_sender.
+#[cfg(feature = "std")]
+pub struct StreamReader<R: std::io::Read> {
+    inner: R,
+    decryptor: Option<StreamDecryptor>,
+    ciphertext: Vec<u8>,
+    plaintext: Vec<u8>,
+    position: usize,
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read> StreamReader<R> {
+    pub fn new(inner: R, decryptor: StreamDecryptor) -> Self {
+        let ciphertext = alloc::vec![0u8; decryptor.cipher.sealed_chunk_len()];
+        Self {
+            inner,
+            decryptor: Some(decryptor),
+            ciphertext,
+            plaintext: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read> std::io::Read for StreamReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.plaintext.len() {
+            let decryptor = match self.decryptor.as_mut() {
+                None => return Ok(0),
+                Some(v) => v,
+            };
+            let read = self.inner.read(&mut self.ciphertext)?;
+            self.plaintext = if read == 0 {
+                match self.decryptor.take() {
+                    None => Vec::new(),
+                    Some(v) => v.finish().map_err(io_error)?,
+                }
+            } else {
+                decryptor
+                    .update(&self.ciphertext[..read])
+                    .map_err(io_error)?
+            };
+            self.position = 0;
+        }
+        let len = buf.len().min(self.plaintext.len() - self.position);
+        buf[..len].copy_from_slice(&self.plaintext[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+#[cfg(feature = "std")]
+fn io_error(e: Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, alloc::format!("{}", e))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, KexMode};
+    use crate::message::parse_header;
+    use crate::suite::ALL_SUITES;
+
+    fn connect() -> (Client, Client) {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let offer = alice.init_kex("bob", KexMode::X25519).unwrap();
+        let response = bob.respond_kex("alice", &offer, KexMode::X25519).unwrap();
+        alice.finish_kex("bob", &response).unwrap();
+        (alice, bob)
+    }
+
+    fn encrypt(alice: &mut Client, plaintext: &[u8], piece: usize) -> (Vec<u8>, Vec<u8>) {
+        let (mut encryptor, header) = alice.encrypt_stream_for_recipient("bob").unwrap();
+        let mut chunks = Vec::new();
+        for part in plaintext.chunks(piece) {
+            chunks.extend_from_slice(&encryptor.update(part).unwrap());
+        }
+        chunks.extend_from_slice(&encryptor.finish().unwrap());
+        (header, chunks)
+    }
+
+    #[test]
+    fn test_stream() {
+        let (mut alice, mut bob) = connect();
+        for len in [0, 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE + 17] {
+            let plaintext: Vec<u8> = (0..len).map(|v| (v % 249) as u8).collect();
+            let (header, chunks) = encrypt(&mut alice, &plaintext, 10_000);
+            assert_eq!(header.len(), STREAM_HEADER_LEN);
+            let chunk_count = len.div_ceil(CHUNK_SIZE).max(1);
+            assert_eq!(chunks.len(), len + chunk_count * TAG_LEN);
+
+            let (mut decryptor, _) = bob.decrypt_stream_from_sender("alice", &header).unwrap();
+            let mut decrypted = Vec::new();
+            for part in chunks.chunks(7777) {
+                decrypted.extend_from_slice(&decryptor.update(part).unwrap());
+            }
+            decrypted.extend_from_slice(&decryptor.finish().unwrap());
+            assert_eq!(decrypted, plaintext);
+
+            // The header is only accepted once
+            assert!(bob.decrypt_stream_from_sender("alice", &header).is_err());
+        }
+    }
+
+    #[test]
+    fn test_stream_suites() {
+        for suite in ALL_SUITES {
+            let (mut alice, mut bob) = connect();
+            bob.set_cipher_suites(&[suite]).unwrap();
+            let offer = alice.init_kex("bob", KexMode::X25519).unwrap();
+            let response = bob.respond_kex("alice", &offer, KexMode::X25519).unwrap();
+            alice.finish_kex("bob", &response).unwrap();
+
+            let plaintext = alloc::vec![5u8; 2 * CHUNK_SIZE + 3];
+            let (header, chunks) = encrypt(&mut alice, &plaintext, 10_000);
+            assert_eq!(parse_header(&header).unwrap().suite, suite);
+            assert_eq!(chunks.len(), plaintext.len() + 3 * suite.overhead());
+            let (mut decryptor, _) = bob.decrypt_stream_from_sender("alice", &header).unwrap();
+            let mut decrypted = decryptor.update(&chunks).unwrap();
+            decrypted.extend_from_slice(&decryptor.finish().unwrap());
+            assert_eq!(decrypted, plaintext);
+
+            // The suite in the header has to be the one of the session
+            let (mut header, _) = encrypt(&mut alice, &plaintext, CHUNK_SIZE);
+            header[5] = if suite == CipherSuite::XChaCha20Poly1305 {
+                CipherSuite::Aes256GcmSiv.id()
+            } else {
+                CipherSuite::XChaCha20Poly1305.id()
+            };
+            assert_eq!(
+                bob.decrypt_stream_from_sender("alice", &header).err(),
+                Some(Error::Authentication(
+                    "Cipher suite does not match the session"
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn test_stream_tampering() {
+        let (mut alice, mut bob) = connect();
+        let plaintext = alloc::vec![3u8; 3 * CHUNK_SIZE];
+        let sealed_chunk = CHUNK_SIZE + TAG_LEN;
+        let mut decrypt = |header: &[u8], chunks: &[u8]| {
+            let (mut decryptor, _) = bob.decrypt_stream_from_sender("alice", header)?;
+            let mut decrypted = decryptor.update(chunks)?;
+            decrypted.extend_from_slice(&decryptor.finish()?);
+            Ok::<_, Error>(decrypted)
+        };
+
+        // Truncated at a chunk boundary
+        let (header, chunks) = encrypt(&mut alice, &plaintext, CHUNK_SIZE);
+        assert!(decrypt(&header, &chunks[..2 * sealed_chunk]).is_err());
+
+        // Chunks swapped
+        let (header, chunks) = encrypt(&mut alice, &plaintext, CHUNK_SIZE);
+        let mut swapped = chunks[sealed_chunk..2 * sealed_chunk].to_vec();
+        swapped.extend_from_slice(&chunks[..sealed_chunk]);
+        swapped.extend_from_slice(&chunks[2 * sealed_chunk..]);
+        assert!(decrypt(&header, &swapped).is_err());
+
+        // Header changed
+        let (mut header, chunks) = encrypt(&mut alice, &plaintext, CHUNK_SIZE);
+        header[HEADER_LEN] ^= 1;
+        assert!(decrypt(&header, &chunks).is_err());
+
+        // Extended after the last chunk
+        let (header, mut chunks) = encrypt(&mut alice, &plaintext, CHUNK_SIZE);
+        chunks.extend_from_slice(&[0u8; TAG_LEN]);
+        assert!(decrypt(&header, &chunks).is_err());
+
+        // Not a stream, and streams are not messages
+        let message = alice
+            .encrypt_message_for_recipient("bob", &[0u8; 64])
+            .unwrap();
+        assert!(decrypt(&message[..STREAM_HEADER_LEN], &[]).is_err());
+        let (header, chunks) = encrypt(&mut alice, &plaintext, CHUNK_SIZE);
+        let mut data = header.clone();
+        data.extend_from_slice(&chunks);
+        assert!(bob.decrypt_message_from_sender("alice", &data).is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_stream_io() {
+        use std::io::{Read, Write};
+
+        let (mut alice, mut bob) = connect();
+        let plaintext: Vec<u8> = (0..200_000u32).map(|v| (v % 251) as u8).collect();
+        let (encryptor, header) = alice.encrypt_stream_for_recipient("bob").unwrap();
+        let mut writer = StreamWriter::new(Vec::new(), encryptor, &header).unwrap();
+        for part in plaintext.chunks(1000) {
+            writer.write_all(part).unwrap();
+        }
+        let data = writer.finish().unwrap();
+
+        let mut input = data.as_slice();
+        let mut header = [0u8; STREAM_HEADER_LEN];
+        input.read_exact(&mut header).unwrap();
+        let (decryptor, _) = bob.decrypt_stream_from_sender("alice", &header).unwrap();
+        let mut decrypted = Vec::new();
+        StreamReader::new(input, decryptor)
+            .read_to_end(&mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, plaintext);
+
+        let (_, header) = alice.encrypt_stream_for_recipient("bob").unwrap();
+        let (decryptor, _) = bob.decrypt_stream_from_sender("alice", &header).unwrap();
+        let mut truncated = &data[STREAM_HEADER_LEN..CHUNK_SIZE];
+        assert!(StreamReader::new(&mut truncated, decryptor)
+            .read_to_end(&mut Vec::new())
+            .is_err());
+    }
+}
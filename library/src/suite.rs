@@ -0,0 +1,316 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// Cipher suites for messages, streams, groups, envelopes and password protected exports
+//
+// Ids are registered here and never reused. Sessions use the suite negotiated in the key
+// exchange: the initiator offers its suites, the responder picks the first of its own that
+// was offered. Both lists are signed with the kex packets, so they cannot be downgraded.
+// Streams use the suite of their session. Sender key groups use the suite of each sender chain,
+// MLS groups the suite of their creator; members only install suites they accept.
+//
+// The committing suite puts SHA3-256(label || key || nonce) in front of the ciphertext and
+// encrypts under a key derived the same way, so a ciphertext only decrypts under one key.
+
+use aes_gcm_siv::Aes256GcmSiv;
+use alloc::vec::Vec;
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::{AeadInPlace, KeyInit, XChaCha20Poly1305};
+use sha3::{Digest, Sha3_256};
+
+use crate::error::{Error, Result};
+
+pub const TAG_LEN: usize = 16;
+const COMMITMENT_LEN: usize = 32;
+
+/// AEAD used to encrypt a payload
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CipherSuite {
+    /// XChaCha20-Poly1305, random 24 byte nonces
+    #[default]
+    XChaCha20Poly1305 = 1,
+    /// AES-256-GCM-SIV, nonce misuse resistant, random 12 byte nonces
+    Aes256GcmSiv = 2,
+    /// XChaCha20-Poly1305 with a key commitment
+    XChaCha20Poly1305Committing = 3,
+}
+
+/// All suites, in the order they are preferred by default
+pub const ALL_SUITES: [CipherSuite; 3] = [
+    CipherSuite::XChaCha20Poly1305,
+    CipherSuite::Aes256GcmSiv,
+    CipherSuite::XChaCha20Poly1305Committing,
+];
+
+impl CipherSuite {
+    pub fn id(&self) -> u8 {
+        *self as u8
+    }
+
+    pub fn from_id(id: u8) -> Result<Self> {
+        match id {
+            1 => Ok(Self::XChaCha20Poly1305),
+            2 => Ok(Self::Aes256GcmSiv),
+            3 => Ok(Self::XChaCha20Poly1305Committing),
+            _ => Err(Error::Unsupported("Unknown cipher suite")),
+        }
+    }
+
+    pub fn nonce_len(&self) -> usize {
+        match self {
+            Self::XChaCha20Poly1305 | Self::XChaCha20Poly1305Committing => 24,
+            Self::Aes256GcmSiv => 12,
+        }
+    }
+
+    /// Bytes the ciphertext is longer than the plaintext
+    pub fn overhead(&self) -> usize {
+        match self {
+            Self::XChaCha20Poly1305 | Self::Aes256GcmSiv => TAG_LEN,
+            Self::XChaCha20Poly1305Committing => COMMITMENT_LEN + TAG_LEN,
+        }
+    }
+
+    /// Encrypt `buffer` in place
+    pub(crate) fn seal(
+        &self,
+        key: &[u8; 32],
+        nonce: &[u8],
+        associated_data: &[u8],
+        buffer: &mut Vec<u8>,
+    ) -> Result<()> {
+        if nonce.len() != self.nonce_len() {
+            return Err(Error::Malformed("Invalid nonce length"));
+        }
+        match self {
+            Self::XChaCha20Poly1305 => XChaCha20Poly1305::new(GenericArray::from_slice(key))
+                .encrypt_in_place(GenericArray::from_slice(nonce), associated_data, buffer)
+                .map_err(|_| Error::Internal("Encryption failed")),
+            Self::Aes256GcmSiv => Aes256GcmSiv::new(GenericArray::from_slice(key))
+                .encrypt_in_place(GenericArray::from_slice(nonce), associated_data, buffer)
+                .map_err(|_| Error::Internal("Encryption failed")),
+            Self::XChaCha20Poly1305Committing => {
+                let (commitment, key) = commit(key, nonce);
+                XChaCha20Poly1305::new(GenericArray::from_slice(&key))
+                    .encrypt_in_place(GenericArray::from_slice(nonce), associated_data, buffer)
+                    .map_err(|_| Error::Internal("Encryption failed"))?;
+                buffer.splice(0..0, commitment);
+                Ok(())
+            }
+        }
+    }
+
+    /// Decrypt `buffer` in place
+    pub(crate) fn open(
+        &self,
+        key: &[u8; 32],
+        nonce: &[u8],
+        associated_data: &[u8],
+        buffer: &mut Vec<u8>,
+    ) -> Result<()> {
+        if nonce.len() != self.nonce_len() {
+            return Err(Error::Malformed("Invalid nonce length"));
+        }
+        match self {
+            Self::XChaCha20Poly1305 => XChaCha20Poly1305::new(GenericArray::from_slice(key))
+                .decrypt_in_place(GenericArray::from_slice(nonce), associated_data, buffer)
+                .map_err(Error::from),
+            Self::Aes256GcmSiv => Aes256GcmSiv::new(GenericArray::from_slice(key))
+                .decrypt_in_place(GenericArray::from_slice(nonce), associated_data, buffer)
+                .map_err(Error::from),
+            Self::XChaCha20Poly1305Committing => {
+                if buffer.len() < COMMITMENT_LEN + TAG_LEN {
+                    return Err(Error::Malformed("Ciphertext is too short"));
+                }
+                let (commitment, key) = commit(key, nonce);
+                let difference = commitment
+                    .iter()
+                    .zip(&buffer[..COMMITMENT_LEN])
+                    .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+                if difference != 0 {
+                    return Err(Error::Authentication("Key commitment does not match"));
+                }
+                buffer.drain(..COMMITMENT_LEN);
+                XChaCha20Poly1305::new(GenericArray::from_slice(&key))
+                    .decrypt_in_place(GenericArray::from_slice(nonce), associated_data, buffer)
+                    .map_err(Error::from)
+            }
+        }
+    }
+}
+
+// Commitment and encryption key for the committing suite
+fn commit(key: &[u8; 32], nonce: &[u8]) -> ([u8; COMMITMENT_LEN], [u8; 32]) {
+    let derive = |label: &[u8]| {
+        let mut hasher = Sha3_256::new();
+        hasher.update(label);
+        hasher.update(key);
+        hasher.update(nonce);
+        let mut output = [0u8; 32];
+        output.copy_from_slice(&hasher.finalize());
+        output
+    };
+    (
+        derive(b"CosmicCipher key commitment"),
+        derive(b"CosmicCipher committed key"),
+    )
+}
+
+/// Suite both sides support: the first of `ours` that is in `offered`
+pub(crate) fn negotiate(ours: &[CipherSuite], offered: &[u8]) -> Result<CipherSuite> {
+    ours.iter()
+        .find(|suite| offered.contains(&suite.id()))
+        .copied()
+        .ok_or(Error::Unsupported("No common cipher suite"))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, EnvelopeRecipient, KexMode};
+    use crate::message::parse_header;
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    #[test]
+    fn test_suites() {
+        let key = [1u8; 32];
+        for suite in ALL_SUITES {
+            assert_eq!(CipherSuite::from_id(suite.id()).unwrap(), suite);
+            let nonce = vec![2u8; suite.nonce_len()];
+            let mut buffer = b"attack at dawn".to_vec();
+            suite.seal(&key, &nonce, b"aad", &mut buffer).unwrap();
+            assert_eq!(buffer.len(), 14 + suite.overhead());
+
+            let mut opened = buffer.clone();
+            suite.open(&key, &nonce, b"aad", &mut opened).unwrap();
+            assert_eq!(opened, b"attack at dawn");
+            assert!(suite
+                .open(&key, &nonce, b"aaa", &mut buffer.clone())
+                .is_err());
+            assert!(suite
+                .open(&[3u8; 32], &nonce, b"aad", &mut buffer.clone())
+                .is_err());
+        }
+        assert!(CipherSuite::from_id(0).is_err());
+
+        // The commitment is checked before decrypting
+        let suite = CipherSuite::XChaCha20Poly1305Committing;
+        let nonce = [2u8; 24];
+        let mut buffer = b"attack at dawn".to_vec();
+        suite.seal(&key, &nonce, &[], &mut buffer).unwrap();
+        buffer[0] ^= 1;
+        assert_eq!(
+            suite
+                .open(&key, &nonce, &[], &mut buffer)
+                .unwrap_err()
+                .to_string(),
+            "Key commitment does not match"
+        );
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let offered = [3u8, 2];
+        assert_eq!(
+            negotiate(&ALL_SUITES, &offered).unwrap(),
+            CipherSuite::Aes256GcmSiv
+        );
+        assert!(negotiate(&[CipherSuite::XChaCha20Poly1305], &offered).is_err());
+    }
+
+    #[test]
+    fn test_suite_negotiation() {
+        let mut alice = Client::new_user();
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        bob.set_cipher_suites(&[
+            CipherSuite::XChaCha20Poly1305Committing,
+            CipherSuite::Aes256GcmSiv,
+        ])
+        .unwrap();
+
+        // The responder picks from the offer
+        let offer = alice.init_kex("bob", KexMode::X25519).unwrap();
+        let response = bob.respond_kex("alice", &offer, KexMode::X25519).unwrap();
+        alice.finish_kex("bob", &response).unwrap();
+        let data = alice
+            .encrypt_message_for_recipient("bob", b"hello")
+            .unwrap();
+        let header = parse_header(&data).unwrap();
+        assert_eq!(header.suite, CipherSuite::XChaCha20Poly1305Committing);
+        assert_eq!(
+            bob.decrypt_message_from_sender("alice", &data).unwrap(),
+            b"hello"
+        );
+
+        // A message claiming another suite is rejected
+        let mut downgraded = bob.encrypt_message_for_recipient("alice", b"hi").unwrap();
+        downgraded[5] = CipherSuite::XChaCha20Poly1305.id();
+        assert!(alice
+            .decrypt_message_from_sender("bob", &downgraded)
+            .is_err());
+
+        // The suite survives a session export
+        let export = alice.export_session("bob", b"password").unwrap();
+        let session_id = alice.sessions("bob")[0];
+        alice.close_session("bob", &session_id).unwrap();
+        alice.import_sessions(b"password", &export).unwrap();
+        let data = alice
+            .encrypt_message_for_recipient("bob", b"again")
+            .unwrap();
+        assert_eq!(
+            parse_header(&data).unwrap().suite,
+            CipherSuite::XChaCha20Poly1305Committing
+        );
+
+        // Nothing in common
+        alice
+            .set_cipher_suites(&[CipherSuite::XChaCha20Poly1305])
+            .unwrap();
+        let offer = alice.init_kex("bob", KexMode::X25519).unwrap();
+        assert_eq!(
+            bob.respond_kex("alice", &offer, KexMode::X25519)
+                .unwrap_err()
+                .to_string(),
+            "No common cipher suite"
+        );
+        assert!(alice.set_cipher_suites(&[]).is_err());
+    }
+
+    #[test]
+    fn test_suite_exports_and_envelopes() {
+        let mut alice = Client::new_user();
+        alice
+            .set_cipher_suites(&[CipherSuite::Aes256GcmSiv])
+            .unwrap();
+        let export = alice.export_user(b"password").unwrap();
+        assert_eq!(&export[..4], b"CCPW");
+        assert_eq!(export[4], CipherSuite::Aes256GcmSiv.id());
+        assert!(Client::import_user(b"password", &export).is_ok());
+        assert!(Client::import_user(b"wrong", &export).is_err());
+
+        let mut bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+        let offer = alice.init_kex("bob", KexMode::X25519).unwrap();
+        let response = bob.respond_kex("alice", &offer, KexMode::X25519).unwrap();
+        alice.finish_kex("bob", &response).unwrap();
+        let envelope = alice
+            .encrypt_envelope(&[EnvelopeRecipient::Peer("bob")], b"to everyone")
+            .unwrap();
+        assert_eq!(
+            bob.decrypt_envelope(&envelope).unwrap().message,
+            b"to everyone"
+        );
+
+        // Recipients only accept their own suites
+        bob.set_cipher_suites(&[CipherSuite::XChaCha20Poly1305])
+            .unwrap();
+        assert!(bob.decrypt_envelope(&envelope).is_err());
+    }
+}
@@ -0,0 +1,198 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// Interoperability with the rage implementation of age
+
+use std::io::{Read, Write};
+use std::iter;
+
+use age::secrecy::{ExposeSecret, SecretString};
+use libary::age::{self as cc_age, AgeDecryptor, AgeEncryptor, AgeIdentity, AgeRecipient};
+use libary::client::Client;
+use rand_chacha::rand_core::SeedableRng;
+
+fn csprng() -> rand_chacha::ChaChaRng {
+    rand_chacha::ChaChaRng::from_entropy()
+}
+
+// Crosses several chunk boundaries and ends in a partial chunk
+fn large_plaintext() -> Vec<u8> {
+    (0..200_000u32).map(|v| (v % 251) as u8).collect()
+}
+
+fn rage_decrypt(identity: &dyn age::Identity, data: &[u8], armored: bool) -> Vec<u8> {
+    let mut output = Vec::new();
+    if armored {
+        let decryptor = age::Decryptor::new(age::armor::ArmoredReader::new(data)).unwrap();
+        let mut reader = decryptor.decrypt(iter::once(identity)).unwrap();
+        reader.read_to_end(&mut output).unwrap();
+    } else {
+        let decryptor = age::Decryptor::new(data).unwrap();
+        let mut reader = decryptor.decrypt(iter::once(identity)).unwrap();
+        reader.read_to_end(&mut output).unwrap();
+    }
+    output
+}
+
+#[test]
+fn test_x25519_interop() {
+    let rage_identity = age::x25519::Identity::generate();
+    let rage_recipient = rage_identity.to_public();
+    let identity = cc_age::parse_identity(rage_identity.to_string().expose_secret()).unwrap();
+    let recipient = cc_age::parse_recipient(&rage_recipient.to_string()).unwrap();
+    assert_eq!(
+        cc_age::encode_recipient(&recipient).unwrap(),
+        rage_recipient.to_string()
+    );
+    assert_eq!(
+        cc_age::encode_identity(&identity).unwrap(),
+        *rage_identity.to_string().expose_secret()
+    );
+
+    for plaintext in [
+        Vec::new(),
+        b"Hello age".to_vec(),
+        vec![7u8; 65536],
+        large_plaintext(),
+    ] {
+        // rage to us
+        let data = age::encrypt(&rage_recipient, &plaintext).unwrap();
+        let decrypted =
+            cc_age::decrypt(vec![AgeIdentity::X25519(identity.clone())], &data).unwrap();
+        assert_eq!(decrypted, plaintext);
+
+        // us to rage, binary and armored
+        for armored in [false, true] {
+            let data = cc_age::encrypt(
+                &mut csprng(),
+                &[AgeRecipient::X25519(recipient)],
+                &plaintext,
+                armored,
+            )
+            .unwrap();
+            assert_eq!(rage_decrypt(&rage_identity, &data, armored), plaintext);
+        }
+    }
+
+    let armored = age::encrypt_and_armor(&rage_recipient, b"armored").unwrap();
+    let decrypted =
+        cc_age::decrypt(vec![AgeIdentity::X25519(identity)], armored.as_bytes()).unwrap();
+    assert_eq!(decrypted, b"armored");
+}
+
+#[test]
+fn test_scrypt_interop() {
+    let passphrase = "correct horse battery staple";
+
+    let mut rage_recipient =
+        age::scrypt::Recipient::new(SecretString::from(passphrase.to_string()));
+    rage_recipient.set_work_factor(10);
+    let data = age::encrypt(&rage_recipient, b"passphrase protected").unwrap();
+    let identity = AgeIdentity::Scrypt {
+        passphrase: passphrase.as_bytes().to_vec(),
+        max_work_factor: 10,
+    };
+    assert_eq!(
+        cc_age::decrypt(vec![identity], &data).unwrap(),
+        b"passphrase protected"
+    );
+    let too_expensive = AgeIdentity::Scrypt {
+        passphrase: passphrase.as_bytes().to_vec(),
+        max_work_factor: 9,
+    };
+    assert!(cc_age::decrypt(vec![too_expensive], &data).is_err());
+
+    let recipient = AgeRecipient::Scrypt {
+        passphrase: passphrase.as_bytes().to_vec(),
+        work_factor: 10,
+    };
+    let data = cc_age::encrypt(
+        &mut csprng(),
+        std::slice::from_ref(&recipient),
+        b"and back",
+        false,
+    )
+    .unwrap();
+    let mut rage_identity = age::scrypt::Identity::new(SecretString::from(passphrase.to_string()));
+    rage_identity.set_max_work_factor(10);
+    assert_eq!(rage_decrypt(&rage_identity, &data, false), b"and back");
+
+    let other = AgeRecipient::X25519(age_public_key());
+    assert!(cc_age::encrypt(&mut csprng(), &[recipient, other], b"", false).is_err());
+}
+
+fn age_public_key() -> x25519_dalek::PublicKey {
+    cc_age::parse_recipient(&age::x25519::Identity::generate().to_public().to_string()).unwrap()
+}
+
+#[test]
+fn test_streaming_and_tampering() {
+    let rage_identity = age::x25519::Identity::generate();
+    let recipient = cc_age::parse_recipient(&rage_identity.to_public().to_string()).unwrap();
+    let identity = cc_age::parse_identity(rage_identity.to_string().expose_secret()).unwrap();
+    let plaintext = large_plaintext();
+
+    // Feed the encryptor and decryptor in odd pieces
+    let (mut encryptor, mut data) =
+        AgeEncryptor::new(&mut csprng(), &[AgeRecipient::X25519(recipient)]).unwrap();
+    for piece in plaintext.chunks(9999) {
+        data.extend_from_slice(&encryptor.update(piece).unwrap());
+    }
+    data.extend_from_slice(&encryptor.finish().unwrap());
+    assert_eq!(rage_decrypt(&rage_identity, &data, false), plaintext);
+
+    let mut decryptor = AgeDecryptor::new(vec![AgeIdentity::X25519(identity.clone())]);
+    let mut decrypted = Vec::new();
+    for piece in data.chunks(7777) {
+        decrypted.extend_from_slice(&decryptor.update(piece).unwrap());
+    }
+    decrypted.extend_from_slice(&decryptor.finish().unwrap());
+    assert_eq!(decrypted, plaintext);
+
+    let decrypt = |data: &[u8]| cc_age::decrypt(vec![AgeIdentity::X25519(identity.clone())], data);
+    // Truncated at a chunk boundary
+    let header_len = data.len() - (plaintext.len() + 4 * 16);
+    assert!(decrypt(&data[..header_len + 65536 + 16]).is_err());
+    // Header and payload are authenticated
+    let mut tampered = data.clone();
+    tampered[30] ^= 1;
+    assert!(decrypt(&tampered).is_err());
+    let mut tampered = data.clone();
+    let last = tampered.len() - 1;
+    tampered[last] ^= 1;
+    assert!(decrypt(&tampered).is_err());
+    // Wrong identity
+    let other = cc_age::parse_identity(
+        age::x25519::Identity::generate()
+            .to_string()
+            .expose_secret(),
+    )
+    .unwrap();
+    assert!(cc_age::decrypt(vec![AgeIdentity::X25519(other)], &data).is_err());
+}
+
+#[test]
+fn test_client_age_identity() {
+    let client = Client::new_user();
+    let recipient: age::x25519::Recipient = client.age_recipient().unwrap().parse().unwrap();
+    let data = age::encrypt(&recipient, b"for the client").unwrap();
+    assert_eq!(client.age_decrypt(&data).unwrap(), b"for the client");
+
+    // The identity works with rage too
+    let identity: age::x25519::Identity = client.age_identity().unwrap().parse().unwrap();
+    let mut output = Vec::new();
+    let mut writer = age::Encryptor::with_recipients(iter::once(&identity.to_public() as _))
+        .unwrap()
+        .wrap_output(&mut output)
+        .unwrap();
+    writer.write_all(b"written by rage").unwrap();
+    writer.finish().unwrap();
+    assert_eq!(client.age_decrypt(&output).unwrap(), b"written by rage");
+    assert_eq!(rage_decrypt(&identity, &output, false), b"written by rage");
+}
@@ -7,16 +7,48 @@
  * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
  */
 
-use lz4_flex::compress_prepend_size;
+// Codecs compared on a typical JSON payload
+
+use libary::compression::{compress, compress_adaptive, decompress, Compression};
 
 #[test]
-fn test_lz4_compression() {
+fn test_compression_codecs() {
     let input = include_bytes!("input.json");
 
-    let compressed = compress_prepend_size(input);
-
     println!("Uncompressed: {}", input.len());
-    println!("Compressed: {}", compressed.len());
-    let ratio = input.len() as f64 / compressed.len() as f64;
-    print!("Ratio: {}", ratio);
+    for compression in [Compression::Lz4, Compression::Zstd, Compression::Deflate] {
+        let compressed = compress(compression, input);
+        let ratio = input.len() as f64 / compressed.len() as f64;
+        println!(
+            "{:?}: {} bytes, ratio {:.2}",
+            compression,
+            compressed.len(),
+            ratio
+        );
+        assert!(ratio > 1.0);
+        assert_eq!(
+            decompress(compression, &compressed, input.len()).unwrap(),
+            input
+        );
+        assert!(decompress(compression, &compressed, input.len() - 1).is_err());
+    }
+}
+
+#[test]
+fn test_compression_skipped() {
+    // Random data does not get smaller
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let input: Vec<u8> = (0..4096)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    for compression in [Compression::Lz4, Compression::Zstd, Compression::Deflate] {
+        let (used, output) = compress_adaptive(compression, &input);
+        assert_eq!(used, Compression::None);
+        assert_eq!(output, input);
+    }
 }
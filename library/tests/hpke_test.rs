@@ -0,0 +1,169 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// The vectors use the inputs of RFC 9180 appendix A.2 (DHKEM(X25519, HKDF-SHA256),
+// HKDF-SHA256, ChaCha20Poly1305) in all four modes. The outputs were generated with the
+// HPKE implementation of OpenSSL 3.5 and opened again with it.
+
+use libary::client::Client;
+use libary::hpke::{self, HpkeMode, HpkePsk};
+use rand_chacha::rand_core::SeedableRng;
+use serde_json::Value;
+
+fn hex(value: &Value) -> Vec<u8> {
+    let s = value.as_str().unwrap();
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn vectors() -> Value {
+    serde_json::from_str(include_str!("hpke_vectors.json")).unwrap()
+}
+
+#[test]
+fn test_hpke_vectors() {
+    let modes = [
+        HpkeMode::Base,
+        HpkeMode::Psk,
+        HpkeMode::Auth,
+        HpkeMode::AuthPsk,
+    ];
+    for vector in vectors()["x25519_sha256_chacha20poly1305"]
+        .as_array()
+        .unwrap()
+    {
+        let info = hex(&vector["info"]);
+        let (sk_e, pk_e) = hpke::derive_key_pair(&hex(&vector["ikmE"])).unwrap();
+        assert_eq!(pk_e.as_bytes().to_vec(), hex(&vector["pkEm"]));
+        let (sk_r, pk_r) = hpke::derive_key_pair(&hex(&vector["ikmR"])).unwrap();
+        assert_eq!(pk_r.as_bytes().to_vec(), hex(&vector["pkRm"]));
+        let sender = vector.get("ikmS").map(|ikm| {
+            let (sk_s, pk_s) = hpke::derive_key_pair(&hex(ikm)).unwrap();
+            assert_eq!(pk_s.as_bytes().to_vec(), hex(&vector["pkSm"]));
+            (sk_s, pk_s)
+        });
+        let (psk, psk_id) = match vector.get("psk") {
+            None => (None, None),
+            Some(psk) => (Some(hex(psk)), Some(hex(&vector["psk_id"]))),
+        };
+        let psk = psk.as_ref().map(|psk| HpkePsk {
+            psk,
+            psk_id: psk_id.as_ref().unwrap(),
+        });
+
+        let (enc, mut sender_context) = hpke::setup_sender_with_ephemeral(
+            &sk_e,
+            &pk_r,
+            &info,
+            psk,
+            sender.as_ref().map(|(sk, _)| sk),
+        )
+        .unwrap();
+        assert_eq!(enc.to_vec(), hex(&vector["enc"]));
+        let mode = modes[vector["mode"].as_u64().unwrap() as usize];
+        assert_eq!(sender_context.mode(), mode);
+        let mut recipient_context =
+            hpke::setup_recipient(&enc, &sk_r, &info, psk, sender.as_ref().map(|(_, pk)| pk))
+                .unwrap();
+
+        let encryptions = vector["encryptions"].as_array().unwrap();
+        let last = encryptions.last().unwrap()["seq"].as_u64().unwrap();
+        for seq in 0..=last {
+            let aad = format!("Count-{}", seq).into_bytes();
+            let pt = hex(&encryptions[0]["pt"]);
+            let ct = sender_context.seal(&aad, &pt).unwrap();
+            assert_eq!(recipient_context.open(&aad, &ct).unwrap(), pt);
+            if let Some(encryption) = encryptions.iter().find(|e| e["seq"] == seq) {
+                assert_eq!(aad, hex(&encryption["aad"]));
+                assert_eq!(ct, hex(&encryption["ct"]));
+            }
+        }
+
+        for export in vector["exports"].as_array().unwrap() {
+            let context = hex(&export["exporter_context"]);
+            let len = export["L"].as_u64().unwrap() as usize;
+            assert_eq!(
+                sender_context.export(&context, len).unwrap(),
+                hex(&export["exported_value"])
+            );
+            assert_eq!(
+                recipient_context.export(&context, len).unwrap(),
+                hex(&export["exported_value"])
+            );
+        }
+    }
+}
+
+#[test]
+fn test_hpke_single_shot() {
+    let mut csprng = rand_chacha::ChaChaRng::from_entropy();
+    let (sk_r, pk_r) = hpke::derive_key_pair(b"recipient key material").unwrap();
+    let (sk_s, pk_s) = hpke::derive_key_pair(b"sender key material").unwrap();
+    let psk = HpkePsk {
+        psk: &[7u8; 32],
+        psk_id: b"psk",
+    };
+
+    let data = hpke::seal(
+        &mut csprng,
+        &pk_r,
+        b"info",
+        b"aad",
+        b"Hello",
+        Some(psk),
+        Some(&sk_s),
+    )
+    .unwrap();
+    let opened = hpke::open(&sk_r, b"info", b"aad", &data, Some(psk), Some(&pk_s)).unwrap();
+    assert_eq!(opened, b"Hello");
+
+    // Any difference in the inputs fails
+    assert!(hpke::open(&sk_r, b"other", b"aad", &data, Some(psk), Some(&pk_s)).is_err());
+    assert!(hpke::open(&sk_r, b"info", b"other", &data, Some(psk), Some(&pk_s)).is_err());
+    assert!(hpke::open(&sk_r, b"info", b"aad", &data, None, Some(&pk_s)).is_err());
+    assert!(hpke::open(&sk_r, b"info", b"aad", &data, Some(psk), Some(&pk_r)).is_err());
+    assert!(hpke::open(&sk_r, b"info", b"aad", &data, Some(psk), None).is_err());
+
+    let short_psk = HpkePsk {
+        psk: &[7u8; 16],
+        psk_id: b"psk",
+    };
+    assert!(hpke::seal(&mut csprng, &pk_r, b"", b"", b"", Some(short_psk), None).is_err());
+}
+
+#[test]
+fn test_hpke_certified_identities() {
+    let mut alice = Client::new_user();
+    let bob = Client::import_instance(&alice.generate_instance().unwrap()).unwrap();
+    let mallory = Client::new_user();
+
+    let bob_certificate = bob.certificate();
+    let alice_certificate = alice.certificate();
+    let data = alice
+        .hpke_seal(&bob_certificate, b"mailbox", b"", b"While you were away")
+        .unwrap();
+    let opened = bob
+        .hpke_open(&alice_certificate, b"mailbox", b"", &data)
+        .unwrap();
+    assert_eq!(opened, b"While you were away");
+
+    assert!(alice
+        .hpke_seal(&mallory.certificate(), b"mailbox", b"", b"")
+        .is_err());
+    assert!(bob
+        .hpke_open(&bob_certificate, b"mailbox", b"", &data)
+        .is_err());
+
+    // The static key is bound to the identity, it cannot be swapped
+    let mut swapped = bob_certificate;
+    swapped.static_key = mallory.certificate().static_key;
+    assert!(alice.hpke_seal(&swapped, b"mailbox", b"", b"").is_err());
+}
@@ -0,0 +1,66 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// The ML-KEM-768 vectors were generated with OpenSSL 3.5
+// (genpkey -pkeyopt hexseed, pkeyutl -encap and pkeyutl -decap),
+// the combiner vector with Python's hashlib.
+
+use libary::client::combine_hybrid_secrets;
+use libary::mlkem;
+use serde_json::Value;
+use x25519_dalek::PublicKey;
+
+fn hex(value: &Value) -> Vec<u8> {
+    let s = value.as_str().unwrap();
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn array32(value: &Value) -> [u8; 32] {
+    hex(value).try_into().unwrap()
+}
+
+fn vectors() -> Value {
+    serde_json::from_str(include_str!("kex_vectors.json")).unwrap()
+}
+
+#[test]
+fn test_mlkem768_vectors() {
+    for vector in vectors()["mlkem768"].as_array().unwrap() {
+        let seed: [u8; mlkem::SEED_LEN] = hex(&vector["seed"]).try_into().unwrap();
+        let (ek, dk) = mlkem::generate_from_seed(&seed);
+        assert_eq!(ek.as_bytes(), hex(&vector["encapsulation_key"]));
+
+        let shared_secret = mlkem::decapsulate(&dk, &hex(&vector["ciphertext"])).unwrap();
+        assert_eq!(shared_secret.to_vec(), hex(&vector["shared_secret"]));
+
+        let rejected = mlkem::decapsulate(&dk, &hex(&vector["rejected_ciphertext"])).unwrap();
+        assert_eq!(rejected.to_vec(), hex(&vector["rejected_shared_secret"]));
+    }
+}
+
+#[test]
+fn test_hybrid_combiner_vectors() {
+    for vector in vectors()["hybrid_combiner"].as_array().unwrap() {
+        let ek_byte = vector["encapsulation_key_byte"].as_u64().unwrap() as u8;
+        let ct_byte = vector["ciphertext_byte"].as_u64().unwrap() as u8;
+
+        let shared_key = combine_hybrid_secrets(
+            &array32(&vector["x25519_secret"]),
+            &array32(&vector["mlkem_secret"]),
+            &PublicKey::from(array32(&vector["initiator_public_key"])),
+            &PublicKey::from(array32(&vector["responder_public_key"])),
+            &[ek_byte; mlkem::ENCAPSULATION_KEY_LEN],
+            &[ct_byte; mlkem::CIPHERTEXT_LEN],
+        );
+        assert_eq!(shared_key.to_vec(), hex(&vector["shared_key"]));
+    }
+}
@@ -0,0 +1,58 @@
+/*
+ * SPDX-License-Identifier: Apache-2.0 OR MIT
+ * Copyright (c) 2024 Ferdinand Linnenberg
+ *
+ * This file is part of CosmicCipher Project, which is dual-licensed under the Apache License 2.0
+ * and the MIT License. You may choose either license to govern your use of this file.
+ * See the LICENSE-APACHE.md and LICENSE-MIT.md files in the project root for more information.
+ */
+
+// Signatures made with the CA key verify with the minisign-verify implementation
+
+use libary::client::Client;
+use libary::minisign;
+use minisign_verify::{PublicKey, Signature};
+
+#[test]
+fn test_minisign_interop() {
+    let mut user = Client::new_user();
+    let instance = Client::import_instance(&user.generate_instance().unwrap()).unwrap();
+    let tarball: Vec<u8> = (0..100_000u32).map(|v| (v % 253) as u8).collect();
+    let trusted_comment = "timestamp:1700000000\tfile:release-1.0.tar.gz";
+
+    // Every instance exports the same public key
+    let public_key_file = user.minisign_public_key().unwrap();
+    assert_eq!(instance.minisign_public_key().unwrap(), public_key_file);
+    assert!(public_key_file.starts_with("untrusted comment: minisign public key "));
+    let public_key = PublicKey::decode(&public_key_file).unwrap();
+
+    for prehashed in [true, false] {
+        let signature_file = user
+            .minisign_sign(&tarball, trusted_comment, prehashed)
+            .unwrap();
+        let signature = Signature::decode(&signature_file).unwrap();
+        assert_eq!(signature.trusted_comment(), trusted_comment);
+        public_key.verify(&tarball, &signature, !prehashed).unwrap();
+        assert!(public_key.verify(b"other", &signature, true).is_err());
+
+        assert_eq!(
+            instance.minisign_verify(&tarball, &signature_file).unwrap(),
+            trusted_comment
+        );
+        assert_eq!(
+            minisign::verify(&public_key_file, &tarball, &signature_file).unwrap(),
+            trusted_comment
+        );
+    }
+
+    // Only the user holds the CA key
+    assert!(instance.minisign_sign(&tarball, "", true).is_err());
+    assert!(user.minisign_sign(&tarball, "two\nlines", true).is_err());
+
+    // Signatures from another CA are rejected
+    let other = Client::new_user();
+    let signature_file = other
+        .minisign_sign(&tarball, trusted_comment, true)
+        .unwrap();
+    assert!(instance.minisign_verify(&tarball, &signature_file).is_err());
+}
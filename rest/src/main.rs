@@ -9,6 +9,7 @@
 
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use axum::extract::State;
 use axum::routing::put;
@@ -18,14 +19,52 @@ use axum::{
     Json, Router,
 };
 use base64::prelude::*;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 
-use libary::client::Client;
+use libary::client::{Client, Error, KexMode, MessageOptions};
+use libary::kex::KexMachine;
+use libary::sealed_sender;
+
+// Sealed sender messages waiting in a mailbox before it refuses more
+const MAX_MAILBOX_LEN: usize = 1000;
+// Sealed sender messages are dropped undelivered after this many seconds in a mailbox
+const MAX_MESSAGE_AGE_SECS: u64 = 7 * 24 * 60 * 60;
 
 #[derive(Clone)]
 struct AppState {
     data: Arc<Mutex<HashMap<String, Client>>>,
+    // Key exchanges we started, by (username, recipient username). Lock after data.
+    exchanges: Arc<Mutex<HashMap<(String, String), KexMachine>>>,
+    // Sealed sender mailboxes by recipient, the relay does not know who sent the messages.
+    // Lock after data.
+    mailboxes: Arc<Mutex<HashMap<String, Mailbox>>>,
+}
+
+// The clients have no clock of their own
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// HTTP status for a library error, by its kind
+fn status(e: &Error) -> StatusCode {
+    match e {
+        Error::Authentication(_) => StatusCode::UNAUTHORIZED,
+        Error::UntrustedKey(_) => StatusCode::FORBIDDEN,
+        Error::Malformed(_) | Error::InvalidArgument(_) | Error::Decompression(_) => {
+            StatusCode::BAD_REQUEST
+        }
+        Error::Unsupported(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        Error::NoSession(_) | Error::NotFound(_) => StatusCode::NOT_FOUND,
+        Error::NoCa(_) | Error::InvalidState(_) | Error::Replay(_) => StatusCode::CONFLICT,
+        Error::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        Error::Expired(_) => StatusCode::GONE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
 }
 
 #[tokio::main]
@@ -35,6 +74,8 @@ async fn main() {
 
     let state = AppState {
         data: Arc::new(Mutex::new(HashMap::new())),
+        exchanges: Arc::new(Mutex::new(HashMap::new())),
+        mailboxes: Arc::new(Mutex::new(HashMap::new())),
     };
 
     let app = Router::new()
@@ -42,12 +83,22 @@ async fn main() {
         .route("/user", post(new_user))
         .route("/user", get(export_user))
         .route("/user", put(import_user))
+        .route("/sessions", get(export_sessions))
+        .route("/sessions", put(import_sessions))
         .route("/instance", post(generate_instance))
         .route("/instance", put(import_instance))
         .route("/kex", get(init_kex))
         .route("/kex", put(finish_kex))
+        .route("/kex/respond", post(respond_kex))
         .route("/encrypt", get(encrypt))
         .route("/decrypt", get(decrypt))
+        .route("/sign", get(sign))
+        .route("/verify", get(verify))
+        .route("/sealed/encrypt", get(seal_sender))
+        .route("/sealed/decrypt", get(open_sealed_sender))
+        .route("/relay/mailbox", post(open_mailbox))
+        .route("/relay", post(relay))
+        .route("/relay", get(fetch_relayed))
         .with_state(state);
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
@@ -91,8 +142,8 @@ async fn export_user(
     let export = client
         .export_user(payload.password.as_bytes())
         .map_err(|e| {
-            format!("export failed: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
+            tracing::error!("export failed: {}", e);
+            status(&e)
         })?;
     Ok(Json(ExportedUser {
         export: BASE64_STANDARD.encode(export.as_slice()),
@@ -114,18 +165,70 @@ async fn import_user(
     let export = BASE64_STANDARD
         .decode(payload.export.as_bytes())
         .map_err(|e| {
-            format!("decode failed: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
+            tracing::error!("decode failed: {}", e);
+            StatusCode::BAD_REQUEST
         })?;
     let client =
         Client::import_user(payload.password.as_bytes(), export.as_slice()).map_err(|e| {
-            format!("import failed: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
+            tracing::error!("import failed: {}", e);
+            status(&e)
         })?;
     data.insert(payload.username.clone(), client);
     Ok(StatusCode::CREATED)
 }
 
+#[derive(Deserialize)]
+struct ExportSessions {
+    username: String,
+    password: String,
+    // Only export the session with this peer
+    peer_username: Option<String>,
+}
+
+async fn export_sessions(
+    State(state): State<AppState>,
+    Json(payload): Json<ExportSessions>,
+) -> Result<Json<ExportedUser>, StatusCode> {
+    let mut data = state.data.lock().await;
+    let client: &mut Client = data
+        .get_mut(&payload.username)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let export = match &payload.peer_username {
+        None => client.export_sessions(payload.password.as_bytes()),
+        Some(peer) => client.export_session(peer, payload.password.as_bytes()),
+    }
+    .map_err(|e| {
+        tracing::error!("export failed: {}", e);
+        status(&e)
+    })?;
+    Ok(Json(ExportedUser {
+        export: BASE64_STANDARD.encode(export.as_slice()),
+    }))
+}
+
+async fn import_sessions(
+    State(state): State<AppState>,
+    Json(payload): Json<ImportUser>,
+) -> Result<StatusCode, StatusCode> {
+    let mut data = state.data.lock().await;
+    let export = BASE64_STANDARD
+        .decode(payload.export.as_bytes())
+        .map_err(|e| {
+            tracing::error!("decode failed: {}", e);
+            StatusCode::BAD_REQUEST
+        })?;
+    let client: &mut Client = data
+        .get_mut(&payload.username)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    client
+        .import_sessions(payload.password.as_bytes(), export.as_slice())
+        .map_err(|e| {
+            tracing::error!("import failed: {}", e);
+            status(&e)
+        })?;
+    Ok(StatusCode::CREATED)
+}
+
 #[derive(Deserialize)]
 struct GenerateInstance {
     owner_username: String,
@@ -145,8 +248,8 @@ async fn generate_instance(
         .get_mut(&payload.owner_username)
         .ok_or(StatusCode::NOT_FOUND)?;
     let instance = client.generate_instance().map_err(|e| {
-        format!("generate failed: {}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
+        tracing::error!("generate failed: {}", e);
+        status(&e)
     })?;
     Ok(Json(GeneratedInstance {
         instance: BASE64_STANDARD.encode(instance.as_slice()),
@@ -167,12 +270,12 @@ async fn import_instance(
     let instance = BASE64_STANDARD
         .decode(payload.instance.as_bytes())
         .map_err(|e| {
-            format!("decode failed: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
+            tracing::error!("decode failed: {}", e);
+            StatusCode::BAD_REQUEST
         })?;
     let client = Client::import_instance(instance.as_slice()).map_err(|e| {
-        format!("import failed: {}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
+        tracing::error!("import failed: {}", e);
+        status(&e)
     })?;
     data.insert(payload.instance_username.clone(), client);
     Ok(StatusCode::CREATED)
@@ -182,6 +285,8 @@ async fn import_instance(
 struct InitKex {
     username: String,
     recipient_username: String,
+    #[serde(default)]
+    hybrid: bool,
 }
 
 #[derive(Serialize)]
@@ -197,16 +302,25 @@ async fn init_kex(
     let client: &mut Client = data
         .get_mut(&payload.username)
         .ok_or(StatusCode::NOT_FOUND)?;
-    let kex = client
-        .init_dh_kex(&payload.recipient_username)
-        .map_err(|e| {
-            format!("init failed: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
+    let mode = if payload.hybrid {
+        KexMode::HybridMlKem768
+    } else {
+        KexMode::X25519
+    };
+    let mut machine =
+        KexMachine::initiator(client, &payload.recipient_username, mode).map_err(|e| {
+            tracing::error!("init failed: {}", e);
+            status(&e)
         })?;
-    let kex_packet = client.generate_kex_packet(kex.0, kex.1).map_err(|e| {
-        format!("generate failed: {}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    let kex_packet = machine
+        .poll_transmit()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    // A new exchange with the same recipient replaces the old one
+    state
+        .exchanges
+        .lock()
+        .await
+        .insert((payload.username, payload.recipient_username), machine);
 
     Ok(Json(KexPacket {
         kex: BASE64_STANDARD.encode(kex_packet.as_slice()),
@@ -228,30 +342,80 @@ async fn finish_kex(
     let kex_packet = BASE64_STANDARD
         .decode(payload.kex_packet.as_bytes())
         .map_err(|e| {
-            format!("decode failed: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
+            tracing::error!("decode failed: {}", e);
+            StatusCode::BAD_REQUEST
         })?;
     let client: &mut Client = data
         .get_mut(&payload.username)
         .ok_or(StatusCode::NOT_FOUND)?;
-    let (pubk, sigpk, vk, sig) = Client::unpack_kex_packet(kex_packet.as_slice()).map_err(|e| {
-        format!("unpack failed: {}", e);
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
-    client
-        .complete_dh_kex(&payload.recipient_username, &pubk, sigpk, vk, sig)
+    let mut exchanges = state.exchanges.lock().await;
+    let key = (payload.username, payload.recipient_username);
+    let machine = exchanges.get_mut(&key).ok_or(StatusCode::CONFLICT)?;
+    // A rejected packet leaves the machine as it was, the real response can still come
+    machine
+        .handle_input(client, kex_packet.as_slice())
         .map_err(|e| {
-            format!("complete failed: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
+            tracing::error!("complete failed: {}", e);
+            status(&e)
         })?;
+    exchanges.remove(&key);
     Ok(StatusCode::CREATED)
 }
 
+#[derive(Deserialize)]
+struct RespondKex {
+    username: String,
+    recipient_username: String,
+    kex_packet: String,
+    #[serde(default)]
+    require_hybrid: bool,
+}
+
+async fn respond_kex(
+    State(state): State<AppState>,
+    Json(payload): Json<RespondKex>,
+) -> Result<Json<KexPacket>, StatusCode> {
+    let mut data = state.data.lock().await;
+    let kex_packet = BASE64_STANDARD
+        .decode(payload.kex_packet.as_bytes())
+        .map_err(|e| {
+            tracing::error!("decode failed: {}", e);
+            StatusCode::BAD_REQUEST
+        })?;
+    let client: &mut Client = data
+        .get_mut(&payload.username)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let min_mode = if payload.require_hybrid {
+        KexMode::HybridMlKem768
+    } else {
+        KexMode::X25519
+    };
+    let mut machine = KexMachine::responder(&payload.recipient_username, min_mode);
+    machine
+        .handle_input(client, kex_packet.as_slice())
+        .map_err(|e| {
+            tracing::error!("respond failed: {}", e);
+            status(&e)
+        })?;
+    let response = machine
+        .poll_transmit()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(KexPacket {
+        kex: BASE64_STANDARD.encode(response.as_slice()),
+    }))
+}
+
 #[derive(Deserialize)]
 struct Encrypt {
     username: String,
     recipient_username: String,
     plaintext: String,
+    // Context the ciphertext is bound to, decrypt needs the same
+    #[serde(default)]
+    aad: Option<String>,
+    // Decrypt refuses the message this many seconds from now
+    #[serde(default)]
+    ttl_secs: Option<u64>,
 }
 
 #[derive(Serialize)]
@@ -267,11 +431,28 @@ async fn encrypt(
     let client: &mut Client = data
         .get_mut(&payload.username)
         .ok_or(StatusCode::NOT_FOUND)?;
+    client.set_time(unix_now());
+    let session_id = client
+        .sessions(&payload.recipient_username)
+        .last()
+        .copied()
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let aad = payload.aad.unwrap_or_default();
+    let options = MessageOptions {
+        aad: aad.as_bytes(),
+        ttl_secs: payload.ttl_secs,
+        ..Default::default()
+    };
     let ciphertext = client
-        .encrypt_message_for_recipient(&payload.recipient_username, payload.plaintext.as_bytes())
+        .encrypt_message_with_options(
+            &payload.recipient_username,
+            &session_id,
+            payload.plaintext.as_bytes(),
+            &options,
+        )
         .map_err(|e| {
-            format!("encrypt failed: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
+            tracing::error!("encrypt failed: {}", e);
+            status(&e)
         })?;
     Ok(Json(Ciphertext {
         ciphertext: BASE64_STANDARD.encode(ciphertext.as_slice()),
@@ -283,6 +464,8 @@ struct Decrypt {
     username: String,
     sender_username: String,
     ciphertext: String,
+    #[serde(default)]
+    aad: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -298,19 +481,351 @@ async fn decrypt(
     let ciphertext = BASE64_STANDARD
         .decode(payload.ciphertext.as_bytes())
         .map_err(|e| {
-            format!("decode failed: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
+            tracing::error!("decode failed: {}", e);
+            StatusCode::BAD_REQUEST
         })?;
     let client: &mut Client = data
         .get_mut(&payload.username)
         .ok_or(StatusCode::NOT_FOUND)?;
+    client.set_time(unix_now());
+    let aad = payload.aad.unwrap_or_default();
     let plaintext = client
-        .decrypt_message_from_sender(&payload.sender_username, ciphertext.as_slice())
+        .decrypt_message_with_aad(
+            &payload.sender_username,
+            ciphertext.as_slice(),
+            aad.as_bytes(),
+        )
         .map_err(|e| {
-            format!("decrypt failed: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
+            tracing::error!("decrypt failed: {}", e);
+            status(&e)
         })?;
     Ok(Json(Plaintext {
         plaintext: String::from_utf8_lossy(plaintext.as_slice()).to_string(),
     }))
 }
+
+#[derive(Deserialize)]
+struct Sign {
+    username: String,
+    // Purpose of the signature, it only verifies under the same context
+    context: String,
+    message: String,
+    // Embed the message in the signature
+    #[serde(default)]
+    attached: bool,
+}
+
+#[derive(Serialize)]
+struct SignatureResponse {
+    signature: String,
+}
+
+async fn sign(
+    State(state): State<AppState>,
+    Json(payload): Json<Sign>,
+) -> Result<Json<SignatureResponse>, StatusCode> {
+    let data = state.data.lock().await;
+    let client: &Client = data.get(&payload.username).ok_or(StatusCode::NOT_FOUND)?;
+    let signature = if payload.attached {
+        client.sign_attached(&payload.context, payload.message.as_bytes())
+    } else {
+        client.sign_detached(&payload.context, payload.message.as_bytes())
+    }
+    .map_err(|e| {
+        tracing::error!("sign failed: {}", e);
+        status(&e)
+    })?;
+    Ok(Json(SignatureResponse {
+        signature: BASE64_STANDARD.encode(signature.as_slice()),
+    }))
+}
+
+#[derive(Deserialize)]
+struct Verify {
+    username: String,
+    context: String,
+    // Required for detached signatures, attached ones carry the message
+    message: Option<String>,
+    signature: String,
+}
+
+#[derive(Serialize)]
+struct Verified {
+    signer: String,
+    timestamp: Option<u64>,
+    message: String,
+}
+
+async fn verify(
+    State(state): State<AppState>,
+    Json(payload): Json<Verify>,
+) -> Result<Json<Verified>, StatusCode> {
+    let data = state.data.lock().await;
+    let signature = BASE64_STANDARD
+        .decode(payload.signature.as_bytes())
+        .map_err(|e| {
+            tracing::error!("decode failed: {}", e);
+            StatusCode::BAD_REQUEST
+        })?;
+    let client: &Client = data.get(&payload.username).ok_or(StatusCode::NOT_FOUND)?;
+    let (info, message) = match payload.message {
+        None => client.verify_attached(&payload.context, signature.as_slice()),
+        Some(message) => client
+            .verify_detached(&payload.context, message.as_bytes(), signature.as_slice())
+            .map(|info| (info, message.into_bytes())),
+    }
+    .map_err(|e| {
+        tracing::error!("verify failed: {}", e);
+        status(&e)
+    })?;
+    Ok(Json(Verified {
+        signer: BASE64_STANDARD.encode(info.signer.as_bytes()),
+        timestamp: info.timestamp,
+        message: String::from_utf8_lossy(message.as_slice()).to_string(),
+    }))
+}
+
+#[derive(Deserialize)]
+struct SealSender {
+    username: String,
+    recipient_username: String,
+    plaintext: String,
+    // Unix seconds until the sender certificate is valid
+    expires_at: u64,
+}
+
+async fn seal_sender(
+    State(state): State<AppState>,
+    Json(payload): Json<SealSender>,
+) -> Result<Json<Ciphertext>, StatusCode> {
+    let mut data = state.data.lock().await;
+    let recipient_certificate = data
+        .get(&payload.recipient_username)
+        .ok_or(StatusCode::NOT_FOUND)?
+        .certificate();
+    let client: &mut Client = data
+        .get_mut(&payload.username)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let certificate = client
+        .sender_certificate(&payload.username, payload.expires_at)
+        .map_err(|e| {
+            tracing::error!("certificate failed: {}", e);
+            status(&e)
+        })?;
+    let ciphertext = client
+        .seal_sender(
+            &payload.recipient_username,
+            &recipient_certificate,
+            &certificate,
+            payload.plaintext.as_bytes(),
+        )
+        .map_err(|e| {
+            tracing::error!("seal failed: {}", e);
+            status(&e)
+        })?;
+    Ok(Json(Ciphertext {
+        ciphertext: BASE64_STANDARD.encode(ciphertext.as_slice()),
+    }))
+}
+
+#[derive(Deserialize)]
+struct OpenSealedSender {
+    username: String,
+    ciphertext: String,
+}
+
+#[derive(Serialize)]
+struct SealedPlaintext {
+    sender_username: String,
+    plaintext: String,
+}
+
+async fn open_sealed_sender(
+    State(state): State<AppState>,
+    Json(payload): Json<OpenSealedSender>,
+) -> Result<Json<SealedPlaintext>, StatusCode> {
+    let mut data = state.data.lock().await;
+    let ciphertext = BASE64_STANDARD
+        .decode(payload.ciphertext.as_bytes())
+        .map_err(|e| {
+            tracing::error!("decode failed: {}", e);
+            StatusCode::BAD_REQUEST
+        })?;
+    let client: &mut Client = data
+        .get_mut(&payload.username)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let opened = client
+        .open_sealed_sender(ciphertext.as_slice())
+        .map_err(|e| {
+            tracing::error!("open failed: {}", e);
+            status(&e)
+        })?;
+    Ok(Json(SealedPlaintext {
+        sender_username: opened.sender,
+        plaintext: String::from_utf8_lossy(opened.message.as_slice()).to_string(),
+    }))
+}
+
+#[derive(Deserialize)]
+struct OpenMailbox {
+    username: String,
+    // Delivery tokens of earlier epochs stop working
+    epoch: u64,
+    // Needed to rotate the token of an existing mailbox
+    #[serde(default)]
+    fetch_secret: Option<String>,
+}
+
+// The delivery token goes to the contacts, the fetch secret stays with the recipient
+#[derive(Serialize)]
+struct OpenedMailbox {
+    delivery_token: String,
+    fetch_secret: String,
+}
+
+// Opens a mailbox or rotates its delivery token, messages already waiting are kept
+async fn open_mailbox(
+    State(state): State<AppState>,
+    Json(payload): Json<OpenMailbox>,
+) -> Result<Json<OpenedMailbox>, StatusCode> {
+    let data = state.data.lock().await;
+    let client: &Client = data.get(&payload.username).ok_or(StatusCode::NOT_FOUND)?;
+    let mut mailboxes = state.mailboxes.lock().await;
+    let fetch_secret = match mailboxes.get(&payload.username) {
+        None => {
+            let mut secret = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut secret);
+            secret
+        }
+        Some(mailbox) => {
+            let secret = decode_secret(payload.fetch_secret.as_deref())?;
+            if !mailbox.check_fetch_secret(&secret) {
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+            secret
+        }
+    };
+    let mailbox = mailboxes
+        .entry(payload.username)
+        .or_insert_with(|| Mailbox {
+            verifier: [0u8; 32],
+            fetch_secret,
+            messages: Vec::new(),
+        });
+    mailbox.verifier = client.delivery_token_verifier(payload.epoch);
+    Ok(Json(OpenedMailbox {
+        delivery_token: BASE64_STANDARD.encode(client.delivery_token(payload.epoch)),
+        fetch_secret: BASE64_STANDARD.encode(fetch_secret),
+    }))
+}
+
+fn decode_secret(secret: Option<&str>) -> Result<[u8; 32], StatusCode> {
+    let secret = BASE64_STANDARD
+        .decode(secret.ok_or(StatusCode::UNAUTHORIZED)?.as_bytes())
+        .map_err(|e| {
+            tracing::error!("decode failed: {}", e);
+            StatusCode::BAD_REQUEST
+        })?;
+    secret.try_into().map_err(|_| StatusCode::UNAUTHORIZED)
+}
+
+// Only the recipient is named, the delivery token shows the sender is a contact without saying which
+#[derive(Deserialize)]
+struct Relay {
+    recipient_username: String,
+    delivery_token: String,
+    ciphertext: String,
+}
+
+async fn relay(
+    State(state): State<AppState>,
+    Json(payload): Json<Relay>,
+) -> Result<StatusCode, StatusCode> {
+    let token = BASE64_STANDARD
+        .decode(payload.delivery_token.as_bytes())
+        .map_err(|e| {
+            tracing::error!("decode failed: {}", e);
+            StatusCode::BAD_REQUEST
+        })?;
+    let now = unix_now();
+    let mut mailboxes = state.mailboxes.lock().await;
+    let mailbox = mailboxes
+        .get_mut(&payload.recipient_username)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if !sealed_sender::check_delivery_token(&mailbox.verifier, &token) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    mailbox.messages.retain(|relayed| !relayed.is_stale(now));
+    if mailbox.messages.len() >= MAX_MAILBOX_LEN {
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+    mailbox.messages.push(Relayed {
+        ciphertext: payload.ciphertext,
+        received_at: now,
+    });
+    Ok(StatusCode::ACCEPTED)
+}
+
+struct Mailbox {
+    // Checks the delivery token of the current epoch
+    verifier: [u8; 32],
+    fetch_secret: [u8; 32],
+    messages: Vec<Relayed>,
+}
+
+impl Mailbox {
+    fn check_fetch_secret(&self, secret: &[u8; 32]) -> bool {
+        // Looks at every byte so the time taken does not tell how much was right
+        self.fetch_secret
+            .iter()
+            .zip(secret)
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+}
+
+struct Relayed {
+    ciphertext: String,
+    received_at: u64,
+}
+
+impl Relayed {
+    // The expiry inside the ciphertext is not visible here, recipients check it when decrypting
+    fn is_stale(&self, now: u64) -> bool {
+        now >= self.received_at.saturating_add(MAX_MESSAGE_AGE_SECS)
+    }
+}
+
+#[derive(Deserialize)]
+struct FetchRelayed {
+    username: String,
+    fetch_secret: String,
+}
+
+#[derive(Serialize)]
+struct RelayedMessages {
+    ciphertexts: Vec<String>,
+}
+
+// Empties the mailbox, stale messages are dropped undelivered
+async fn fetch_relayed(
+    State(state): State<AppState>,
+    Json(payload): Json<FetchRelayed>,
+) -> Result<Json<RelayedMessages>, StatusCode> {
+    let secret = decode_secret(Some(&payload.fetch_secret))?;
+    let now = unix_now();
+    let mut mailboxes = state.mailboxes.lock().await;
+    let mailbox = mailboxes
+        .get_mut(&payload.username)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if !mailbox.check_fetch_secret(&secret) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(Json(RelayedMessages {
+        ciphertexts: core::mem::take(&mut mailbox.messages)
+            .into_iter()
+            .filter(|relayed| !relayed.is_stale(now))
+            .map(|relayed| relayed.ciphertext)
+            .collect(),
+    }))
+}
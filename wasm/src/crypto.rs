@@ -10,95 +10,221 @@
 use base64::prelude::*;
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
 use wasm_bindgen::prelude::*;
 
-use libary::client::Client;
+use libary::client::{Client, Error, KexMode, StreamDecryptor, StreamEncryptor};
+use libary::kex::KexMachine;
+use libary::stream::STREAM_HEADER_LEN;
+
+static CLIENT: Lazy<Mutex<HashMap<String, Client>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+// Key exchanges we started, by (username, recipient username)
+static KEX: Lazy<Mutex<HashMap<(String, String), KexMachine>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// wasm is single threaded, a poisoned lock only means a previous call panicked
+fn clients() -> MutexGuard<'static, HashMap<String, Client>> {
+    CLIENT.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn exchanges() -> MutexGuard<'static, HashMap<(String, String), KexMachine>> {
+    KEX.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn user_not_found() -> JsValue {
+    js_error(Error::NotFound("User not found"))
+}
+
+// Start an exchange, a new one with the same recipient replaces the old one
+fn init_kex(username: &str, recipient_username: &str, mode: KexMode) -> Result<String, JsValue> {
+    let mut machine = match clients().get_mut(username) {
+        None => return Err(user_not_found()),
+        Some(v) => KexMachine::initiator(v, recipient_username, mode).map_err(js_error)?,
+    };
+    let kex_packet = machine
+        .poll_transmit()
+        .ok_or_else(|| js_error(Error::Internal("No key exchange offer")))?;
+    exchanges().insert(
+        (username.to_string(), recipient_username.to_string()),
+        machine,
+    );
+    Ok(BASE64_STANDARD.encode(kex_packet.as_slice()))
+}
+
+// A JS Error named after the kind of failure, e.g. AuthenticationError,
+// with the stable code of the library error in `code`
+fn js_error(e: Error) -> JsValue {
+    let error = js_sys::Error::new(e.message());
+    error.set_name(match e {
+        Error::Authentication(_) => "AuthenticationError",
+        Error::Malformed(_) => "MalformedInputError",
+        Error::Unsupported(_) => "UnsupportedError",
+        Error::NoSession(_) => "NoSessionError",
+        Error::NoCa(_) => "NoCaError",
+        Error::UntrustedKey(_) => "UntrustedKeyError",
+        Error::NotFound(_) => "NotFoundError",
+        Error::Decompression(_) => "DecompressionError",
+        Error::TooLarge(_) => "TooLargeError",
+        Error::Replay(_) => "ReplayError",
+        Error::Expired(_) => "ExpiredError",
+        Error::InvalidArgument(_) => "InvalidArgumentError",
+        Error::InvalidState(_) => "InvalidStateError",
+        _ => "Error",
+    });
+    // Only fails for frozen objects
+    let _ = js_sys::Reflect::set(&error, &"code".into(), &e.code().into());
+    error.into()
+}
 
-static mut CLIENT: Lazy<HashMap<String, Client>> = Lazy::new(HashMap::new);
 #[wasm_bindgen]
 pub fn new_user(username: &str) {
-    unsafe {
-        CLIENT.insert(username.to_string(), Client::new_user());
-    }
+    clients().insert(username.to_string(), Client::new_user());
 }
 
 #[wasm_bindgen]
-pub fn export_user(username: &str, password: &str) -> Result<String, JsError> {
-    let export;
-    unsafe {
-        match CLIENT.get_mut(username) {
-            None => {
-                return Err(JsError::new(&format!("User {} not found", username)));
-            }
-            Some(v) => {
-                export = v
-                    .export_user(password.as_bytes())
-                    .map_err(|e| JsError::new(&format!("{}", e)))?;
-            }
+pub fn export_user(username: &str, password: &str) -> Result<String, JsValue> {
+    let export = match clients().get_mut(username) {
+        None => {
+            return Err(user_not_found());
         }
-    }
-    return Ok(BASE64_STANDARD.encode(export.as_slice()));
+        Some(v) => v.export_user(password.as_bytes()).map_err(js_error)?,
+    };
+    Ok(BASE64_STANDARD.encode(export.as_slice()))
 }
 
 #[wasm_bindgen]
-pub fn import_user(username: &str, password: &str, export: &str) -> Result<(), JsError> {
+pub fn import_user(username: &str, password: &str, export: &str) -> Result<(), JsValue> {
     let export = BASE64_STANDARD
         .decode(export.as_bytes())
-        .map_err(|e| JsError::new(&format!("{}", e)))?;
-    unsafe {
-        let u = Client::import_user(password.as_bytes(), export.as_slice())
-            .map_err(|e| JsError::new(&format!("{}", e)))?;
-        CLIENT.insert(username.to_string(), u);
-    }
+        .map_err(|_| js_error(Error::Malformed("Invalid base64")))?;
+    let u = Client::import_user(password.as_bytes(), export.as_slice()).map_err(js_error)?;
+    clients().insert(username.to_string(), u);
     Ok(())
 }
 
 #[wasm_bindgen]
-pub fn generate_instance(owner_username: &str) -> Result<String, JsError> {
-    let instance;
-    unsafe {
-        match CLIENT.get_mut(owner_username) {
-            None => {
-                return Err(JsError::new(&format!("User {} not found", owner_username)));
-            }
-            Some(v) => {
-                instance = v
-                    .generate_instance()
-                    .map_err(|e| JsError::new(&format!("{}", e)))?;
-            }
+pub fn export_sessions(username: &str, password: &str) -> Result<String, JsValue> {
+    let export = match clients().get_mut(username) {
+        None => {
+            return Err(user_not_found());
+        }
+        Some(v) => v.export_sessions(password.as_bytes()).map_err(js_error)?,
+    };
+    Ok(BASE64_STANDARD.encode(export.as_slice()))
+}
+
+#[wasm_bindgen]
+pub fn export_session(
+    username: &str,
+    peer_username: &str,
+    password: &str,
+) -> Result<String, JsValue> {
+    let export = match clients().get_mut(username) {
+        None => {
+            return Err(user_not_found());
         }
+        Some(v) => v
+            .export_session(peer_username, password.as_bytes())
+            .map_err(js_error)?,
+    };
+    Ok(BASE64_STANDARD.encode(export.as_slice()))
+}
+
+#[wasm_bindgen]
+pub fn import_sessions(username: &str, password: &str, export: &str) -> Result<(), JsValue> {
+    let export = BASE64_STANDARD
+        .decode(export.as_bytes())
+        .map_err(|_| js_error(Error::Malformed("Invalid base64")))?;
+    match clients().get_mut(username) {
+        None => Err(user_not_found()),
+        Some(v) => v
+            .import_sessions(password.as_bytes(), export.as_slice())
+            .map_err(js_error),
     }
+}
+
+#[wasm_bindgen]
+pub fn generate_instance(owner_username: &str) -> Result<String, JsValue> {
+    let instance = match clients().get_mut(owner_username) {
+        None => {
+            return Err(user_not_found());
+        }
+        Some(v) => v.generate_instance().map_err(js_error)?,
+    };
     Ok(BASE64_STANDARD.encode(instance.as_slice()))
 }
 
 #[wasm_bindgen]
-pub fn import_instance(instance_username: &str, instance: &str) -> Result<(), JsError> {
+pub fn import_instance(instance_username: &str, instance: &str) -> Result<(), JsValue> {
     let instance = BASE64_STANDARD
         .decode(instance.as_bytes())
-        .map_err(|e| JsError::new(&format!("{}", e)))?;
-    unsafe {
-        let instance = Client::import_instance(instance.as_slice())
-            .map_err(|e| JsError::new(&format!("{}", e)))?;
-        CLIENT.insert(instance_username.to_string(), instance);
-    }
+        .map_err(|_| js_error(Error::Malformed("Invalid base64")))?;
+    let instance = Client::import_instance(instance.as_slice()).map_err(js_error)?;
+    clients().insert(instance_username.to_string(), instance);
     Ok(())
 }
 
+// There is no clock in the library, expiring messages cannot be read before this is called
 #[wasm_bindgen]
-pub fn init_dh_kex(username: &str, recipient_username: &str) -> Result<String, JsError> {
-    unsafe {
-        return match CLIENT.get_mut(username) {
-            None => Err(JsError::new(&format!("User {} not found", username))),
-            Some(v) => {
-                let kexdata = v
-                    .init_dh_kex(recipient_username)
-                    .map_err(|e| JsError::new(&format!("{}", e)))?;
-                let kex_packet = v
-                    .generate_kex_packet(kexdata.0, kexdata.1)
-                    .map_err(|e| JsError::new(&format!("{}", e)))?;
-                Ok(BASE64_STANDARD.encode(kex_packet.as_slice()))
-            }
-        };
+pub fn set_time(username: &str, unix_secs: u64) -> Result<(), JsValue> {
+    match clients().get_mut(username) {
+        None => Err(user_not_found()),
+        Some(v) => {
+            v.set_time(unix_secs);
+            Ok(())
+        }
+    }
+}
+
+// Time-to-live of the messages from encrypt, needs set_time
+#[wasm_bindgen]
+pub fn set_message_ttl(username: &str, ttl_secs: Option<u64>) -> Result<(), JsValue> {
+    match clients().get_mut(username) {
+        None => Err(user_not_found()),
+        Some(v) => {
+            v.set_message_ttl(ttl_secs);
+            Ok(())
+        }
+    }
+}
+
+#[wasm_bindgen]
+pub fn init_dh_kex(username: &str, recipient_username: &str) -> Result<String, JsValue> {
+    init_kex(username, recipient_username, KexMode::X25519)
+}
+
+#[wasm_bindgen]
+pub fn init_hybrid_kex(username: &str, recipient_username: &str) -> Result<String, JsValue> {
+    init_kex(username, recipient_username, KexMode::HybridMlKem768)
+}
+
+#[wasm_bindgen]
+pub fn respond_kex(
+    username: &str,
+    recipient_username: &str,
+    kex_packet: &str,
+    require_hybrid: bool,
+) -> Result<String, JsValue> {
+    let kex_packet = BASE64_STANDARD
+        .decode(kex_packet.as_bytes())
+        .map_err(|_| js_error(Error::Malformed("Invalid base64")))?;
+    let min_mode = if require_hybrid {
+        KexMode::HybridMlKem768
+    } else {
+        KexMode::X25519
+    };
+    match clients().get_mut(username) {
+        None => Err(user_not_found()),
+        Some(v) => {
+            let mut machine = KexMachine::responder(recipient_username, min_mode);
+            machine
+                .handle_input(v, kex_packet.as_slice())
+                .map_err(js_error)?;
+            let response = machine
+                .poll_transmit()
+                .ok_or_else(|| js_error(Error::Internal("No key exchange response")))?;
+            Ok(BASE64_STANDARD.encode(response.as_slice()))
+        }
     }
 }
 
@@ -107,21 +233,25 @@ pub fn finalize_dh_kex(
     username: &str,
     recipient_username: &str,
     kex_packet: &str,
-) -> Result<(), JsError> {
+) -> Result<(), JsValue> {
     let kex_packet = BASE64_STANDARD
         .decode(kex_packet.as_bytes())
-        .map_err(|e| JsError::new(&format!("{}", e)))?;
-    unsafe {
-        return match CLIENT.get_mut(username) {
-            None => Err(JsError::new(&format!("User {} not found", username))),
-            Some(v) => {
-                let (pubk, sigpk, vk, sig) = Client::unpack_kex_packet(kex_packet.as_slice())
-                    .map_err(|e| JsError::new(&format!("{}", e)))?;
-                v.complete_dh_kex(recipient_username, &pubk, sigpk, vk, sig)
-                    .map_err(|e| JsError::new(&format!("{}", e)))?;
-                Ok(())
-            }
-        };
+        .map_err(|_| js_error(Error::Malformed("Invalid base64")))?;
+    match clients().get_mut(username) {
+        None => Err(user_not_found()),
+        Some(v) => {
+            let key = (username.to_string(), recipient_username.to_string());
+            let mut exchanges = exchanges();
+            let machine = exchanges
+                .get_mut(&key)
+                .ok_or_else(|| js_error(Error::InvalidState("No key exchange started")))?;
+            // A rejected packet leaves the machine as it was, the real response can still come
+            machine
+                .handle_input(v, kex_packet.as_slice())
+                .map_err(js_error)?;
+            exchanges.remove(&key);
+            Ok(())
+        }
     }
 }
 
@@ -130,34 +260,177 @@ pub fn encrypt(
     username: &str,
     recipient_username: &str,
     plaintext: &str,
-) -> Result<String, JsError> {
-    unsafe {
-        return match CLIENT.get_mut(username) {
-            None => Err(JsError::new(&format!("User {} not found", username))),
-            Some(v) => {
-                let ciphertext = v
-                    .encrypt_message_for_recipient(recipient_username, plaintext.as_bytes())
-                    .map_err(|e| JsError::new(&format!("{}", e)))?;
-                Ok(BASE64_STANDARD.encode(ciphertext.as_slice()))
-            }
-        };
+    aad: Option<String>,
+) -> Result<String, JsValue> {
+    let aad = aad.unwrap_or_default();
+    match clients().get_mut(username) {
+        None => Err(user_not_found()),
+        Some(v) => {
+            let ciphertext = v
+                .encrypt_message_with_aad(recipient_username, plaintext.as_bytes(), aad.as_bytes())
+                .map_err(js_error)?;
+            Ok(BASE64_STANDARD.encode(ciphertext.as_slice()))
+        }
     }
 }
 
 #[wasm_bindgen]
-pub fn decrypt(username: &str, sender_username: &str, ciphertext: &str) -> Result<String, JsError> {
+pub fn decrypt(
+    username: &str,
+    sender_username: &str,
+    ciphertext: &str,
+    aad: Option<String>,
+) -> Result<String, JsValue> {
+    let aad = aad.unwrap_or_default();
     let ciphertext = BASE64_STANDARD
         .decode(ciphertext.as_bytes())
-        .map_err(|e| JsError::new(&format!("{}", e)))?;
-    unsafe {
-        return match CLIENT.get_mut(username) {
-            None => Err(JsError::new(&format!("User {} not found", username))),
-            Some(v) => {
-                let plaintext = v
-                    .decrypt_message_from_sender(sender_username, ciphertext.as_slice())
-                    .map_err(|e| JsError::new(&format!("{}", e)))?;
-                Ok(String::from_utf8(plaintext).map_err(|e| JsError::new(&format!("{}", e)))?)
+        .map_err(|_| js_error(Error::Malformed("Invalid base64")))?;
+    match clients().get_mut(username) {
+        None => Err(user_not_found()),
+        Some(v) => {
+            let plaintext = v
+                .decrypt_message_with_aad(sender_username, ciphertext.as_slice(), aad.as_bytes())
+                .map_err(js_error)?;
+            Ok(String::from_utf8(plaintext)
+                .map_err(|_| js_error(Error::Malformed("Message is not valid UTF-8")))?)
+        }
+    }
+}
+
+#[wasm_bindgen]
+pub fn sign(
+    username: &str,
+    context: &str,
+    message: &str,
+    attached: bool,
+) -> Result<String, JsValue> {
+    match clients().get(username) {
+        None => Err(user_not_found()),
+        Some(v) => {
+            let signature = if attached {
+                v.sign_attached(context, message.as_bytes())
+            } else {
+                v.sign_detached(context, message.as_bytes())
             }
-        };
+            .map_err(js_error)?;
+            Ok(BASE64_STANDARD.encode(signature.as_slice()))
+        }
+    }
+}
+
+/// Returns the signer's public key
+#[wasm_bindgen]
+pub fn verify_detached(
+    username: &str,
+    context: &str,
+    message: &str,
+    signature: &str,
+) -> Result<String, JsValue> {
+    let signature = BASE64_STANDARD
+        .decode(signature.as_bytes())
+        .map_err(|_| js_error(Error::Malformed("Invalid base64")))?;
+    match clients().get(username) {
+        None => Err(user_not_found()),
+        Some(v) => {
+            let info = v
+                .verify_detached(context, message.as_bytes(), signature.as_slice())
+                .map_err(js_error)?;
+            Ok(BASE64_STANDARD.encode(info.signer.as_bytes()))
+        }
+    }
+}
+
+/// Returns the signed message
+#[wasm_bindgen]
+pub fn verify_attached(username: &str, context: &str, signed: &str) -> Result<String, JsValue> {
+    let signed = BASE64_STANDARD
+        .decode(signed.as_bytes())
+        .map_err(|_| js_error(Error::Malformed("Invalid base64")))?;
+    match clients().get(username) {
+        None => Err(user_not_found()),
+        Some(v) => {
+            let (_, message) = v
+                .verify_attached(context, signed.as_slice())
+                .map_err(js_error)?;
+            Ok(String::from_utf8(message)
+                .map_err(|_| js_error(Error::Malformed("Message is not valid UTF-8")))?)
+        }
+    }
+}
+
+/// Incremental encryption of a large message, see encrypt_stream
+#[wasm_bindgen]
+pub struct EncryptStream {
+    encryptor: StreamEncryptor,
+    header: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl EncryptStream {
+    /// Goes in front of the output of update and finish
+    pub fn header(&self) -> Vec<u8> {
+        self.header.clone()
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>, JsValue> {
+        self.encryptor.update(data).map_err(js_error)
+    }
+
+    pub fn finish(self) -> Result<Vec<u8>, JsValue> {
+        self.encryptor.finish().map_err(js_error)
+    }
+}
+
+#[wasm_bindgen]
+pub fn encrypt_stream(username: &str, recipient_username: &str) -> Result<EncryptStream, JsValue> {
+    match clients().get_mut(username) {
+        None => Err(user_not_found()),
+        Some(v) => {
+            let (encryptor, header) = v
+                .encrypt_stream_for_recipient(recipient_username)
+                .map_err(js_error)?;
+            Ok(EncryptStream { encryptor, header })
+        }
+    }
+}
+
+/// Incremental decryption of a large message, see decrypt_stream
+#[wasm_bindgen]
+pub struct DecryptStream {
+    decryptor: StreamDecryptor,
+}
+
+#[wasm_bindgen]
+impl DecryptStream {
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>, JsValue> {
+        self.decryptor.update(data).map_err(js_error)
+    }
+
+    /// Fails if the stream was truncated
+    pub fn finish(self) -> Result<Vec<u8>, JsValue> {
+        self.decryptor.finish().map_err(js_error)
+    }
+}
+
+/// `header` is the first stream_header_len() bytes of the stream
+#[wasm_bindgen]
+pub fn decrypt_stream(
+    username: &str,
+    sender_username: &str,
+    header: &[u8],
+) -> Result<DecryptStream, JsValue> {
+    match clients().get_mut(username) {
+        None => Err(user_not_found()),
+        Some(v) => {
+            let (decryptor, _) = v
+                .decrypt_stream_from_sender(sender_username, header)
+                .map_err(js_error)?;
+            Ok(DecryptStream { decryptor })
+        }
     }
 }
+
+#[wasm_bindgen]
+pub fn stream_header_len() -> usize {
+    STREAM_HEADER_LEN
+}